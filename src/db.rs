@@ -1,12 +1,12 @@
 use ::metrics::increment_counter;
 use crossbeam::select;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use lru::LruCache;
 use metrics::{absolute_counter, gauge};
 use rmp_serde::encode::Error;
 use std::borrow::{Borrow, BorrowMut};
 use std::cell::RefCell;
-use std::collections::{hash_set, HashMap, HashSet, VecDeque};
+use std::collections::{hash_set, BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs::{self, File};
 use std::io::Read;
 use std::num::{NonZeroIsize, NonZeroUsize};
@@ -20,91 +20,154 @@ use crossbeam::channel::{
 use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread::{spawn, JoinHandle};
 use std::time::{Duration, Instant, SystemTime};
-use std::{sync, thread};
+use std::{iter, sync, thread};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde_json::{from_str, to_string};
 
 use key::Key;
 use memtable::Memtable;
-use value::Value;
+use value::{Value, VALUE_SIZE_LIMIT};
 
+use crate::db::column_family::{
+    ColumnFamily, ColumnFamilyChange, ColumnFamilyId, DEFAULT_COLUMN_FAMILY_ID,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+use crate::db::common::{IterError, KVIterItem, SortedKVIter};
 use crate::db::db_metrics::{
     COMPACT_COUNT, CURRENT_LEVEL_DEPTH, READ_HIT_MEMTABLE_COUNTER, READ_REQUEST_COUNT,
-    READ_REQUEST_TIME, WRITE_REQUEST_COUNT, WRITE_WAIT_FOR_COMAPCT,
+    READ_REQUEST_TIME, WRITE_REQUEST_COUNT, WRITE_TRANSACTION_CONFLICT_COUNT,
+    WRITE_WAIT_FOR_COMAPCT,
 };
+use crate::db::error::{BackgroundError, ConflictError, CorruptionError, TransactionTooOldError};
 use crate::db::file_storage::{FileId, FileStorageManager, ThreadSafeFileManager};
 use crate::db::level::{Level, LevelChange, SStableFileMeta};
+use crate::db::lock_manager::LockManager;
 use crate::db::memtable_log::MemtableLog;
 use crate::db::meta_log::MetaLog;
 use crate::db::sstable::SSTable;
 use crate::db::version::Version;
 
-use self::config::Config;
+use self::config::{Config, ReadOptions};
+use self::db_iter::DBIter;
 use self::db_metrics::{DBMetric, TimeRecorder, WRITE_REQUEST_TIME};
+use self::key::{SeqNumber, MAX_SEQUENCE};
 use self::memtable::MemtableIter;
 use self::memtable_log::MemtableLogReader;
 use self::meta_log::MetaLogIter;
-use self::sstable::SStableBlockMeta;
+use self::snapshot::{Snapshot, SnapshotList};
+use self::sstable::{SStableBlockMeta, ThreadSafeBlockCache};
+use self::transaction::{ConflictCheck, Transaction};
 use self::write_batch::{Operation, WriteBatch};
 
+mod blob;
+pub mod column_family;
 mod common;
+pub mod compaction_filter;
 pub mod config;
+pub mod db_iter;
 mod db_metrics;
 pub mod debug_util;
+pub mod error;
 mod file_storage;
 pub mod key;
 mod level;
+pub mod lock_manager;
+mod log_record;
 mod memtable;
 mod memtable_log;
+pub mod merge_operator;
 mod meta_log;
+mod range_tombstone;
+pub mod snapshot;
 mod sstable;
+pub mod transaction;
 pub mod value;
 pub mod write_batch;
 
 mod version;
 
-// (memtable,immutable_memtable,version)
+// (memtable,immutable memtables from new to old,version)
 type ThreadSafeData = Arc<
     RwLock<(
         Arc<Mutex<Arc<Memtable>>>,
-        Option<Arc<Memtable>>,
+        Vec<Arc<Memtable>>,
         Arc<Mutex<Arc<Version>>>,
     )>,
 >;
+// column families of db by id, default family is always in it
+type ColumnFamilies = Arc<BTreeMap<ColumnFamilyId, Arc<ColumnFamily>>>;
+// first error of flush or compaction thread, new writes fail with it
+type BackgroundErrorSlot = Arc<Mutex<Option<BackgroundError>>>;
+
+fn set_background_error(slot: &BackgroundErrorSlot, err: &anyhow::Error) {
+    error!("background thread stops with error: {:#}", err);
+    let mut slot = slot.lock().unwrap();
+    if slot.is_none() {
+        *slot = Some(BackgroundError::new(format!("{:#}", err)));
+    }
+}
 pub fn new_sstable_cache(config: &Config) -> Arc<Mutex<LruCache<FileId, Arc<SStableBlockMeta>>>> {
-    let sstable_cache = Arc::new(Mutex::new(LruCache::new(
+    Arc::new(Mutex::new(LruCache::new(
         NonZeroUsize::new(config.sstable_meta_cache).unwrap(),
-    )));
-    sstable_cache
+    )))
+}
+
+pub fn new_block_cache(config: &Config) -> ThreadSafeBlockCache {
+    SSTable::new_block_cache(config.block_cache)
 }
 
 pub struct DBServer {
     path: PathBuf,
+    // data of default column family
     data: ThreadSafeData,
+    column_families: ColumnFamilies,
     write_request_sender: Sender<WriteRequest>,
     config: Config,
     metrics: Arc<DBMetric>,
+    snapshot_list: Arc<SnapshotList>,
+    // row locks of pessimistic transactions of all clients
+    lock_manager: Arc<LockManager>,
     thread_handles: Vec<JoinHandle<Result<()>>>,
+    // used by compact range, it runs in caller thread
+    meta_log: Arc<Mutex<MetaLog>>,
+    file_id_inc_sender: Sender<(ColumnFamilyId, HashSet<FileId>)>,
+    // number of memtable switches not flushed, memtables of all column families are switched at the same time
+    flush_condition_pair: Arc<(Mutex<usize>, Condvar)>,
+    background_error: BackgroundErrorSlot,
 }
 
 pub struct DBClient {
     data: ThreadSafeData,
-    finish_notify_sender: Sender<()>,
-    finish_notify_receiver: Receiver<()>,
+    column_families: ColumnFamilies,
+    finish_notify_sender: Sender<Result<()>>,
+    finish_notify_receiver: Receiver<Result<()>>,
     write_request_sender: Sender<WriteRequest>,
+    snapshot_list: Arc<SnapshotList>,
+    lock_manager: Arc<LockManager>,
+    config: Config,
 }
 
 pub struct WriteRequest {
     wirte_batch: WriteBatch,
-    finish: Sender<()>,
+    // error is sent if batch is not written
+    finish: Sender<Result<()>>,
+    // sequence number of first operation in batch, assigned when batch is written to log
+    sequence: SeqNumber,
+    // move memtable to immutable memtables after batch, finished after memtable is moved
+    flush: bool,
+    // batch of transaction is written only if keys it read are not changed
+    conflict_check: Option<ConflictCheck>,
 }
 
 impl WriteRequest {
-    pub fn new(sender: Sender<()>, write_batch: WriteBatch) -> Self {
+    pub fn new(sender: Sender<Result<()>>, write_batch: WriteBatch) -> Self {
         WriteRequest {
             wirte_batch: write_batch,
             finish: sender,
+            sequence: 0,
+            flush: false,
+            conflict_check: None,
         }
     }
 }
@@ -113,17 +176,57 @@ impl WriteRequest {
 #[derive(Debug)]
 pub struct MyError {}
 
-fn get_current_data(data: &ThreadSafeData) -> (Arc<Memtable>, Option<Arc<Memtable>>, Arc<Version>) {
+fn get_current_data(data: &ThreadSafeData) -> (Arc<Memtable>, Vec<Arc<Memtable>>, Arc<Version>) {
     let read_res = data.read().unwrap();
     let (a, b, c) = read_res.deref();
     let memtable = a.lock().unwrap().clone();
-    let imm_memtable = if let Some(n) = b {
-        Some(n.clone())
-    } else {
-        None
-    };
+    let imm_memtables = b.clone();
     let version = c.lock().unwrap().clone();
-    (memtable, imm_memtable, version)
+    (memtable, imm_memtables, version)
+}
+
+// newest version of key which sequence number is not greater than seq
+fn get_with_sequence(
+    data: &ThreadSafeData,
+    key: &Key,
+    seq: SeqNumber,
+    options: &ReadOptions,
+) -> Result<Option<Value>> {
+    let recorder = TimeRecorder::new(READ_REQUEST_TIME);
+    increment_counter!(READ_REQUEST_COUNT);
+
+    let (memtable, immutable_memtables, version) = get_current_data(data);
+    // merge operands found before value, combined with it by version
+    let mut operands = Vec::new();
+    // search in current memtable, then immutable memtables from new to old
+    for memtable in iter::once(&memtable).chain(&immutable_memtables) {
+        if let Some(res) = memtable.get(key, seq, &mut operands) {
+            increment_counter!(READ_HIT_MEMTABLE_COUNTER);
+            return version.merge(key, res, operands);
+        }
+    }
+
+    // search in current version
+    version.get_with_operands(key, seq, operands, options)
+}
+
+fn scan_with_sequence(
+    data: &ThreadSafeData,
+    start_key: &Key,
+    end_key: Option<&Key>,
+    seq: SeqNumber,
+    options: &ReadOptions,
+) -> Result<DBIter> {
+    let (memtable, immutable_memtables, version) = get_current_data(data);
+    DBIter::new(
+        &memtable,
+        &immutable_memtables,
+        version,
+        start_key,
+        end_key,
+        seq,
+        options,
+    )
 }
 
 impl DBClient {
@@ -132,37 +235,107 @@ impl DBClient {
     }
 
     pub fn get(&self, key: &Key) -> Result<Option<Value>> {
-        let recorder = TimeRecorder::new(READ_REQUEST_TIME);
-        increment_counter!(READ_REQUEST_COUNT);
+        self.get_with_options(key, &ReadOptions::new())
+    }
 
-        let (memtable, immutable_memtable, version) = get_current_data(&self.data);
-        // search in current memtable
-        let res = memtable.get(&key);
-        if res.is_some() {
-            increment_counter!(READ_HIT_MEMTABLE_COUNTER);
-            if let Some(v) = res.unwrap() {
-                return Ok(Some(v));
-            } else {
-                return Ok(None);
-            }
-        }
+    pub fn get_with_options(&self, key: &Key, options: &ReadOptions) -> Result<Option<Value>> {
+        get_with_sequence(&self.data, key, MAX_SEQUENCE, options)
+    }
 
-        // search in immutable memtable
-        if let Some(memtable) = immutable_memtable.as_deref() {
-            let res = memtable.get(key);
-            if res.is_some() {
-                increment_counter!(READ_HIT_MEMTABLE_COUNTER);
-                if let Some(v) = res.unwrap() {
-                    return Ok(Some(v));
-                } else {
-                    return Ok(None);
-                }
-            }
-        }
+    // iter all kvs in key order
+    pub fn iter(&self) -> Result<DBIter> {
+        scan_with_sequence(
+            &self.data,
+            &Key::new(""),
+            None,
+            self.snapshot_list.last_sequence(),
+            &ReadOptions::new(),
+        )
+    }
+
+    // iter kvs which key is in [start_key,end_key) in key order
+    pub fn scan(&self, start_key: &Key, end_key: &Key) -> Result<DBIter> {
+        self.scan_with_options(start_key, end_key, &ReadOptions::new())
+    }
+
+    pub fn scan_with_options(
+        &self,
+        start_key: &Key,
+        end_key: &Key,
+        options: &ReadOptions,
+    ) -> Result<DBIter> {
+        // writes after iter is created are invisible, memtable held by iter may still be written
+        let seq = self.snapshot_list.last_sequence();
+        scan_with_sequence(&self.data, start_key, Some(end_key), seq, options)
+    }
 
-        // search in current version
-        let res = version.get(&key);
-        res
+    // id of column family with name, none if it is not opened
+    pub fn column_family_id(&self, name: &str) -> Option<ColumnFamilyId> {
+        self.column_families
+            .values()
+            .find(|family| family.name() == name)
+            .map(|family| family.id())
+    }
+
+    pub fn get_cf(&self, column_family: ColumnFamilyId, key: &Key) -> Result<Option<Value>> {
+        let family = self.column_family(column_family)?;
+        get_with_sequence(family.data(), key, MAX_SEQUENCE, &ReadOptions::new())
+    }
+
+    // iter all kvs of column family in key order
+    pub fn iter_cf(&self, column_family: ColumnFamilyId) -> Result<DBIter> {
+        let family = self.column_family(column_family)?;
+        let seq = self.snapshot_list.last_sequence();
+        scan_with_sequence(family.data(), &Key::new(""), None, seq, &ReadOptions::new())
+    }
+
+    // iter kvs of column family which key is in [start_key,end_key) in key order
+    pub fn scan_cf(
+        &self,
+        column_family: ColumnFamilyId,
+        start_key: &Key,
+        end_key: &Key,
+    ) -> Result<DBIter> {
+        let family = self.column_family(column_family)?;
+        let seq = self.snapshot_list.last_sequence();
+        scan_with_sequence(
+            family.data(),
+            start_key,
+            Some(end_key),
+            seq,
+            &ReadOptions::new(),
+        )
+    }
+
+    fn column_family(&self, column_family: ColumnFamilyId) -> Result<&Arc<ColumnFamily>> {
+        self.column_families
+            .get(&column_family)
+            .ok_or_else(|| anyhow!("column family {} is not found", column_family))
+    }
+
+    // reads of snapshot only see writes finished before it is created
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.data.clone(), self.snapshot_list.clone())
+    }
+
+    pub fn snapshot_cf(&self, column_family: ColumnFamilyId) -> Result<Snapshot> {
+        let family = self.column_family(column_family)?;
+        Ok(Snapshot::new(
+            family.data().clone(),
+            self.snapshot_list.clone(),
+        ))
+    }
+
+    // transaction reads db at the time it begins, keys are checked on commit or locked by transaction_mode of config
+    pub fn begin_transaction(&self) -> Transaction {
+        Transaction::new(
+            self.config.transaction_mode,
+            self.data.clone(),
+            self.snapshot_list.clone(),
+            self.write_request_sender.clone(),
+            self.lock_manager.clone(),
+            self.config.lock_timeout,
+        )
     }
 
     pub fn delete(&mut self, key: &Key) -> Result<()> {
@@ -171,21 +344,102 @@ impl DBClient {
         self.put_impl(batch)
     }
 
+    // delete keys in [start_key,end_key)
+    pub fn delete_range(&mut self, start_key: &Key, end_key: &Key) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(start_key.clone(), end_key.clone());
+        self.put_impl(batch)
+    }
+
+    pub fn delete_range_cf(
+        &mut self,
+        column_family: ColumnFamilyId,
+        start_key: &Key,
+        end_key: &Key,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range_cf(column_family, start_key.clone(), end_key.clone());
+        self.put_impl(batch)
+    }
+
     pub fn put(&mut self, key: &Key, value: Value) -> Result<()> {
         let mut batch = WriteBatch::new();
         batch.put(key.clone(), value);
         self.put_impl(batch)
     }
+    pub fn put_cf(&mut self, column_family: ColumnFamilyId, key: &Key, value: Value) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf(column_family, key.clone(), value);
+        self.put_impl(batch)
+    }
+    pub fn delete_cf(&mut self, column_family: ColumnFamilyId, key: &Key) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_cf(column_family, key.clone());
+        self.put_impl(batch)
+    }
+    // operand is combined by merge operator of column family config
+    pub fn merge_cf(
+        &mut self,
+        column_family: ColumnFamilyId,
+        key: &Key,
+        operand: Value,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge_cf(column_family, key.clone(), operand);
+        self.put_impl(batch)
+    }
+    // value is read as deleted after ttl, it is removed when its sstable is compacted
+    pub fn put_with_ttl(&mut self, key: &Key, value: Value, ttl: Duration) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(key.clone(), value, ttl);
+        self.put_impl(batch)
+    }
+    pub fn put_with_ttl_cf(
+        &mut self,
+        column_family: ColumnFamilyId,
+        key: &Key,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl_cf(column_family, key.clone(), value, ttl);
+        self.put_impl(batch)
+    }
+    // operand is combined with value of key by merge operator of config when key is read or compacted
+    pub fn merge(&mut self, key: &Key, operand: Value) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key.clone(), operand);
+        self.put_impl(batch)
+    }
     pub fn write_batch(&mut self, write_batch: WriteBatch) -> Result<()> {
         self.put_impl(write_batch)
     }
     fn put_impl(&mut self, write_batch: WriteBatch) -> Result<()> {
+        check_write_batch(&write_batch)?;
+        for (column_family, _) in write_batch.iter() {
+            self.column_family(column_family)?;
+        }
         let time_recorder = TimeRecorder::new(WRITE_REQUEST_TIME);
         let write_request = WriteRequest::new(self.finish_notify_sender.clone(), write_batch);
         self.write_request_sender.send(write_request).unwrap();
-        let _ = self.finish_notify_receiver.recv()?;
-        Ok(())
+        self.finish_notify_receiver.recv()?
+    }
+}
+
+fn check_write_batch(write_batch: &WriteBatch) -> Result<()> {
+    // operands are never moved to blob file, they must fit in sstable block
+    for op in write_batch.to_opertions() {
+        if let Operation::MERGE { value, .. } = op {
+            if value.len() >= VALUE_SIZE_LIMIT {
+                return Err(anyhow!(
+                    "merge operand size {} is not less than {}",
+                    value.len(),
+                    VALUE_SIZE_LIMIT
+                ));
+            }
+        }
     }
+    Ok(())
 }
 
 impl DBServer {
@@ -193,39 +447,79 @@ impl DBServer {
         let file_storage = FileStorageManager::from(path.clone())?;
         let thread_safe_file_storage = Arc::new(Mutex::new(file_storage));
 
-        let memtable = Self::build_memtable(&path, &config)?;
         let (s, r) = unbounded();
-        let veresion = Self::build_version(&path, &config, thread_safe_file_storage.clone(), s)?;
+        let recorded = Self::read_column_families(&path, &config)?;
+        let versions = Self::build_versions(
+            &path,
+            &config,
+            thread_safe_file_storage.clone(),
+            s,
+            recorded,
+        )?;
+        let log_numbers = versions
+            .iter()
+            .map(|(id, (_, version))| (*id, version.memtable_log_number()))
+            .collect();
+        let mut memtables = Self::build_memtables(&path, &config, &log_numbers)?;
+        // sstable being written when db crashed is not in version
+        Self::delete_unused_files(&path, &Self::all_file_ids(&versions))?;
 
         let file_manager = Arc::new(Mutex::new(FileStorageManager::from(path.clone())?));
-        Self::new_impl(path, config, file_manager, memtable, veresion, r)
+        let families = versions
+            .into_iter()
+            .map(|(id, (name, version))| (id, (name, memtables.remove(&id).unwrap(), version)))
+            .collect();
+        Self::new_impl(path, config, file_manager, families, r)
     }
 
-    fn build_memtable(path: &Path, config: &Config) -> Result<Memtable> {
-        let memtable_log_path = path.to_path_buf().join(&config.memtable_log_file_path);
-        let memtable_log_file = File::open(memtable_log_path)?;
-        let memtable_log_iter = MemtableLogReader::new(memtable_log_file)?;
-
-        let memtable = Memtable::new();
-        for (k, v) in memtable_log_iter {
-            memtable.insert_option_value(&k, v.as_ref())
+    // replay memtable logs not persisted in sstable, a record is replayed only if its log is not before
+    // memtable log number of its column family
+    fn build_memtables(
+        path: &Path,
+        config: &Config,
+        memtable_log_numbers: &BTreeMap<ColumnFamilyId, u64>,
+    ) -> Result<BTreeMap<ColumnFamilyId, Memtable>> {
+        let memtables: BTreeMap<ColumnFamilyId, Memtable> = memtable_log_numbers
+            .keys()
+            .map(|id| (*id, Memtable::new()))
+            .collect();
+        let min_log_number = memtable_log_numbers.values().min().copied().unwrap_or(0);
+        for number in MemtableLog::log_numbers(path, &config.memtable_log_file_path)? {
+            if number < min_log_number {
+                continue;
+            }
+            let memtable_log_iter =
+                MemtableLogReader::open(path, &config.memtable_log_file_path, number)?;
+            for record in memtable_log_iter {
+                let (seq, column_family, op) = record?;
+                let memtable = match memtables.get(&column_family) {
+                    Some(memtable) => memtable,
+                    None => {
+                        warn!(
+                            "column family {} of memtable log record is not found, skip it",
+                            column_family
+                        );
+                        continue;
+                    }
+                };
+                if number >= memtable_log_numbers[&column_family] {
+                    write_operation_to_memtable(memtable, &op, seq);
+                }
+            }
         }
-        Ok(memtable)
+        Ok(memtables)
     }
-    fn build_version(
+
+    // (id, name, level changes) of column families in meta log
+    fn read_column_families(
         path: &Path,
         config: &Config,
-        file_storage: ThreadSafeFileManager,
-        file_id_sender: Sender<HashSet<FileId>>,
-    ) -> Result<Version> {
-        // build version from log
-        let home_path = PathBuf::from(path);
-        let meta_file_path = path.join(&config.meta_log_file_name);
-        let meta_file = File::open(meta_file_path).unwrap();
-        let iter = MetaLogIter::new(meta_file);
-
-        // build version
-        let mut level_changes = Vec::new();
+    ) -> Result<Vec<(ColumnFamilyId, String, Vec<LevelChange>)>> {
+        let iter = MetaLog::current_iter(path, &config.meta_log_file_name)?
+            .ok_or_else(|| anyhow::anyhow!("meta log is not found in {:?}", path))?;
+
+        let mut families: BTreeMap<ColumnFamilyId, (Option<String>, Vec<LevelChange>)> =
+            BTreeMap::new();
         for data_res in iter {
             match data_res {
                 Err(err) => {
@@ -233,35 +527,115 @@ impl DBServer {
                     return Err(err);
                 }
                 Ok(data) => {
-                    let level_change: LevelChange = serde_json::from_slice(&data)?;
-                    level_changes.push(level_change);
+                    let change: ColumnFamilyChange = serde_json::from_slice(&data)?;
+                    let (name, level_changes) = families.entry(change.column_family).or_default();
+                    if change.name.is_some() {
+                        *name = change.name;
+                    }
+                    level_changes.push(change.change);
                 }
             }
         }
+        families
+            .into_iter()
+            .map(|(id, (name, level_changes))| {
+                // level changes of old version are all in default family, they have no name
+                let name = match name {
+                    Some(name) => name,
+                    None if id == DEFAULT_COLUMN_FAMILY_ID => {
+                        String::from(DEFAULT_COLUMN_FAMILY_NAME)
+                    }
+                    None => {
+                        return Err(CorruptionError::new(format!(
+                            "column family {} in meta log has no name",
+                            id
+                        ))
+                        .into())
+                    }
+                };
+                Ok((id, name, level_changes))
+            })
+            .collect()
+    }
 
-        let sstable_cache = new_sstable_cache(&config);
+    // versions of column families in meta log, default family and new families in config are added
+    // new family gets id after all existing ones
+    fn build_versions(
+        path: &Path,
+        config: &Config,
+        file_storage: ThreadSafeFileManager,
+        file_id_sender: Sender<HashSet<FileId>>,
+        recorded: Vec<(ColumnFamilyId, String, Vec<LevelChange>)>,
+    ) -> Result<BTreeMap<ColumnFamilyId, (String, Version)>> {
+        // file ids are unique in db, so caches are shared by families
+        let sstable_cache = new_sstable_cache(config);
+        let block_cache = new_block_cache(config);
+        let mut versions = BTreeMap::new();
+        for (id, name, level_changes) in recorded {
+            let version = Version::from(
+                &mut level_changes.into_iter(),
+                PathBuf::from(path),
+                file_storage.clone(),
+                sstable_cache.clone(),
+                block_cache.clone(),
+                file_id_sender.clone(),
+                Self::column_family_config(config, &name),
+            )?;
+            versions.insert(id, (name, version));
+        }
 
-        let mut iter = level_changes.into_iter();
+        let names = iter::once(DEFAULT_COLUMN_FAMILY_NAME)
+            .chain(config.column_families.iter().map(|(name, _)| name.as_str()));
+        for name in names {
+            if versions.values().any(|(n, _)| n == name) {
+                continue;
+            }
+            let id = if name == DEFAULT_COLUMN_FAMILY_NAME {
+                DEFAULT_COLUMN_FAMILY_ID
+            } else {
+                versions.keys().last().map_or(1, |id| id + 1)
+            };
+            info!("add column family {} with id {}", name, id);
+            let version = Version::new(
+                path,
+                file_storage.clone(),
+                sstable_cache.clone(),
+                block_cache.clone(),
+                file_id_sender.clone(),
+                Self::column_family_config(config, name),
+            );
+            versions.insert(id, (String::from(name), version));
+        }
+        Ok(versions)
+    }
 
-        let f_clone = file_storage.clone();
-        let version: Version = Version::from(
-            &mut iter,
-            home_path.clone(),
-            f_clone,
-            sstable_cache,
-            file_id_sender,
-        )?;
+    // default family and family not in config use config of db
+    fn column_family_config(config: &Config, name: &str) -> Config {
+        config
+            .column_families
+            .iter()
+            .find(|(n, _)| n == name && name != DEFAULT_COLUMN_FAMILY_NAME)
+            .map_or_else(|| config.clone(), |(_, c)| c.clone())
+    }
 
-        Ok(version)
+    fn all_file_ids(versions: &BTreeMap<ColumnFamilyId, (String, Version)>) -> HashSet<FileId> {
+        versions
+            .values()
+            .flat_map(|(_, version)| version.get_all_file_ids())
+            .collect()
     }
 
     pub fn new_client(&self) -> Result<DBClient> {
         let (send, recv) = unbounded();
         Ok(DBClient {
             data: self.data.clone(),
+            column_families: self.column_families.clone(),
             finish_notify_sender: send,
             finish_notify_receiver: recv,
             write_request_sender: self.write_request_sender.clone(),
+            snapshot_list: self.snapshot_list.clone(),
+            lock_manager: self.lock_manager.clone(),
+            config: self.config.clone(),
         })
     }
     pub fn new(path: PathBuf) -> Result<Self> {
@@ -270,129 +644,241 @@ impl DBServer {
     }
     pub fn new_with_confing(home_path: PathBuf, c: Config) -> Result<Self> {
         // create open memtable_log
-        let memtable = Memtable::new();
-        let cache = new_sstable_cache(&c);
         let file_manager = Arc::new(Mutex::new(FileStorageManager::new(&home_path)));
 
         let (file_id_dec_sender, file_id_dec_recv) = unbounded();
-        let version = Version::new(&home_path, file_manager.clone(), cache, file_id_dec_sender);
+        let versions = Self::build_versions(
+            &home_path,
+            &c,
+            file_manager.clone(),
+            file_id_dec_sender,
+            Vec::new(),
+        )?;
 
-        //  delete all unused files
+        Self::delete_unused_files(&home_path, &Self::all_file_ids(&versions))?;
+        MemtableLog::delete_logs_before(&home_path, &c.memtable_log_file_path, u64::MAX)?;
 
-        let all_files = FileStorageManager::get_all_file_ids(&home_path)?;
-        let all_active_files = version.get_all_file_ids();
+        let families = versions
+            .into_iter()
+            .map(|(id, (name, version))| (id, (name, Memtable::new(), version)))
+            .collect();
+        Self::new_impl(home_path, c, file_manager, families, file_id_dec_recv)
+    }
+
+    // delete all sstable files not in versions
+    fn delete_unused_files(home_path: &Path, all_active_files: &HashSet<FileId>) -> Result<()> {
+        let all_files = FileStorageManager::get_all_file_ids(home_path)?;
         for id in all_files {
             if !all_active_files.contains(&id) {
                 info!("file {:} is unnused, deleting it", id);
-                let path = home_path.join(&id.to_string());
+                let path = home_path.join(id.to_string());
                 fs::remove_file(path)?;
             }
         }
-
-        Self::new_impl(
-            home_path,
-            c,
-            file_manager,
-            memtable,
-            version,
-            file_id_dec_recv,
-        )
+        Ok(())
     }
 
     fn new_impl(
         path: PathBuf,
         default_config: Config,
         file_strorage: ThreadSafeFileManager,
-        memtable: Memtable,
-        version: Version,
+        families: BTreeMap<ColumnFamilyId, (String, Memtable, Version)>,
         file_id_dec_recv: Receiver<HashSet<FileId>>,
     ) -> Result<Self> {
-        let memtable_log_path = path.join(PathBuf::from(&default_config.memtable_log_file_path));
-        let memtable_log_file = File::create(memtable_log_path)?;
-
-        let meta_log_file_path = path.join(&default_config.meta_log_file_name);
-        let meta_log_file = File::create(meta_log_file_path)?;
-        let meta_log = MetaLog::new(meta_log_file);
-
-        let cache = new_sstable_cache(&default_config);
+        // memtables recovered from logs not persisted, new writes go to a new log after them
+        let memtable_log_name = &default_config.memtable_log_file_path;
+        let min_memtable_log_number = families
+            .values()
+            .map(|(_, _, version)| version.memtable_log_number())
+            .min()
+            .unwrap_or(0);
+        let max_memtable_log_number = families
+            .values()
+            .map(|(_, _, version)| version.memtable_log_number())
+            .max()
+            .unwrap_or(0);
+        MemtableLog::delete_logs_before(&path, memtable_log_name, min_memtable_log_number)?;
+        let last_memtable_log_number = MemtableLog::log_numbers(&path, memtable_log_name)?
+            .last()
+            .copied()
+            .unwrap_or(0);
+        let memtable_log = MemtableLog::create(
+            &path,
+            last_memtable_log_number.max(max_memtable_log_number) + 1,
+            default_config.clone(),
+        )?;
 
-        let all_active_files = version.get_all_file_ids();
+        // new writes start after writes in sstable and recovered memtable of all families
+        let last_sequence = families
+            .values()
+            .map(|(_, memtable, version)| version.last_sequence().max(memtable.last_sequence()))
+            .max()
+            .unwrap_or(0);
+        let snapshot_list = Arc::new(SnapshotList::new(last_sequence));
+
+        let all_active_files: HashMap<ColumnFamilyId, HashSet<FileId>> = families
+            .iter()
+            .map(|(id, (_, _, version))| (*id, version.get_all_file_ids()))
+            .collect();
+        let column_families: ColumnFamilies = Arc::new(
+            families
+                .into_iter()
+                .map(|(id, (name, memtable, version))| {
+                    let config = Self::column_family_config(&default_config, &name);
+                    let family = ColumnFamily::new(id, &name, config, memtable, version);
+                    (id, Arc::new(family))
+                })
+                .collect(),
+        );
 
-        let data = Arc::new(RwLock::new((
-            Arc::new(Mutex::new(Arc::new(memtable))),
-            None,
-            Arc::new(Mutex::new(Arc::new(version))),
-        )));
+        // start a new meta log from snapshots of recovered versions
+        let meta_log_number = MetaLog::current_number(&path, &default_config.meta_log_file_name)?;
+        let meta_log = MetaLog::create(
+            &path,
+            &default_config.meta_log_file_name,
+            meta_log_number + 1,
+            &Self::column_family_snapshots(&column_families)?,
+        )?;
 
+        let data = column_families[&DEFAULT_COLUMN_FAMILY_ID].data().clone();
         let (sender, recv) = unbounded();
+        let background_error = BackgroundErrorSlot::default();
 
         let metric = Arc::new(DBMetric::new());
 
         let mut thread_handles = Vec::new();
 
-        let mutex = Mutex::new(true);
+        // number of memtable switches not flushed
+        let mutex = Mutex::new(0);
         let convar = Condvar::new();
         let condition_pair = Arc::new((mutex, convar));
 
+        let (start_flush_sender, start_flush_recv) = unbounded();
         let (start_compact_sender, start_compact_recv) = unbounded();
+        let meta_log = Arc::new(Mutex::new(meta_log));
 
-        let data_clone = data.clone();
+        let families_clone = column_families.clone();
         let condition_pair_clone = condition_pair.clone();
+        let flush_condition_pair = condition_pair.clone();
         let metric_clone = metric.clone();
 
         let path_clone = path.clone();
         let (file_id_inc_sender, file_id_inc_recv) = bounded(0);
 
         let prune_file_handle = spawn(move || {
-            let res = Self::prune_file_routine(
+            Self::prune_file_routine(
                 path_clone,
                 all_active_files,
                 file_id_dec_recv,
                 file_id_inc_recv,
-            );
-            res
+            )
         });
 
-        let compact_routine_join_handle = thread::spawn(move || {
-            Self::compact_routine(
-                data_clone,
-                file_strorage,
+        let config_clone = default_config.clone();
+        let path_clone = path.clone();
+        let meta_log_clone = meta_log.clone();
+        let file_id_inc_sender_clone = file_id_inc_sender.clone();
+        let snapshot_list_clone = snapshot_list.clone();
+        let background_error_clone = background_error.clone();
+        let flush_stop_pair = condition_pair.clone();
+        let flush_routine_join_handle = thread::spawn(move || {
+            let res = Self::flush_routine(
+                families_clone,
+                path_clone,
+                config_clone,
                 condition_pair_clone,
-                meta_log,
-                start_compact_recv,
+                meta_log_clone,
+                start_flush_recv,
+                start_compact_sender,
                 metric_clone,
-                file_id_inc_sender,
-            )
+                file_id_inc_sender_clone,
+                snapshot_list_clone,
+            );
+            if let Err(err) = &res {
+                set_background_error(&background_error_clone, err);
+                // wake up writer and flush callers waiting for immutable memtables to be flushed
+                let (lock, cvar) = &*flush_stop_pair;
+                let _guard = lock.lock().unwrap();
+                cvar.notify_all();
+            }
+            res
         });
 
+        // compaction threads share files being compacted of each family, so they never pick the same sstable
+        let mut compact_routine_join_handles = Vec::new();
+        for _ in 0..default_config.compaction_thread_number.max(1) {
+            let families_clone = column_families.clone();
+            let metric_clone = metric.clone();
+            let config_clone = default_config.clone();
+            let snapshot_list_clone = snapshot_list.clone();
+            let meta_log_clone = meta_log.clone();
+            let start_compact_recv_clone = start_compact_recv.clone();
+            let file_id_inc_sender_clone = file_id_inc_sender.clone();
+            let background_error_clone = background_error.clone();
+            let waiting_families = column_families.clone();
+            compact_routine_join_handles.push(thread::spawn(move || {
+                let res = Self::compact_routine(
+                    families_clone,
+                    config_clone,
+                    meta_log_clone,
+                    start_compact_recv_clone,
+                    metric_clone,
+                    file_id_inc_sender_clone,
+                    snapshot_list_clone,
+                );
+                if let Err(err) = &res {
+                    set_background_error(&background_error_clone, err);
+                    // compact range waiting for files of other tasks returns the error
+                    for family in waiting_families.values() {
+                        let _guard = family.compacting_files().lock().unwrap();
+                        family.compaction_finished().notify_all();
+                    }
+                }
+                res
+            }));
+        }
+        drop(start_compact_recv);
+
         let metric_clone = metric.clone();
-        let data_clone = data.clone();
+        let families_clone = column_families.clone();
         let config_clone = default_config.clone();
+        let snapshot_list_clone = snapshot_list.clone();
+        let background_error_clone = background_error.clone();
         let write_routine_join = thread::spawn(move || {
             let res = Self::write_routine(
-                data_clone,
+                families_clone,
                 recv,
                 condition_pair,
                 config_clone,
-                start_compact_sender,
+                start_flush_sender,
                 metric_clone,
-                memtable_log_file,
+                memtable_log,
+                snapshot_list_clone,
+                background_error_clone,
             );
             info!("write_routine return res is {:?}", res);
             res
         });
 
         thread_handles.push(write_routine_join);
-        thread_handles.push(compact_routine_join_handle);
+        thread_handles.push(flush_routine_join_handle);
+        thread_handles.append(&mut compact_routine_join_handles);
         thread_handles.push(prune_file_handle);
 
         let db = DBServer {
-            path: PathBuf::from(path),
-            data: data.clone(),
+            path,
+            data,
+            column_families,
             config: default_config,
             write_request_sender: sender,
             metrics: metric.clone(),
+            snapshot_list,
+            lock_manager: Arc::new(LockManager::new()),
             thread_handles,
+            meta_log,
+            file_id_inc_sender,
+            flush_condition_pair,
+            background_error,
         };
 
         Ok(db)
@@ -402,6 +888,9 @@ impl DBServer {
         info!("close db");
         drop(self.write_request_sender);
         drop(self.data);
+        drop(self.column_families);
+        // prune file routine stops after all senders are dropped
+        drop(self.file_id_inc_sender);
 
         while let Some(h) = self.thread_handles.pop() {
             // TODO: log
@@ -410,6 +899,93 @@ impl DBServer {
         Ok(())
     }
 
+    // move memtable to immutable memtables and wait until all of them are flushed to level 0
+    pub fn flush(&self) -> Result<()> {
+        let (sender, receiver) = bounded(1);
+        let mut request = WriteRequest::new(sender, WriteBatch::new());
+        request.flush = true;
+        self.write_request_sender
+            .send(request)
+            .map_err(|_| anyhow::anyhow!("db is closed"))?;
+        receiver.recv()??;
+        let (lock, cvar) = &*self.flush_condition_pair;
+        let mut immutable_number = lock.lock().unwrap();
+        while *immutable_number > 0 {
+            if let Some(err) = self.background_error.lock().unwrap().clone() {
+                return Err(err.into());
+            }
+            immutable_number = cvar.wait(immutable_number).unwrap();
+        }
+        Ok(())
+    }
+
+    // flush memtable and compact sstables which have keys in [start_key,end_key] to bottom level
+    // in all column families, deleted kvs in range are dropped, block until it is finished
+    pub fn compact_range(&self, start_key: &Key, end_key: &Key) -> Result<()> {
+        self.flush()?;
+        for family in self.column_families.values() {
+            self.compact_range_in_family(family, start_key, end_key)?;
+        }
+        info!("compact range [{:?},{:?}] finished", start_key, end_key);
+        Ok(())
+    }
+
+    fn compact_range_in_family(
+        &self,
+        family: &ColumnFamily,
+        start_key: &Key,
+        end_key: &Key,
+    ) -> Result<()> {
+        let file_ids: HashSet<FileId> = {
+            let (_, _, version) = get_current_data(family.data());
+            version.get_all_file_ids()
+        };
+        loop {
+            // same as compaction thread, files of task are marked before version is changed by others
+            let picked = {
+                let mut compacting = family.compacting_files().lock().unwrap();
+                loop {
+                    // failed compaction thread may never finish its task
+                    if let Some(err) = self.background_error.lock().unwrap().clone() {
+                        return Err(err.into());
+                    }
+                    let (_, _, version) = get_current_data(family.data());
+                    let task = match version.pick_range_compaction(start_key, end_key, &file_ids) {
+                        Some(task) => task,
+                        None => break None,
+                    };
+                    if !compacting.conflict(&task) {
+                        let task_id = compacting.add(task.clone());
+                        break Some((version, task, task_id));
+                    }
+                    // wait for compaction thread to finish task using same files
+                    compacting = family.compaction_finished().wait(compacting).unwrap();
+                }
+            };
+            let (version, task, task_id) = match picked {
+                Some(picked) => picked,
+                None => break,
+            };
+            let res = version
+                .compact(&task, &self.snapshot_list.sequences())
+                .and_then(|level_change| {
+                    Self::install_level_change(
+                        &self.column_families,
+                        family,
+                        &self.meta_log,
+                        level_change,
+                        false,
+                        &self.config,
+                        &self.metrics,
+                        &self.file_id_inc_sender,
+                    )
+                });
+            family.finish_compaction(task_id);
+            res?;
+        }
+        Ok(())
+    }
+
     pub fn depth(&self) -> usize {
         let (a, b, c) = get_current_data(&self.data);
         c.depth()
@@ -422,26 +998,62 @@ impl DBServer {
 
     fn save_level_change_to_meta_log(
         meta_log: &mut MetaLog,
-        level_change: &LevelChange,
+        column_family: ColumnFamilyId,
+        level_change: LevelChange,
     ) -> Result<()> {
-        let data = serde_json::to_string(level_change)?;
+        let change = ColumnFamilyChange {
+            column_family,
+            name: None,
+            change: level_change,
+        };
+        let data = serde_json::to_string(&change)?;
         meta_log.add_data(data.as_bytes())
     }
 
+    // snapshot records of current versions of all families
+    fn column_family_snapshots(
+        families: &BTreeMap<ColumnFamilyId, Arc<ColumnFamily>>,
+    ) -> Result<Vec<Vec<u8>>> {
+        families
+            .values()
+            .map(|family| {
+                let (_, _, version) = get_current_data(family.data());
+                Ok(serde_json::to_vec(&family.snapshot(&version))?)
+            })
+            .collect()
+    }
+
+    // write snapshots of all families to new meta log if current one is too large
+    fn roll_meta_log_if_needed(
+        meta_log: &mut MetaLog,
+        families: &BTreeMap<ColumnFamilyId, Arc<ColumnFamily>>,
+        config: &Config,
+    ) -> Result<()> {
+        if meta_log.size() <= config.meta_log_size_limit as u64 {
+            return Ok(());
+        }
+        meta_log.roll(&Self::column_family_snapshots(families)?)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn write_routine(
-        data: ThreadSafeData,
+        families: ColumnFamilies,
         write_request_channel: Receiver<WriteRequest>,
-        compact_condition_pair: Arc<(Mutex<bool>, Condvar)>,
+        flush_condition_pair: Arc<(Mutex<usize>, Condvar)>,
         config: Config,
-        start_compact_sender: Sender<()>,
+        start_flush_sender: Sender<(u64, Vec<ColumnFamilyId>)>,
         metric: Arc<DBMetric>,
-        memtable_log_file: File,
+        mut memtable_log: MemtableLog,
+        snapshot_list: Arc<SnapshotList>,
+        background_error: BackgroundErrorSlot,
     ) -> Result<()> {
-        let mut memtable_log = MemtableLog::new(memtable_log_file, config.clone());
         let mut request_buffer: Vec<WriteRequest> = Vec::new();
+        // sequence number of last write saved to log
+        let mut last_sequence = snapshot_list.last_sequence();
+        // transactions read default family only
+        let family = &families[&DEFAULT_COLUMN_FAMILY_ID];
 
         let mut channal_is_open = true;
-        let mut memtable_size = 0;
         loop {
             if !channal_is_open {
                 info!("Channal is closed, write routine return");
@@ -449,63 +1061,96 @@ impl DBServer {
                 return Ok(());
             }
             channal_is_open = save_to_log(
+                family,
                 &config,
                 &write_request_channel,
                 &mut memtable_log,
                 &mut request_buffer,
+                &mut last_sequence,
+                &background_error,
             )?;
 
+            let mut flush_waiters = Vec::new();
             let need_compact = write_to_memtable(
-                &data,
+                &families,
                 &mut request_buffer,
+                &mut flush_waiters,
                 &metric,
-                &config,
-                &mut memtable_size,
+                &snapshot_list,
             );
             if !need_compact {
+                for finish in flush_waiters {
+                    let _ = finish.send(Ok(()));
+                }
                 continue;
             }
-            // need compact
-            memtable_size = 0;
+            // need flush
 
-            let (lock, cvar) = &*compact_condition_pair;
-            // wait compact finish
-            let mut compact_is_finish = lock.lock().unwrap();
-            {
+            let (lock, cvar) = &*flush_condition_pair;
+            // wait until queue of immutable memtables has room
+            let max_immutable_number = config.max_write_buffer_number.max(2) - 1;
+            let mut immutable_number = lock.lock().unwrap();
+            let stopped = {
                 let r = TimeRecorder::new(WRITE_WAIT_FOR_COMAPCT);
-                while !*compact_is_finish {
-                    info!("compact is running, wait for finish");
-                    compact_is_finish = cvar.wait(compact_is_finish).unwrap();
+                loop {
+                    // memtable is kept if flush thread is stopped, later writes fail with its error
+                    if let Some(err) = background_error.lock().unwrap().clone() {
+                        break Some(err);
+                    }
+                    if *immutable_number < max_immutable_number {
+                        break None;
+                    }
+                    info!("too many immutable memtables, wait for flush");
+                    immutable_number = cvar.wait(immutable_number).unwrap();
+                }
+            };
+            if let Some(err) = stopped {
+                for finish in flush_waiters {
+                    let _ = finish.send(Err(err.clone().into()));
                 }
+                continue;
             }
-            info!("receive compact chan, compact is finished");
 
-            // set immutable memtable
-            let mut lock_result = data.write().unwrap();
-            let (memtable_ref, immutable_memtable, c) = lock_result.deref_mut();
-            assert!(immutable_memtable.is_none());
-            let mut memtable = memtable_ref.lock().unwrap();
-            let t = memtable.clone();
-            *immutable_memtable = Some(t);
-            *memtable = Arc::new(Memtable::new());
+            // writes of new memtables go to new log
+            let memtable_log_number = memtable_log.roll()?;
+
+            // memtables of all families are switched, so logs before new log can be deleted after they are flushed
+            let mut flushed_families = Vec::new();
+            for family in families.values() {
+                let mut lock_result = family.data().write().unwrap();
+                let (memtable_ref, immutable_memtables, c) = lock_result.deref_mut();
+                let mut memtable = memtable_ref.lock().unwrap();
+                if memtable.is_empty() {
+                    continue;
+                }
+                // add memtable to immutable memtables
+                immutable_memtables.insert(0, memtable.clone());
+                *memtable = Arc::new(Memtable::new());
+                flushed_families.push(family.id());
+            }
+            *immutable_number += 1;
 
             // TODO: log res
-            let send_res = start_compact_sender.send(());
-            info!("send signal to compact thread,send res is {:?}", send_res);
-            *compact_is_finish = false;
+            let send_res = start_flush_sender.send((memtable_log_number, flushed_families));
+            info!("send signal to flush thread,send res is {:?}", send_res);
+            for finish in flush_waiters {
+                let _ = finish.send(Ok(()));
+            }
         }
     }
 
     fn prune_file_routine(
         home_path: PathBuf,
-        file_ids: HashSet<FileId>,
+        file_ids: HashMap<ColumnFamilyId, HashSet<FileId>>,
         file_ref_decrease_recv: Receiver<HashSet<FileId>>,
-        file_ref_increase_recv: Receiver<HashSet<FileId>>,
+        file_ref_increase_recv: Receiver<(ColumnFamilyId, HashSet<FileId>)>,
     ) -> Result<()> {
         let mut file_id_count = HashMap::new();
-        for id in file_ids.iter() {
+        for id in file_ids.values().flatten() {
             file_id_count.insert(*id, 1);
         }
+        // files of latest version of each family, they are kept after all versions are dropped (db is closed)
+        let mut current_file_ids = file_ids;
         let mut select = Select::new();
         let mut index_set = HashSet::new();
         let inc_index = select.recv(&file_ref_increase_recv);
@@ -532,6 +1177,9 @@ impl DBServer {
                             let count = file_id_count.get_mut(id).unwrap();
                             if *count == 1 {
                                 file_id_count.remove(id);
+                                if current_file_ids.values().any(|ids| ids.contains(id)) {
+                                    continue;
+                                }
                                 let path = FileStorageManager::file_path(&home_path, id);
                                 fs::remove_file(&path)?;
                                 info!("delete file with id {}", id);
@@ -550,14 +1198,15 @@ impl DBServer {
                         index_set.remove(&inc_index);
                         continue;
                     }
-                    Ok(ids) => {
-                        for id in ids {
-                            if let Some(i) = file_id_count.get_mut(&id) {
+                    Ok((column_family, ids)) => {
+                        for id in ids.iter() {
+                            if let Some(i) = file_id_count.get_mut(id) {
                                 *i += 1;
                             } else {
-                                file_id_count.insert(id, 1);
+                                file_id_count.insert(*id, 1);
                             }
                         }
+                        current_file_ids.insert(column_family, ids);
                     }
                 }
             }
@@ -566,174 +1215,323 @@ impl DBServer {
         Ok(())
     }
 
-    fn compact_routine(
-        data: ThreadSafeData,
-        file_manager: ThreadSafeFileManager,
-        compact_condition_pair: Arc<(Mutex<bool>, Condvar)>,
-        mut meta_log: MetaLog,
-        start_compact: Receiver<()>,
+    // save level change of family to meta log and apply it to current version of family
+    // meta log lock is held until version is set, so changes in meta log are in the same order as applied
+    #[allow(clippy::too_many_arguments)]
+    fn install_level_change(
+        families: &BTreeMap<ColumnFamilyId, Arc<ColumnFamily>>,
+        family: &ColumnFamily,
+        meta_log: &Mutex<MetaLog>,
+        level_change: LevelChange,
+        flushed_memtable: bool,
+        config: &Config,
+        metric: &DBMetric,
+        file_id_inc_sender: &Sender<(ColumnFamilyId, HashSet<FileId>)>,
+    ) -> Result<()> {
+        let mut meta_log = meta_log.lock().unwrap();
+        Self::save_level_change_to_meta_log(&mut meta_log, family.id(), level_change.clone())?;
+        {
+            let mut lock_result = family.data().write().unwrap();
+            let (_, immutable_memtables, version) = lock_result.deref_mut();
+            if flushed_memtable {
+                // oldest immutable memtable is in level 0 now
+                let flushed = immutable_memtables
+                    .pop()
+                    .expect("flushed memtable must exist");
+                family.flushed_memtables().lock().unwrap().push(flushed);
+            }
+            let mut current_version = version.lock().unwrap();
+            let new_version = current_version.apply_change(level_change);
+            file_id_inc_sender
+                .send((family.id(), new_version.get_all_file_ids()))
+                .unwrap();
+
+            debug!("set version of {} to {:?}", family.name(), new_version);
+            gauge!(CURRENT_LEVEL_DEPTH, new_version.depth() as f64);
+            increment_counter!(COMPACT_COUNT);
+            new_version.record_metrics(metric);
+            *current_version = Arc::new(new_version);
+        }
+        Self::roll_meta_log_if_needed(&mut meta_log, families, config)
+    }
+
+    // flush immutable memtables to level 0 from old to new, it doesn't wait for level compaction
+    #[allow(clippy::too_many_arguments)]
+    fn flush_routine(
+        families: ColumnFamilies,
+        home_path: PathBuf,
+        config: Config,
+        flush_condition_pair: Arc<(Mutex<usize>, Condvar)>,
+        meta_log: Arc<Mutex<MetaLog>>,
+        start_flush: Receiver<(u64, Vec<ColumnFamilyId>)>,
+        start_compact_sender: Sender<()>,
         metric: Arc<DBMetric>,
-        file_id_inc_sender: Sender<HashSet<FileId>>,
+        file_id_inc_sender: Sender<(ColumnFamilyId, HashSet<FileId>)>,
+        snapshot_list: Arc<SnapshotList>,
     ) -> Result<()> {
-        let mut start_immediate = false;
         loop {
-            if !start_immediate {
-                let res = start_compact.recv();
-                if res.is_err() {
-                    info!("compact channel is closed, stop compaction routine");
+            // number of log after the oldest immutable memtables' logs, and families switched with the log
+            let (memtable_log_number, flushed_families) = match start_flush.recv() {
+                Ok(n) => n,
+                Err(_) => {
+                    info!("flush channel is closed, stop flush routine");
                     return Ok(());
                 }
+            };
+            info!("flush thread recv signal");
+
+            for id in flushed_families {
+                let family = &families[&id];
+                let (_, immutable_memtables, version) = get_current_data(family.data());
+                let imm_memtable = immutable_memtables.last().expect("must exits");
+                //     append sstable to level 0
+                let level_change = version.add_memtable_to_level_0(
+                    imm_memtable.as_ref(),
+                    memtable_log_number,
+                    &snapshot_list.sequences(),
+                )?;
+                Self::install_level_change(
+                    &families,
+                    family,
+                    &meta_log,
+                    level_change,
+                    true,
+                    &config,
+                    &metric,
+                    &file_id_inc_sender,
+                )?;
             }
-            start_immediate = false;
-            info!("compact thread recv signal");
-
-            // compact memtable
-            let (_, immutable_memtable_option, version) = get_current_data(&data);
-            //     append sstable to level 0
-            let imm_memtable = immutable_memtable_option.expect("must exits");
-            let level_change = version.add_memtable_to_level_0(imm_memtable.as_ref())?;
-            let new_version = version.apply_change(level_change.clone());
-            let new_version_ids = new_version.get_all_file_ids();
-            file_id_inc_sender.send(new_version_ids).unwrap();
-
-            let mut new_version_arc = Arc::new(new_version);
-            // write level change to meta log
-            Self::save_level_change_to_meta_log(&mut meta_log, &level_change)?;
-            //     lock data
-            {
-                let mut lock_result = data.write().unwrap();
-                let (memtable_ref, immutable_memtable, version) = lock_result.deref_mut();
-                //     set immutable memtable to None
-                *immutable_memtable = None;
-                // set version
-                let mut current_version = version.lock().unwrap();
-                debug!("set version to {:?}", new_version_arc);
-                gauge!(CURRENT_LEVEL_DEPTH, new_version_arc.depth() as f64);
-                increment_counter!(COMPACT_COUNT);
-
-                new_version_arc.record_metrics(metric.as_ref());
-                *current_version = new_version_arc.clone();
-                //     unlock data
-            }
+            // memtables of all families are persisted, their logs are useless
+            MemtableLog::delete_logs_before(
+                &home_path,
+                &config.memtable_log_file_path,
+                memtable_log_number,
+            )?;
             {
                 //     notify write thread
-                let (lock, cvar) = &*compact_condition_pair;
-                let mut compact_is_finish = lock.lock().unwrap();
-                *compact_is_finish = true;
+                let (lock, cvar) = &*flush_condition_pair;
+                let mut immutable_number = lock.lock().unwrap();
+                *immutable_number -= 1;
                 cvar.notify_all();
             }
-            // compact sstable
-            loop {
-                //     check level from 0 to n, do one level compact
-                let compact_res = new_version_arc.compact_one_level()?;
-                if compact_res.is_none() {
-                    debug!("check level finished, no need to compact");
-                    break;
-                }
-                let level_change = compact_res.unwrap();
-                Self::save_level_change_to_meta_log(&mut meta_log, &level_change)?;
-                {
-                    let mut lock_result = data.write().unwrap();
-                    let (_, _, version) = lock_result.deref_mut();
-                    let mut current_verison = version.lock().unwrap();
-                    let new_version = current_verison.apply_change(level_change);
-                    let new_version_ids = new_version.get_all_file_ids();
-                    file_id_inc_sender.send(new_version_ids).unwrap();
-
-                    debug!("set version to {:?}", new_version);
-                    gauge!(CURRENT_LEVEL_DEPTH, new_version_arc.depth() as f64);
-                    increment_counter!(COMPACT_COUNT);
-                    new_version.record_metrics(&metric);
-                    *current_verison = Arc::new(new_version);
-                    new_version_arc = current_verison.clone();
-                    //     unlock data
+            // level 0 is changed, check levels in all compact threads
+            for _ in 0..config.compaction_thread_number.max(1) {
+                let send_res = start_compact_sender.send(());
+                debug!("send signal to compact thread,send res is {:?}", send_res);
+            }
+        }
+    }
+
+    // one of compaction threads, compactions of different threads are installed in the order they finish
+    fn compact_routine(
+        families: ColumnFamilies,
+        config: Config,
+        meta_log: Arc<Mutex<MetaLog>>,
+        start_compact: Receiver<()>,
+        metric: Arc<DBMetric>,
+        file_id_inc_sender: Sender<(ColumnFamilyId, HashSet<FileId>)>,
+        snapshot_list: Arc<SnapshotList>,
+    ) -> Result<()> {
+        loop {
+            // wake up to check expired kvs even if there is no signal
+            match start_compact.recv_timeout(config.expired_kv_check_interval) {
+                Ok(()) => info!("compact thread recv signal"),
+                Err(RecvTimeoutError::Timeout) => debug!("compact thread checks expired kvs"),
+                Err(RecvTimeoutError::Disconnected) => {
+                    info!("compact channel is closed, stop compaction routine");
+                    return Ok(());
                 }
-                // check if need compact memtable
-                let res = start_compact.try_recv();
-                match res {
-                    Ok(()) => {
-                        debug!("need compact memtable immediately");
-                        start_immediate = true;
+            }
+
+            // compact sstable of any family until no family needs compaction
+            loop {
+                // version is read under lock, so it has changes of compactions removed from compacting files
+                let picked = families.values().find_map(|family| {
+                    let mut compacting = family.compacting_files().lock().unwrap();
+                    let (_, _, version) = get_current_data(family.data());
+                    let task = version.pick_compaction(&compacting)?;
+                    let task_id = compacting.add(task.clone());
+                    Some((family, version, task, task_id))
+                });
+                let (family, version, task, task_id) = match picked {
+                    Some(picked) => picked,
+                    None => {
+                        debug!("check level finished, no need to compact");
                         break;
                     }
-                    Err(TryRecvError::Empty) => {
-                        debug!("try to check level and compact");
-                        continue;
-                    }
-                    Err(TryRecvError::Disconnected) => {
-                        info!("compact channel is closed, stop compaction routine");
-                        return Ok(());
-                    }
+                };
+                let res =
+                    version
+                        .compact(&task, &snapshot_list.sequences())
+                        .and_then(|level_change| {
+                            Self::install_level_change(
+                                &families,
+                                family,
+                                &meta_log,
+                                level_change,
+                                false,
+                                &config,
+                                &metric,
+                                &file_id_inc_sender,
+                            )
+                        });
+                family.finish_compaction(task_id);
+                res?;
+                // stop if db is closed
+                if let Err(TryRecvError::Disconnected) = start_compact.try_recv() {
+                    info!("compact channel is closed, stop compaction routine");
+                    return Ok(());
                 }
+                debug!("try to check level and compact");
             }
         }
     }
 }
 
-// return true if need compact memtable
+// return true if memtable of any family needs flush
+// finish senders of flush requests are put to flush_waiters, they are notified after memtables are moved
 fn write_to_memtable(
-    data: &Arc<
-        RwLock<(
-            Arc<Mutex<Arc<Memtable>>>,
-            Option<Arc<Memtable>>,
-            Arc<Mutex<Arc<Version>>>,
-        )>,
-    >,
+    families: &BTreeMap<ColumnFamilyId, Arc<ColumnFamily>>,
     request_buffer: &mut Vec<WriteRequest>,
+    flush_waiters: &mut Vec<Sender<Result<()>>>,
     metric: &Arc<DBMetric>,
-    config: &Config,
-    memtable_size: &mut usize,
+    snapshot_list: &SnapshotList,
 ) -> bool {
-    // get current memtable
-    let lock_result = data.write().unwrap();
-    let (memtable_ref, b, c) = lock_result.deref();
-    let memtable = (*memtable_ref).lock().unwrap().clone();
-    drop(lock_result);
-    // write data to memtable
-    while let Some(request) = request_buffer.pop() {
+    // memtable has memory used by index even if it is empty, check size only after writes
+    if request_buffer.is_empty() {
+        return false;
+    }
+    // get current memtables
+    let memtables: BTreeMap<ColumnFamilyId, Arc<Memtable>> = families
+        .iter()
+        .map(|(id, family)| {
+            let (memtable, _, _) = get_current_data(family.data());
+            (*id, memtable)
+        })
+        .collect();
+    // write data to memtable in log order
+    for request in request_buffer.drain(..) {
         let batch = &request.wirte_batch;
-        for op in batch.to_opertions() {
-            match op {
-                Operation::PUT { key, value } => {
-                    memtable.insert_option_value(key, Some(value));
-                }
-                Operation::DELETE { key } => {
-                    memtable.insert_option_value(key, None);
-                }
-            }
+        let mut seq = request.sequence;
+        for (column_family, op) in batch.iter() {
+            // column families of batch are checked by client
+            write_operation_to_memtable(&memtables[&column_family], op, seq);
+            seq += 1;
         }
-
-        // memtable.insert_option_value(&request.key, &request.value);
-        *memtable_size += batch.size();
+        // batch is visible to snapshots created after it is finished
+        snapshot_list.set_last_sequence(seq - 1);
 
         // TODO: log error;
         let current_level_0_len = metric.get_level_n_file_number(0);
         if current_level_0_len > 4 {
             thread::sleep(Duration::from_millis(2));
         }
-        let send_res = request.finish.send(());
+        if request.flush {
+            flush_waiters.push(request.finish);
+            continue;
+        }
+        let send_res = request.finish.send(Ok(()));
         increment_counter!(WRITE_REQUEST_COUNT);
     }
-    debug!("current memtable size {:}", *memtable_size);
     // check size
-    if *memtable_size > config.memtable_size_limit {
-        info!("memtable write size limit try to start compact");
-        return true;
+    for (id, memtable) in memtables.iter() {
+        let memtable_size = memtable.memory_usage();
+        debug!("current memtable size of {} {:}", id, memtable_size);
+        if memtable_size > families[id].config().memtable_size_limit {
+            info!("memtable write size limit try to start compact");
+            return true;
+        }
     }
-    return false;
+    !flush_waiters.is_empty() && memtables.values().any(|memtable| !memtable.is_empty())
 }
 
-// return false if write channel is closed
-fn save_to_log(
-    config: &Config,
-    write_request_channel: &Receiver<WriteRequest>,
-    memtable_log: &mut MemtableLog,
-    request_buffer: &mut Vec<WriteRequest>,
-) -> Result<bool, anyhow::Error> {
-    let mut write_size_count = 0;
-    let start_time = Instant::now();
-    let mut channel_is_open = true;
-    loop {
+// error if a read key has newer version than sequence number it is read at,
+// batches in request_buffer are logged but not in memtable, they are all newer than it
+// memtables and flushed memtables kept in memory are checked so write thread doesn't read files,
+// transaction is too old only if a write after it is read may be in dropped flushed memtables
+fn check_conflict(
+    family: &ColumnFamily,
+    request_buffer: &[WriteRequest],
+    check: &ConflictCheck,
+) -> Result<()> {
+    // memtable flushed after data is read is already in flushed memtables
+    let (memtable, mut memtables, _) = get_current_data(family.data());
+    memtables.insert(0, memtable);
+    let flushed_memtables = family.flushed_memtables().lock().unwrap();
+    memtables.extend(flushed_memtables.memtables().cloned());
+    for (key, seq) in &check.read_keys {
+        if request_buffer
+            .iter()
+            .any(|request| request.wirte_batch.contains_key(key))
+            || latest_sequence(&memtables, key) > *seq
+        {
+            return Err(ConflictError::new(key.clone()).into());
+        }
+        if flushed_memtables.dropped_sequence() > *seq {
+            return Err(TransactionTooOldError::new(key.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+// sequence number of newest version of key or range tombstone deleting it in memtables, 0 if not found
+fn latest_sequence(memtables: &[Arc<Memtable>], key: &Key) -> SeqNumber {
+    let mut end_key = key.data().to_vec();
+    end_key.push(0);
+    let end_key = Key::from(end_key.as_slice());
+    let mut seq = 0;
+    for memtable in memtables {
+        // versions of key are sorted from new to old
+        if let Some((k, _)) = memtable.range_iter(key, Some(&end_key)).next() {
+            seq = seq.max(k.seq());
+        }
+        let tombstone_seq = memtable
+            .range_tombstones()
+            .max_covering_seq(key.data(), MAX_SEQUENCE);
+        seq = seq.max(tombstone_seq.unwrap_or(0));
+    }
+    seq
+}
+
+fn write_operation_to_memtable(memtable: &Memtable, op: &Operation, seq: SeqNumber) {
+    match op {
+        Operation::PUT { key, value } => {
+            memtable.insert_option_value(key, seq, Some(value));
+        }
+        Operation::DELETE { key } => {
+            memtable.insert_option_value(key, seq, None);
+        }
+        Operation::DELETE_RANGE { start, end } => {
+            memtable.delete_range(start, end, seq);
+        }
+        Operation::MERGE { key, value } => {
+            memtable.insert_merge_operand(key, seq, value);
+        }
+        Operation::PUT_WITH_EXPIRE {
+            key,
+            value,
+            expire_time,
+        } => {
+            memtable.insert_with_expire_time(key, seq, value, *expire_time);
+        }
+    }
+}
+
+// return false if write channel is closed
+// batch of transaction is checked before it is written to log, conflict error is sent if check fails
+fn save_to_log(
+    family: &ColumnFamily,
+    config: &Config,
+    write_request_channel: &Receiver<WriteRequest>,
+    memtable_log: &mut MemtableLog,
+    request_buffer: &mut Vec<WriteRequest>,
+    last_sequence: &mut SeqNumber,
+    background_error: &BackgroundErrorSlot,
+) -> Result<bool, anyhow::Error> {
+    let mut write_size_count = 0;
+    let start_time = Instant::now();
+    let mut channel_is_open = true;
+    loop {
         let now = Instant::now();
         let pass_time = now.duration_since(start_time);
         let rest_time = config
@@ -760,77 +1558,115 @@ fn save_to_log(
                 channel_is_open = false;
                 break;
             }
-            Ok(request) => {
+            Ok(mut request) => {
                 trace!("received write request");
+                // background work is stopped, new writes may never be flushed
+                if let Some(err) = background_error.lock().unwrap().clone() {
+                    let _ = request.finish.send(Err(err.into()));
+                    continue;
+                }
+                if let Some(check) = &request.conflict_check {
+                    if let Err(err) = check_conflict(family, request_buffer, check) {
+                        increment_counter!(WRITE_TRANSACTION_CONFLICT_COUNT);
+                        let _ = request.finish.send(Err(err));
+                        continue;
+                    }
+                }
+                // operations of batch get consecutive sequence numbers in order
+                request.sequence = *last_sequence + 1;
                 let batch = &request.wirte_batch;
                 write_size_count += batch.size();
-                for op in batch.to_opertions() {
-                    match op {
-                        Operation::PUT { key, value } => {
-                            memtable_log.add(key, Some(value))?;
-                        }
-                        Operation::DELETE { key } => {
-                            memtable_log.add(key, None)?;
-                        }
-                    }
+                for (column_family, op) in batch.iter() {
+                    *last_sequence += 1;
+                    memtable_log.add_operation(column_family, op, *last_sequence)?;
                 }
                 request_buffer.push(request);
                 if write_size_count > config.request_write_batch_size {
-                    memtable_log.flush_buf()?;
                     info!("reach write buffer size limit, save log return");
                     break;
                 }
             }
         }
     }
+    // log must be written to file before request is finished, or it is lost when process is killed
+    if !request_buffer.is_empty() {
+        memtable_log.flush_buf()?;
+    }
 
     Ok(channel_is_open)
 }
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashSet;
+    use std::collections::{BTreeMap, BTreeSet, HashSet};
     use std::fs::File;
-    use std::sync::{Arc, Mutex};
-    use std::time::Duration;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, Instant};
     use std::{fs, thread};
 
+    use anyhow::Result;
     use byteorder::LE;
     use crossbeam::channel::unbounded;
     use log::{debug, error, info, warn};
     use tempfile::{tempdir, TempDir};
 
-    use crate::db::config::Config;
-    use crate::db::key::Key;
+    use crate::db::column_family::{ColumnFamilyChange, DEFAULT_COLUMN_FAMILY_ID};
+    use crate::db::compaction_filter::test::SoftDeleteFilter;
+    use crate::db::config::{
+        CompactionStyle, CompressionType, Config, ReadOptions, TransactionMode,
+    };
+    use crate::db::error::{
+        BackgroundError, ConflictError, CorruptionError, DeadlockError, LockTimeoutError,
+        TransactionTooOldError,
+    };
+    use crate::db::key::{Key, MAX_SEQUENCE};
+    use crate::db::memtable::Memtable;
+    use crate::db::merge_operator::test::AddOperator;
     use crate::db::sstable::SSTable;
-    use crate::db::value::Value;
-    use crate::db::{get_current_data, DBServer};
+    use crate::db::value::{Value, VALUE_SIZE_LIMIT};
+    use crate::db::version::Version;
+    use crate::db::{
+        get_current_data, get_with_sequence, new_block_cache, new_sstable_cache,
+        scan_with_sequence, DBServer,
+    };
 
     use super::debug_util::{dump_recv, init_test_log_as_debug_and_metric};
     use super::file_storage::FileStorageManager;
+    use super::memtable_log::MemtableLog;
+    use super::meta_log::MetaLog;
     use super::write_batch::WriteBatch;
     use super::DBClient;
 
     fn build_config_for_test() -> Config {
         let mut c = Config::new();
         // use small memetable to make more compact and depth level easier
-        c.memtable_size_limit = 10;
+        c.memtable_size_limit = 4 * 1024;
         c
     }
 
-    // #[test]
+    #[test]
     fn test_reopen_db() {
         let dir = TempDir::new().unwrap();
         let number = 1000;
         let (server, _, config) = build_db(&dir, number);
         server.close().unwrap();
 
-        let server = DBServer::open_db(dir.into_path(), config).unwrap();
-        let client = server.new_client().unwrap();
+        // data written by all previous opens is kept
+        for generation in 1..4 {
+            let server = DBServer::open_db(dir.path().to_path_buf(), config.clone()).unwrap();
+            let mut client = server.new_client().unwrap();
 
-        for i in 0..number {
-            let res = client.get_str(&i.to_string());
-            assert_eq!(res.unwrap().unwrap(), Value::new(&i.to_string()));
+            for i in 0..number * generation {
+                let res = client.get_str(&i.to_string());
+                assert_eq!(res.unwrap().unwrap(), Value::new(&i.to_string()));
+            }
+            for i in number * generation..number * (generation + 1) {
+                let key = Key::new(&i.to_string());
+                client.put(&key, Value::new(&i.to_string())).unwrap();
+            }
+            drop(client);
+            server.close().unwrap();
         }
     }
 
@@ -841,13 +1677,8 @@ mod test {
         let number = 1000;
         let (server, _, config) = build_db(&dir, number);
         server.close().unwrap();
-        let file_storage = FileStorageManager::from(dir.path().to_path_buf()).unwrap();
-        let thread_safe_storage = Arc::new(Mutex::new(file_storage));
-        let memtable = DBServer::build_memtable(dir.path(), &config).unwrap();
-
-        let (s, r) = unbounded();
-        dump_recv(r);
-        let version = DBServer::build_version(dir.path(), &config, thread_safe_storage, s).unwrap();
+        let version = build_default_version(dir.path(), &config);
+        let memtable = build_default_memtable(dir.path(), &config, version.memtable_log_number());
 
         for i in 0..number {
             let res = memtable.get_str(&i.to_string());
@@ -860,9 +1691,102 @@ mod test {
             }
         }
     }
-    fn build_db(dir: &TempDir, number: usize) -> (DBServer, super::DBClient, Config) {
+    #[test]
+    fn test_rotate_memtable_log() {
+        let dir = TempDir::new().unwrap();
+        let number = 1000;
+        let (server, client, config) = build_db(&dir, number);
+        drop(client);
+        server.close().unwrap();
+
+        let version = build_default_version(dir.path(), &config);
+        let memtable_log_number = version.memtable_log_number();
+        assert!(memtable_log_number > 1);
+
+        // only logs not persisted are kept
+        let log_numbers =
+            MemtableLog::log_numbers(dir.path(), &config.memtable_log_file_path).unwrap();
+        assert!(!log_numbers.is_empty());
+        assert!(log_numbers.iter().all(|n| *n >= memtable_log_number));
+
+        // memtable only has data of unflushed tail
+        let memtable = build_default_memtable(dir.path(), &config, memtable_log_number);
+        assert!(memtable.iter().count() < number);
+        for i in 0..number {
+            let key = Key::new(&i.to_string());
+            let res = match memtable.get(&key, MAX_SEQUENCE, &mut Vec::new()) {
+                Some(v) => v,
+                None => version.get(&key).unwrap(),
+            };
+            assert_eq!(res.unwrap(), Value::new(&i.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_roll_meta_log() {
+        let dir = TempDir::new().unwrap();
         let mut c = Config::new();
         c.memtable_size_limit = 1000;
+        c.meta_log_size_limit = 256;
+        let db = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        let mut client = db.new_client().unwrap();
+        let number = 1000;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        drop(client);
+        db.close().unwrap();
+
+        // only current meta log is kept
+        let meta_logs: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|n| n.starts_with("meta"))
+            .collect();
+        assert_eq!(meta_logs.len(), 1);
+        let current = MetaLog::current_path(dir.path(), &c.meta_log_file_name)
+            .unwrap()
+            .unwrap();
+        assert_eq!(current, dir.path().join(&meta_logs[0]));
+        let number_before_reopen =
+            MetaLog::current_number(dir.path(), &c.meta_log_file_name).unwrap();
+        assert!(number_before_reopen > 1);
+
+        // version is rebuilt from snapshot and following changes
+        let version = build_default_version(dir.path(), &c);
+        assert!(version.depth() > 1);
+        drop(version);
+
+        // reopen start a new meta log from snapshot
+        let db = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        assert_eq!(
+            MetaLog::current_number(dir.path(), &c.meta_log_file_name).unwrap(),
+            number_before_reopen + 1
+        );
+        db.close().unwrap();
+    }
+
+    // version of default family rebuilt from meta log
+    fn build_default_version(path: &Path, config: &Config) -> Version {
+        let file_storage = FileStorageManager::from(path.to_path_buf()).unwrap();
+        let (s, r) = unbounded();
+        dump_recv(r);
+        let recorded = DBServer::read_column_families(path, config).unwrap();
+        let mut versions =
+            DBServer::build_versions(path, config, file_storage.into_thread_safe(), s, recorded)
+                .unwrap();
+        versions.remove(&DEFAULT_COLUMN_FAMILY_ID).unwrap().1
+    }
+
+    fn build_default_memtable(path: &Path, config: &Config, memtable_log_number: u64) -> Memtable {
+        let log_numbers = BTreeMap::from([(DEFAULT_COLUMN_FAMILY_ID, memtable_log_number)]);
+        let mut memtables = DBServer::build_memtables(path, config, &log_numbers).unwrap();
+        memtables.remove(&DEFAULT_COLUMN_FAMILY_ID).unwrap()
+    }
+
+    fn build_db(dir: &TempDir, number: usize) -> (DBServer, super::DBClient, Config) {
+        let mut c = Config::new();
+        c.memtable_size_limit = 16 * 1024;
         let db = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
         let mut client = db.new_client().unwrap();
         for i in 0..number {
@@ -887,7 +1811,7 @@ mod test {
         for i in 0..number {
             // add 0 to make key enough long to trigger bug
             let mut key = String::from("0");
-            key.push_str("_");
+            key.push('_');
             key.push_str(&i.to_string());
 
             db_client
@@ -897,7 +1821,7 @@ mod test {
 
         for i in 0..number {
             let mut key = String::from("0");
-            key.push_str("_");
+            key.push('_');
             key.push_str(&i.to_string());
             let value_res = db_client.get(&Key::new(&key));
             assert_eq!(value_res.unwrap().unwrap(), Value::new(&i.to_string()))
@@ -923,7 +1847,7 @@ mod test {
                 //     for each thread do set from 1 to 1000, delete even key in [0,to number/2) and check
                 for i in 0..number {
                     let mut key = thread_id.to_string();
-                    key.push_str("_");
+                    key.push('_');
                     // delete even while insert
                     let mut delete_key = key.clone();
                     delete_key.push_str(&(i / 2).to_string());
@@ -941,7 +1865,7 @@ mod test {
 
                 for i in 0..number {
                     let mut key = thread_id.to_string();
-                    key.push_str("_");
+                    key.push('_');
                     key.push_str(&i.to_string());
                     let value_res = db_client.get(&Key::new(&key));
                     if i % 2 == 0 && i < number / 2 {
@@ -962,8 +1886,7 @@ mod test {
             });
             handles.push(handle);
         }
-        while handles.len() > 0 {
-            let handle = handles.pop().unwrap();
+        while let Some(handle) = handles.pop() {
             handle.join().unwrap();
         }
         assert!(db_server.depth() >= 2);
@@ -1026,6 +1949,1249 @@ mod test {
         assert!(fs::metadata(file_path_1).is_err());
         assert!(fs::metadata(file_path_2).is_err());
     }
+    #[test]
+    fn test_binary_key() {
+        let dir = tempdir().unwrap();
+        let mut c = Config::new();
+        c.memtable_size_limit = 1000;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        // big endian integer with invalid utf8 bytes
+        let key = |i: u64| Key::from(&(i * 0x0101_0101).to_be_bytes());
+        let number = 500;
+        for i in 0..number {
+            client.put(&key(i), Value::from_u64(i)).unwrap();
+        }
+        drop(client);
+        db_server.close().unwrap();
+
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), c).unwrap();
+        assert!(db_server.depth() > 0);
+        let client = db_server.new_client().unwrap();
+        for i in 0..number {
+            assert_eq!(client.get(&key(i)).unwrap(), Some(Value::from_u64(i)));
+        }
+        // keys are ordered by bytes
+        let kvs: Vec<(Key, Value)> = client.iter().unwrap().collect();
+        let expect: Vec<(Key, Value)> = (0..number).map(|i| (key(i), Value::from_u64(i))).collect();
+        assert_eq!(kvs, expect);
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_blob_value() {
+        let dir = tempdir().unwrap();
+        let mut c = Config::new();
+        c.memtable_size_limit = 20 * 1024;
+        c.blob_value_threshold = 200;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        // small values are kept in sstable, others are written to blob file
+        let value = |i: u64, round: u64| {
+            let size = 10 + (i * 97 % 5000) as usize;
+            Value::from_u8(&vec![(i + round) as u8; size])
+        };
+        let number = 200;
+        let rounds = 10;
+        let mut written = 0;
+        for round in 0..rounds {
+            for i in 0..number {
+                let v = value(i, round);
+                written += v.len();
+                client.put(&Key::from_u64(i), v).unwrap();
+            }
+        }
+        assert_eq!(
+            client.get(&Key::from_u64(3)).unwrap(),
+            Some(value(3, rounds - 1))
+        );
+        drop(client);
+        db_server.close().unwrap();
+
+        // blob files only referenced by overwritten values are deleted
+        let disk_usage: u64 = FileStorageManager::get_all_file_ids(dir.path())
+            .unwrap()
+            .iter()
+            .map(|id| {
+                let path = FileStorageManager::file_path(dir.path(), id);
+                fs::metadata(path).unwrap().len()
+            })
+            .sum();
+        assert!(disk_usage < written as u64 / 2);
+
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), c).unwrap();
+        let client = db_server.new_client().unwrap();
+        for i in 0..number {
+            assert_eq!(
+                client.get(&Key::from_u64(i)).unwrap(),
+                Some(value(i, rounds - 1))
+            );
+        }
+        let kvs: Vec<(Key, Value)> = client.iter().unwrap().collect();
+        assert_eq!(kvs.len(), number as usize);
+        for (k, v) in kvs {
+            let i: u64 = k.as_str().unwrap().parse().unwrap();
+            assert_eq!(v, value(i, rounds - 1));
+        }
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_blob_garbage_collection() {
+        let dir = tempdir().unwrap();
+        let mut c = Config::new();
+        c.blob_value_threshold = 200;
+        c.expired_kv_check_interval = Duration::from_millis(50);
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let value = |i: u64, round: u8| Value::from_u8(&vec![round; 400 + i as usize % 100]);
+        let blob_file_ids = || {
+            let (_, _, version) = get_current_data(&db_server.data);
+            let mut res = BTreeSet::new();
+            for l in 0..version.depth() {
+                for meta in version.get_level_for_test(l).copy_sstable_meta() {
+                    res.extend(meta.blob_file_ids());
+                }
+            }
+            res
+        };
+        let number = 300;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), value(i, 0)).unwrap();
+        }
+        db_server.flush().unwrap();
+        let old_blob_file_ids = blob_file_ids();
+        assert!(!old_blob_file_ids.is_empty());
+
+        // most values of old blob file are overwritten, live ones are moved to new blob file
+        for i in (0..number).filter(|i| !i.is_multiple_of(3)) {
+            client.put(&Key::from_u64(i), value(i, 1)).unwrap();
+        }
+        db_server
+            .compact_range(&Key::from(&[]), &Key::from(&[u8::MAX; 16]))
+            .unwrap();
+        let start = Instant::now();
+        while old_blob_file_ids
+            .iter()
+            .any(|id| FileStorageManager::file_path(dir.path(), id).exists())
+        {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut iter = client.iter().unwrap();
+        let kvs: Vec<(Key, Value)> = iter.by_ref().collect();
+        iter.finish().unwrap();
+        assert_eq!(kvs.len(), number as usize);
+        for (k, v) in kvs {
+            let i: u64 = k.as_str().unwrap().parse().unwrap();
+            assert_eq!(v, value(i, if i.is_multiple_of(3) { 0 } else { 1 }));
+        }
+
+        // iter stops when blob file is lost
+        for id in blob_file_ids() {
+            fs::remove_file(FileStorageManager::file_path(dir.path(), &id)).unwrap();
+        }
+        let mut iter = client.iter().unwrap();
+        assert_eq!(iter.by_ref().count(), 0);
+        assert!(iter.finish().is_err());
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempdir().unwrap();
+        let config = build_config_for_test();
+        let db_server =
+            DBServer::new_with_confing(dir.path().to_path_buf(), config.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 300;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        let snapshot = client.snapshot();
+        // overwrite and delete, memtable is small so old versions are flushed and compacted
+        for round in 1..4 {
+            for i in 0..number {
+                if i % 3 == 0 {
+                    client.delete(&Key::from_u64(i)).unwrap();
+                } else {
+                    client
+                        .put(&Key::from_u64(i), Value::from_u64(i + round * number))
+                        .unwrap();
+                }
+            }
+        }
+        client
+            .put(&Key::from_u64(number), Value::from_u64(0))
+            .unwrap();
+
+        for i in 0..number {
+            let key = Key::from_u64(i);
+            assert_eq!(snapshot.get(&key).unwrap(), Some(Value::from_u64(i)));
+            let expect = if i % 3 == 0 {
+                None
+            } else {
+                Some(Value::from_u64(i + 3 * number))
+            };
+            assert_eq!(client.get(&key).unwrap(), expect);
+        }
+        assert!(snapshot.get(&Key::from_u64(number)).unwrap().is_none());
+        let kvs: Vec<(Key, Value)> = snapshot.iter().unwrap().collect();
+        assert_eq!(kvs.len(), number as usize);
+        assert!(kvs
+            .iter()
+            .all(|(k, v)| *v == Value::from_u64(k.as_str().unwrap().parse().unwrap())));
+        let kvs: Vec<(Key, Value)> = snapshot
+            .scan(&Key::from_u64(10), &Key::from_u64(20))
+            .unwrap()
+            .collect();
+        // keys are compared as bytes, "10" <= "100" < "20"
+        let expect: Vec<(Key, Value)> = (0..number)
+            .map(|i| (Key::from_u64(i), Value::from_u64(i)))
+            .filter(|(k, _)| k >= &Key::from_u64(10) && k < &Key::from_u64(20))
+            .collect();
+        assert_eq!(kvs.len(), expect.len());
+        assert!(kvs.iter().all(|kv| expect.contains(kv)));
+        assert_eq!(
+            client.iter().unwrap().count(),
+            (number - number / 3 + 1) as usize
+        );
+        let last_sequence = snapshot.sequence();
+        drop(snapshot);
+        drop(client);
+        db_server.close().unwrap();
+
+        // sequence number keeps increasing after reopen
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), config).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let snapshot = client.snapshot();
+        assert!(snapshot.sequence() > last_sequence);
+        client.put(&Key::from_u64(0), Value::from_u64(1)).unwrap();
+        assert!(snapshot.get(&Key::from_u64(0)).unwrap().is_none());
+        assert_eq!(
+            client.get(&Key::from_u64(0)).unwrap(),
+            Some(Value::from_u64(1))
+        );
+        drop(snapshot);
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_read_immutable_memtables() {
+        let dir = tempdir().unwrap();
+        let c = Config::new();
+        let file_manager = FileStorageManager::new(dir.path()).into_thread_safe();
+        let (sender, _recv) = unbounded();
+        let version = Version::new(
+            dir.path(),
+            file_manager,
+            new_sstable_cache(&c),
+            new_block_cache(&c),
+            sender,
+            c,
+        );
+        let memtable = Memtable::new();
+        memtable.insert(&Key::new("a"), 5, &Value::new("a5"));
+        let new_imm = Memtable::new();
+        new_imm.insert(&Key::new("a"), 3, &Value::new("a3"));
+        new_imm.delete(&Key::new("b"), 4);
+        let old_imm = Memtable::new();
+        old_imm.insert(&Key::new("b"), 2, &Value::new("b2"));
+        old_imm.insert(&Key::new("c"), 1, &Value::new("c1"));
+        let data = Arc::new(RwLock::new((
+            Arc::new(Mutex::new(Arc::new(memtable))),
+            vec![Arc::new(new_imm), Arc::new(old_imm)],
+            Arc::new(Mutex::new(Arc::new(version))),
+        )));
+
+        let options = ReadOptions::new();
+        let get = |key: &str, seq| {
+            get_with_sequence(&data, &Key::new(key), seq, &options)
+                .unwrap()
+                .map(|v| String::from_utf8(v.data().to_vec()).unwrap())
+        };
+        assert_eq!(get("a", MAX_SEQUENCE), Some(String::from("a5")));
+        assert_eq!(get("a", 4), Some(String::from("a3")));
+        assert_eq!(get("b", MAX_SEQUENCE), None);
+        assert_eq!(get("b", 3), Some(String::from("b2")));
+        assert_eq!(get("c", MAX_SEQUENCE), Some(String::from("c1")));
+
+        let scan = |seq| {
+            scan_with_sequence(&data, &Key::new(""), None, seq, &options)
+                .unwrap()
+                .map(|(k, v)| format!("{}{}", k, String::from_utf8(v.data().to_vec()).unwrap()))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        assert_eq!(scan(MAX_SEQUENCE), "aa5,cc1");
+        assert_eq!(scan(3), "aa3,bb2,cc1");
+    }
+
+    #[test]
+    fn test_multiple_write_buffers() {
+        let dir = tempdir().unwrap();
+        let mut c = build_config_for_test();
+        c.max_write_buffer_number = 3;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 3000;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+            // writes in memtables not flushed are readable
+            if i % 100 == 0 {
+                for j in (0..i).step_by(7) {
+                    assert_eq!(
+                        client.get(&Key::from_u64(j)).unwrap(),
+                        Some(Value::from_u64(j))
+                    );
+                }
+            }
+        }
+        let (_, immutable_memtables, version) = get_current_data(&db_server.data);
+        assert!(immutable_memtables.len() < 3);
+        assert!(version.depth() >= 2);
+        drop(immutable_memtables);
+        drop(version);
+        assert_eq!(client.iter().unwrap().count(), number as usize);
+        drop(client);
+        db_server.close().unwrap();
+
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), c).unwrap();
+        let client = db_server.new_client().unwrap();
+        for i in 0..number {
+            assert_eq!(
+                client.get(&Key::from_u64(i)).unwrap(),
+                Some(Value::from_u64(i))
+            );
+        }
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_universal_compaction() {
+        let dir = tempdir().unwrap();
+        let mut c = build_config_for_test();
+        c.compaction_style = CompactionStyle::Universal;
+        c.universal_max_sorted_runs = 4;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 2000;
+        for round in 0..3 {
+            for i in 0..number {
+                client
+                    .put(&Key::from_u64(i), Value::from_u64(i + round))
+                    .unwrap();
+            }
+        }
+        for i in (0..number).step_by(2) {
+            client.delete(&Key::from_u64(i)).unwrap();
+        }
+        let check = |client: &DBClient| {
+            for i in 0..number {
+                let expect = if i % 2 == 0 {
+                    None
+                } else {
+                    Some(Value::from_u64(i + 2))
+                };
+                assert_eq!(client.get(&Key::from_u64(i)).unwrap(), expect);
+            }
+            assert_eq!(client.iter().unwrap().count(), number as usize / 2);
+        };
+        check(&client);
+        // all sorted runs are in level 0
+        assert_eq!(db_server.depth(), 1);
+        drop(client);
+        db_server.close().unwrap();
+
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), c).unwrap();
+        let client = db_server.new_client().unwrap();
+        check(&client);
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_fifo_compaction() {
+        let dir = tempdir().unwrap();
+        let mut c = build_config_for_test();
+        c.compaction_style = CompactionStyle::Fifo;
+        c.fifo_max_table_files_size = 16 * 1024;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 3000;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        // oldest keys are dropped, newest are kept
+        assert!(client.get(&Key::from_u64(0)).unwrap().is_none());
+        assert_eq!(
+            client.get(&Key::from_u64(number - 1)).unwrap(),
+            Some(Value::from_u64(number - 1))
+        );
+        assert_eq!(db_server.depth(), 1);
+        drop(client);
+        db_server.close().unwrap();
+
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), c).unwrap();
+        let (_, _, version) = get_current_data(&db_server.data);
+        let level_0 = version.get_level_for_test(0);
+        assert!(level_0.size() <= 16 * 1024);
+        drop(version);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_compact_range() {
+        for style in [CompactionStyle::Level, CompactionStyle::Universal] {
+            let dir = tempdir().unwrap();
+            let mut c = build_config_for_test();
+            c.compaction_style = style;
+            let db_server =
+                DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+            let mut client = db_server.new_client().unwrap();
+            let number = 2000;
+            for i in 0..number {
+                client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+            }
+            // delete keys start with 1
+            let deleted = |i: u64| i.to_string().starts_with('1');
+            for i in (0..number).filter(|i| deleted(*i)) {
+                client.delete(&Key::from_u64(i)).unwrap();
+            }
+            db_server
+                .compact_range(&Key::new("1"), &Key::new("1~"))
+                .unwrap();
+
+            // memtable is flushed and deleted keys are removed from sstables
+            let (memtable, immutable_memtables, version) = get_current_data(&db_server.data);
+            assert!(memtable.is_empty());
+            assert!(immutable_memtables.is_empty());
+            let mut kv_number = 0;
+            for l in 0..version.depth() {
+                for (k, v) in version.get_level_for_test(l).get_kvs_for_test() {
+                    assert!(v.is_some());
+                    assert!(!k.data().starts_with(b"1"));
+                    kv_number += 1;
+                }
+            }
+            assert_eq!(kv_number, (0..number).filter(|i| !deleted(*i)).count());
+            drop(memtable);
+            drop(version);
+            for i in 0..number {
+                let expect = if deleted(i) {
+                    None
+                } else {
+                    Some(Value::from_u64(i))
+                };
+                assert_eq!(client.get(&Key::from_u64(i)).unwrap(), expect);
+            }
+            drop(client);
+            db_server.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_compact_range_in_bottom_level() {
+        let dir = tempdir().unwrap();
+        let mut c = build_config_for_test();
+        c.compression = CompressionType::None;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        for i in 0..100 {
+            let value = Value::new(&format!("secret-{}", i));
+            client.put(&Key::from_u64(i), value).unwrap();
+        }
+        // true if any sstable file has deleted values
+        let has_secret = || {
+            fs::read_dir(dir.path()).unwrap().any(|entry| {
+                let entry = entry.unwrap();
+                let is_sstable = entry.file_name().to_str().unwrap().parse::<u64>().is_ok();
+                is_sstable
+                    && fs::read(entry.path())
+                        .unwrap()
+                        .windows(6)
+                        .any(|w| w == b"secret")
+            })
+        };
+
+        // deleted kvs read by snapshot go to bottom level with range tombstone
+        let snapshot = client.snapshot();
+        client.delete_range(&Key::new(""), &Key::new("~")).unwrap();
+        db_server
+            .compact_range(&Key::new(""), &Key::new("~"))
+            .unwrap();
+        assert!(has_secret());
+        assert_eq!(snapshot.iter().unwrap().count(), 100);
+
+        // sstable in bottom level is rewritten when snapshot is released
+        drop(snapshot);
+        db_server
+            .compact_range(&Key::new(""), &Key::new("~"))
+            .unwrap();
+        // old sstable is deleted by prune file routine after its version is dropped
+        let start = Instant::now();
+        while has_secret() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.iter().unwrap().count(), 0);
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let dir = tempdir().unwrap();
+        let config = build_config_for_test();
+        let db_server =
+            DBServer::new_with_confing(dir.path().to_path_buf(), config.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 1000;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        let snapshot = client.snapshot();
+        // delete keys start with 1, then write one of them again
+        client.delete_range(&Key::new("1"), &Key::new("2")).unwrap();
+        client.put(&Key::from_u64(150), Value::new("new")).unwrap();
+        let expect = |i: u64| {
+            if i == 150 {
+                Some(Value::new("new"))
+            } else if i.to_string().starts_with('1') {
+                None
+            } else {
+                Some(Value::from_u64(i))
+            }
+        };
+        let check = |client: &super::DBClient| {
+            for i in 0..number {
+                assert_eq!(client.get(&Key::from_u64(i)).unwrap(), expect(i));
+            }
+            let keys: Vec<Key> = client.iter().unwrap().map(|(k, _)| k).collect();
+            let expect_keys: Vec<Key> = (0..number)
+                .filter(|i| expect(*i).is_some())
+                .map(Key::from_u64)
+                .collect::<BTreeSet<Key>>()
+                .into_iter()
+                .collect();
+            assert_eq!(keys, expect_keys);
+        };
+        // tombstone in memtable
+        check(&client);
+        assert_eq!(
+            snapshot.get(&Key::from_u64(120)).unwrap(),
+            Some(Value::from_u64(120))
+        );
+        assert_eq!(snapshot.iter().unwrap().count(), number as usize);
+
+        // tombstone in sstable
+        db_server.flush().unwrap();
+        check(&client);
+        assert_eq!(
+            snapshot.get(&Key::from_u64(120)).unwrap(),
+            Some(Value::from_u64(120))
+        );
+        drop(snapshot);
+
+        // tombstone is dropped when kvs are compacted to bottom level
+        db_server
+            .compact_range(&Key::new("0"), &Key::new("9~"))
+            .unwrap();
+        check(&client);
+        let (_, _, version) = get_current_data(&db_server.data);
+        assert!(version
+            .range_tombstones(&Key::new(""), None)
+            .unwrap()
+            .is_empty());
+        for l in 0..version.depth() {
+            for (k, _) in version.get_level_for_test(l).get_kvs_for_test() {
+                assert!(expect(k.as_str().unwrap().parse().unwrap()).is_some());
+            }
+        }
+        drop(version);
+
+        // tombstone in memtable log is replayed
+        client.delete_range(&Key::new("3"), &Key::new("4")).unwrap();
+        drop(client);
+        db_server.close().unwrap();
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), config).unwrap();
+        let client = db_server.new_client().unwrap();
+        assert_eq!(client.get(&Key::from_u64(350)).unwrap(), None);
+        assert_eq!(
+            client.get(&Key::from_u64(450)).unwrap(),
+            Some(Value::from_u64(450))
+        );
+        assert_eq!(client.get(&Key::from_u64(150)).unwrap(), expect(150));
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempdir().unwrap();
+        let mut config = build_config_for_test();
+        config.merge_operator = Some(Arc::new(AddOperator));
+        let db_server =
+            DBServer::new_with_confing(dir.path().to_path_buf(), config.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 200;
+        // counter i starts from i if i is even, or from nothing
+        for i in (0..number).step_by(2) {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        let add_all = |client: &mut DBClient, n: u64| {
+            for i in 0..number {
+                client.merge(&Key::from_u64(i), Value::from_u64(n)).unwrap();
+            }
+        };
+        let expect = |i: u64, total: u64| {
+            if i.is_multiple_of(2) {
+                i + total
+            } else {
+                total
+            }
+        };
+        let check = |client: &DBClient, total: u64| {
+            for i in 0..number {
+                assert_eq!(
+                    client.get(&Key::from_u64(i)).unwrap(),
+                    Some(Value::from_u64(expect(i, total)))
+                );
+            }
+            let mut kvs: Vec<(Key, Value)> = (0..number)
+                .map(|i| (Key::from_u64(i), Value::from_u64(expect(i, total))))
+                .collect();
+            kvs.sort();
+            assert_eq!(client.iter().unwrap().collect::<Vec<_>>(), kvs);
+        };
+        // operands in memtable
+        add_all(&mut client, 1);
+        add_all(&mut client, 2);
+        check(&client, 3);
+        let snapshot = client.snapshot();
+
+        // operands in sstables and memtable
+        db_server.flush().unwrap();
+        add_all(&mut client, 10);
+        check(&client, 13);
+        // deleted counter starts from nothing
+        client.delete(&Key::from_u64(0)).unwrap();
+        client.merge(&Key::from_u64(0), Value::from_u64(5)).unwrap();
+        assert_eq!(
+            client.get(&Key::from_u64(0)).unwrap(),
+            Some(Value::from_u64(5))
+        );
+        client.put(&Key::from_u64(0), Value::from_u64(13)).unwrap();
+
+        // snapshot reads old counters
+        db_server.flush().unwrap();
+        assert_eq!(
+            snapshot.get(&Key::from_u64(5)).unwrap(),
+            Some(Value::from_u64(3))
+        );
+        assert_eq!(
+            snapshot.get(&Key::from_u64(6)).unwrap(),
+            Some(Value::from_u64(9))
+        );
+        drop(snapshot);
+
+        // operands are combined by compaction
+        db_server
+            .compact_range(&Key::new("0"), &Key::new("9~"))
+            .unwrap();
+        check(&client, 13);
+        let (_, _, version) = get_current_data(&db_server.data);
+        for l in 0..version.depth() {
+            for (k, v) in version.get_level_for_test(l).get_kvs_for_test() {
+                let i = k.as_str().unwrap().parse().unwrap();
+                assert_eq!(v, Some(Value::from_u64(expect(i, 13))));
+            }
+        }
+        drop(version);
+
+        // operand in memtable log is replayed
+        add_all(&mut client, 100);
+        let large = Value::from_u8(&[b'1'; VALUE_SIZE_LIMIT]);
+        assert!(client.merge(&Key::from_u64(0), large).is_err());
+        drop(client);
+        db_server.close().unwrap();
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), config.clone()).unwrap();
+        let client = db_server.new_client().unwrap();
+        check(&client, 113);
+        drop(client);
+        db_server.close().unwrap();
+
+        // operands can't be read without merge operator
+        config.merge_operator = None;
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), config).unwrap();
+        let client = db_server.new_client().unwrap();
+        assert!(client.get(&Key::from_u64(0)).is_err());
+        let mut iter = client.iter().unwrap();
+        assert_eq!(iter.by_ref().count(), 0);
+        assert!(iter.finish().is_err());
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        check_put_with_ttl(CompactionStyle::Level);
+    }
+
+    #[test]
+    fn test_put_with_ttl_universal() {
+        check_put_with_ttl(CompactionStyle::Universal);
+    }
+
+    fn check_put_with_ttl(compaction_style: CompactionStyle) {
+        let dir = tempdir().unwrap();
+        let mut config = build_config_for_test();
+        config.compaction_style = compaction_style;
+        config.expired_kv_check_interval = Duration::from_millis(50);
+        let db_server =
+            DBServer::new_with_confing(dir.path().to_path_buf(), config.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 100;
+        let ttl = Duration::from_millis(500);
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        db_server.flush().unwrap();
+        // even keys expire, their old versions are not read again
+        for i in (0..number).step_by(2) {
+            client
+                .put_with_ttl(&Key::from_u64(i), Value::new("ttl"), ttl)
+                .unwrap();
+        }
+        let long_ttl = Duration::from_secs(3600);
+        client
+            .put_with_ttl(&Key::new("long"), Value::new("long"), long_ttl)
+            .unwrap();
+        assert_eq!(
+            client.get(&Key::from_u64(0)).unwrap(),
+            Some(Value::new("ttl"))
+        );
+        if compaction_style == CompactionStyle::Level {
+            // ttl kvs go to bottom level before they expire
+            db_server
+                .compact_range(&Key::from(&[]), &Key::from(&[u8::MAX; 16]))
+                .unwrap();
+        } else {
+            db_server.flush().unwrap();
+        }
+        thread::sleep(ttl);
+
+        let check = |client: &DBClient| {
+            for i in 0..number {
+                let expect = (!i.is_multiple_of(2)).then(|| Value::from_u64(i));
+                assert_eq!(client.get(&Key::from_u64(i)).unwrap(), expect);
+            }
+            assert_eq!(
+                client.get(&Key::new("long")).unwrap(),
+                Some(Value::new("long"))
+            );
+            assert_eq!(client.iter().unwrap().count() as u64, number / 2 + 1);
+        };
+        check(&client);
+
+        // expired kvs are compacted without new writes
+        let has_expired_value = || {
+            let (_, _, version) = get_current_data(&db_server.data);
+            (0..version.depth()).any(|l| {
+                version
+                    .get_level_for_test(l)
+                    .get_kvs_for_test()
+                    .iter()
+                    .any(|(_, v)| v.as_ref() == Some(&Value::new("ttl")))
+            })
+        };
+        let start = Instant::now();
+        while has_expired_value() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(50));
+        }
+        check(&client);
+
+        // expire time in memtable log is replayed
+        client
+            .put_with_ttl(&Key::from_u64(1), Value::new("ttl"), ttl)
+            .unwrap();
+        drop(client);
+        db_server.close().unwrap();
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), config).unwrap();
+        let client = db_server.new_client().unwrap();
+        thread::sleep(ttl);
+        assert_eq!(client.get(&Key::from_u64(1)).unwrap(), None);
+        assert_eq!(
+            client.get(&Key::new("long")).unwrap(),
+            Some(Value::new("long"))
+        );
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_transaction() {
+        let dir = tempdir().unwrap();
+        let config = build_config_for_test();
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), config).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        client.put(&Key::new("a"), Value::new("a")).unwrap();
+        let is_conflict =
+            |res: Result<()>| res.unwrap_err().downcast_ref::<ConflictError>().is_some();
+
+        // transaction reads its own writes
+        let mut txn = client.begin_transaction();
+        assert_eq!(txn.get(&Key::new("a")).unwrap(), Some(Value::new("a")));
+        txn.put(&Key::new("a"), Value::new("a1")).unwrap();
+        txn.delete(&Key::new("b")).unwrap();
+        assert_eq!(txn.get(&Key::new("a")).unwrap(), Some(Value::new("a1")));
+        assert_eq!(txn.get(&Key::new("b")).unwrap(), None);
+        assert_eq!(client.get(&Key::new("a")).unwrap(), Some(Value::new("a")));
+        txn.commit().unwrap();
+        assert_eq!(client.get(&Key::new("a")).unwrap(), Some(Value::new("a1")));
+
+        // read key is written after transaction begins
+        let mut txn = client.begin_transaction();
+        let mut other = client.begin_transaction();
+        txn.get(&Key::new("a")).unwrap();
+        other.get(&Key::new("c")).unwrap();
+        txn.put(&Key::new("c"), Value::new("c")).unwrap();
+        other.put(&Key::new("a"), Value::new("a2")).unwrap();
+        txn.commit().unwrap();
+        assert!(is_conflict(other.commit()));
+        assert_eq!(client.get(&Key::new("a")).unwrap(), Some(Value::new("a1")));
+
+        // newer version is deleted by range
+        let mut txn = client.begin_transaction();
+        txn.get(&Key::new("c")).unwrap();
+        txn.put(&Key::new("d"), Value::new("d")).unwrap();
+        client.delete_range(&Key::new("b"), &Key::new("d")).unwrap();
+        assert!(is_conflict(txn.commit()));
+        assert_eq!(client.get(&Key::new("d")).unwrap(), None);
+
+        // newer version or range tombstone is flushed to sstable, flushed memtable is still checked
+        let mut txn = client.begin_transaction();
+        txn.get(&Key::new("a")).unwrap();
+        txn.put(&Key::new("d"), Value::new("d")).unwrap();
+        client.put(&Key::new("a"), Value::new("a3")).unwrap();
+        db_server.flush().unwrap();
+        assert!(is_conflict(txn.commit()));
+        client.put(&Key::new("c"), Value::new("c")).unwrap();
+        let mut txn = client.begin_transaction();
+        txn.get(&Key::new("c")).unwrap();
+        txn.put(&Key::new("d"), Value::new("d")).unwrap();
+        client.delete_range(&Key::new("b"), &Key::new("d")).unwrap();
+        db_server.flush().unwrap();
+        assert!(is_conflict(txn.commit()));
+        assert_eq!(client.get(&Key::new("c")).unwrap(), None);
+        assert_eq!(client.get(&Key::new("d")).unwrap(), None);
+        // unrelated key is flushed while transaction runs
+        let mut txn = client.begin_transaction();
+        txn.get(&Key::new("a")).unwrap();
+        txn.put(&Key::new("d"), Value::new("d")).unwrap();
+        client.put(&Key::new("e"), Value::new("e")).unwrap();
+        db_server.flush().unwrap();
+        txn.commit().unwrap();
+        assert_eq!(client.get(&Key::new("d")).unwrap(), Some(Value::new("d")));
+
+        // concurrent read-modify-write doesn't lose updates
+        let key = Key::new("counter");
+        client.put(&key, Value::from_u64(0)).unwrap();
+        let thread_number = 4;
+        let add_number = 50;
+        let handles: Vec<_> = (0..thread_number)
+            .map(|_| {
+                let client = db_server.new_client().unwrap();
+                let key = key.clone();
+                thread::spawn(move || {
+                    let mut added = 0;
+                    while added < add_number {
+                        let mut txn = client.begin_transaction();
+                        let value = txn.get(&key).unwrap().unwrap();
+                        let n: u64 = std::str::from_utf8(value.data()).unwrap().parse().unwrap();
+                        txn.put(&key, Value::from_u64(n + 1)).unwrap();
+                        match txn.commit() {
+                            Ok(()) => added += 1,
+                            Err(err) => assert!(
+                                err.downcast_ref::<ConflictError>().is_some()
+                                    || err.downcast_ref::<TransactionTooOldError>().is_some()
+                            ),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(
+            client.get(&key).unwrap(),
+            Some(Value::from_u64(thread_number * add_number))
+        );
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_transaction_too_old() {
+        let dir = tempdir().unwrap();
+        let mut config = build_config_for_test();
+        config.max_write_buffer_size_to_maintain = 0;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), config).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let is_too_old = |res: Result<()>| {
+            res.unwrap_err()
+                .downcast_ref::<TransactionTooOldError>()
+                .is_some()
+        };
+
+        // flushed memtable isn't kept, memtables can't tell whether read key is written
+        let mut txn = client.begin_transaction();
+        txn.get(&Key::new("a")).unwrap();
+        txn.put(&Key::new("b"), Value::new("b")).unwrap();
+        client.put(&Key::new("c"), Value::new("c")).unwrap();
+        db_server.flush().unwrap();
+        assert!(is_too_old(txn.commit()));
+        assert_eq!(client.get(&Key::new("b")).unwrap(), None);
+        // transaction begins after flush is checked in memtables
+        let mut txn = client.begin_transaction();
+        txn.get(&Key::new("a")).unwrap();
+        txn.put(&Key::new("b"), Value::new("b")).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(client.get(&Key::new("b")).unwrap(), Some(Value::new("b")));
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_pessimistic_transaction() {
+        let dir = tempdir().unwrap();
+        let mut config = build_config_for_test();
+        config.transaction_mode = TransactionMode::Pessimistic;
+        config.lock_timeout = Duration::from_secs(1);
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), config).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let (a, b) = (Key::new("a"), Key::new("b"));
+
+        // locked key can't be locked by others until transaction is finished
+        let mut txn = client.begin_transaction();
+        txn.put(&a, Value::new("a")).unwrap();
+        let mut other = client.begin_transaction();
+        let err = other.get_for_update(&a, false).unwrap_err();
+        assert!(err.downcast_ref::<LockTimeoutError>().is_some());
+        // read without lock
+        assert_eq!(other.get(&a).unwrap(), None);
+        txn.commit().unwrap();
+        assert_eq!(
+            other.get_for_update(&a, true).unwrap(),
+            Some(Value::new("a"))
+        );
+        drop(other);
+
+        // shared locks are held together, upgrading both of them is a deadlock
+        let mut txn = client.begin_transaction();
+        let mut other = client.begin_transaction();
+        txn.get_for_update(&b, false).unwrap();
+        other.get_for_update(&b, false).unwrap();
+        let handle = thread::spawn(move || {
+            let res = txn.put(&b, Value::new("b"));
+            (txn, res)
+        });
+        // upgrade of other makes the cycle once txn waits for it
+        while db_server.lock_manager.waiter_number() == 0 {
+            thread::yield_now();
+        }
+        let err = other.put(&Key::new("b"), Value::new("other")).unwrap_err();
+        assert!(err.downcast_ref::<DeadlockError>().is_some());
+        drop(other);
+        let (txn, txn_res) = handle.join().unwrap();
+        txn_res.unwrap();
+        drop(txn);
+
+        // write without transaction after key is read for update fails commit
+        let mut txn = client.begin_transaction();
+        txn.get_for_update(&a, true).unwrap();
+        txn.put(&a, Value::new("txn")).unwrap();
+        client.put(&a, Value::new("client")).unwrap();
+        assert!(txn
+            .commit()
+            .unwrap_err()
+            .downcast_ref::<ConflictError>()
+            .is_some());
+
+        // concurrent read-modify-write waits for lock instead of retrying
+        let key = Key::new("counter");
+        client.put(&key, Value::from_u64(0)).unwrap();
+        let thread_number = 4;
+        let add_number = 50;
+        let handles: Vec<_> = (0..thread_number)
+            .map(|_| {
+                let client = db_server.new_client().unwrap();
+                let key = key.clone();
+                thread::spawn(move || {
+                    for _ in 0..add_number {
+                        let mut txn = client.begin_transaction();
+                        let value = txn.get_for_update(&key, true).unwrap().unwrap();
+                        let n: u64 = std::str::from_utf8(value.data()).unwrap().parse().unwrap();
+                        txn.put(&key, Value::from_u64(n + 1)).unwrap();
+                        txn.commit().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(
+            client.get(&key).unwrap(),
+            Some(Value::from_u64(thread_number * add_number))
+        );
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let dir = tempdir().unwrap();
+        let mut config = build_config_for_test();
+        config.compaction_filter = Some(Arc::new(SoftDeleteFilter));
+        let db_server =
+            DBServer::new_with_confing(dir.path().to_path_buf(), config.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 300;
+        let value = |i: u64| match i % 3 {
+            0 => Value::new("deleted"),
+            1 => Value::new("change"),
+            _ => Value::from_u64(i),
+        };
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        db_server
+            .compact_range(&Key::new("0"), &Key::new("9~"))
+            .unwrap();
+        for i in 0..number {
+            client.put(&Key::from_u64(i), value(i)).unwrap();
+        }
+        // removed kv hides old version in bottom level
+        db_server
+            .compact_range(&Key::new("0"), &Key::new("9~"))
+            .unwrap();
+        let expect = |i: u64| match i % 3 {
+            0 => None,
+            1 => Some(Value::new("CHANGE")),
+            _ => Some(Value::from_u64(i)),
+        };
+        for i in 0..number {
+            assert_eq!(client.get(&Key::from_u64(i)).unwrap(), expect(i));
+        }
+        assert_eq!(
+            client.iter().unwrap().count(),
+            (0..number).filter(|i| expect(*i).is_some()).count()
+        );
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = tempdir().unwrap();
+        let c = build_config_for_test();
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 400;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        // delete and overwrite after kv are compacted to level
+        for i in (0..number).step_by(3) {
+            client.delete(&Key::from_u64(i)).unwrap();
+        }
+        client.put(&Key::from_u64(1), Value::new("new")).unwrap();
+        assert!(db_server.depth() >= 2);
+
+        let mut expect: Vec<String> = (0..number)
+            .filter(|i| !i.is_multiple_of(3))
+            .map(|i| i.to_string())
+            .collect();
+        expect.sort();
+
+        let kvs: Vec<(Key, Value)> = client.iter().unwrap().collect();
+        let keys: Vec<&str> = kvs.iter().map(|(k, _)| k.as_str().unwrap()).collect();
+        assert_eq!(keys, expect);
+        assert_eq!(kvs[0], (Key::from_u64(1), Value::new("new")));
+        assert_eq!(kvs[1], (Key::from_u64(10), Value::from_u64(10)));
+
+        let kvs: Vec<(Key, Value)> = client
+            .scan(&Key::new("2"), &Key::new("21"))
+            .unwrap()
+            .collect();
+        let keys: Vec<&str> = kvs.iter().map(|(k, _)| k.as_str().unwrap()).collect();
+        assert_eq!(
+            keys,
+            vec!["2", "20", "200", "202", "203", "205", "206", "208", "209"]
+        );
+
+        // large scan don't fill block cache
+        let options = ReadOptions { fill_cache: false };
+        let kvs: Vec<(Key, Value)> = client
+            .scan_with_options(&Key::new("2"), &Key::new("21"), &options)
+            .unwrap()
+            .collect();
+        assert_eq!(kvs.len(), 9);
+        let v = client
+            .get_with_options(&Key::from_u64(10), &options)
+            .unwrap();
+        assert_eq!(v, Some(Value::from_u64(10)));
+
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    // sstable files of version held by iter should not be pruned by compaction
+    #[test]
+    fn test_iter_hold_version() {
+        let dir = tempdir().unwrap();
+        let c = build_config_for_test();
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        for i in 0..200 {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        let iter = client.iter().unwrap();
+        for i in 0..1000 {
+            client.put(&Key::from_u64(i), Value::new("new")).unwrap();
+        }
+        let (_, _, version) = get_current_data(&db_server.data);
+        assert!(version.depth() >= 2);
+        drop(version);
+
+        let kvs: Vec<(Key, Value)> = iter.collect();
+        assert_eq!(kvs.len(), 200);
+        for (k, v) in kvs {
+            assert_eq!(k.data(), v.data());
+        }
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_iter_corrupted_sstable() {
+        let dir = tempdir().unwrap();
+        let mut c = Config::new();
+        c.compression = CompressionType::None;
+        c.block_cache = 0;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), c).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        let number = 1000;
+        for i in 0..number {
+            client.put(&Key::from_u64(i), Value::from_u64(i)).unwrap();
+        }
+        db_server.flush().unwrap();
+        let (_, _, version) = get_current_data(&db_server.data);
+        let file_ids = version.get_all_file_ids();
+        assert_eq!(file_ids.len(), 1);
+        let path = FileStorageManager::file_path(dir.path(), file_ids.iter().next().unwrap());
+        drop(version);
+
+        // block in the middle of sstable is damaged, iter stops at it
+        let mut data = fs::read(&path).unwrap();
+        let position = data.len() / 3;
+        data[position] ^= 0xff;
+        fs::write(&path, data).unwrap();
+        let mut iter = client.iter().unwrap();
+        let count = iter.by_ref().count() as u64;
+        assert!(count > 0 && count < number);
+        let err = iter.finish().unwrap_err();
+        assert!(err.downcast_ref::<CorruptionError>().is_some());
+
+        // missing sstable file
+        fs::remove_file(&path).unwrap();
+        let mut iter = client.iter().unwrap();
+        assert_eq!(iter.by_ref().count(), 0);
+        let err = iter.finish().unwrap_err();
+        assert!(err.downcast_ref::<CorruptionError>().is_some());
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_compaction_error_stops_writes() {
+        let dir = tempdir().unwrap();
+        let mut config = build_config_for_test();
+        config.level_0_file_limit = 2;
+        let db_server = DBServer::new_with_confing(dir.path().to_path_buf(), config).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        client.put(&Key::new("a"), Value::new("a")).unwrap();
+        db_server.flush().unwrap();
+        let (_, _, version) = get_current_data(&db_server.data);
+        let id = *version.get_all_file_ids().iter().next().unwrap();
+        drop(version);
+        fs::remove_file(FileStorageManager::file_path(dir.path(), &id)).unwrap();
+
+        // compaction of overlapping level 0 sstables fails to read missing one, then writes fail
+        let start = Instant::now();
+        let err = loop {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            match client
+                .put(&Key::new("a"), Value::new("b"))
+                .and_then(|_| db_server.flush())
+            {
+                Ok(()) => thread::sleep(Duration::from_millis(10)),
+                Err(err) => break err,
+            }
+        };
+        let err = err.downcast_ref::<BackgroundError>().unwrap();
+        assert!(err.message().contains("missing"));
+        // compact range doesn't wait for task of failed thread
+        assert!(db_server
+            .compact_range(&Key::new("a"), &Key::new("b"))
+            .unwrap_err()
+            .downcast_ref::<BackgroundError>()
+            .is_some());
+        assert!(client
+            .put(&Key::new("c"), Value::new("c"))
+            .unwrap_err()
+            .downcast_ref::<BackgroundError>()
+            .is_some());
+        drop(client);
+        db_server.close().unwrap();
+    }
+
+    #[test]
+    fn test_flush_error_stops_writes() {
+        let dir = tempdir().unwrap();
+        let db_server =
+            DBServer::new_with_confing(dir.path().to_path_buf(), Config::new()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        client.put(&Key::new("a"), Value::new("a")).unwrap();
+        db_server.flush().unwrap();
+        let (_, _, version) = get_current_data(&db_server.data);
+        let id = *version.get_all_file_ids().iter().next().unwrap();
+        drop(version);
+        // sstable of next flush can't be created
+        fs::create_dir(FileStorageManager::file_path(dir.path(), &(id + 1))).unwrap();
+
+        client.put(&Key::new("b"), Value::new("b")).unwrap();
+        let is_background_error =
+            |res: Result<()>| res.unwrap_err().downcast_ref::<BackgroundError>().is_some();
+        assert!(is_background_error(db_server.flush()));
+        assert!(is_background_error(
+            client.put(&Key::new("c"), Value::new("c"))
+        ));
+        assert!(is_background_error(db_server.flush()));
+        // unflushed writes are still readable
+        assert_eq!(client.get(&Key::new("b")).unwrap(), Some(Value::new("b")));
+        drop(client);
+        db_server.close().unwrap();
+    }
+
     #[test]
     fn test_write_batch() {
         let dir = tempdir().unwrap();
@@ -1044,4 +3210,126 @@ mod test {
             Value::from_u64(2)
         );
     }
+
+    #[test]
+    fn test_column_families() {
+        let dir = tempdir().unwrap();
+        let mut config = build_config_for_test();
+        let mut counter_config = build_config_for_test();
+        counter_config.merge_operator = Some(Arc::new(AddOperator));
+        config.column_families = vec![
+            (String::from("counter"), counter_config.clone()),
+            (String::from("other"), build_config_for_test()),
+        ];
+        let db_server =
+            DBServer::new_with_confing(dir.path().to_path_buf(), config.clone()).unwrap();
+        let mut client = db_server.new_client().unwrap();
+        assert_eq!(
+            client.column_family_id("default"),
+            Some(DEFAULT_COLUMN_FAMILY_ID)
+        );
+        let counter = client.column_family_id("counter").unwrap();
+        let other = client.column_family_id("other").unwrap();
+        assert!(client.column_family_id("unknown").is_none());
+
+        // each batch writes all families, memtables are flushed by size of each family
+        let number = 500;
+        for i in 0..number {
+            let mut batch = WriteBatch::new();
+            batch.put(Key::from_u64(i), Value::from_u64(i));
+            batch.put_cf(other, Key::from_u64(i), Value::from_u64(i + 1));
+            batch.merge_cf(counter, Key::from_u64(i % 10), Value::from_u64(1));
+            client.write_batch(batch).unwrap();
+        }
+        client.delete_cf(other, &Key::from_u64(0)).unwrap();
+        // ttl, range deletion and snapshot of family only see that family
+        let x = Key::new("x");
+        client
+            .put_with_ttl_cf(other, &x, Value::new("x"), Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(client.get_cf(other, &x).unwrap(), Some(Value::new("x")));
+        assert_eq!(client.get(&x).unwrap(), None);
+        let snapshot = client.snapshot_cf(other).unwrap();
+        client
+            .delete_range_cf(other, &Key::new("x"), &Key::new("y"))
+            .unwrap();
+        assert_eq!(client.get_cf(other, &x).unwrap(), None);
+        assert_eq!(snapshot.get(&x).unwrap(), Some(Value::new("x")));
+        drop(snapshot);
+        assert!(client.snapshot_cf(99).is_err());
+        assert!(client.put_cf(99, &Key::new("a"), Value::new("a")).is_err());
+        assert!(client.get_cf(99, &Key::new("a")).is_err());
+
+        let check = |client: &DBClient| {
+            for i in 0..number {
+                let key = Key::from_u64(i);
+                assert_eq!(client.get(&key).unwrap(), Some(Value::from_u64(i)));
+                let expect = if i == 0 {
+                    None
+                } else {
+                    Some(Value::from_u64(i + 1))
+                };
+                assert_eq!(client.get_cf(other, &key).unwrap(), expect);
+            }
+            let counters: Vec<(Key, Value)> = client.iter_cf(counter).unwrap().collect();
+            let expect: Vec<(Key, Value)> = (0..10)
+                .map(|i| (Key::from_u64(i), Value::from_u64(number / 10)))
+                .collect();
+            assert_eq!(counters, expect);
+            assert_eq!(client.iter().unwrap().count(), number as usize);
+            assert_eq!(
+                client
+                    .scan_cf(other, &Key::new("1"), &Key::new("2"))
+                    .unwrap()
+                    .count(),
+                111
+            );
+        };
+        check(&client);
+
+        db_server.flush().unwrap();
+        db_server
+            .compact_range(&Key::new(""), &Key::new("z"))
+            .unwrap();
+        check(&client);
+        // level changes of each family are tagged with its id
+        let families: HashSet<u32> = MetaLog::current_iter(dir.path(), &config.meta_log_file_name)
+            .unwrap()
+            .unwrap()
+            .map(|data| {
+                let change: ColumnFamilyChange = serde_json::from_slice(&data.unwrap()).unwrap();
+                change.column_family
+            })
+            .collect();
+        assert_eq!(
+            families,
+            HashSet::from([DEFAULT_COLUMN_FAMILY_ID, counter, other])
+        );
+
+        // unflushed writes are replayed to their families
+        client
+            .merge_cf(counter, &Key::from_u64(0), Value::from_u64(1))
+            .unwrap();
+        drop(client);
+        db_server.close().unwrap();
+
+        // family not in config is still opened, new family gets a new id
+        config.column_families = vec![
+            (String::from("counter"), counter_config),
+            (String::from("new"), build_config_for_test()),
+        ];
+        let db_server = DBServer::open_db(dir.path().to_path_buf(), config.clone()).unwrap();
+        let client = db_server.new_client().unwrap();
+        assert_eq!(client.column_family_id("counter"), Some(counter));
+        assert_eq!(client.column_family_id("other"), Some(other));
+        let new = client.column_family_id("new").unwrap();
+        assert!(new > counter && new > other);
+        assert_eq!(
+            client.get_cf(counter, &Key::from_u64(0)).unwrap(),
+            Some(Value::from_u64(number / 10 + 1))
+        );
+        assert_eq!(client.iter_cf(new).unwrap().count(), 0);
+        drop(client);
+        db_server.close().unwrap();
+    }
 }
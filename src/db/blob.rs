@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::info;
+
+use crate::db::common::KVIterItem;
+use crate::db::error::CorruptionError;
+use crate::db::file_storage::{FileId, FileStorageManager, ThreadSafeFileManager};
+use crate::db::value::{Value, ValueSlice, VALUE_SIZE_LIMIT};
+
+// [crc32 of value (u32),value len (u32)]
+const RECORD_HEADER_SIZE: usize = 8;
+pub const BLOB_POINTER_SIZE: usize = 16;
+
+/// position of value in blob file, saved in sstable entry instead of value
+/// [file id (u32),offset of record (u64),value len (u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobPointer {
+    file_id: FileId,
+    offset: u64,
+    size: u32,
+}
+
+/// blob file is append only and named by file id like sstable,
+/// it is deleted when no sstable in any version points to it
+/// record format: [crc32 of value (u32),value len (u32),value]
+pub struct BlobWriter {
+    file_manager: ThreadSafeFileManager,
+    // blob file is created when first value is added
+    writer: Option<(BufWriter<File>, FileId)>,
+    offset: u64,
+}
+
+/// write large value to blob file and replace it with pointer, used when memtable is flushed
+/// and sstables are compacted, value in blob files to relocate is moved to new blob file
+/// pointer is valid until next() is called, same as key and value slice of other iters
+pub struct BlobSeparateIter<'a, I: Iterator<Item = KVIterItem>> {
+    iter: I,
+    blob_writer: &'a mut BlobWriter,
+    threshold: usize,
+    pointer: [u8; BLOB_POINTER_SIZE],
+    error: Option<anyhow::Error>,
+    // (dir of blob files, blob files which have much garbage), empty if nothing to relocate
+    relocate: Option<(PathBuf, HashSet<FileId>)>,
+}
+
+impl BlobPointer {
+    pub fn encode(&self) -> [u8; BLOB_POINTER_SIZE] {
+        let mut res = [0; BLOB_POINTER_SIZE];
+        let mut w = &mut res[..];
+        w.write_u32::<LittleEndian>(self.file_id).unwrap();
+        w.write_u64::<LittleEndian>(self.offset).unwrap();
+        w.write_u32::<LittleEndian>(self.size).unwrap();
+        res
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Self> {
+        if data.len() != BLOB_POINTER_SIZE {
+            return Err(CorruptionError::new(format!(
+                "blob pointer size is {}, expect {}",
+                data.len(),
+                BLOB_POINTER_SIZE
+            ))
+            .into());
+        }
+        Ok(BlobPointer {
+            file_id: data.read_u32::<LittleEndian>()?,
+            offset: data.read_u64::<LittleEndian>()?,
+            size: data.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    pub fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    // bytes of record in blob file
+    pub fn record_size(&self) -> u64 {
+        (RECORD_HEADER_SIZE + self.size as usize) as u64
+    }
+
+    fn read(&self, home_path: &Path) -> Result<Value> {
+        let mut file = FileStorageManager::open_file(home_path, &self.file_id)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let crc = file.read_u32::<LittleEndian>()?;
+        let size = file.read_u32::<LittleEndian>()?;
+        if size != self.size {
+            return Err(CorruptionError::new(format!(
+                "value size in blob file {} is {}, expect {}",
+                self.file_id, size, self.size
+            ))
+            .into());
+        }
+        let mut data = vec![0; size as usize];
+        file.read_exact(&mut data)?;
+        if crc32fast::hash(&data) != crc {
+            return Err(CorruptionError::new(format!(
+                "blob file {} record at {} checksum mismatch",
+                self.file_id, self.offset
+            ))
+            .into());
+        }
+        Ok(Value::from_u8(&data))
+    }
+}
+
+// value of sstable entry, read it from blob file if entry is a pointer
+pub fn read_value(home_path: &Path, value: &ValueSlice) -> Result<Value> {
+    unsafe {
+        if !value.is_blob_pointer() {
+            return Ok(Value::from_u8(value.data()));
+        }
+        BlobPointer::decode(value.data())?.read(home_path)
+    }
+}
+
+impl BlobWriter {
+    pub fn new(file_manager: ThreadSafeFileManager) -> Self {
+        BlobWriter {
+            file_manager,
+            writer: None,
+            offset: 0,
+        }
+    }
+
+    pub fn add(&mut self, value: &[u8]) -> Result<BlobPointer> {
+        if self.writer.is_none() {
+            let (file, file_id, _) = self.file_manager.lock().unwrap().new_file()?;
+            info!("create blob file {}", file_id);
+            self.writer = Some((BufWriter::new(file), file_id));
+        }
+        let (writer, file_id) = self.writer.as_mut().unwrap();
+        writer.write_u32::<LittleEndian>(crc32fast::hash(value))?;
+        writer.write_u32::<LittleEndian>(value.len() as u32)?;
+        writer.write_all(value)?;
+        let pointer = BlobPointer {
+            file_id: *file_id,
+            offset: self.offset,
+            size: value.len() as u32,
+        };
+        self.offset += (RECORD_HEADER_SIZE + value.len()) as u64;
+        Ok(pointer)
+    }
+
+    // sync blob file, it must be persisted before sstable pointing to it is added to version
+    pub fn finish(&mut self) -> Result<Option<FileId>> {
+        match self.writer.as_mut() {
+            None => Ok(None),
+            Some((writer, file_id)) => {
+                writer.flush()?;
+                writer.get_mut().sync_all()?;
+                Ok(Some(*file_id))
+            }
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = KVIterItem>> BlobSeparateIter<'a, I> {
+    // value not less than threshold is written to blob file, threshold is at most VALUE_SIZE_LIMIT
+    pub fn new(iter: I, blob_writer: &'a mut BlobWriter, threshold: usize) -> Self {
+        BlobSeparateIter {
+            iter,
+            blob_writer,
+            threshold: threshold.min(VALUE_SIZE_LIMIT),
+            pointer: [0; BLOB_POINTER_SIZE],
+            error: None,
+            relocate: None,
+        }
+    }
+
+    // values in blob files are moved to new blob file, so old files are deleted when no sstable points to them
+    pub fn with_relocation(mut self, home_path: &Path, file_ids: HashSet<FileId>) -> Self {
+        if !file_ids.is_empty() {
+            self.relocate = Some((home_path.to_path_buf(), file_ids));
+        }
+        self
+    }
+
+    // value of blob pointer in blob file to relocate, none if it is not relocated
+    fn read_relocated_value(&self, value: &ValueSlice) -> Result<Option<Value>> {
+        let (home_path, file_ids) = match &self.relocate {
+            Some(relocate) if value.is_blob_pointer() => relocate,
+            _ => return Ok(None),
+        };
+        let pointer = BlobPointer::decode(unsafe { value.data() })?;
+        if !file_ids.contains(&pointer.file_id()) {
+            return Ok(None);
+        }
+        pointer.read(home_path).map(Some)
+    }
+
+    // iter stops when fail to write blob file, return the error
+    pub fn finish(self) -> Result<()> {
+        match self.error {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = KVIterItem>> Iterator for BlobSeparateIter<'a, I> {
+    type Item = KVIterItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        let (k, v) = self.iter.next()?;
+        let relocated = match v.as_ref().map(|value| self.read_relocated_value(value)) {
+            Some(Err(err)) => {
+                self.error = Some(err);
+                return None;
+            }
+            Some(Ok(relocated)) => relocated,
+            None => None,
+        };
+        let value = match v {
+            Some(value) if relocated.is_some() => value,
+            // merge operands are read and combined by compaction, they are kept in sstable
+            Some(value)
+                if !value.is_blob_pointer()
+                    && !value.is_merge_operand()
+                    && value.len() >= self.threshold =>
+            {
+                value
+            }
+            _ => return Some((k, v)),
+        };
+        let data = match &relocated {
+            Some(relocated) => relocated.data(),
+            None => unsafe { value.data() },
+        };
+        match self.blob_writer.add(data) {
+            Ok(pointer) => {
+                self.pointer = pointer.encode();
+                Some((
+                    k,
+                    Some(
+                        ValueSlice::new_blob_pointer(&self.pointer)
+                            .with_expire_time(value.expire_time()),
+                    ),
+                ))
+            }
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use crate::db::common::KVIterItem;
+    use crate::db::error::CorruptionError;
+    use crate::db::file_storage::FileStorageManager;
+    use crate::db::key::{Key, KeySlice};
+    use crate::db::value::{Value, ValueSlice};
+
+    use super::{read_value, BlobPointer, BlobSeparateIter, BlobWriter};
+
+    #[test]
+    fn test_write_and_read_blob() {
+        let dir = tempdir().unwrap();
+        let file_manager = FileStorageManager::new(dir.path()).into_thread_safe();
+        let mut writer = BlobWriter::new(file_manager);
+        assert!(writer.finish().unwrap().is_none());
+
+        let values: Vec<Vec<u8>> = vec![vec![1; 5000], vec![], vec![2; 100 * 1024]];
+        let pointers: Vec<BlobPointer> = values.iter().map(|v| writer.add(v).unwrap()).collect();
+        let file_id = writer.finish().unwrap().unwrap();
+
+        for (value, pointer) in values.iter().zip(pointers.iter()) {
+            assert_eq!(pointer.file_id(), file_id);
+            let data = pointer.encode();
+            assert_eq!(BlobPointer::decode(&data).unwrap(), *pointer);
+            let res = read_value(dir.path(), &ValueSlice::new_blob_pointer(&data)).unwrap();
+            assert_eq!(res.data(), value.as_slice());
+        }
+        // inline value
+        let res = read_value(dir.path(), &ValueSlice::new(&[3, 4])).unwrap();
+        assert_eq!(res, Value::from_u8(&[3, 4]));
+
+        // damaged blob file
+        let path = FileStorageManager::file_path(dir.path(), &file_id);
+        let mut data = fs::read(&path).unwrap();
+        data[100] ^= 1;
+        fs::write(&path, data).unwrap();
+        let err = read_value(
+            dir.path(),
+            &ValueSlice::new_blob_pointer(&pointers[0].encode()),
+        )
+        .err()
+        .unwrap();
+        assert!(err.downcast_ref::<CorruptionError>().is_some());
+        assert!(BlobPointer::decode(&[0; 3]).is_err());
+    }
+
+    #[test]
+    fn test_blob_separate_iter() {
+        let dir = tempdir().unwrap();
+        let file_manager = FileStorageManager::new(dir.path()).into_thread_safe();
+        let mut writer = BlobWriter::new(file_manager);
+        let keys: Vec<Key> = (0..3).map(Key::from_u64).collect();
+        let small = vec![1; 10];
+        let large = vec![2; 2000];
+        let input: Vec<KVIterItem> = vec![
+            (KeySlice::new(keys[0].data()), Some(ValueSlice::new(&small))),
+            (KeySlice::new(keys[1].data()), Some(ValueSlice::new(&large))),
+            (KeySlice::new(keys[2].data()), None),
+        ];
+        let mut iter = BlobSeparateIter::new(input.into_iter(), &mut writer, 100);
+        let mut entries = Vec::new();
+        for (k, v) in iter.by_ref() {
+            // copy pointer, it is invalid after next()
+            let v = v.map(|v| (v.is_blob_pointer(), Vec::from(unsafe { v.data() })));
+            entries.push((Key::from(unsafe { k.data() }), v));
+        }
+        iter.finish().unwrap();
+        assert!(writer.finish().unwrap().is_some());
+        let res: Vec<(Key, Option<Value>)> = entries
+            .into_iter()
+            .map(|(k, v)| {
+                let value = v.map(|(is_blob_pointer, data)| {
+                    assert_eq!(is_blob_pointer, data.len() != small.len());
+                    let slice = if is_blob_pointer {
+                        ValueSlice::new_blob_pointer(&data)
+                    } else {
+                        ValueSlice::new(&data)
+                    };
+                    read_value(dir.path(), &slice).unwrap()
+                });
+                (k, value)
+            })
+            .collect();
+        assert_eq!(
+            res,
+            vec![
+                (keys[0].clone(), Some(Value::from_u8(&small))),
+                (keys[1].clone(), Some(Value::from_u8(&large))),
+                (keys[2].clone(), None),
+            ]
+        );
+    }
+}
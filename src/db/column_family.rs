@@ -0,0 +1,157 @@
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::config::Config;
+use crate::db::level::LevelChange;
+use crate::db::memtable::{FlushedMemtables, Memtable};
+use crate::db::version::{CompactingFiles, Version};
+
+use super::ThreadSafeData;
+
+pub type ColumnFamilyId = u32;
+
+// family which always exists, it is used by methods without column family
+pub const DEFAULT_COLUMN_FAMILY_ID: ColumnFamilyId = 0;
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+/// logical table in db with its own memtables, sstables and config
+/// families share memtable log, sequence numbers and meta log, so one write batch can span them
+pub struct ColumnFamily {
+    id: ColumnFamilyId,
+    name: String,
+    // db wide fields like log names and thread number are read from config of db
+    config: Config,
+    data: ThreadSafeData,
+    // compactions picked but not installed in this family
+    compacting_files: Mutex<CompactingFiles>,
+    // notified when a task is removed from compacting files
+    compaction_finished: Condvar,
+    // pushed when immutable memtable is removed from data, under write lock of data
+    flushed_memtables: Mutex<FlushedMemtables>,
+}
+
+/// level change of a family in meta log, change without family id is written by old version for default family
+/// snapshot record has name of family, so family id is found by name when db is opened
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ColumnFamilyChange {
+    #[serde(default)]
+    pub column_family: ColumnFamilyId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub change: LevelChange,
+}
+
+impl ColumnFamily {
+    pub fn new(
+        id: ColumnFamilyId,
+        name: &str,
+        config: Config,
+        memtable: Memtable,
+        version: Version,
+    ) -> Self {
+        let flushed_memtables = FlushedMemtables::new(
+            config.max_write_buffer_size_to_maintain,
+            version.last_sequence(),
+        );
+        ColumnFamily {
+            id,
+            name: String::from(name),
+            config,
+            data: Arc::new(RwLock::new((
+                Arc::new(Mutex::new(Arc::new(memtable))),
+                Vec::new(),
+                Arc::new(Mutex::new(Arc::new(version))),
+            ))),
+            compacting_files: Mutex::new(CompactingFiles::new()),
+            compaction_finished: Condvar::new(),
+            flushed_memtables: Mutex::new(flushed_memtables),
+        }
+    }
+
+    pub fn id(&self) -> ColumnFamilyId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn data(&self) -> &ThreadSafeData {
+        &self.data
+    }
+
+    pub fn compacting_files(&self) -> &Mutex<CompactingFiles> {
+        &self.compacting_files
+    }
+
+    // wait with lock of compacting files for a task to be removed
+    pub fn compaction_finished(&self) -> &Condvar {
+        &self.compaction_finished
+    }
+
+    // task is installed or failed, its files can be picked again
+    pub fn finish_compaction(&self, task_id: u64) {
+        self.compacting_files.lock().unwrap().remove(task_id);
+        self.compaction_finished.notify_all();
+    }
+
+    pub fn flushed_memtables(&self) -> &Mutex<FlushedMemtables> {
+        &self.flushed_memtables
+    }
+
+    // snapshot of current version for meta log
+    pub fn snapshot(&self, version: &Version) -> ColumnFamilyChange {
+        ColumnFamilyChange {
+            column_family: self.id,
+            name: Some(self.name.clone()),
+            change: version.snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::level::LevelChange;
+
+    use super::{ColumnFamilyChange, DEFAULT_COLUMN_FAMILY_ID};
+
+    #[test]
+    fn test_column_family_change_serde() {
+        let change = ColumnFamilyChange {
+            column_family: 2,
+            name: Some(String::from("cf")),
+            change: LevelChange::DropSStables {
+                level: 0,
+                sstables: vec![],
+            },
+        };
+        let data = serde_json::to_string(&change).unwrap();
+        let res: ColumnFamilyChange = serde_json::from_str(&data).unwrap();
+        assert_eq!(res.column_family, 2);
+        assert_eq!(res.name.as_deref(), Some("cf"));
+        assert!(matches!(
+            res.change,
+            LevelChange::DropSStables { level: 0, .. }
+        ));
+
+        // level change written before column family is added
+        let data = serde_json::to_string(&LevelChange::DropSStables {
+            level: 1,
+            sstables: vec![],
+        })
+        .unwrap();
+        let res: ColumnFamilyChange = serde_json::from_str(&data).unwrap();
+        assert_eq!(res.column_family, DEFAULT_COLUMN_FAMILY_ID);
+        assert!(res.name.is_none());
+        assert!(matches!(
+            res.change,
+            LevelChange::DropSStables { level: 1, .. }
+        ));
+    }
+}
@@ -1,43 +1,90 @@
 use std::cmp::{Ordering, Reverse};
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, VecDeque};
 use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
 
 use serde_json::map::Values;
 
-use crate::db::key::KeySlice;
-use crate::db::value::{Value, ValueSlice};
+use crate::db::key::{Key, KeySlice, SeqNumber};
+use crate::db::merge_operator::MergeOperator;
+use crate::db::range_tombstone::RangeTombstones;
+use crate::db::value::{now_millis, Value, ValueSlice, VALUE_SIZE_LIMIT};
 
 // None if value is deleted
 pub type ValueSliceTag = Option<ValueSlice>;
 pub type ValueWithTag = Option<Value>;
 pub type KVIterItem = (KeySlice, ValueSliceTag);
+// first error of iters which stop early, shared with reader as iters are boxed in SortedKVIter
+pub type IterError = Arc<Mutex<Option<anyhow::Error>>>;
 
 #[derive(PartialEq, Eq)]
 struct KVPair(KVIterItem, usize);
 
-/// input: sorted kv pair(by key), output: sorted kv pair
-/// if find same key, return the kv from the iter which was the smallest number in the input iter vec
+/// input: sorted kv pair(by internal key), output: sorted kv pair
+/// versions of same user key with different sequence number are all returned, newer first
+/// if find same internal key, return the kv from the iter which was the smallest number in the input iter vec
 /// that is to say, overwrite priority is decided by the order in the iters.eg iters[0]>iters[1]>..>iters[n]
 pub struct SortedKVIter<'a> {
-    iters: Vec<&'a mut dyn Iterator<Item = KVIterItem>>,
+    iters: Vec<Box<dyn Iterator<Item = KVIterItem> + 'a>>,
     heap: BinaryHeap<Reverse<KVPair>>,
     iters_need_push_to_heap: Vec<usize>,
 }
 
+/// drop versions which can't be read by anyone, used when memtable is flushed and sstables are compacted
+/// versions of a user key are split into ranges by live snapshots, reader with snapshot reads the newest
+/// version not newer than it, so only the newest version in each range is kept
+/// deleted kv in the oldest range is dropped if discard_deleted_kv is true, eg. there is no older level
+/// version deleted by newer range tombstone in same range is dropped too
+/// merge operands are combined with older version in same range if merge operator is set,
+/// versions under operands are kept if they can't be combined
+/// expired value is dropped like deleted kv
+pub struct CompactKVIter<I: Iterator<Item = KVIterItem>> {
+    iter: I,
+    // sequence number of live snapshots in ascending order
+    snapshots: Vec<SeqNumber>,
+    discard_deleted_kv: bool,
+    range_tombstones: RangeTombstones,
+    last_key: Vec<u8>,
+    // range of last version of last_key, none before first kv
+    last_range: Option<usize>,
+    // older versions in same range are still needed by last version
+    last_is_operand: bool,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    // (sequence number,operand) of last_key in last_range from new to old, waiting for older version
+    operands: Vec<(SeqNumber, Value)>,
+    // kv read after operands, returned after them
+    pending: Option<KVIterItem>,
+    // (key,sequence number,value,is merge operand,expire time) built from operands
+    output: VecDeque<(Key, SeqNumber, Value, bool, u64)>,
+    // last returned kv of output, returned slices point to it
+    current: Option<(Key, SeqNumber, Value, bool, u64)>,
+    // milliseconds since unix epoch, value expired before it is deleted
+    now: u64,
+}
+
 impl PartialOrd for KVPair {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        unsafe { Some(self.0 .0.data().cmp(other.0 .0.data())) }
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for KVPair {
     fn cmp(&self, other: &Self) -> Ordering {
-        unsafe { self.0 .0.data().cmp(other.0 .0.data()) }
+        self.0 .0.cmp(&other.0 .0)
     }
 }
 
 impl<'a> SortedKVIter<'a> {
-    pub fn new(mut iters: Vec<&'a mut dyn Iterator<Item = KVIterItem>>) -> Self {
+    pub fn new(iters: Vec<&'a mut dyn Iterator<Item = KVIterItem>>) -> Self {
+        let boxed_iters = iters
+            .into_iter()
+            .map(|iter| Box::new(iter) as Box<dyn Iterator<Item = KVIterItem> + 'a>)
+            .collect();
+        Self::from_boxed(boxed_iters)
+    }
+
+    // same as new, but SortedKVIter owns the input iters
+    pub fn from_boxed(mut iters: Vec<Box<dyn Iterator<Item = KVIterItem> + 'a>>) -> Self {
         let mut heap = BinaryHeap::new();
 
         for (p, iter_ref) in iters.iter_mut().enumerate() {
@@ -64,14 +111,13 @@ impl<'a> SortedKVIter<'a> {
                 let entry = reversed_entry.0;
                 let iter_index = entry.1;
                 self.iters_need_push_to_heap.push(iter_index);
-                return Some(entry);
+                Some(entry)
             }
             None => None,
         }
     }
     fn push_iters_to_heap(&mut self) {
-        while !self.iters_need_push_to_heap.is_empty() {
-            let position = self.iters_need_push_to_heap.pop().unwrap();
+        while let Some(position) = self.iters_need_push_to_heap.pop() {
             let next = self.iters[position].next();
             if let Some(e) = next {
                 self.heap.push(Reverse(KVPair(e, position)));
@@ -81,13 +127,9 @@ impl<'a> SortedKVIter<'a> {
 
     fn build_next(&mut self) -> Option<(KeySlice, Option<ValueSlice>)> {
         //         pop one,check if exits
-        let res_option = self.pop_min();
-        if res_option.is_none() {
-            return None;
-        }
+        let mut res = self.pop_min()?;
         //         loop check top and pop until key is not same
         //         return kv from smallest iter
-        let mut res = res_option.unwrap();
         loop {
             let top_option = self.top();
             if top_option.is_none() {
@@ -121,36 +163,218 @@ impl<'a> Iterator for SortedKVIter<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.push_iters_to_heap();
-        let res = self.build_next();
+        self.build_next()
+    }
+}
+
+impl<I: Iterator<Item = KVIterItem>> CompactKVIter<I> {
+    pub fn new(iter: I, mut snapshots: Vec<SeqNumber>, discard_deleted_kv: bool) -> Self {
+        snapshots.sort_unstable();
+        CompactKVIter {
+            iter,
+            snapshots,
+            discard_deleted_kv,
+            range_tombstones: RangeTombstones::new(),
+            last_key: Vec::new(),
+            last_range: None,
+            last_is_operand: false,
+            merge_operator: None,
+            operands: Vec::new(),
+            pending: None,
+            output: VecDeque::new(),
+            current: None,
+            now: now_millis(),
+        }
+    }
+
+    pub fn with_merge_operator(mut self, merge_operator: Option<Arc<dyn MergeOperator>>) -> Self {
+        self.merge_operator = merge_operator;
+        self
+    }
+
+    // range tombstones of all input iters
+    pub fn with_range_tombstones(mut self, range_tombstones: RangeTombstones) -> Self {
+        self.range_tombstones = range_tombstones;
+        self
+    }
+
+    // range tombstones to write with output kvs, tombstone in the oldest range is dropped like deleted kv
+    pub fn output_range_tombstones(&self) -> RangeTombstones {
+        let mut res = self.range_tombstones.clone();
+        if self.discard_deleted_kv {
+            res.retain(|t| self.range(t.seq()) > 0);
+        }
         res
     }
+
+    // index of the oldest snapshot which can read version with seq, snapshots.len() if no snapshot can
+    fn range(&self, seq: SeqNumber) -> usize {
+        self.snapshots.partition_point(|s| *s < seq)
+    }
+
+    // combine operands with existing value into one value,
+    // return false and keep operands if it is too large for sstable block
+    // combined value expires with existing value
+    fn merge_operands(&mut self, existing: Option<Value>, expire_time: u64) -> bool {
+        let operator = self.merge_operator.as_ref().unwrap();
+        let key = Key::from(self.last_key.as_slice());
+        let operands: Vec<Value> = self.operands.iter().rev().map(|(_, v)| v.clone()).collect();
+        let value = operator.full_merge(&key, existing.as_ref(), &operands);
+        if value.len() >= VALUE_SIZE_LIMIT {
+            return false;
+        }
+        self.output
+            .push_back((key, self.operands[0].0, value, false, expire_time));
+        self.operands.clear();
+        true
+    }
+
+    // output operands which are not combined with older version,
+    // they are combined with nothing if there is no older version anywhere
+    fn flush_operands(&mut self, has_older_version: bool) {
+        if !has_older_version
+            && self.discard_deleted_kv
+            && self.last_range == Some(0)
+            && self.merge_operands(None, 0)
+        {
+            return;
+        }
+        let key = Key::from(self.last_key.as_slice());
+        if self.operands.len() > 1 {
+            let operands: Vec<Value> = self.operands.iter().rev().map(|(_, v)| v.clone()).collect();
+            let operator = self.merge_operator.as_ref().unwrap();
+            if let Some(value) = operator.partial_merge(&key, &operands) {
+                if value.len() < VALUE_SIZE_LIMIT {
+                    self.output
+                        .push_back((key, self.operands[0].0, value, true, 0));
+                    self.operands.clear();
+                    return;
+                }
+            }
+        }
+        for (seq, value) in self.operands.drain(..) {
+            self.output.push_back((key.clone(), seq, value, true, 0));
+        }
+    }
+}
+
+impl<I: Iterator<Item = KVIterItem>> Iterator for CompactKVIter<I> {
+    type Item = KVIterItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.output.pop_front() {
+                let (key, seq, value, is_operand, expire_time) = self.current.insert(entry);
+                let value = if *is_operand {
+                    ValueSlice::new_merge_operand(value.data())
+                } else {
+                    ValueSlice::new(value.data()).with_expire_time(*expire_time)
+                };
+                return Some((KeySlice::new_with_seq(key.data(), *seq), Some(value)));
+            }
+            let (k, v) = match self.pending.take().or_else(|| self.iter.next()) {
+                Some(kv) => kv,
+                None if self.operands.is_empty() => return None,
+                None => {
+                    self.flush_operands(false);
+                    continue;
+                }
+            };
+            let v = v.filter(|v| !v.is_expired(self.now));
+            let range = self.range(k.seq());
+            let key = unsafe { k.data() };
+            let same_key = self.last_range.is_some() && key == self.last_key.as_slice();
+            let same_range = same_key && self.last_range == Some(range);
+            if !self.operands.is_empty() && !same_range {
+                // no older version of operands in input
+                self.pending = Some((k, v));
+                self.flush_operands(false);
+                continue;
+            }
+            if same_key {
+                if same_range && !self.last_is_operand {
+                    // hidden by newer version in same range
+                    continue;
+                }
+            } else {
+                self.last_key.clear();
+                self.last_key.extend_from_slice(key);
+            }
+            self.last_range = Some(range);
+            let covered = self
+                .range_tombstones
+                .covering(key)
+                .any(|t| t.seq() > k.seq() && self.range(t.seq()) == range);
+            let is_operand = !covered && v.is_some_and(|v| v.is_merge_operand());
+            if self.merge_operator.is_some() {
+                if is_operand {
+                    self.operands
+                        .push((k.seq(), Value::from_u8(unsafe { v.unwrap().data() })));
+                    self.last_is_operand = true;
+                    continue;
+                }
+                if !self.operands.is_empty() {
+                    // blob value is not read by compaction
+                    let existing = match v {
+                        _ if covered => Some(None),
+                        Some(v) if v.is_blob_pointer() => None,
+                        v => Some(v.map(|v| Value::from_u8(unsafe { v.data() }))),
+                    };
+                    let expire_time = v.filter(|_| !covered).map_or(0, |v| v.expire_time());
+                    if let Some(existing) = existing {
+                        if self.merge_operands(existing, expire_time) {
+                            self.last_is_operand = false;
+                            continue;
+                        }
+                    }
+                    // return it after operands
+                    self.pending = Some((k, v));
+                    self.flush_operands(true);
+                    continue;
+                }
+            }
+            self.last_is_operand = is_operand;
+            if v.is_none() && self.discard_deleted_kv && range == 0 {
+                continue;
+            }
+            if covered {
+                continue;
+            }
+            return Some((k, v));
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::str::from_utf8;
 
-    use crate::db::common::SortedKVIter;
+    use crate::db::common::{CompactKVIter, KVIterItem, SortedKVIter};
+    use std::sync::Arc;
+
     use crate::db::key::{Key, KeySlice};
+    use crate::db::merge_operator::test::AddOperator;
+    use crate::db::merge_operator::MergeOperator;
+    use crate::db::range_tombstone::{RangeTombstone, RangeTombstones};
     use crate::db::value::{Value, ValueSlice};
 
     #[test]
     pub fn test_sorted_kv_iter() {
         // a,b,c,f
-        let a = vec![
+        let a = [
             (Key::new("a"), Value::new("a1")),
             (Key::new("b"), Value::new("b1")),
             (Key::new("c"), Value::new("c1")),
             (Key::new("f"), Value::new("f1")),
         ];
         // a,b,e
-        let b = vec![
+        let b = [
             (Key::new("a"), Value::new("a2")),
             (Key::new("b"), Value::new("b2")),
             (Key::new("e"), Value::new("e2")),
         ];
         // b,d,e
-        let c = vec![
+        let c = [
             (Key::new("b"), Value::new("b3")),
             (Key::new("d"), Value::new("d3")),
             (Key::new("e"), Value::new("e3")),
@@ -177,7 +401,7 @@ mod test {
 
     #[test]
     pub fn test_sorted_kv_iter_top() {
-        let a = vec![
+        let a = [
             (Key::new("a"), Value::new("a1")),
             (Key::new("b"), Value::new("b1")),
         ];
@@ -190,5 +414,164 @@ mod test {
         kv_iter.next();
         // assert!(!kv_iter.has_next());
     }
-}
 
+    #[test]
+    pub fn test_compact_kv_iter() {
+        // (key,seq,value), none if deleted
+        let a = vec![("a", 9, Some("a9")), ("b", 8, None), ("c", 2, Some("c2"))];
+        let b = vec![
+            ("a", 5, Some("a5")),
+            ("a", 3, None),
+            ("a", 1, Some("a1")),
+            ("b", 4, Some("b4")),
+            ("c", 2, Some("old")),
+        ];
+        let to_iter = |kvs: &Vec<(&'static str, u64, Option<&'static str>)>| {
+            kvs.iter()
+                .map(|(k, seq, v)| {
+                    (
+                        KeySlice::new_with_seq(k.as_bytes(), *seq),
+                        v.map(|v| ValueSlice::new(v.as_bytes())),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let collect = |snapshots: Vec<u64>, discard_deleted_kv: bool| {
+            let iters: Vec<Box<dyn Iterator<Item = KVIterItem>>> = vec![
+                Box::new(to_iter(&a).into_iter()),
+                Box::new(to_iter(&b).into_iter()),
+            ];
+            let iter = CompactKVIter::new(
+                SortedKVIter::from_boxed(iters),
+                snapshots,
+                discard_deleted_kv,
+            );
+            iter.map(|(k, v)| unsafe {
+                let v = v.as_ref().map_or("-", |v| from_utf8(v.data()).unwrap());
+                format!("{}{}{}", k, k.seq(), v)
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+        };
+        // same internal key in iter a overwrites b
+        assert_eq!(collect(vec![], false), "a9a9,b8-,c2c2");
+        assert_eq!(collect(vec![], true), "a9a9,c2c2");
+        assert_eq!(collect(vec![5, 2], false), "a9a9,a5a5,a1a1,b8-,b4b4,c2c2");
+        assert_eq!(collect(vec![4], true), "a9a9,b8-,b4b4,c2c2");
+    }
+
+    #[test]
+    pub fn test_compact_kv_iter_expired_value() {
+        // (key,seq,value,expire time)
+        let kvs = [
+            ("a", 5, "a5", 1),
+            ("a", 3, "a3", 0),
+            ("b", 4, "b4", u64::MAX),
+        ];
+        let collect = |discard_deleted_kv: bool| {
+            let iter = kvs.iter().map(|(k, seq, v, expire_time)| {
+                (
+                    KeySlice::new_with_seq(k.as_bytes(), *seq),
+                    Some(ValueSlice::new(v.as_bytes()).with_expire_time(*expire_time)),
+                )
+            });
+            CompactKVIter::new(iter, vec![], discard_deleted_kv)
+                .map(|(k, v)| unsafe {
+                    let v = v.as_ref().map_or("-", |v| from_utf8(v.data()).unwrap());
+                    format!("{}{}{}", k, k.seq(), v)
+                })
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        // expired value hides older versions like deleted kv
+        assert_eq!(collect(false), "a5-,b4b4");
+        assert_eq!(collect(true), "b4b4");
+    }
+
+    #[test]
+    pub fn test_compact_kv_iter_merge_operands() {
+        // (key,seq,value,is merge operand), none if deleted
+        let kvs = vec![
+            ("a", 9, Some("4"), true),
+            ("a", 8, Some("3"), true),
+            ("a", 5, Some("2"), true),
+            ("a", 4, Some("10"), false),
+            ("a", 1, Some("1"), true),
+            ("b", 7, Some("2"), true),
+            ("b", 6, None, false),
+            ("b", 2, Some("5"), false),
+            ("c", 3, Some("1"), true),
+            ("c", 2, Some("1"), true),
+        ];
+        let collect = |snapshots: Vec<u64>, discard_deleted_kv: bool, operator: bool| {
+            let iter = kvs.iter().map(|(k, seq, v, is_operand)| {
+                let v = v.map(|v| {
+                    if *is_operand {
+                        ValueSlice::new_merge_operand(v.as_bytes())
+                    } else {
+                        ValueSlice::new(v.as_bytes())
+                    }
+                });
+                (KeySlice::new_with_seq(k.as_bytes(), *seq), v)
+            });
+            let operator: Option<Arc<dyn MergeOperator>> = if operator {
+                Some(Arc::new(AddOperator))
+            } else {
+                None
+            };
+            CompactKVIter::new(iter, snapshots, discard_deleted_kv)
+                .with_merge_operator(operator)
+                .map(|(k, v)| unsafe {
+                    let v = v.as_ref().map_or("-".to_string(), |v| {
+                        let mark = if v.is_merge_operand() { "+" } else { "" };
+                        format!("{}{}", mark, from_utf8(v.data()).unwrap())
+                    });
+                    format!("{}{}:{}", k, k.seq(), v)
+                })
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        // operands are combined with older value or deletion, c has no older version
+        assert_eq!(collect(vec![], false, true), "a9:19,b7:2,c3:+2");
+        // nothing is older than operands of c
+        assert_eq!(collect(vec![], true, true), "a9:19,b7:2,c3:2");
+        // operands are not combined across snapshot
+        assert_eq!(collect(vec![5], false, true), "a9:+7,a5:12,b7:2,b2:5,c3:+2");
+        // versions under operands are kept without merge operator
+        assert_eq!(
+            collect(vec![], false, false),
+            "a9:+4,a8:+3,a5:+2,a4:10,b7:+2,b6:-,c3:+1,c2:+1"
+        );
+    }
+
+    #[test]
+    pub fn test_compact_kv_iter_range_tombstones() {
+        let kvs = [("a", 6), ("b", 7), ("b", 3), ("c", 2), ("d", 1)];
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add(RangeTombstone::new(Key::new("b"), Key::new("d"), 5));
+        let collect = |snapshots: Vec<u64>, discard_deleted_kv: bool| {
+            let iter = kvs.iter().map(|(k, seq)| {
+                (
+                    KeySlice::new_with_seq(k.as_bytes(), *seq),
+                    Some(ValueSlice::new(k.as_bytes())),
+                )
+            });
+            let iter = CompactKVIter::new(iter, snapshots, discard_deleted_kv)
+                .with_range_tombstones(tombstones.clone());
+            let tombstone_number = iter.output_range_tombstones().len();
+            let res = iter
+                .map(|(k, _)| format!("{}{}", k, k.seq()))
+                .collect::<Vec<String>>()
+                .join(",");
+            (res, tombstone_number)
+        };
+        // b3 and c2 are deleted by tombstone, d is not in range
+        assert_eq!(collect(vec![], false), ("a6,b7,d1".to_string(), 1));
+        // snapshot 3 reads b3 and c2
+        assert_eq!(collect(vec![3], false), ("a6,b7,b3,c2,d1".to_string(), 1));
+        // tombstone is dropped with deleted kvs in the oldest range
+        assert_eq!(collect(vec![], true), ("a6,b7,d1".to_string(), 0));
+        assert_eq!(collect(vec![4], true), ("a6,b7,b3,c2,d1".to_string(), 1));
+        assert_eq!(collect(vec![5], true), ("a6,b7,d1".to_string(), 0));
+    }
+}
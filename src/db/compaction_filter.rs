@@ -0,0 +1,198 @@
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use metrics::increment_counter;
+
+use crate::db::blob;
+use crate::db::common::KVIterItem;
+use crate::db::db_metrics::{COMPACTION_FILTER_CHANGE_COUNT, COMPACTION_FILTER_REMOVE_COUNT};
+use crate::db::key::{Key, KeySlice, SeqNumber};
+use crate::db::value::{Value, ValueSlice, VALUE_SIZE_LIMIT};
+
+pub enum CompactionFilterDecision {
+    Keep,
+    Remove,
+    // replace value, it must be less than VALUE_SIZE_LIMIT
+    Change(Value),
+}
+
+/// decide whether kv is kept, removed or changed when sstables are compacted, registered in config
+/// eg. remove expired sessions or soft deleted rows
+pub trait CompactionFilter: Debug + Send + Sync {
+    fn filter(&self, key: &Key, value: &Value) -> CompactionFilterDecision;
+}
+
+/// apply compaction filter to output of CompactKVIter
+/// only the newest version which no snapshot reads is filtered, deleted kv and merge operand are not,
+/// removed kv is written as deleted kv if older versions of it may exist
+pub struct CompactionFilterIter<I: Iterator<Item = KVIterItem>> {
+    iter: I,
+    filter: Option<Arc<dyn CompactionFilter>>,
+    // kv newer than it is filtered
+    newest_snapshot: Option<SeqNumber>,
+    discard_deleted_kv: bool,
+    // read blob value
+    home_path: PathBuf,
+    // changed value, returned slice points to it
+    value: Value,
+    error: Option<anyhow::Error>,
+}
+
+impl<I: Iterator<Item = KVIterItem>> CompactionFilterIter<I> {
+    pub fn new(
+        iter: I,
+        filter: Option<Arc<dyn CompactionFilter>>,
+        snapshots: &[SeqNumber],
+        discard_deleted_kv: bool,
+        home_path: PathBuf,
+    ) -> Self {
+        CompactionFilterIter {
+            iter,
+            filter,
+            newest_snapshot: snapshots.iter().max().copied(),
+            discard_deleted_kv,
+            home_path,
+            value: Value::new(""),
+            error: None,
+        }
+    }
+
+    // iter stops when fail to read blob value or changed value is too large, return the error
+    pub fn finish(self) -> Result<()> {
+        match self.error {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
+
+    fn decide(&self, k: &KeySlice, v: &ValueSlice) -> Result<CompactionFilterDecision> {
+        let filter = self.filter.as_ref().unwrap();
+        let key = Key::from(unsafe { k.data() });
+        let value = blob::read_value(&self.home_path, v)?;
+        let decision = filter.filter(&key, &value);
+        if let CompactionFilterDecision::Change(value) = &decision {
+            if value.len() >= VALUE_SIZE_LIMIT {
+                return Err(anyhow!(
+                    "value of key {} changed by compaction filter is too large",
+                    key
+                ));
+            }
+        }
+        Ok(decision)
+    }
+}
+
+impl<I: Iterator<Item = KVIterItem>> Iterator for CompactionFilterIter<I> {
+    type Item = KVIterItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.error.is_some() {
+                return None;
+            }
+            let (k, v) = self.iter.next()?;
+            let value = match v {
+                Some(value)
+                    if self.filter.is_some()
+                        && !value.is_merge_operand()
+                        && self.newest_snapshot.is_none_or(|s| k.seq() > s) =>
+                {
+                    value
+                }
+                _ => return Some((k, v)),
+            };
+            match self.decide(&k, &value) {
+                Ok(CompactionFilterDecision::Keep) => return Some((k, v)),
+                Ok(CompactionFilterDecision::Remove) => {
+                    increment_counter!(COMPACTION_FILTER_REMOVE_COUNT);
+                    // older versions are read by snapshots or in lower levels
+                    if self.discard_deleted_kv && self.newest_snapshot.is_none() {
+                        continue;
+                    }
+                    return Some((k, None));
+                }
+                Ok(CompactionFilterDecision::Change(new_value)) => {
+                    increment_counter!(COMPACTION_FILTER_CHANGE_COUNT);
+                    self.value = new_value;
+                    // changed value keeps expire time
+                    let value =
+                        ValueSlice::new(self.value.data()).with_expire_time(value.expire_time());
+                    return Some((k, Some(value)));
+                }
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::path::PathBuf;
+    use std::str::from_utf8;
+    use std::sync::Arc;
+
+    use crate::db::key::{Key, KeySlice};
+    use crate::db::value::{Value, ValueSlice};
+
+    use super::{CompactionFilter, CompactionFilterDecision, CompactionFilterIter};
+
+    // remove value "deleted", upper case value "change"
+    #[derive(Debug)]
+    pub struct SoftDeleteFilter;
+
+    impl CompactionFilter for SoftDeleteFilter {
+        fn filter(&self, key: &Key, value: &Value) -> CompactionFilterDecision {
+            match value.data() {
+                b"deleted" => CompactionFilterDecision::Remove,
+                b"change" => CompactionFilterDecision::Change(Value::new("CHANGE")),
+                _ => CompactionFilterDecision::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter_iter() {
+        let kvs = [
+            ("a", 5, Some("deleted")),
+            ("b", 6, Some("change")),
+            ("c", 2, Some("deleted")),
+            ("d", 7, Some("keep")),
+            ("e", 8, None),
+        ];
+        let collect = |snapshots: Vec<u64>, discard_deleted_kv: bool| {
+            let iter = kvs.iter().map(|(k, seq, v)| {
+                (
+                    KeySlice::new_with_seq(k.as_bytes(), *seq),
+                    v.map(|v| ValueSlice::new(v.as_bytes())),
+                )
+            });
+            let mut iter = CompactionFilterIter::new(
+                iter,
+                Some(Arc::new(SoftDeleteFilter)),
+                &snapshots,
+                discard_deleted_kv,
+                PathBuf::new(),
+            );
+            let res = iter
+                .by_ref()
+                .map(|(k, v)| unsafe {
+                    let v = v.as_ref().map_or("-", |v| from_utf8(v.data()).unwrap());
+                    format!("{}{}", k, v)
+                })
+                .collect::<Vec<String>>()
+                .join(",");
+            iter.finish().unwrap();
+            res
+        };
+        assert_eq!(collect(vec![], true), "bCHANGE,dkeep,e-");
+        // removed kv hides older versions in lower levels
+        assert_eq!(collect(vec![], false), "a-,bCHANGE,c-,dkeep,e-");
+        // kv read by snapshot is not filtered
+        assert_eq!(collect(vec![3], true), "a-,bCHANGE,cdeleted,dkeep,e-");
+    }
+}
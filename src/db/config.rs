@@ -1,18 +1,128 @@
+use std::sync::Arc;
 use std::time::Duration;
 
+use super::compaction_filter::CompactionFilter;
+use super::merge_operator::MergeOperator;
+use super::value::VALUE_SIZE_LIMIT;
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub sstable_file_limit: usize,
     pub level_0_file_limit: usize,
     pub level_size_expand_factor: usize,
+    // target bytes of level n is level_size_base * level_size_expand_factor^n, level 0 is limited by file number
+    pub level_size_base: usize,
     pub meta_log_file_name: String,
+    // write snapshot of version to a new meta log when meta log is larger than it
+    pub meta_log_size_limit: usize,
     pub sstable_meta_cache: usize,
+    // number of data blocks in block cache, 0 disables it
+    pub block_cache: usize,
+    // memory of memtable in bytes, include keys, values and index
     pub memtable_size_limit: usize,
+    // number of memtables include the one being written, writes wait when all others are not flushed
+    // less than 2 is same as 2
+    pub max_write_buffer_number: usize,
+    // bytes of flushed memtables kept in memory, optimistic transaction commit checks writes after
+    // it begins in them, it fails with TransactionTooOldError if some of them are dropped
+    pub max_write_buffer_size_to_maintain: usize,
     pub level_0_len_to_slow_write_threshold: usize,
+    pub compaction_style: CompactionStyle,
+    // universal compaction: sorted run is merged with newer runs if its size is not larger than
+    // (100 + universal_size_ratio)% of their total size
+    pub universal_size_ratio: usize,
+    // universal compaction: compact when there are more sorted runs than it
+    pub universal_max_sorted_runs: usize,
+    // fifo compaction: drop oldest sstables when total size is larger than it, 0 means no limit
+    pub fifo_max_table_files_size: usize,
+    // fifo compaction: drop sstables created before it, zero means no limit
+    // it is checked after memtable is flushed
+    pub fifo_ttl: Duration,
+    // number of compaction threads, compactions of different files and key ranges run in parallel
+    // 0 is same as 1
+    pub compaction_thread_number: usize,
+    // compaction threads check sstables with expired kvs at least once in it even if no memtable is flushed
+    pub expired_kv_check_interval: Duration,
     pub memtable_log_file_path: String,
     pub request_write_batch_size: usize,
     pub request_write_buffer_wait_time: Duration,
     pub sync_write: bool,
+    // 0 means no bloom filter in sstable
+    pub bloom_filter_bits_per_key: usize,
+    // codec of sstable data block
+    pub compression: CompressionType,
+    // value not less than it is saved in blob file when memtable is flushed, sstable only saves its position
+    // larger than VALUE_SIZE_LIMIT is same as VALUE_SIZE_LIMIT
+    pub blob_value_threshold: usize,
+    // blob file which ratio of overwritten or deleted values is not less than it is rewritten by compaction,
+    // its live values are moved to new blob file, 1.0 or larger disables it
+    pub blob_garbage_ratio: f64,
+    // combine operands written by merge, reading key with operands fails if it is none
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    // called with newest version of each kv when sstables are compacted, not called when memtable is flushed
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    // how transactions keep keys they read from being changed
+    pub transaction_mode: TransactionMode,
+    // pessimistic transaction fails if lock of key is not granted in it
+    pub lock_timeout: Duration,
+    // (name, config) of column families besides default one, family in meta log but not here is opened with config of db
+    // db wide options like log names, thread numbers and write buffer number are read from config of db
+    // families share memtable log, when memtable of one family is over its memtable_size_limit,
+    // memtables of all non-empty families are flushed together so old logs can be deleted,
+    // a family with few writes gets small level 0 sstables when it shares db with a busy family
+    pub column_families: Vec<(String, Config)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStyle {
+    // sstable of level n is merged to level n+1 when level is larger than its target size
+    Level,
+    // each level 0 sstable is a sorted run, runs of similar size are merged into one sstable in level 0
+    // sstables in level 1 to n written by level style are not compacted
+    Universal,
+    // all sstables are kept in level 0 and never merged, oldest ones are dropped by size and age
+    // for data which keys are never updated
+    Fifo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionMode {
+    // commit fails if keys read by transaction are written by others after they are read
+    Optimistic,
+    // keys written or read for update are locked until transaction is finished
+    Pessimistic,
+}
+
+// tag is saved in each block, so don't change value of existing type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionType {
+    None = 0,
+    Snappy = 1,
+}
+
+// options for one read request
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    // put blocks read from file to block cache, set it false for large scan
+    pub fill_cache: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadOptions {
+    pub fn new() -> Self {
+        ReadOptions { fill_cache: true }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
@@ -21,14 +131,35 @@ impl Config {
             sstable_file_limit: 2 * 1024 * 1024,
             level_0_file_limit: 4,
             level_size_expand_factor: 10,
+            level_size_base: 1024 * 1024,
             meta_log_file_name: String::from("meta"),
+            meta_log_size_limit: 2 * 1024 * 1024,
             sstable_meta_cache: 100,
+            block_cache: 1024,
             memtable_size_limit: 2 * 1024 * 1024,
+            max_write_buffer_number: 4,
+            max_write_buffer_size_to_maintain: 8 * 1024 * 1024,
             level_0_len_to_slow_write_threshold: 4,
+            compaction_style: CompactionStyle::Level,
+            universal_size_ratio: 1,
+            universal_max_sorted_runs: 8,
+            fifo_max_table_files_size: 1024 * 1024 * 1024,
+            fifo_ttl: Duration::ZERO,
+            compaction_thread_number: 2,
+            expired_kv_check_interval: Duration::from_secs(60),
             memtable_log_file_path: String::from("memtable_log"),
             request_write_batch_size: 1 << 20,
             request_write_buffer_wait_time: Duration::from_micros(5),
             sync_write: false,
+            bloom_filter_bits_per_key: 10,
+            compression: CompressionType::Snappy,
+            blob_value_threshold: VALUE_SIZE_LIMIT,
+            blob_garbage_ratio: 0.5,
+            merge_operator: None,
+            compaction_filter: None,
+            transaction_mode: TransactionMode::Optimistic,
+            lock_timeout: Duration::from_secs(1),
+            column_families: Vec::new(),
         }
     }
 }
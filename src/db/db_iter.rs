@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::db::blob;
+use crate::db::common::{IterError, KVIterItem, SortedKVIter};
+use crate::db::config::ReadOptions;
+use crate::db::key::{Key, SeqNumber};
+use crate::db::memtable::Memtable;
+use crate::db::range_tombstone::RangeTombstones;
+use crate::db::value::{now_millis, Value, ValueSlice};
+use crate::db::version::Version;
+
+/// iter kv in [start_key,end_key) in key order, deleted kv, expired kv and kv deleted by range tombstone are skipped
+/// only newest version not greater than seq of each key is returned, merge operands are combined with older version
+/// iter reads a snapshot of memtable, immutable memtables and version when it is created,
+/// version is held by iter, so its sstable files won't be pruned until iter is dropped
+/// iter stops when fail to read sstable or blob file, use finish to check the error
+pub struct DBIter {
+    // drop before version
+    sorted_iter: SortedKVIter<'static>,
+    end_key: Option<Key>,
+    seq: SeqNumber,
+    // tombstones of memtables and sstables, compared with version by sequence number
+    range_tombstones: RangeTombstones,
+    // user key of last returned or deleted kv, its older versions are skipped
+    last_key: Option<Key>,
+    // merge operands of last_key from new to old, waiting for older version
+    operands: Vec<Value>,
+    // kv of next key read after operands
+    pending: Option<KVIterItem>,
+    // milliseconds since unix epoch when iter is created, value expired before it is deleted
+    now: u64,
+    version: Arc<Version>,
+    // first error of iter and level iters, iter stops after it
+    error: IterError,
+}
+
+impl DBIter {
+    pub fn new(
+        memtable: &Arc<Memtable>,
+        immutable_memtables: &[Arc<Memtable>],
+        version: Arc<Version>,
+        start_key: &Key,
+        end_key: Option<&Key>,
+        seq: SeqNumber,
+        options: &ReadOptions,
+    ) -> Result<Self> {
+        // order by overwrite priority: memtable > immutable memtables from new to old > level 0 > .. > level n
+        let mut iters: Vec<Box<dyn Iterator<Item = KVIterItem>>> = Vec::new();
+        let mut range_tombstones = memtable.range_tombstones();
+        iters.push(Box::new(memtable.range_iter(start_key, end_key)));
+        for m in immutable_memtables {
+            range_tombstones.extend(&m.range_tombstones());
+            iters.push(Box::new(m.range_iter(start_key, end_key)));
+        }
+        range_tombstones.extend(&version.range_tombstones(start_key, end_key)?);
+        let error = IterError::default();
+        for level_iter in version.range_iters(start_key, end_key, options, &error) {
+            iters.push(Box::new(level_iter));
+        }
+
+        Ok(DBIter {
+            sorted_iter: SortedKVIter::from_boxed(iters),
+            end_key: end_key.cloned(),
+            seq,
+            range_tombstones,
+            last_key: None,
+            operands: Vec::new(),
+            pending: None,
+            now: now_millis(),
+            version,
+            error,
+        })
+    }
+
+    // iter stops when fail to read sstable or blob file or merge operands, return the error
+    pub fn finish(self) -> Result<()> {
+        match self.error.lock().unwrap().take() {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
+
+    fn has_error(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    // read value from blob file if it is a pointer, none if it fails and iter stops
+    fn read_value(&mut self, value: &ValueSlice) -> Option<Value> {
+        match blob::read_value(self.version.home_path(), value) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.set_error(err);
+                None
+            }
+        }
+    }
+
+    fn set_error(&mut self, err: anyhow::Error) {
+        self.error.lock().unwrap().get_or_insert(err);
+    }
+}
+
+impl DBIter {
+    // combine operands of last_key with older version, iter stops if it fails, eg. no merge operator
+    fn merge_operands(&mut self, existing: Option<Value>) -> Option<(Key, Value)> {
+        let key = self.last_key.clone().unwrap();
+        let operands = std::mem::take(&mut self.operands);
+        match self.version.merge(&key, existing, operands) {
+            Ok(value) => value.map(|v| (key, v)),
+            Err(err) => {
+                self.set_error(err);
+                None
+            }
+        }
+    }
+}
+
+impl Iterator for DBIter {
+    type Item = (Key, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let kv = self.pending.take().or_else(|| self.sorted_iter.next());
+            // kvs after failed sstable are unknown
+            if self.has_error() {
+                return None;
+            }
+            let (k, v) = match kv {
+                Some(kv) => kv,
+                None if self.operands.is_empty() => return None,
+                None => return self.merge_operands(None),
+            };
+            if k.seq() > self.seq {
+                continue;
+            }
+            let v = v.filter(|v| !v.is_expired(self.now));
+            unsafe {
+                if let Some(last_key) = &self.last_key {
+                    if last_key.data() == k.data() {
+                        if self.operands.is_empty() {
+                            continue;
+                        }
+                        if self
+                            .range_tombstones
+                            .is_deleted(k.data(), k.seq(), self.seq)
+                        {
+                            return self.merge_operands(None);
+                        }
+                        match v {
+                            Some(v) if v.is_merge_operand() => {
+                                self.operands.push(Value::from_u8(v.data()));
+                                continue;
+                            }
+                            Some(v) => {
+                                let value = self.read_value(&v)?;
+                                return self.merge_operands(Some(value));
+                            }
+                            None => return self.merge_operands(None),
+                        }
+                    }
+                }
+                if !self.operands.is_empty() {
+                    // no older version of last_key
+                    self.pending = Some((k, v));
+                    return self.merge_operands(None);
+                }
+                if let Some(end_key) = &self.end_key {
+                    if k.data() >= end_key.data() {
+                        return None;
+                    }
+                }
+                self.last_key = Some(Key::from(k.data()));
+                if self
+                    .range_tombstones
+                    .is_deleted(k.data(), k.seq(), self.seq)
+                {
+                    continue;
+                }
+                match v {
+                    Some(v) if v.is_merge_operand() => {
+                        self.operands.push(Value::from_u8(v.data()));
+                    }
+                    Some(v) => {
+                        let value = self.read_value(&v)?;
+                        return Some((Key::from(k.data()), value));
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
@@ -18,20 +18,23 @@ use metrics::{
 pub const FILE_NUMBER_METRIC_PREFIX: &str = "file_number.level.";
 pub const CURRENT_LEVEL_DEPTH: &str = "current_level";
 pub const WRITE_REQUEST_COUNT: &str = "write_request.count";
+pub const WRITE_TRANSACTION_CONFLICT_COUNT: &str = "write_request.transaction_conflict";
 pub const READ_REQUEST_COUNT: &str = "read_request.count";
 pub const WRITE_REQUEST_TIME: &str = "write_request.time";
 pub const READ_REQUEST_TIME: &str = "read_request.time";
 pub const COMPACT_COUNT: &str = "compact.count";
 pub const SSTABLE_COMPACT_TIME: &str = "sstable_compatct.time";
+pub const COMPACTION_FILTER_REMOVE_COUNT: &str = "compact.filter_remove";
+pub const COMPACTION_FILTER_CHANGE_COUNT: &str = "compact.filter_change";
 
 pub const READ_HIT_MEMTABLE_COUNTER: &str = "read_request.hit_memtable";
 pub const READ_HIT_SSTABLE_LEVEL: &str = "read_request.hit_sstable_level";
+pub const BLOOM_FILTER_SKIP_COUNT: &str = "read_request.bloom_filter_skip";
+pub const BLOCK_CACHE_HIT_COUNT: &str = "block_cache.hit";
+pub const BLOCK_CACHE_MISS_COUNT: &str = "block_cache.miss";
 
 pub const WRITE_WAIT_FOR_COMAPCT: &str = "write_request.wait_for_comapct";
 
-/////////////////////////////
-///
-
 pub fn init_metric() -> MetricExporter {
     let (record, exporter) = MetricRecord::new();
     metrics::set_boxed_recorder(Box::new(record)).unwrap();
@@ -152,15 +155,13 @@ impl MetricExporter {
         let map = self.counters.lock().unwrap();
         let counter = map.get(name).unwrap();
 
-        let res = counter.1.load(Ordering::SeqCst);
-        res
+        counter.1.load(Ordering::SeqCst)
     }
     pub fn get_gauge_value(&self, name: &str) -> u64 {
         let map = self.gauges.lock().unwrap();
         let c = map.get(name).unwrap();
 
-        let res = c.1.load(Ordering::SeqCst);
-        res
+        c.1.load(Ordering::SeqCst)
     }
 
     pub fn log_current_metric(&self) {
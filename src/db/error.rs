@@ -0,0 +1,158 @@
+//! errors are returned in anyhow::Error, use downcast_ref to check them
+
+use std::fmt::{Display, Formatter};
+
+use crate::db::key::Key;
+
+/// returned when data read from file is damaged, eg. checksum mismatch
+#[derive(Debug)]
+pub struct CorruptionError {
+    message: String,
+}
+
+impl CorruptionError {
+    pub fn new(message: String) -> Self {
+        CorruptionError { message }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for CorruptionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "corruption: {}", self.message)
+    }
+}
+
+impl std::error::Error for CorruptionError {}
+
+/// returned by commit when key read by transaction is written by others after transaction begins,
+/// transaction is not committed
+#[derive(Debug)]
+pub struct ConflictError {
+    key: Key,
+}
+
+impl ConflictError {
+    pub fn new(key: Key) -> Self {
+        ConflictError { key }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+impl Display for ConflictError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transaction conflict on key {}", self.key)
+    }
+}
+
+impl std::error::Error for ConflictError {}
+
+/// returned by commit when flushed memtables with writes after transaction begins are dropped from memory,
+/// so conflict can't be checked,
+/// transaction is not committed and can be retried
+#[derive(Debug)]
+pub struct TransactionTooOldError {
+    key: Key,
+}
+
+impl TransactionTooOldError {
+    pub fn new(key: Key) -> Self {
+        TransactionTooOldError { key }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+impl Display for TransactionTooOldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "transaction is too old to check conflict on key {}",
+            self.key
+        )
+    }
+}
+
+impl std::error::Error for TransactionTooOldError {}
+
+/// returned when lock of key is not granted before lock timeout
+#[derive(Debug)]
+pub struct LockTimeoutError {
+    key: Key,
+}
+
+impl LockTimeoutError {
+    pub fn new(key: Key) -> Self {
+        LockTimeoutError { key }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+impl Display for LockTimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lock wait timeout on key {}", self.key)
+    }
+}
+
+impl std::error::Error for LockTimeoutError {}
+
+/// returned when waiting for lock of key makes a cycle of transactions waiting for each other,
+/// transaction should be rolled back to release its locks
+#[derive(Debug)]
+pub struct DeadlockError {
+    key: Key,
+}
+
+impl DeadlockError {
+    pub fn new(key: Key) -> Self {
+        DeadlockError { key }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+impl Display for DeadlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadlock on key {}", self.key)
+    }
+}
+
+impl std::error::Error for DeadlockError {}
+
+/// returned by writes, flush and compact range after flush or compaction thread stopped with error,
+/// until db is reopened
+#[derive(Clone, Debug)]
+pub struct BackgroundError {
+    message: String,
+}
+
+impl BackgroundError {
+    pub fn new(message: String) -> Self {
+        BackgroundError { message }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for BackgroundError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "background error: {}", self.message)
+    }
+}
+
+impl std::error::Error for BackgroundError {}
@@ -19,7 +19,7 @@ pub struct FileStorageManager {
 const START_ID: FileId = 0;
 
 impl FileStorageManager {
-    pub fn to_thread_safe(self) -> ThreadSafeFileManager {
+    pub fn into_thread_safe(self) -> ThreadSafeFileManager {
         Arc::new(Mutex::new(self))
     }
     pub fn from(home_path: PathBuf) -> Result<Self> {
@@ -52,13 +52,13 @@ impl FileStorageManager {
             .write(true)
             .read(true)
             .create(true)
+            .truncate(true)
             .open(path.clone())?;
         Ok((res, file_id, path))
     }
 
     pub fn file_path(home_path: &Path, file_id: &FileId) -> PathBuf {
-        let path = home_path.clone().join(file_id.to_string());
-        path
+        home_path.join(file_id.to_string())
     }
 
     pub fn open_file(home_path: &Path, file_id: &FileId) -> Result<File> {
@@ -67,8 +67,8 @@ impl FileStorageManager {
         Ok(res)
     }
     // decrease file count by one, remove file  if is count is 0
-    pub fn get_all_file_ids(home_path: &PathBuf) -> Result<Vec<u32>, Error> {
-        let paths = fs::read_dir(home_path.clone()).unwrap();
+    pub fn get_all_file_ids(home_path: &Path) -> Result<Vec<u32>, Error> {
+        let paths = fs::read_dir(home_path).unwrap();
         let mut file_names: Vec<FileId> = Vec::new();
         for path in paths {
             match path {
@@ -130,14 +130,13 @@ mod test {
         manager.new_file().unwrap();
         manager.new_file().unwrap();
         manager.new_file().unwrap();
-
     }
 
     #[test]
     fn test_multiple_thread() {
         let dir = tempdir().unwrap();
         let manager = FileStorageManager::new(dir.path());
-        let thread_safe_manager = manager.to_thread_safe();
+        let thread_safe_manager = manager.into_thread_safe();
         let mut handles = Vec::new();
         for _ in 0..10 {
             let manager_clone = thread_safe_manager.clone();
@@ -147,8 +146,7 @@ mod test {
             });
             handles.push(handle);
         }
-        while handles.len() > 0 {
-            let handle = handles.pop().unwrap();
+        while let Some(handle) = handles.pop() {
             handle.join().unwrap();
         }
     }
@@ -1,27 +1,108 @@
 use std::cmp::Ordering;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::slice::from_raw_parts;
 
-use serde::{Deserialize, Serialize};
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Debug, Hash, Deserialize, Serialize)]
+/// arbitrary bytes, ordered by bytes
+#[derive(Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct Key {
-    k: String,
+    k: Vec<u8>,
 }
 
+/// sequence number of write, newer write has larger number
+/// data written before sequence number is added has number 0
+pub type SeqNumber = u64;
+// read with it sees all writes
+pub const MAX_SEQUENCE: SeqNumber = u64::MAX;
+
+/// internal key: user key and sequence number of the write
+/// ordered by user key, versions of same user key are ordered from new to old
 #[derive(Clone, Eq, Copy, Debug)]
 pub struct KeySlice {
     ptr: *const u8,
     size: usize,
+    seq: SeqNumber,
+}
+
+// valid utf-8 is displayed as it is, other bytes are escaped as \xNN
+fn write_escaped(f: &mut Formatter<'_>, data: &[u8]) -> std::fmt::Result {
+    for chunk in data.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() {
+                write!(f, "{}", c.escape_default())?;
+            } else {
+                write!(f, "{}", c)?;
+            }
+        }
+        for b in chunk.invalid() {
+            write!(f, "\\x{:02x}", b)?;
+        }
+    }
+    Ok(())
 }
 
 impl Display for KeySlice {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        unsafe {
-            let a = from_raw_parts(self.ptr, self.size);
-            let res = std::str::from_utf8_unchecked(a);
-            write!(f, "{}", res)
+        unsafe { write_escaped(f, from_raw_parts(self.ptr, self.size)) }
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_escaped(f, &self.k)
+    }
+}
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key {{ k: \"")?;
+        // all bytes except printable ascii are escaped, so keys which look same are told apart
+        for b in &self.k {
+            write!(f, "{}", std::ascii::escape_default(*b))?;
+        }
+        write!(f, "\" }}")
+    }
+}
+
+// key is saved as bytes, key saved as string by old version is also readable
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.k)
+    }
+}
+
+struct KeyVisitor;
+
+impl<'de> Visitor<'de> for KeyVisitor {
+    type Value = Key;
+
+    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "key bytes or string")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Key, E> {
+        Ok(Key::from(v))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Key, E> {
+        Ok(Key::from(v.as_bytes()))
+    }
+
+    // json has no bytes type, bytes are saved as array
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Key, A::Error> {
+        let mut k = Vec::new();
+        while let Some(b) = seq.next_element()? {
+            k.push(b);
         }
+        Ok(Key::from_u8_vec(k))
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeyVisitor)
     }
 }
 
@@ -30,18 +111,14 @@ impl Ord for KeySlice {
         unsafe {
             let a = from_raw_parts(self.ptr, self.size);
             let b = from_raw_parts(other.ptr, other.size);
-            a.cmp(&b)
+            a.cmp(b).then_with(|| other.seq.cmp(&self.seq))
         }
     }
 }
 
 impl PartialOrd for KeySlice {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        unsafe {
-            let a = from_raw_parts(self.ptr, self.size);
-            let b = from_raw_parts(other.ptr, other.size);
-            a.partial_cmp(&b)
-        }
+        Some(self.cmp(other))
     }
 }
 
@@ -50,7 +127,7 @@ impl PartialEq for KeySlice {
         unsafe {
             let a = from_raw_parts(self.ptr, self.size);
             let b = from_raw_parts(other.ptr, other.size);
-            a.eq(b)
+            a.eq(b) && self.seq == other.seq
         }
     }
 }
@@ -58,15 +135,28 @@ impl PartialEq for KeySlice {
 pub const KEY_SIZE_LIMIT: usize = 1024;
 
 impl KeySlice {
+    // sequence number is 0
     pub fn new(data: &[u8]) -> Self {
+        Self::new_with_seq(data, 0)
+    }
+    pub fn new_with_seq(data: &[u8], seq: SeqNumber) -> Self {
         KeySlice {
             ptr: data.as_ptr(),
             size: data.len(),
+            seq,
         }
     }
+    pub fn seq(&self) -> SeqNumber {
+        self.seq
+    }
     pub fn len(&self) -> usize {
         self.size
     }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+    /// # Safety
+    /// data the slice points to must be alive and unchanged
     pub unsafe fn data(&self) -> &[u8] {
         from_raw_parts(self.ptr, self.size)
     }
@@ -74,8 +164,7 @@ impl KeySlice {
 
 impl Key {
     pub fn new(s: &str) -> Self {
-        assert!(s.len() < KEY_SIZE_LIMIT);
-        Key { k: s.to_string() }
+        Self::from(s.as_bytes())
     }
     pub fn from_u32(i: u32) -> Self {
         Self::new(&i.to_string())
@@ -85,29 +174,29 @@ impl Key {
     }
 
     pub fn from(s: &[u8]) -> Self {
-        assert!(s.len() < KEY_SIZE_LIMIT);
-        Key {
-            k: String::from_utf8(s.to_vec()).unwrap(),
-        }
+        Self::from_u8_vec(s.to_vec())
     }
 
     pub fn from_u8_vec(v: Vec<u8>) -> Self {
         assert!(v.len() < KEY_SIZE_LIMIT);
-        Key {
-            k: String::from_utf8(v).unwrap(),
-        }
+        Key { k: v }
     }
 
-    pub fn to_string(&self) -> &str {
+    pub fn data(&self) -> &[u8] {
         &self.k
     }
 
-    pub fn data(&self) -> &[u8] {
-        self.k.as_bytes()
+    // none if key is not utf-8, use data() or Display for binary key
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.k).ok()
     }
 
     pub fn len(&self) -> usize {
-        self.k.as_bytes().len()
+        self.k.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.k.is_empty()
     }
 
     pub fn equal_u8(&self, data: &[u8]) -> bool {
@@ -119,6 +208,8 @@ impl Key {
 mod test {
     use std::cmp::Ordering;
 
+    use serde::Serialize;
+
     use crate::db::key::{Key, KeySlice};
 
     #[test]
@@ -149,6 +240,17 @@ mod test {
 
         assert!(!a_key_slice.eq(&b_key_slice));
         assert_eq!(a_key_slice.cmp(&b_key_slice), Ordering::Greater);
+
+        // newer version of same key is smaller
+        let a = Key::new("abc");
+        let new_version = KeySlice::new_with_seq(a.data(), 10);
+        let old_version = KeySlice::new_with_seq(a.data(), 9);
+        assert!(!new_version.eq(&old_version));
+        assert_eq!(new_version.cmp(&old_version), Ordering::Less);
+        assert_eq!(
+            new_version.cmp(&KeySlice::new_with_seq(b"abd", 1)),
+            Ordering::Less
+        );
     }
 
     #[test]
@@ -156,5 +258,41 @@ mod test {
         let key = Key::new("123");
         let key_slice = KeySlice::new(key.data());
         assert_eq!(key_slice.to_string(), "123");
+
+        // printable utf-8 is not escaped
+        let key = Key::new("键 a");
+        assert_eq!(key.to_string(), "键 a");
+        assert_eq!(key.as_str(), Some("键 a"));
+
+        // control chars and invalid utf-8 are escaped
+        let key = Key::from(&[b'a', 0, 0xff, b'\n']);
+        assert_eq!(key.to_string(), "a\\u{0}\\xff\\n");
+        assert_eq!(key.as_str(), None);
+        assert_eq!(KeySlice::new(key.data()).to_string(), format!("{}", key));
+        assert_eq!(format!("{:?}", key), "Key { k: \"a\\x00\\xff\\n\" }");
+    }
+
+    #[test]
+    pub fn test_key_serialize() {
+        let key = Key::from(&[0, 1, 0xff, 0x80]);
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(serde_json::from_str::<Key>(&json).unwrap(), key);
+        let mut msgpack = Vec::new();
+        key.serialize(&mut rmp_serde::Serializer::new(&mut msgpack))
+            .unwrap();
+        assert_eq!(
+            rmp_serde::decode::from_read::<_, Key>(msgpack.as_slice()).unwrap(),
+            key
+        );
+
+        // key saved as string by old version
+        let key: Key = serde_json::from_str("\"abc\"").unwrap();
+        assert_eq!(key, Key::new("abc"));
+        let mut msgpack = Vec::new();
+        "abc"
+            .serialize(&mut rmp_serde::Serializer::new(&mut msgpack))
+            .unwrap();
+        let key: Key = rmp_serde::decode::from_read(msgpack.as_slice()).unwrap();
+        assert_eq!(key, Key::new("abc"));
     }
 }
@@ -1,22 +1,29 @@
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
+use std::iter::Peekable;
 use std::num::NonZeroUsize;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use log::debug;
 use lru::LruCache;
 use serde::{Deserialize, Serialize};
 
-use crate::db::common::{KVIterItem, SortedKVIter, ValueSliceTag};
+use crate::db::blob::{BlobSeparateIter, BlobWriter};
+use crate::db::common::{CompactKVIter, IterError, KVIterItem, SortedKVIter, ValueSliceTag};
+use crate::db::compaction_filter::CompactionFilterIter;
+use crate::db::config::{Config, ReadOptions};
+use crate::db::error::CorruptionError;
 use crate::db::file_storage::{FileId, FileStorageManager, ThreadSafeFileManager};
-use crate::db::key::{Key, KeySlice};
+use crate::db::key::{Key, KeySlice, SeqNumber};
 use crate::db::memtable::Memtable;
-use crate::db::sstable::{SSTable, SStableBlockMeta, SStableIter};
+use crate::db::range_tombstone::RangeTombstones;
+use crate::db::sstable::{SSTable, SStableBlockMeta, SStableIter, ThreadSafeBlockCache};
 use crate::db::value::{Value, ValueSlice};
 
 use super::common::ValueWithTag;
@@ -27,16 +34,34 @@ pub type ThreadSafeSSTableMetaCache = Arc<Mutex<SSTableBlockMetaCache>>;
 // immutable, own by version
 pub struct Level {
     sstable_cache: ThreadSafeSSTableMetaCache,
+    block_cache: ThreadSafeBlockCache,
     sstable_file_metas: Vec<SStableFileMeta>,
     file_manager: ThreadSafeFileManager,
     home_path: PathBuf,
 }
 
+// iter kvs of level in key order, sstable is opened only when iter reach it
+pub struct LevelIter {
+    level: Level,
+    start_key: Key,
+    next_sstable_position: usize,
+    sstable_iter: Option<SStableIter<SSTable>>,
+    fill_cache: bool,
+    // iter stops when fail to read sstable, error is set if it is none
+    error: IterError,
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub enum LevelChange {
     // add new sstable to level start from position_in_level,sstable order is same as sstable_file_metas
     MemtableCompact {
         sstable_file_metas: SStableFileMeta,
+        // memtable logs with number smaller than it are persisted, 0 in meta log of old version
+        #[serde(default)]
+        memtable_log_number: u64,
+        // largest sequence number of memtable, 0 in meta log of old version
+        #[serde(default)]
+        last_sequence: SeqNumber,
     },
     LevelCompact {
         // compact 1 to 2, compact_from_leve is 1
@@ -44,6 +69,29 @@ pub enum LevelChange {
         compact_sstable: SStableFileMeta,
         compact_result: CompactSStableResult,
     },
+    // all sstables of each level, replace current levels, first record of meta log
+    Snapshot {
+        levels: Vec<Vec<SStableFileMeta>>,
+        memtable_log_number: u64,
+        #[serde(default)]
+        last_sequence: SeqNumber,
+    },
+    // sstables removed from level without merging, by fifo compaction
+    DropSStables {
+        level: usize,
+        sstables: Vec<SStableFileMeta>,
+    },
+    // sorted runs in level 0 are replaced by the run merged from them, at position of the newest one
+    SortedRunCompact {
+        remove_sstables: Vec<SStableFileMeta>,
+        add_sstables: Vec<SStableFileMeta>,
+    },
+    // sstables of level are replaced by sstables rewritten from them, by compaction of bottom level
+    LevelRewrite {
+        level: usize,
+        remove_sstables: Vec<SStableFileMeta>,
+        add_sstables: Vec<SStableFileMeta>,
+    },
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -51,6 +99,22 @@ pub struct SStableFileMeta {
     file_id: FileId,
     start_key: Key,
     last_key: Key,
+    // blob files which values of sstable are saved in, empty in meta log of old version
+    #[serde(default)]
+    blob_file_ids: Vec<FileId>,
+    // bytes of records pointed by sstable in each blob file, empty in meta log of old version
+    #[serde(default)]
+    blob_live_bytes: Vec<u64>,
+    // bytes of sstable file, 0 in meta log of old version and it is read from file when version is loaded
+    #[serde(default)]
+    file_size: u64,
+    // seconds since unix epoch when sstable is built, 0 in meta log of old version and it is read from file
+    #[serde(default)]
+    create_time: u64,
+    // earliest expire time of values in milliseconds since unix epoch, 0 if no value expires
+    // or it is unknown in meta log of old version
+    #[serde(default)]
+    expire_time: u64,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -66,67 +130,103 @@ impl Level {
         sstable_metas: Vec<SStableFileMeta>,
         home_path: PathBuf,
         cache: ThreadSafeSSTableMetaCache,
+        block_cache: ThreadSafeBlockCache,
         file_manager: ThreadSafeFileManager,
     ) -> Self {
         Level {
             sstable_file_metas: sstable_metas,
             sstable_cache: cache,
+            block_cache,
             home_path,
             file_manager,
         }
     }
-    pub fn get_in_level_0(&self, key: &Key) -> Result<Option<ValueWithTag>> {
-        assert!(!self.sstable_file_metas.is_empty());
-
+    // newest version of key which sequence number is not greater than seq,
+    // merge operands newer than it are pushed to operands from new to old
+    pub fn get_in_level_0(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        operands: &mut Vec<Value>,
+        options: &ReadOptions,
+    ) -> Result<Option<ValueWithTag>> {
         for meta in &self.sstable_file_metas {
-            let sstable = self.get_sstable(meta)?;
-            let res = sstable.get(key)?;
+            let sstable = self.get_sstable(meta, options.fill_cache)?;
+            let res = sstable.get(key, seq, operands)?;
             if let Some(v) = res {
                 return Ok(Some(v));
             }
         }
         Ok(None)
     }
-    pub fn get(&self, key: &Key) -> Result<Option<ValueWithTag>> {
-        assert!(!self.sstable_file_metas.is_empty());
-
-        if self.last_key().lt(key) {
+    pub fn get(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        operands: &mut Vec<Value>,
+        options: &ReadOptions,
+    ) -> Result<Option<ValueWithTag>> {
+        // level is empty if all kvs in it are deleted by compaction
+        if self.sstable_file_metas.is_empty() || self.last_key().lt(key) {
             return Ok(None);
         }
         // binary search sstable which key range contains key
         let position = self
             .sstable_file_metas
             .partition_point(|meta| meta.last_key().lt(key));
-        // find in sstable
-        let sstable_file_meta: &SStableFileMeta =
-            self.sstable_file_metas.get(position).expect("must find");
-        let sstable = self.get_sstable(&sstable_file_meta)?;
-        sstable.get(key)
+        // range tombstone cut at key makes end of sstable equal to start of next one, key is only in next one
+        for sstable_file_meta in self.sstable_file_metas[position..]
+            .iter()
+            .take_while(|meta| meta.start_key().le(key))
+        {
+            let sstable = self.get_sstable(sstable_file_meta, options.fill_cache)?;
+            if let Some(res) = sstable.get(key, seq, operands)? {
+                return Ok(Some(res));
+            }
+        }
+        Ok(None)
     }
 
-    fn get_sstable(&self, sstable_file_meta: &SStableFileMeta) -> Result<SSTable> {
+    // blocks are read through block cache, only put into cache if fill_cache is true
+    fn get_sstable(
+        &self,
+        sstable_file_meta: &SStableFileMeta,
+        fill_cache: bool,
+    ) -> Result<SSTable> {
         let file_id = sstable_file_meta.file_id();
         let sstable_file_meta = self.get_sstable_meta(&file_id)?;
-        let file = File::open(FileStorageManager::file_path(
+        let file = self.open_sstable_file(&file_id)?;
+        let sstable = SSTable::from_with_block_cache(
+            sstable_file_meta,
+            file,
+            file_id,
             self.home_path.as_path(),
-            &file_id,
-        ))?;
-        let sstable = SSTable::from(sstable_file_meta, file)?;
+            self.block_cache.clone(),
+            fill_cache,
+        )?;
         Ok(sstable)
     }
 
+    // sstable file in version is never deleted while version is alive, it is damaged if missing
+    fn open_sstable_file(&self, file_id: &FileId) -> Result<File> {
+        let path = FileStorageManager::file_path(self.home_path.as_path(), file_id);
+        match File::open(path) {
+            Ok(file) => Ok(file),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Err(CorruptionError::new(format!("sstable file {} is missing", file_id)).into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
     fn get_sstable_meta(&self, file_id: &FileId) -> Result<Arc<SStableBlockMeta>> {
         let mut cache = self.sstable_cache.lock().unwrap();
-        let res = cache.get(file_id);
-        return if res.is_none() {
-            let path = FileStorageManager::file_path(self.home_path.as_path(), file_id);
-            let mut file = File::open(&path)?;
-            let sstable_meta = Arc::new(SSTable::get_meta_from_file(&mut file)?);
-            cache.push(*file_id, sstable_meta.clone());
-            Ok(sstable_meta)
-        } else {
-            Ok(res.unwrap().clone())
-        };
+        if let Some(sstable_meta) = cache.get(file_id) {
+            return Ok(sstable_meta.clone());
+        }
+        let mut file = self.open_sstable_file(file_id)?;
+        let sstable_meta = Arc::new(SSTable::get_meta_from_file(&mut file)?);
+        cache.push(*file_id, sstable_meta.clone());
+        Ok(sstable_meta)
     }
     fn last_key(&self) -> Key {
         let sstable_meta = self
@@ -144,42 +244,50 @@ impl Level {
     }
     // find all sstable which key range has overlap in [start_key,end_key]
     // return first overlaps sstable position
-    fn key_overlap(&self, start_key: &Key, end_key: &Key) -> Option<(Vec<SStableFileMeta>, usize)> {
+    pub fn key_overlap(
+        &self,
+        start_key: &Key,
+        end_key: &Key,
+    ) -> Option<(Vec<SStableFileMeta>, usize)> {
+        if self.sstable_file_metas.is_empty() {
+            return None;
+        }
         let last_key = self.last_key();
-        if last_key.lt(&start_key) {
+        if last_key.lt(start_key) {
             return None;
         }
-        if self.first_key().gt(&end_key) {
+        if self.first_key().gt(end_key) {
             return None;
         }
         // find first sstable which last key is greater or equal to start_key as first sstable
         let start = self
             .sstable_file_metas
-            .partition_point(|sstable_meta| sstable_meta.last_key().lt(&start_key));
+            .partition_point(|sstable_meta| sstable_meta.last_key().lt(start_key));
         // find last sstable which last key is greater or equal to end_key as end sstable
-        if last_key.le(&end_key) {
+        if last_key.le(end_key) {
             return Some((Vec::from(&self.sstable_file_metas[start..]), start));
         }
         let end = self
             .sstable_file_metas
-            .partition_point(|sstable_meta| sstable_meta.last_key().lt(&end_key));
-        return Some((Vec::from(&self.sstable_file_metas[start..end + 1]), start));
+            .partition_point(|sstable_meta| sstable_meta.last_key().lt(end_key));
+        Some((Vec::from(&self.sstable_file_metas[start..end + 1]), start))
     }
 
     pub fn write_memtable_to_sstable_file(
         memtable: &Memtable,
         file_manager: &mut FileStorageManager,
+        config: &Config,
     ) -> Result<Vec<SStableFileMeta>> {
         let mut iter = memtable.iter();
         let mut res = Vec::new();
         loop {
             let (file, file_id, _) = file_manager.new_file()?;
-            let (sstable_opt, has_next) = SSTable::from_iter(&mut iter, file)?;
+            let (sstable_opt, has_next) = SSTable::from_iter(&mut iter, file, config)?;
             if sstable_opt.is_none() {
                 break;
             }
             let sstable = sstable_opt.unwrap();
-            res.push(SStableFileMeta::from(&sstable, file_id));
+            res.push(SStableFileMeta::from(&sstable, file_id)?);
             if !has_next {
                 break;
             }
@@ -188,12 +296,18 @@ impl Level {
     }
 
     // compact n-1 level sstable to this level, build new sstable,
-    // level is unchanged in compact
+    // level is unchanged in compact, versions read by snapshots are kept
+    // input sstables not overlapped with this level are moved without rewriting unless force_rewrite is true
+    // values in garbage_blob_files are moved to new blob file
     // return (new_sstable in current level ,remove_sstable  start_position in current level)
     pub fn compact_sstable(
         &self,
         mut input_sstables_metas: Vec<SStableFileMeta>,
         discard_deleted_kv: bool,
+        force_rewrite: bool,
+        snapshots: &[SeqNumber],
+        garbage_blob_files: &HashSet<FileId>,
+        config: &Config,
     ) -> Result<CompactSStableResult> {
         let start_key: Key = input_sstables_metas
             .iter()
@@ -207,70 +321,244 @@ impl Level {
             .unwrap();
         // find key overlap sstable
         let key_overlap_res = self.key_overlap(&start_key, &end_key);
-        if key_overlap_res.is_none() {
-            let position;
-            if self.last_key().lt(&start_key) {
-                position = self.len();
-            } else {
-                position = 0;
+        let (sstable_overlap, start_position) = match key_overlap_res {
+            Some(res) => res,
+            None => {
+                let position = if self.len() > 0 && self.last_key().lt(&start_key) {
+                    self.len()
+                } else {
+                    0
+                };
+                if !force_rewrite {
+                    return Ok(CompactSStableResult {
+                        remove_sstables: vec![],
+                        add_sstables: input_sstables_metas,
+                        position,
+                    });
+                }
+                (vec![], position)
             }
-            return Ok(CompactSStableResult {
-                remove_sstables: vec![],
-                add_sstables: input_sstables_metas,
-                position,
-            });
-        }
-        let (sstable_overlap, start_position) = key_overlap_res.unwrap();
+        };
         input_sstables_metas.append(&mut sstable_overlap.clone());
+        let res = self.merge_sstables(
+            input_sstables_metas,
+            discard_deleted_kv,
+            snapshots,
+            garbage_blob_files,
+            SSTable::SSTABLE_SIZE_LIMIT,
+            config,
+        )?;
+        Ok(CompactSStableResult {
+            remove_sstables: sstable_overlap,
+            add_sstables: res,
+            position: start_position,
+        })
+    }
 
+    // rewrite sstables of this level into new sstables, deleted kvs are dropped if it is bottom level
+    pub fn rewrite_sstables(
+        &self,
+        sstables: Vec<SStableFileMeta>,
+        discard_deleted_kv: bool,
+        snapshots: &[SeqNumber],
+        garbage_blob_files: &HashSet<FileId>,
+        config: &Config,
+    ) -> Result<Vec<SStableFileMeta>> {
+        self.merge_sstables(
+            sstables,
+            discard_deleted_kv,
+            snapshots,
+            garbage_blob_files,
+            SSTable::SSTABLE_SIZE_LIMIT,
+            config,
+        )
+    }
+
+    // merge sorted runs (level 0 sstables) from new to old into one sstable, none if all kvs are deleted
+    pub fn merge_sorted_runs(
+        &self,
+        sorted_runs: Vec<SStableFileMeta>,
+        discard_deleted_kv: bool,
+        snapshots: &[SeqNumber],
+        garbage_blob_files: &HashSet<FileId>,
+        config: &Config,
+    ) -> Result<Option<SStableFileMeta>> {
+        let mut res = self.merge_sstables(
+            sorted_runs,
+            discard_deleted_kv,
+            snapshots,
+            garbage_blob_files,
+            0,
+            config,
+        )?;
+        assert!(res.len() <= 1);
+        Ok(res.pop())
+    }
+
+    // merge sstables ordered by priority into new sstables, no file size limit if file_limit is 0
+    fn merge_sstables(
+        &self,
+        input_sstables_metas: Vec<SStableFileMeta>,
+        discard_deleted_kv: bool,
+        snapshots: &[SeqNumber],
+        garbage_blob_files: &HashSet<FileId>,
+        file_limit: usize,
+        config: &Config,
+    ) -> Result<Vec<SStableFileMeta>> {
         let mut input_sstables = Vec::new();
         for sstable_file_meta in input_sstables_metas {
-            let sstable_block_metas = self.get_sstable_meta(&sstable_file_meta.file_id())?;
-            let file = FileStorageManager::open_file(
-                self.home_path.as_path(),
-                &sstable_file_meta.file_id(),
-            )?;
-            let sstable = SSTable::from(sstable_block_metas, file)?;
+            // compaction reads every block once, don't evict hot blocks
+            let sstable = self.get_sstable(&sstable_file_meta, false)?;
             input_sstables.push(sstable);
         }
 
         let mut input_sstables_iter = Vec::new();
+        let mut range_tombstones = RangeTombstones::new();
         for sstable in &input_sstables {
             let iter = sstable.iter()?;
+            range_tombstones.extend(iter.range_tombstones());
             input_sstables_iter.push(iter)
         }
 
-        let mut input_sstable_iter_ref: Vec<&mut SStableIter> =
+        let mut input_sstable_iter_ref: Vec<&mut SStableIter<&SSTable>> =
             input_sstables_iter.iter_mut().collect();
         let mut sstable_iters: Vec<&mut dyn Iterator<Item = (KeySlice, ValueSliceTag)>> =
             Vec::new();
         input_sstable_iter_ref.reverse();
-        while !input_sstable_iter_ref.is_empty() {
-            sstable_iters.push(input_sstable_iter_ref.pop().unwrap());
+        while let Some(element) = input_sstable_iter_ref.pop() {
+            sstable_iters.push(element);
         }
 
         // build new sstable, write to stable_writer
-        let mut sorted_iter = SortedKVIter::new(sstable_iters);
+        let compact_iter = CompactKVIter::new(
+            SortedKVIter::new(sstable_iters),
+            snapshots.to_vec(),
+            discard_deleted_kv,
+        )
+        .with_range_tombstones(range_tombstones)
+        .with_merge_operator(config.merge_operator.clone());
+        let mut range_tombstones = compact_iter.output_range_tombstones();
+        let mut filter_iter = CompactionFilterIter::new(
+            compact_iter,
+            config.compaction_filter.clone(),
+            snapshots,
+            discard_deleted_kv,
+            self.home_path.clone(),
+        );
+        let mut blob_writer = BlobWriter::new(self.file_manager.clone());
+        let mut blob_iter = BlobSeparateIter::new(
+            filter_iter.by_ref(),
+            &mut blob_writer,
+            config.blob_value_threshold,
+        )
+        .with_relocation(&self.home_path, garbage_blob_files.clone());
+        let mut compact_iter = blob_iter.by_ref().peekable();
         let mut res = Vec::new();
         loop {
             let (file, file_id, _) = self.file_manager.lock().unwrap().new_file()?;
-            let (sstable_opt, has_next) =
-                build_sstable_from_iters(&mut sorted_iter, file, discard_deleted_kv)?;
+            let (sstable_opt, has_next) = build_sstable_from_iters(
+                &mut compact_iter,
+                &mut range_tombstones,
+                file,
+                file_limit,
+                config,
+            )?;
             if sstable_opt.is_none() {
                 break;
             }
             let sstable = sstable_opt.unwrap();
-            let meta = SStableFileMeta::from(&sstable, file_id);
+            let meta = SStableFileMeta::from(&sstable, file_id)?;
             res.push(meta);
             if !has_next {
                 break;
             }
         }
-        Ok(CompactSStableResult {
-            remove_sstables: sstable_overlap,
-            add_sstables: res,
-            position: start_position,
-        })
+        drop(compact_iter);
+        blob_iter.finish()?;
+        filter_iter.finish()?;
+        for iter in input_sstables_iter {
+            iter.finish()?;
+        }
+        // blob file must be persisted before sstables pointing to it are added to version
+        blob_writer.finish()?;
+        Ok(res)
+    }
+
+    // iter kvs which key is greater or equal to start_key, skip sstable which has no key in [start_key,end_key)
+    // sstables in level must not overlap, use range_iters_in_level_0 for level 0
+    pub fn range_iter(
+        &self,
+        start_key: &Key,
+        end_key: Option<&Key>,
+        options: &ReadOptions,
+        error: &IterError,
+    ) -> LevelIter {
+        let metas = self
+            .sstable_file_metas
+            .iter()
+            .filter(|meta| meta.in_range(start_key, end_key))
+            .cloned()
+            .collect();
+        LevelIter {
+            level: Level::new(
+                metas,
+                self.home_path.clone(),
+                self.sstable_cache.clone(),
+                self.block_cache.clone(),
+                self.file_manager.clone(),
+            ),
+            start_key: start_key.clone(),
+            next_sstable_position: 0,
+            sstable_iter: None,
+            fill_cache: options.fill_cache,
+            error: error.clone(),
+        }
+    }
+
+    // sstables in level 0 may overlap, so return one iter for each sstable, from new to old
+    pub fn range_iters_in_level_0(
+        &self,
+        start_key: &Key,
+        end_key: Option<&Key>,
+        options: &ReadOptions,
+        error: &IterError,
+    ) -> Vec<LevelIter> {
+        let mut res = Vec::new();
+        for meta in &self.sstable_file_metas {
+            if !meta.in_range(start_key, end_key) {
+                continue;
+            }
+            res.push(LevelIter {
+                level: Level::new(
+                    vec![meta.clone()],
+                    self.home_path.clone(),
+                    self.sstable_cache.clone(),
+                    self.block_cache.clone(),
+                    self.file_manager.clone(),
+                ),
+                start_key: start_key.clone(),
+                next_sstable_position: 0,
+                sstable_iter: None,
+                fill_cache: options.fill_cache,
+                error: error.clone(),
+            });
+        }
+        res
+    }
+
+    // range tombstones of sstables which may contain key in [start_key,end_key)
+    pub fn range_tombstones(
+        &self,
+        start_key: &Key,
+        end_key: Option<&Key>,
+    ) -> Result<RangeTombstones> {
+        let mut res = RangeTombstones::new();
+        for meta in &self.sstable_file_metas {
+            if meta.in_range(start_key, end_key) {
+                res.extend(self.get_sstable_meta(&meta.file_id())?.range_tombstones());
+            }
+        }
+        Ok(res)
     }
 
     pub fn copy_sstable_meta(&self) -> Vec<SStableFileMeta> {
@@ -287,23 +575,59 @@ impl Level {
         self.sstable_file_metas.len()
     }
 
-    pub fn pick_file_to_compact(&self) -> &SStableFileMeta {
-        self.find_oldest_sstable()
+    // bytes of all sstable files
+    pub fn size(&self) -> u64 {
+        self.sstable_file_metas.iter().map(|m| m.file_size()).sum()
+    }
+
+    // sstable which has least bytes to rewrite in next level for each byte of it, oldest one first if equal
+    // only sstables which can_compact returns true are picked
+    pub fn pick_file_with_min_overlap(
+        &self,
+        next_level: Option<&Level>,
+        can_compact: impl Fn(&SStableFileMeta) -> bool,
+    ) -> Option<&SStableFileMeta> {
+        let overlap_ratio = |meta: &SStableFileMeta| {
+            let overlap_size: u64 = next_level
+                .and_then(|l| l.key_overlap(&meta.start_key, &meta.last_key))
+                .map_or(0, |(metas, _)| metas.iter().map(|m| m.file_size()).sum());
+            overlap_size as f64 / meta.file_size().max(1) as f64
+        };
+        let mut metas: Vec<(f64, &SStableFileMeta)> = self
+            .sstable_file_metas
+            .iter()
+            .map(|meta| (overlap_ratio(meta), meta))
+            .collect();
+        metas.sort_by(|(a_ratio, a), (b_ratio, b)| {
+            a_ratio
+                .total_cmp(b_ratio)
+                .then_with(|| a.file_id.cmp(&b.file_id))
+        });
+        metas
+            .into_iter()
+            .map(|(_, meta)| meta)
+            .find(|meta| can_compact(meta))
+    }
+
+    // oldest sstable which can_compact returns true
+    pub fn pick_file_to_compact(
+        &self,
+        can_compact: impl Fn(&SStableFileMeta) -> bool,
+    ) -> Option<&SStableFileMeta> {
+        let mut metas: Vec<&SStableFileMeta> = self.sstable_file_metas.iter().collect();
+        metas.sort_by_key(|meta| meta.file_id);
+        metas.into_iter().find(|meta| can_compact(meta))
     }
 
     // for test
     pub fn get_kvs_for_test(&self) -> Vec<(Key, Option<Value>)> {
         let mut res = Vec::new();
         for meta in &self.sstable_file_metas {
-            let sstable = self.get_sstable(&meta).unwrap();
+            let sstable = self.get_sstable(meta, false).unwrap();
             let iter = sstable.iter().unwrap();
             for (k, v) in iter {
                 unsafe {
-                    let value = if let Some(v_data) = v {
-                        Some(Value::from_u8(v_data.data()))
-                    } else {
-                        None
-                    };
+                    let value = v.map(|v_data| Value::from_u8(v_data.data()));
                     res.push((Key::from(k.data()), value))
                 }
             }
@@ -311,37 +635,79 @@ impl Level {
         res
     }
 
+    // sstable files and blob files referenced by them
     pub fn get_all_file_id(&self) -> HashSet<FileId> {
         let mut res = HashSet::new();
         for meta in self.sstable_file_metas.iter() {
             res.insert(meta.file_id());
+            res.extend(meta.blob_file_ids());
         }
         res
     }
+}
 
-    fn find_oldest_sstable(&self) -> &SStableFileMeta {
-        let res = self
-            .sstable_file_metas
-            .iter()
-            .min_by(|a, b| a.file_id.cmp(&b.file_id))
-            .unwrap();
-        res
+impl LevelIter {
+    // keep the first error, iter won't be read again after it
+    fn set_error(&mut self, err: anyhow::Error) {
+        self.error.lock().unwrap().get_or_insert(err);
+        self.next_sstable_position = self.level.len();
+    }
+}
+
+impl Iterator for LevelIter {
+    type Item = KVIterItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(iter) = &mut self.sstable_iter {
+                if let Some(kv) = iter.next() {
+                    return Some(kv);
+                }
+                if let Err(err) = self.sstable_iter.take().unwrap().finish() {
+                    self.set_error(err);
+                    return None;
+                }
+            }
+            if self.next_sstable_position == self.level.len() {
+                return None;
+            }
+            let meta = &self.level.sstable_file_metas[self.next_sstable_position];
+            // only first sstable may contain key less than start_key
+            let first = self.next_sstable_position == 0;
+            let iter =
+                self.level
+                    .get_sstable(meta, self.fill_cache)
+                    .and_then(|sstable| match first {
+                        true => SStableIter::seek(sstable, &self.start_key),
+                        false => sstable.into_iter(),
+                    });
+            self.next_sstable_position += 1;
+            match iter {
+                Ok(iter) => self.sstable_iter = Some(iter),
+                Err(err) => {
+                    self.set_error(err);
+                    return None;
+                }
+            }
+        }
     }
 }
 
-fn build_sstable_from_iters(
-    sorted_iter: &mut SortedKVIter,
+fn build_sstable_from_iters<I: Iterator<Item = KVIterItem>>(
+    compact_iter: &mut Peekable<I>,
+    range_tombstones: &mut RangeTombstones,
     file: File,
-    discard_deleted_kv: bool,
+    file_limit: usize,
+    config: &Config,
 ) -> Result<(Option<SSTable>, bool), anyhow::Error> {
-    if discard_deleted_kv {
-        let mut prune_deleted_kv_iter = sorted_iter.filter(|kv| kv.1.is_some());
-        let (sstable_opt, has_next) = SSTable::from_iter(&mut prune_deleted_kv_iter, file)?;
-        Ok((sstable_opt, has_next))
-    } else {
-        let (sstable_opt, has_next) = SSTable::from_iter(sorted_iter, file)?;
-        Ok((sstable_opt, has_next))
-    }
+    let (sstable_opt, has_next) = SSTable::from_iter_with_file_limit(
+        compact_iter,
+        range_tombstones,
+        file,
+        file_limit,
+        config,
+    )?;
+    Ok((sstable_opt, has_next))
 }
 
 impl SStableFileMeta {
@@ -350,11 +716,22 @@ impl SStableFileMeta {
             start_key,
             last_key: end_key,
             file_id,
+            blob_file_ids: vec![],
+            blob_live_bytes: vec![],
+            file_size: 0,
+            create_time: 0,
+            expire_time: 0,
         }
     }
-    pub fn from(sstable: &SSTable, file_id: FileId) -> Self {
+    pub fn from(sstable: &SSTable, file_id: FileId) -> Result<Self> {
         let sstable_meta = sstable.block_metadata();
-        Self::new(sstable_meta.first_key(), sstable_meta.last_key(), file_id)
+        let mut res = Self::new(sstable_meta.first_key(), sstable_meta.last_key(), file_id);
+        res.blob_file_ids = sstable.blob_file_ids().to_vec();
+        res.blob_live_bytes = sstable.blob_live_bytes().to_vec();
+        res.expire_time = sstable.expire_time();
+        res.file_size = sstable.file_size()?;
+        res.create_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(res)
     }
     pub fn start_key(&self) -> Key {
         self.start_key.clone()
@@ -365,11 +742,51 @@ impl SStableFileMeta {
     pub fn file_id(&self) -> FileId {
         self.file_id
     }
+    pub fn blob_file_ids(&self) -> &[FileId] {
+        &self.blob_file_ids
+    }
+    // (blob file id, bytes of records pointed by sstable), none if it is unknown
+    pub fn blob_live_bytes(&self) -> Option<impl Iterator<Item = (FileId, u64)> + '_> {
+        (self.blob_live_bytes.len() == self.blob_file_ids.len()).then(|| {
+            self.blob_file_ids
+                .iter()
+                .copied()
+                .zip(self.blob_live_bytes.iter().copied())
+        })
+    }
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+    pub fn set_file_size(&mut self, file_size: u64) {
+        self.file_size = file_size
+    }
+    pub fn create_time(&self) -> u64 {
+        self.create_time
+    }
+    // true if some values in sstable are expired at now, milliseconds since unix epoch
+    pub fn has_expired_value(&self, now: u64) -> bool {
+        self.expire_time != 0 && self.expire_time <= now
+    }
+    pub fn set_create_time(&mut self, create_time: u64) {
+        self.create_time = create_time
+    }
+    // true if sstable may contain key in [start_key,end_key)
+    pub fn in_range(&self, start_key: &Key, end_key: Option<&Key>) -> bool {
+        if self.last_key.lt(start_key) {
+            return false;
+        }
+        if let Some(end) = end_key {
+            if self.start_key.ge(end) {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::num::NonZeroUsize;
     use std::path::PathBuf;
     use std::sync::{Arc, Mutex};
@@ -377,8 +794,10 @@ mod test {
     use lru::LruCache;
     use tempfile::tempdir;
 
+    use crate::db::common::IterError;
+    use crate::db::config::{Config, ReadOptions};
     use crate::db::file_storage::FileStorageManager;
-    use crate::db::key::Key;
+    use crate::db::key::{Key, MAX_SEQUENCE};
     use crate::db::level::{Level, SStableFileMeta};
     use crate::db::memtable::Memtable;
     use crate::db::sstable::test::{build_sstable, build_sstable_with_special_value};
@@ -404,6 +823,7 @@ mod test {
             vec![a_meta, b_meta, c_meta],
             path,
             Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()))),
+            SSTable::new_block_cache(10),
             Arc::new(Mutex::new(file_manager)),
         )
     }
@@ -434,58 +854,99 @@ mod test {
             vec![a_meta, b_meta, c_meta],
             path,
             Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()))),
+            SSTable::new_block_cache(10),
             Arc::new(Mutex::new(file_manager)),
         );
 
-        let res = level.get_in_level_0(&Key::new("12")).unwrap();
-        assert_eq!(res, Some(Some(Value::new("12"))));
-
-        let res = level.get_in_level_0(&Key::new("16")).unwrap();
-        assert_eq!(res, Some(Some(Value::new("a"))));
-
-        let res = level.get_in_level_0(&Key::new("19")).unwrap();
-        assert_eq!(res, Some(Some(Value::new("19"))));
-
-        let res = level.get_in_level_0(&Key::new("29")).unwrap();
-        assert_eq!(res, Some(Some(Value::new("29"))));
-
-        let res = level.get_in_level_0(&Key::new("1")).unwrap();
-        assert!(res.is_none());
+        let get = |key: &str| {
+            level
+                .get_in_level_0(
+                    &Key::new(key),
+                    MAX_SEQUENCE,
+                    &mut Vec::new(),
+                    &ReadOptions::new(),
+                )
+                .unwrap()
+        };
+        assert_eq!(get("12"), Some(Some(Value::new("12"))));
+        assert_eq!(get("16"), Some(Some(Value::new("a"))));
+        assert_eq!(get("19"), Some(Some(Value::new("19"))));
+        assert_eq!(get("29"), Some(Some(Value::new("29"))));
+        assert!(get("1").is_none());
     }
 
     #[test]
     fn test_get() {
         let level = build_level();
-        assert_eq!(
-            Value::new("126"),
-            level.get(&Key::new("126")).unwrap().unwrap().unwrap()
-        );
-        assert_eq!(
-            Value::new("226"),
-            level.get(&Key::new("226")).unwrap().unwrap().unwrap()
-        );
-        assert_eq!(
-            Value::new("399"),
-            level.get(&Key::new("399")).unwrap().unwrap().unwrap()
-        );
-        assert_eq!(
-            Value::new("305"),
-            level.get(&Key::new("305")).unwrap().unwrap().unwrap()
-        );
-        assert!(level.get(&Key::new("526")).unwrap().is_none());
-        assert!(level.get(&Key::new("303")).unwrap().is_none());
-        assert!(level.get(&Key::new("304")).unwrap().is_none());
-        assert!(level.get(&Key::new("400")).unwrap().is_none());
+        let get = |key: &str| {
+            level
+                .get(
+                    &Key::new(key),
+                    MAX_SEQUENCE,
+                    &mut Vec::new(),
+                    &ReadOptions::new(),
+                )
+                .unwrap()
+        };
+        for key in ["126", "226", "399", "305"] {
+            assert_eq!(get(key), Some(Some(Value::new(key))));
+        }
+        for key in ["526", "303", "304", "400"] {
+            assert!(get(key).is_none());
+        }
     }
 
     #[test]
-    fn test_find_oldest_sstable() {
+    fn test_pick_file_to_compact() {
         let level = build_level();
-        let res = level.find_oldest_sstable();
-        assert_eq!(res.file_id, 0);
-        assert_eq!(res.start_key, Key::new("100"));
-        let res = level.pick_file_to_compact();
+        let res = level.pick_file_to_compact(|_| true).unwrap();
+        assert_eq!(res.file_id(), 0);
         assert_eq!(res.start_key(), Key::new("100"));
+        let res = level
+            .pick_file_to_compact(|meta| meta.file_id() != 0)
+            .unwrap();
+        assert_eq!(res.file_id(), 1);
+        assert!(level.pick_file_to_compact(|_| false).is_none());
+    }
+
+    #[test]
+    fn test_pick_file_with_min_overlap() {
+        let dir = tempdir().unwrap();
+        let home_path = PathBuf::from(dir.path());
+        let file_manager = FileStorageManager::new_thread_safe_manager(dir.into_path());
+        let build = |start, end| {
+            let (file, file_id, _) = file_manager.lock().unwrap().new_file().unwrap();
+            SStableFileMeta::from(&build_sstable(start, end, 1, file), file_id).unwrap()
+        };
+        // a [150,160) overlaps large c [100,300), b [300,305) overlaps small d [300,310)
+        let a = build(150, 160);
+        let b = build(300, 305);
+        let c = build(100, 300);
+        let d = build(300, 310);
+        let new_level = |metas| {
+            Level::new(
+                metas,
+                home_path.clone(),
+                Level::new_cache(10),
+                SSTable::new_block_cache(10),
+                file_manager.clone(),
+            )
+        };
+        let level = new_level(vec![a, b]);
+        let next_level = new_level(vec![c, d]);
+        assert!(next_level.size() > level.size());
+
+        let res = level
+            .pick_file_with_min_overlap(Some(&next_level), |_| true)
+            .unwrap();
+        assert_eq!(res.file_id(), 1);
+        let res = level
+            .pick_file_with_min_overlap(Some(&next_level), |meta| meta.file_id() != 1)
+            .unwrap();
+        assert_eq!(res.file_id(), 0);
+        // no overlap, oldest one
+        let res = level.pick_file_with_min_overlap(None, |_| true).unwrap();
+        assert_eq!(res.file_id(), 0);
     }
 
     #[test]
@@ -500,14 +961,14 @@ mod test {
             .unwrap()
             .0;
         assert_eq!(res.len(), 1);
-        assert_eq!(res.get(0).unwrap().last_key(), Key::new("199"));
+        assert_eq!(res.first().unwrap().last_key(), Key::new("199"));
 
         let res = level
             .key_overlap(&Key::new("399"), &Key::new("480"))
             .unwrap()
             .0;
         assert_eq!(res.len(), 1);
-        assert_eq!(res.get(0).unwrap().last_key(), Key::new("399"));
+        assert_eq!(res.first().unwrap().last_key(), Key::new("399"));
 
         let res = level.key_overlap(&Key::new("450"), &Key::new("480"));
         assert!(res.is_none());
@@ -560,38 +1021,46 @@ mod test {
 
         let (a_file, a_file_id, _) = file_manager.lock().unwrap().new_file().unwrap();
         let a = build_sstable_with_special_value(100, 110, 1, special_value_map, a_file);
-        let a_file_meta = SStableFileMeta::from(&a, a_file_id);
+        let a_file_meta = SStableFileMeta::from(&a, a_file_id).unwrap();
 
         let mut special_value_map = HashMap::new();
         special_value_map.insert(109, Some(Value::new("Z")));
         special_value_map.insert(113, Some(Value::new("Z")));
         let (b_file, b_file_id, _) = file_manager.lock().unwrap().new_file().unwrap();
         let b = build_sstable_with_special_value(108, 115, 1, special_value_map, b_file);
-        let b_file_meta = SStableFileMeta::from(&b, b_file_id);
+        let b_file_meta = SStableFileMeta::from(&b, b_file_id).unwrap();
 
         let (c_file, c_file_id, _) = file_manager.lock().unwrap().new_file().unwrap();
         let c = build_sstable(105, 108, 1, c_file);
-        let c_file_meta = SStableFileMeta::from(&c, c_file_id);
+        let c_file_meta = SStableFileMeta::from(&c, c_file_id).unwrap();
 
         let mut special_value_map = HashMap::new();
         special_value_map.insert(112, Some(Value::new("Y")));
         let (d_file, d_file_id, _) = file_manager.lock().unwrap().new_file().unwrap();
         let d = build_sstable_with_special_value(110, 115, 1, special_value_map, d_file);
-        let d_file_meta = SStableFileMeta::from(&d, d_file_id);
+        let d_file_meta = SStableFileMeta::from(&d, d_file_id).unwrap();
 
         let (e_file, e_file_id, _) = file_manager.lock().unwrap().new_file().unwrap();
         let e = build_sstable(122, 124, 1, e_file);
-        let e_file_meta = SStableFileMeta::from(&e, e_file_id);
+        let e_file_meta = SStableFileMeta::from(&e, e_file_id).unwrap();
 
         let level = Level::new(
             vec![c_file_meta, d_file_meta, e_file_meta],
             home_path.clone(),
             Level::new_cache(10),
+            SSTable::new_block_cache(10),
             file_manager,
         );
 
         let mut file_sstable = level
-            .compact_sstable(vec![a_file_meta, b_file_meta], false)
+            .compact_sstable(
+                vec![a_file_meta, b_file_meta],
+                false,
+                false,
+                &[],
+                &HashSet::new(),
+                &Config::new(),
+            )
             .unwrap()
             .add_sstables;
         assert_eq!(file_sstable.len(), 1);
@@ -608,6 +1077,7 @@ mod test {
         for i in 0..10 {
             memtable.insert(
                 &Key::from(i.to_string().as_bytes()),
+                i,
                 &Value::new(&i.to_string()),
             );
         }
@@ -615,7 +1085,8 @@ mod test {
         let home_path = dir.path();
         let mut file_manager = FileStorageManager::new(home_path);
         let mut sstables =
-            Level::write_memtable_to_sstable_file(&memtable, &mut file_manager).unwrap();
+            Level::write_memtable_to_sstable_file(&memtable, &mut file_manager, &Config::new())
+                .unwrap();
         assert_eq!(sstables.len(), 1);
         let meta = sstables.pop().unwrap();
         let file = FileStorageManager::open_file(home_path, &meta.file_id).unwrap();
@@ -623,10 +1094,38 @@ mod test {
 
         assert_eq!(format!("{:}", sstable), "(key: 0,value: 0)(key: 1,value: 1)(key: 2,value: 2)(key: 3,value: 3)(key: 4,value: 4)(key: 5,value: 5)(key: 6,value: 6)(key: 7,value: 7)(key: 8,value: 8)(key: 9,value: 9)");
     }
+    #[test]
+    fn test_range_iter() {
+        // [100-200),[205-300),[305-400)
+        let level = build_level();
+        let error = IterError::default();
+        let iter = level.range_iter(
+            &Key::new("150"),
+            Some(&Key::new("210")),
+            &ReadOptions::new(),
+            &error,
+        );
+        let mut keys = Vec::new();
+        for (k, _) in iter {
+            unsafe {
+                keys.push(Key::from(k.data()));
+            }
+        }
+        // sstable [305,400) is skipped, last sstable in range is iterated to its end
+        assert_eq!(keys.first().unwrap(), &Key::new("150"));
+        assert_eq!(keys.last().unwrap(), &Key::new("299"));
+        assert_eq!(keys.len(), 50 + 95);
+        assert!(error.lock().unwrap().is_none());
+
+        let iters =
+            level.range_iters_in_level_0(&Key::new("250"), None, &ReadOptions::new(), &error);
+        assert_eq!(iters.len(), 2);
+    }
+
     #[test]
     fn test_all_file_id() {
         let level = build_level();
-        let ids=level.get_all_file_id();
+        let ids = level.get_all_file_id();
         assert!(ids.contains(&0));
         assert!(ids.contains(&1));
         assert!(ids.contains(&2));
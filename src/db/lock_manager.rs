@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::db::error::{DeadlockError, LockTimeoutError};
+use crate::db::key::Key;
+
+pub type TransactionId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    // held by many transactions at same time
+    Shared,
+    // held by one transaction, conflicts with any other lock
+    Exclusive,
+}
+
+/// row locks of transactions keyed by user key, shared by clients of db
+/// waiter blocks until lock is granted or timeout, waiting which makes a cycle in wait-for graph fails at once
+/// shared lock isn't granted while others wait for exclusive lock of key, so shared readers can't starve writers
+pub struct LockManager {
+    state: Mutex<LockState>,
+    // notified when locks are released
+    released: Condvar,
+    next_transaction_id: AtomicU64,
+}
+
+#[derive(Default)]
+struct LockState {
+    // key -> transactions holding lock of it
+    locks: HashMap<Key, HashMap<TransactionId, LockMode>>,
+    // waiting transaction -> transactions holding the lock it waits for
+    wait_for: HashMap<TransactionId, HashSet<TransactionId>>,
+    // key -> transactions waiting for exclusive lock of it, new shared locks wait behind them
+    exclusive_waiters: HashMap<Key, HashSet<TransactionId>>,
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        LockManager {
+            state: Mutex::new(LockState::default()),
+            released: Condvar::new(),
+            next_transaction_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn new_transaction_id(&self) -> TransactionId {
+        self.next_transaction_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // lock held by transaction is upgraded if exclusive lock is required
+    pub fn lock(
+        &self,
+        id: TransactionId,
+        key: &Key,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let holders = state.blockers(id, key, mode);
+            if holders.is_empty() {
+                state.stop_waiting(id, key);
+                let held = state.locks.entry(key.clone()).or_default();
+                let mode = match held.get(&id) {
+                    Some(LockMode::Exclusive) => LockMode::Exclusive,
+                    _ => mode,
+                };
+                held.insert(id, mode);
+                return Ok(());
+            }
+            state.wait_for.insert(id, holders);
+            if mode == LockMode::Exclusive {
+                state
+                    .exclusive_waiters
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(id);
+            }
+            let now = Instant::now();
+            let err: anyhow::Error = if state.waits_for(id) {
+                DeadlockError::new(key.clone()).into()
+            } else if now >= deadline {
+                LockTimeoutError::new(key.clone()).into()
+            } else {
+                state = self.released.wait_timeout(state, deadline - now).unwrap().0;
+                continue;
+            };
+            // shared waiters queued behind this one can go on
+            state.stop_waiting(id, key);
+            drop(state);
+            self.released.notify_all();
+            return Err(err);
+        }
+    }
+
+    pub fn unlock(&self, id: TransactionId, keys: impl IntoIterator<Item = Key>) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            if let Some(held) = state.locks.get_mut(&key) {
+                held.remove(&id);
+                if held.is_empty() {
+                    state.locks.remove(&key);
+                }
+            }
+        }
+        drop(state);
+        self.released.notify_all();
+    }
+
+    #[cfg(test)]
+    pub fn waiter_number(&self) -> usize {
+        self.state.lock().unwrap().wait_for.len()
+    }
+}
+
+impl LockState {
+    // other transactions holding lock of key which is incompatible with mode,
+    // and exclusive waiters of key if a new shared lock is required
+    fn blockers(&self, id: TransactionId, key: &Key, mode: LockMode) -> HashSet<TransactionId> {
+        let mut blockers = HashSet::new();
+        let held = self.locks.get(key);
+        if let Some(held) = held {
+            blockers.extend(
+                held.iter()
+                    .filter(|(holder, holder_mode)| {
+                        **holder != id
+                            && (mode == LockMode::Exclusive || **holder_mode == LockMode::Exclusive)
+                    })
+                    .map(|(holder, _)| *holder),
+            );
+        }
+        let is_holder = held.is_some_and(|held| held.contains_key(&id));
+        if mode == LockMode::Shared && !is_holder {
+            if let Some(waiters) = self.exclusive_waiters.get(key) {
+                blockers.extend(waiters.iter().filter(|waiter| **waiter != id));
+            }
+        }
+        blockers
+    }
+
+    fn stop_waiting(&mut self, id: TransactionId, key: &Key) {
+        self.wait_for.remove(&id);
+        if let Some(waiters) = self.exclusive_waiters.get_mut(key) {
+            waiters.remove(&id);
+            if waiters.is_empty() {
+                self.exclusive_waiters.remove(key);
+            }
+        }
+    }
+
+    // true if transactions id waits for are waiting for id directly or indirectly
+    fn waits_for(&self, id: TransactionId) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack: Vec<TransactionId> = self.wait_for[&id].iter().copied().collect();
+        while let Some(t) = stack.pop() {
+            if t == id {
+                return true;
+            }
+            if !visited.insert(t) {
+                continue;
+            }
+            if let Some(holders) = self.wait_for.get(&t) {
+                stack.extend(holders);
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::db::error::{DeadlockError, LockTimeoutError};
+    use crate::db::key::Key;
+
+    use super::{LockManager, LockMode};
+
+    #[test]
+    fn test_lock_and_unlock() {
+        let manager = LockManager::new();
+        let (a, b) = (manager.new_transaction_id(), manager.new_transaction_id());
+        let key = Key::new("k");
+        let timeout = Duration::from_millis(10);
+        manager.lock(a, &key, LockMode::Shared, timeout).unwrap();
+        manager.lock(b, &key, LockMode::Shared, timeout).unwrap();
+        // upgrade waits for other shared lock
+        let err = manager
+            .lock(a, &key, LockMode::Exclusive, timeout)
+            .unwrap_err();
+        assert!(err.downcast_ref::<LockTimeoutError>().is_some());
+        manager.unlock(b, vec![key.clone()]);
+        manager.lock(a, &key, LockMode::Exclusive, timeout).unwrap();
+        // exclusive lock is kept when shared lock is required again
+        manager.lock(a, &key, LockMode::Shared, timeout).unwrap();
+        assert!(manager.lock(b, &key, LockMode::Shared, timeout).is_err());
+        manager.unlock(a, vec![key.clone()]);
+        manager.lock(b, &key, LockMode::Exclusive, timeout).unwrap();
+    }
+
+    #[test]
+    fn test_exclusive_waiter_not_starved() {
+        let manager = Arc::new(LockManager::new());
+        let (a, b, c) = (
+            manager.new_transaction_id(),
+            manager.new_transaction_id(),
+            manager.new_transaction_id(),
+        );
+        let key = Key::new("k");
+        let timeout = Duration::from_millis(10);
+        manager.lock(a, &key, LockMode::Shared, timeout).unwrap();
+        let handle = {
+            let manager = manager.clone();
+            let key = key.clone();
+            thread::spawn(move || {
+                manager.lock(b, &key, LockMode::Exclusive, Duration::from_secs(10))
+            })
+        };
+        while manager.waiter_number() == 0 {
+            thread::yield_now();
+        }
+        // new shared lock waits behind exclusive waiter, holder can still lock again
+        let err = manager
+            .lock(c, &key, LockMode::Shared, timeout)
+            .unwrap_err();
+        assert!(err.downcast_ref::<LockTimeoutError>().is_some());
+        manager.lock(a, &key, LockMode::Shared, timeout).unwrap();
+        manager.unlock(a, vec![key.clone()]);
+        handle.join().unwrap().unwrap();
+        assert!(manager.lock(c, &key, LockMode::Shared, timeout).is_err());
+        manager.unlock(b, vec![key.clone()]);
+        manager.lock(c, &key, LockMode::Shared, timeout).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let manager = Arc::new(LockManager::new());
+        let keys = [Key::new("a"), Key::new("b")];
+        let timeout = Duration::from_secs(10);
+        let ids: Vec<u64> = keys
+            .iter()
+            .map(|key| {
+                let id = manager.new_transaction_id();
+                manager.lock(id, key, LockMode::Exclusive, timeout).unwrap();
+                id
+            })
+            .collect();
+        // each transaction waits for key of the other, one of them fails and releases its lock
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let manager = manager.clone();
+                let keys = keys.clone();
+                let id = ids[i];
+                thread::spawn(move || {
+                    let res = manager.lock(id, &keys[1 - i], LockMode::Exclusive, timeout);
+                    if res.is_err() {
+                        manager.unlock(id, vec![keys[i].clone()]);
+                    }
+                    res
+                })
+            })
+            .collect();
+        let errors: Vec<_> = handles
+            .into_iter()
+            .filter_map(|handle| handle.join().unwrap().err())
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].downcast_ref::<DeadlockError>().is_some());
+    }
+}
@@ -0,0 +1,246 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+
+use super::error::CorruptionError;
+
+/// width of data len in record header, memtable log uses u32 and meta log uses u64
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordLen {
+    U32,
+    U64,
+}
+
+impl RecordLen {
+    fn size(self) -> usize {
+        match self {
+            RecordLen::U32 => 4,
+            RecordLen::U64 => 8,
+        }
+    }
+
+    fn encode(self, len: usize) -> Vec<u8> {
+        match self {
+            RecordLen::U32 => (len as u32).to_le_bytes().to_vec(),
+            RecordLen::U64 => (len as u64).to_le_bytes().to_vec(),
+        }
+    }
+
+    fn read(self, reader: &mut impl Read) -> Result<usize> {
+        Ok(match self {
+            RecordLen::U32 => reader.read_u32::<LittleEndian>()? as usize,
+            RecordLen::U64 => reader.read_u64::<LittleEndian>()? as usize,
+        })
+    }
+}
+
+// checksum covers len, so damaged len is not read as truncated record
+fn record_checksum(len: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+// bytes of record with data of len
+pub fn record_size(record_len: RecordLen, len: usize) -> usize {
+    record_len.size() + 4 + len
+}
+
+/// write record [data len,crc32 of len and data (u32),data] to writer
+pub fn write_record(record_len: RecordLen, data: &[u8], writer: &mut impl Write) -> Result<()> {
+    let len = record_len.encode(data.len());
+    writer.write_all(&len)?;
+    writer.write_u32::<LittleEndian>(record_checksum(&len, data))?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+/// read records written by write_record until end of reader
+/// record which goes beyond end of reader is truncated by crash and treated as end of log,
+/// other damaged records are CorruptionError, iteration stops after error
+pub struct RecordReader<R> {
+    reader: R,
+    // bytes not read in reader
+    remain: u64,
+    // position of next record
+    position: u64,
+    record_len: RecordLen,
+    // log written before checksum is added has only len in header
+    has_checksum: bool,
+    // log name in errors and warnings
+    name: &'static str,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(reader: R, size: u64, record_len: RecordLen, name: &'static str) -> Self {
+        RecordReader {
+            reader,
+            remain: size,
+            position: 0,
+            record_len,
+            has_checksum: true,
+            name,
+        }
+    }
+
+    pub fn without_checksum(mut self) -> Self {
+        self.has_checksum = false;
+        self
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // return None if reach end of log
+    pub fn read_record(&mut self) -> Result<Option<Vec<u8>>> {
+        let header_size = if self.has_checksum {
+            self.record_len.size() + 4
+        } else {
+            self.record_len.size()
+        } as u64;
+        if self.remain < header_size {
+            if self.remain > 0 {
+                warn!("{} has truncated record header, ignore it", self.name);
+            }
+            return Ok(None);
+        }
+        let len = self.record_len.read(&mut self.reader)?;
+        let crc = if self.has_checksum {
+            Some(self.reader.read_u32::<LittleEndian>()?)
+        } else {
+            None
+        };
+        if self.remain - header_size < len as u64 {
+            warn!("{} has truncated record, ignore it", self.name);
+            return Ok(None);
+        }
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+        if let Some(crc) = crc {
+            if record_checksum(&self.record_len.encode(len), &data) != crc {
+                return Err(CorruptionError::new(format!(
+                    "{} record at {} checksum mismatch",
+                    self.name, self.position
+                ))
+                .into());
+            }
+        }
+        self.remain -= header_size + len as u64;
+        self.position += header_size + len as u64;
+        Ok(Some(data))
+    }
+
+    // read data without record header by read, for log written before records have header
+    pub fn read_unframed<T>(
+        &mut self,
+        read: impl FnOnce(&mut dyn Read) -> Result<T>,
+    ) -> Result<Option<T>> {
+        if self.remain == 0 {
+            return Ok(None);
+        }
+        let mut reader = (&mut self.reader).take(self.remain);
+        let res = read(&mut reader)?;
+        let read_size = self.remain - reader.limit();
+        self.remain -= read_size;
+        self.position += read_size;
+        Ok(Some(res))
+    }
+
+    // stop reading after error
+    pub fn stop(&mut self) {
+        self.remain = 0;
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.read_record();
+        if res.is_err() {
+            self.stop();
+        }
+        res.transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use crate::db::error::CorruptionError;
+
+    use super::{record_size, write_record, RecordLen, RecordReader};
+
+    fn read_all(data: &[u8], record_len: RecordLen) -> Vec<anyhow::Result<Vec<u8>>> {
+        RecordReader::new(data, data.len() as u64, record_len, "log").collect()
+    }
+
+    #[test]
+    fn test_truncated_and_corrupted_record() {
+        let records = [vec![1, 1], vec![2, 2], vec![3, 3]];
+        for record_len in [RecordLen::U32, RecordLen::U64] {
+            let mut data = Vec::new();
+            for record in &records {
+                write_record(record_len, record, &mut data).unwrap();
+            }
+            let size = record_size(record_len, 2);
+            assert_eq!(data.len(), 3 * size);
+            let res: Vec<Vec<u8>> = read_all(&data, record_len)
+                .into_iter()
+                .map(|r| r.unwrap())
+                .collect();
+            assert_eq!(res, records);
+
+            // last record is truncated by crash
+            for len in [data.len() - 1, data.len() - size + 3] {
+                let res: Vec<Vec<u8>> = read_all(&data[..len], record_len)
+                    .into_iter()
+                    .map(|r| r.unwrap())
+                    .collect();
+                assert_eq!(res, records[..2]);
+            }
+
+            // data or len of second record is changed
+            for position in [size + size - 1, size] {
+                let mut damaged = data.clone();
+                damaged[position] ^= 1;
+                let res = read_all(&damaged, record_len);
+                assert_eq!(res.len(), 2);
+                assert_eq!(res[0].as_ref().unwrap(), &records[0]);
+                let err = res[1].as_ref().unwrap_err();
+                assert!(err.downcast_ref::<CorruptionError>().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_record_without_checksum() {
+        let mut data = Vec::new();
+        for record in [vec![1], vec![2, 3]] {
+            data.write_u64::<LittleEndian>(record.len() as u64).unwrap();
+            data.extend_from_slice(&record);
+        }
+        let res: Vec<Vec<u8>> =
+            RecordReader::new(data.as_slice(), data.len() as u64, RecordLen::U64, "log")
+                .without_checksum()
+                .map(|r| r.unwrap())
+                .collect();
+        assert_eq!(res, vec![vec![1], vec![2, 3]]);
+
+        // data without header is read by caller
+        let mut reader = RecordReader::new(&[1u8, 2, 3][..], 3, RecordLen::U32, "log");
+        let read_two = |r: &mut dyn std::io::Read| {
+            let mut buf = [0; 2];
+            r.read_exact(&mut buf)?;
+            Ok(buf)
+        };
+        assert_eq!(reader.read_unframed(read_two).unwrap(), Some([1, 2]));
+        assert_eq!(reader.position(), 2);
+        assert!(reader.read_unframed(read_two).is_err());
+    }
+}
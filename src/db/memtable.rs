@@ -1,130 +1,459 @@
-use std::cmp::Reverse;
-use std::collections::BinaryHeap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
-use dashmap::{DashMap, ReadOnlyView};
+use crate::db::common::{KVIterItem, ValueWithTag};
+use crate::db::key::{Key, KeySlice, SeqNumber, MAX_SEQUENCE};
+use crate::db::range_tombstone::{RangeTombstone, RangeTombstones};
+use crate::db::value::{now_millis, Value};
 
-use crate::db::common::{KVIterItem, ValueSliceTag, ValueWithTag};
-use crate::db::key::{Key, KeySlice};
-use crate::db::value::{Value, ValueSlice};
+use self::skiplist::{SkipList, SkipListIter};
+
+mod arena;
+mod skiplist;
 
 pub struct Memtable {
-    hash_map: DashMap<Key, ValueWithTag>,
+    list: SkipList,
+    // largest sequence number written to memtable
+    last_sequence: AtomicU64,
+    // written by delete range, kvs in skiplist are not removed
+    range_tombstones: RwLock<RangeTombstones>,
+}
+
+// memtables flushed to level 0 which are still kept in memory, from new to old
+// transaction commit checks writes after it begins in them without reading sstables
+pub struct FlushedMemtables {
+    memtables: VecDeque<Arc<Memtable>>,
+    memory_usage: usize,
+    // oldest memtables are dropped when memory usage is larger than it
+    limit: usize,
+    // writes not greater than it may be flushed and dropped from memory
+    dropped_sequence: SeqNumber,
 }
 
-pub struct MemtableIter(BinaryHeap<Reverse<(KeySlice, ValueSliceTag)>>);
+// iter all versions of kvs in internal key order
+pub struct MemtableIter<'a>(SkipListIter<'a>);
+
+// iter kvs in range, safe to use while memtable is still written by others
+pub struct MemtableRangeIter {
+    // drop before memtable
+    iter: SkipListIter<'static>,
+    end_key: Option<Key>,
+    memtable: Arc<Memtable>,
+}
 
 impl Memtable {
     pub fn new() -> Self {
         Memtable {
-            hash_map: DashMap::new(),
+            list: SkipList::new(),
+            last_sequence: AtomicU64::new(0),
+            range_tombstones: RwLock::new(RangeTombstones::new()),
         }
     }
 
-    pub fn iter(&self) -> MemtableIter {
-        let iter = self.hash_map.iter();
-        let mut heap = BinaryHeap::new();
-        for i in iter {
-            let p: (&Key, &ValueWithTag) = i.pair();
-            let k = KeySlice::new(p.0.data());
-            let v = if let Some(n) = p.1 {
-                Some(ValueSlice::new(n.data()))
-            } else {
-                None
-            };
-            heap.push(Reverse((k, v)));
+    pub fn iter(&self) -> MemtableIter<'_> {
+        MemtableIter(self.list.iter())
+    }
+
+    // iter all kvs which key is in [start_key,end_key), end_key is unbounded if None
+    pub fn range_iter(
+        self: &Arc<Self>,
+        start_key: &Key,
+        end_key: Option<&Key>,
+    ) -> MemtableRangeIter {
+        let memtable = self.clone();
+        // memtable is held by iter, so skiplist outlives it
+        let mut iter: SkipListIter<'static> = unsafe { std::mem::transmute(memtable.list.iter()) };
+        iter.seek(&KeySlice::new_with_seq(start_key.data(), MAX_SEQUENCE));
+        MemtableRangeIter {
+            iter,
+            end_key: end_key.cloned(),
+            memtable,
         }
-        MemtableIter(heap)
     }
 
-    pub fn insert_option_value(&self, key: &Key, value: Option<&Value>) {
-        let t = value.map(|v| v.clone());
-        self.hash_map.insert(key.clone(), t);
+    // value with same key and sequence number inserted later overwrites earlier one
+    pub fn insert_option_value(&self, key: &Key, seq: SeqNumber, value: Option<&Value>) {
+        self.list.insert(key.data(), seq, value.map(|v| v.data()));
+        self.last_sequence.fetch_max(seq, Ordering::SeqCst);
+    }
+
+    pub fn insert(&self, key: &Key, seq: SeqNumber, value: &Value) {
+        self.insert_option_value(key, seq, Some(value));
+    }
+
+    // value reads as absent after expire_time, milliseconds since unix epoch
+    pub fn insert_with_expire_time(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        value: &Value,
+        expire_time: u64,
+    ) {
+        self.list
+            .insert_with_expire_time(key.data(), seq, value.data(), expire_time);
+        self.last_sequence.fetch_max(seq, Ordering::SeqCst);
+    }
+
+    pub fn insert_merge_operand(&self, key: &Key, seq: SeqNumber, operand: &Value) {
+        self.list
+            .insert_merge_operand(key.data(), seq, operand.data());
+        self.last_sequence.fetch_max(seq, Ordering::SeqCst);
     }
 
-    pub fn insert(&self, key: &Key, value: &Value) {
-        self.hash_map.insert(key.clone(), Some(value.clone()));
+    pub fn delete(&self, key: &Key, seq: SeqNumber) {
+        self.insert_option_value(key, seq, None);
     }
+
+    // delete keys in [start,end) written before seq
+    pub fn delete_range(&self, start: &Key, end: &Key, seq: SeqNumber) {
+        let tombstone = RangeTombstone::new(start.clone(), end.clone(), seq);
+        self.range_tombstones.write().unwrap().add(tombstone);
+        self.last_sequence.fetch_max(seq, Ordering::SeqCst);
+    }
+
+    pub fn range_tombstones(&self) -> RangeTombstones {
+        self.range_tombstones.read().unwrap().clone()
+    }
+
+    // newest version
     pub fn get_str(&self, key: &str) -> Option<ValueWithTag> {
-        self.get(&Key::new(key))
+        self.get(&Key::new(key), MAX_SEQUENCE, &mut Vec::new())
     }
 
-    pub fn get(&self, key: &Key) -> Option<ValueWithTag> {
-        if let Some(i) = self.hash_map.get(key) {
-            return Some(i.value().clone());
+    // newest version which sequence number is not greater than seq, merge operands newer than it
+    // are pushed to operands from new to old, None if there are only operands or nothing
+    // expired value is same as deleted
+    pub fn get(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        operands: &mut Vec<Value>,
+    ) -> Option<ValueWithTag> {
+        let tombstone_seq = self
+            .range_tombstones
+            .read()
+            .unwrap()
+            .max_covering_seq(key.data(), seq);
+        let now = now_millis();
+        let mut iter = self.list.iter();
+        iter.seek(&KeySlice::new_with_seq(key.data(), seq));
+        let mut last_seq = None;
+        for (k, v) in iter {
+            if unsafe { k.data() } != key.data() {
+                break;
+            }
+            // same internal key inserted later overwrites earlier one
+            if last_seq == Some(k.seq()) {
+                continue;
+            }
+            last_seq = Some(k.seq());
+            if tombstone_seq.is_some_and(|t| t > k.seq()) {
+                return Some(None);
+            }
+            match v {
+                Some(v) if v.is_merge_operand() => {
+                    operands.push(Value::from_u8(unsafe { v.data() }));
+                }
+                Some(v) if v.is_expired(now) => return Some(None),
+                Some(v) => return Some(Some(Value::from_u8(unsafe { v.data() }))),
+                None => return Some(None),
+            }
         }
-        None
+        // versions in older memtables and sstables are older than tombstone
+        tombstone_seq.map(|_| None)
     }
-    pub fn delete(&mut self, key: &Key) -> Option<Value> {
-        let res = self.hash_map.insert(key.clone(), None);
-        if let Some(i) = res {
-            return i;
+
+    pub fn last_sequence(&self) -> SeqNumber {
+        self.last_sequence.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.iter().next().is_none() && self.range_tombstones.read().unwrap().is_empty()
+    }
+
+    // bytes of memory used by kvs, index and range tombstones
+    pub fn memory_usage(&self) -> usize {
+        self.list.memory_usage() + self.range_tombstones.read().unwrap().size()
+    }
+}
+
+impl FlushedMemtables {
+    // writes before db is opened are flushed, last_sequence is largest sequence number in sstables
+    pub fn new(limit: usize, last_sequence: SeqNumber) -> Self {
+        FlushedMemtables {
+            memtables: VecDeque::new(),
+            memory_usage: 0,
+            limit,
+            dropped_sequence: last_sequence,
         }
-        None
+    }
+
+    pub fn push(&mut self, memtable: Arc<Memtable>) {
+        self.memory_usage += memtable.memory_usage();
+        self.memtables.push_front(memtable);
+        while self.memory_usage > self.limit {
+            let dropped = match self.memtables.pop_back() {
+                Some(dropped) => dropped,
+                None => break,
+            };
+            self.memory_usage -= dropped.memory_usage();
+            self.dropped_sequence = self.dropped_sequence.max(dropped.last_sequence());
+        }
+    }
+
+    pub fn memtables(&self) -> impl Iterator<Item = &Arc<Memtable>> {
+        self.memtables.iter()
+    }
+
+    // writes newer than it are all in kept memtables
+    pub fn dropped_sequence(&self) -> SeqNumber {
+        self.dropped_sequence
     }
 }
 
-impl MemtableIter {
+impl<'a> MemtableIter<'a> {
     pub fn has_next(&self) -> bool {
-        !self.0.is_empty()
+        self.0.has_next()
+    }
+}
+
+impl<'a> Iterator for MemtableIter<'a> {
+    type Item = KVIterItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
     }
 }
 
-impl Iterator for MemtableIter {
+impl Iterator for MemtableRangeIter {
     type Item = KVIterItem;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(reversed_kv) = self.0.pop() {
-            let (k, v) = reversed_kv.0;
-            return Some((k, v));
+        let (k, v) = self.iter.next()?;
+        if let Some(end_key) = &self.end_key {
+            if unsafe { k.data() } >= end_key.data() {
+                return None;
+            }
         }
-        None
+        Some((k, v))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::db::key::Key;
-    use crate::db::memtable::Memtable;
+    use std::sync::Arc;
+
+    use crate::db::key::{Key, MAX_SEQUENCE};
+    use crate::db::memtable::{FlushedMemtables, Memtable};
     use crate::db::value::Value;
 
+    #[test]
+    fn test_flushed_memtables() {
+        let new_memtable = |seq: u64| {
+            let memtable = Arc::new(Memtable::new());
+            memtable.insert(&Key::from_u64(seq), seq, &Value::from_u64(seq));
+            memtable
+        };
+        let size = new_memtable(1).memory_usage();
+        let mut flushed = FlushedMemtables::new(2 * size, 3);
+        assert_eq!(flushed.dropped_sequence(), 3);
+        flushed.push(new_memtable(4));
+        flushed.push(new_memtable(5));
+        assert_eq!(flushed.memtables().count(), 2);
+        assert_eq!(flushed.dropped_sequence(), 3);
+        // oldest memtable is dropped
+        flushed.push(new_memtable(6));
+        let seqs: Vec<u64> = flushed.memtables().map(|m| m.last_sequence()).collect();
+        assert_eq!(seqs, vec![6, 5]);
+        assert_eq!(flushed.dropped_sequence(), 4);
+
+        let mut flushed = FlushedMemtables::new(0, 0);
+        flushed.push(new_memtable(1));
+        assert_eq!(flushed.memtables().count(), 0);
+        assert_eq!(flushed.dropped_sequence(), 1);
+    }
+
     #[test]
     fn test_memtable_get_set_delete() {
-        let mut memtable = Memtable::new();
-        memtable.insert(&Key::new("a"), &Value::new("a"));
-        memtable.insert(&Key::new("b"), &Value::new("b"));
-        memtable.insert(&Key::new("c"), &Value::new("c"));
+        let memtable = Memtable::new();
+        memtable.insert(&Key::new("a"), 1, &Value::new("a"));
+        memtable.insert(&Key::new("b"), 2, &Value::new("b"));
+        memtable.insert(&Key::new("c"), 3, &Value::new("c"));
 
         assert_eq!(
-            memtable.get(&Key::new("a")).unwrap().unwrap(),
+            memtable
+                .get(&Key::new("a"), MAX_SEQUENCE, &mut Vec::new())
+                .unwrap()
+                .unwrap(),
             Value::new("a")
         );
         assert_eq!(
-            memtable.get(&Key::new("b")).unwrap().unwrap(),
+            memtable
+                .get(&Key::new("b"), MAX_SEQUENCE, &mut Vec::new())
+                .unwrap()
+                .unwrap(),
             Value::new("b")
         );
-        memtable.insert(&Key::new("a"), &Value::new("aa"));
+        memtable.insert(&Key::new("a"), 4, &Value::new("aa"));
         assert_eq!(
-            memtable.get(&Key::new("a")).unwrap().unwrap(),
+            memtable
+                .get(&Key::new("a"), MAX_SEQUENCE, &mut Vec::new())
+                .unwrap()
+                .unwrap(),
             Value::new("aa")
         );
-        assert_eq!(memtable.delete(&Key::new("c")).unwrap(), Value::new("c"));
-        assert!(memtable.get(&Key::new("c")).unwrap().is_none());
+        memtable.delete(&Key::new("c"), 5);
+        assert!(memtable
+            .get(&Key::new("c"), MAX_SEQUENCE, &mut Vec::new())
+            .unwrap()
+            .is_none());
+        assert_eq!(memtable.last_sequence(), 5);
+
+        // old versions are readable with smaller sequence number
+        assert_eq!(
+            memtable
+                .get(&Key::new("a"), 3, &mut Vec::new())
+                .unwrap()
+                .unwrap(),
+            Value::new("a")
+        );
+        assert_eq!(
+            memtable
+                .get(&Key::new("c"), 4, &mut Vec::new())
+                .unwrap()
+                .unwrap(),
+            Value::new("c")
+        );
+        assert!(memtable.get(&Key::new("c"), 2, &mut Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_memtable_delete_range() {
+        let memtable = Memtable::new();
+        for i in 0..5 {
+            memtable.insert(&Key::from_u64(i), i + 1, &Value::from_u64(i));
+        }
+        memtable.delete_range(&Key::from_u64(1), &Key::from_u64(3), 6);
+        memtable.insert(&Key::from_u64(2), 7, &Value::new("new"));
+        assert_eq!(memtable.last_sequence(), 7);
+
+        let get = |i: u64, seq| memtable.get(&Key::from_u64(i), seq, &mut Vec::new());
+        assert_eq!(get(0, MAX_SEQUENCE), Some(Some(Value::from_u64(0))));
+        assert_eq!(get(1, MAX_SEQUENCE), Some(None));
+        assert_eq!(get(2, MAX_SEQUENCE), Some(Some(Value::new("new"))));
+        assert_eq!(get(2, 6), Some(None));
+        // end key is not deleted
+        assert_eq!(get(3, MAX_SEQUENCE), Some(Some(Value::from_u64(3))));
+        // tombstone is invisible to older reads
+        assert_eq!(get(1, 5), Some(Some(Value::from_u64(1))));
+        // key not written to this memtable is deleted too
+        assert_eq!(get(15, MAX_SEQUENCE), Some(None));
+        assert_eq!(memtable.range_tombstones().len(), 1);
+
+        let memtable = Memtable::new();
+        memtable.delete_range(&Key::new("a"), &Key::new("b"), 1);
+        assert!(!memtable.is_empty());
+    }
+
+    #[test]
+    fn test_memtable_merge_operand() {
+        let memtable = Memtable::new();
+        let key = Key::new("a");
+        memtable.insert_merge_operand(&key, 1, &Value::new("1"));
+        memtable.insert(&key, 2, &Value::new("base"));
+        memtable.insert_merge_operand(&key, 3, &Value::new("3"));
+        memtable.insert_merge_operand(&key, 4, &Value::new("4"));
+
+        let mut operands = Vec::new();
+        let res = memtable.get(&key, MAX_SEQUENCE, &mut operands);
+        assert_eq!(res, Some(Some(Value::new("base"))));
+        assert_eq!(operands, vec![Value::new("4"), Value::new("3")]);
+
+        // only operands are found, older memtables and sstables should be searched
+        let mut operands = Vec::new();
+        assert_eq!(memtable.get(&key, 1, &mut operands), None);
+        assert_eq!(operands, vec![Value::new("1")]);
+
+        // range tombstone is base of newer operands
+        memtable.delete_range(&Key::new("a"), &Key::new("b"), 5);
+        memtable.insert_merge_operand(&key, 6, &Value::new("6"));
+        let mut operands = Vec::new();
+        assert_eq!(memtable.get(&key, MAX_SEQUENCE, &mut operands), Some(None));
+        assert_eq!(operands, vec![Value::new("6")]);
+    }
+
+    #[test]
+    fn test_memtable_expired_value() {
+        let memtable = Memtable::new();
+        let key = Key::new("a");
+        memtable.insert(&key, 1, &Value::new("old"));
+        memtable.insert_with_expire_time(&key, 2, &Value::new("expired"), 1);
+        memtable.insert_with_expire_time(&Key::new("b"), 3, &Value::new("b"), u64::MAX);
+
+        // expired value hides older versions like deleted kv
+        let res = memtable.get(&key, MAX_SEQUENCE, &mut Vec::new());
+        assert_eq!(res, Some(None));
+        let res = memtable.get(&key, 1, &mut Vec::new());
+        assert_eq!(res, Some(Some(Value::new("old"))));
+        let res = memtable.get(&Key::new("b"), MAX_SEQUENCE, &mut Vec::new());
+        assert_eq!(res, Some(Some(Value::new("b"))));
     }
 
     #[test]
     fn test_memtable_iter() {
         let memtable = Memtable::new();
-        memtable.insert(&Key::new("a"), &Value::new("a"));
-        memtable.insert(&Key::new("c"), &Value::new("c"));
-        memtable.insert(&Key::new("b"), &Value::new("b"));
+        memtable.insert(&Key::new("a"), 1, &Value::new("a"));
+        memtable.insert(&Key::new("c"), 2, &Value::new("c"));
+        memtable.insert(&Key::new("b"), 3, &Value::new("b"));
+        memtable.insert(&Key::new("a"), 4, &Value::new("a"));
 
         let mut it = memtable.iter();
         assert!(it.has_next());
         let mut s = String::new();
         while it.has_next() {
             let i = it.next().unwrap();
-            s.push_str(&i.0.to_string())
+            s.push_str(&format!("{}{}", i.0, i.0.seq()))
+        }
+        assert_eq!(s, "a4a1b3c2");
+    }
+
+    #[test]
+    fn test_memtable_range_iter() {
+        let memtable = Arc::new(Memtable::new());
+        for (seq, i) in ["a", "e", "c", "b", "d"].iter().enumerate() {
+            memtable.insert(&Key::new(i), seq as u64, &Value::new(i));
+        }
+        memtable.delete(&Key::new("c"), 10);
+
+        let mut s = String::new();
+        for (k, v) in memtable.range_iter(&Key::new("b"), Some(&Key::new("e"))) {
+            s.push_str(&k.to_string());
+            if v.is_none() {
+                s.push('-');
+            }
+        }
+        assert_eq!(s, "bc-cd");
+
+        let it = memtable.range_iter(&Key::new("c"), None);
+        // kv inserted after iter is created is visible if iter hasn't passed it
+        memtable.insert(&Key::new("f"), 11, &Value::new("f"));
+        assert_eq!(it.count(), 5);
+    }
+
+    #[test]
+    fn test_memtable_memory_usage() {
+        let memtable = Memtable::new();
+        // head of skiplist
+        let usage = memtable.memory_usage();
+        assert!(usage > 0);
+        for i in 0..100 {
+            memtable.insert(&Key::from_u64(i), i, &Value::from_u8(&[1; 100]));
         }
-        assert_eq!(s, "abc");
+        assert!(memtable.memory_usage() > usage + 100 * 100);
+        // value larger than arena block is allocated alone
+        let usage = memtable.memory_usage();
+        memtable.insert(&Key::new("b"), 100, &Value::from_u8(&[1; 10000]));
+        assert!(memtable.memory_usage() >= usage + 10000);
     }
 }
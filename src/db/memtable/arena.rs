@@ -0,0 +1,99 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+pub const ARENA_BLOCK_SIZE: usize = 4096;
+// allocation larger than it gets its own block, so less space is wasted at end of block
+const LARGE_ALLOCATION_SIZE: usize = ARENA_BLOCK_SIZE / 4;
+
+/// bump allocator for memtable, all memory is freed when arena is dropped
+/// allocation is serialized by lock, allocated memory is never moved
+pub struct Arena {
+    inner: Mutex<ArenaInner>,
+    // bytes of all blocks
+    memory_usage: AtomicUsize,
+}
+
+struct ArenaInner {
+    // u64 makes block 8 byte aligned
+    blocks: Vec<Box<[u64]>>,
+    ptr: *mut u8,
+    remaining: usize,
+}
+
+// memory pointed by ptr is owned by blocks
+unsafe impl Send for ArenaInner {}
+
+impl Arena {
+    pub fn new() -> Self {
+        Arena {
+            inner: Mutex::new(ArenaInner {
+                blocks: Vec::new(),
+                ptr: std::ptr::null_mut(),
+                remaining: 0,
+            }),
+            memory_usage: AtomicUsize::new(0),
+        }
+    }
+
+    // return 8 byte aligned memory of size, it is valid until arena is dropped
+    pub fn allocate(&self, size: usize) -> *mut u8 {
+        let size = (size + 7) & !7;
+        let mut inner = self.inner.lock().unwrap();
+        if size > inner.remaining {
+            if size > LARGE_ALLOCATION_SIZE {
+                return self.new_block(&mut inner, size);
+            }
+            inner.ptr = self.new_block(&mut inner, ARENA_BLOCK_SIZE);
+            inner.remaining = ARENA_BLOCK_SIZE;
+        }
+        let res = inner.ptr;
+        inner.ptr = unsafe { inner.ptr.add(size) };
+        inner.remaining -= size;
+        res
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    fn new_block(&self, inner: &mut ArenaInner, size: usize) -> *mut u8 {
+        let mut block = vec![0u64; size / 8].into_boxed_slice();
+        let ptr = block.as_mut_ptr() as *mut u8;
+        inner.blocks.push(block);
+        self.memory_usage.fetch_add(size, Ordering::Relaxed);
+        ptr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Arena, ARENA_BLOCK_SIZE};
+
+    #[test]
+    fn test_arena_allocate() {
+        let arena = Arena::new();
+        assert_eq!(arena.memory_usage(), 0);
+        let a = arena.allocate(3);
+        let b = arena.allocate(16);
+        assert_eq!(a as usize % 8, 0);
+        assert_eq!(b as usize - a as usize, 8);
+        assert_eq!(arena.memory_usage(), ARENA_BLOCK_SIZE);
+
+        // large allocation doesn't waste current block
+        let large = arena.allocate(5000);
+        assert_eq!(arena.memory_usage(), ARENA_BLOCK_SIZE + 5000);
+        let c = arena.allocate(8);
+        assert_eq!(c as usize - b as usize, 16);
+        unsafe {
+            large.write_bytes(1, 5000);
+            c.write_bytes(2, 8);
+            assert_eq!(*large.add(4999), 1);
+        }
+
+        // current block is full
+        for _ in 0..ARENA_BLOCK_SIZE / 1000 + 1 {
+            arena.allocate(1000);
+        }
+        assert_eq!(arena.memory_usage(), 2 * ARENA_BLOCK_SIZE + 5000);
+    }
+}
@@ -0,0 +1,312 @@
+use std::mem::size_of;
+use std::ptr::{self, null_mut};
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::db::common::{KVIterItem, ValueSliceTag};
+use crate::db::key::{KeySlice, SeqNumber};
+use crate::db::value::ValueSlice;
+
+use super::arena::Arena;
+
+const MAX_HEIGHT: usize = 12;
+// probability of node having next level is 1/BRANCHING
+const BRANCHING: u64 = 4;
+
+/// skiplist ordered by internal key, key and value are copied to arena
+/// insert is lock free and can run concurrently with other inserts and reads,
+/// node is never removed until skiplist is dropped
+pub struct SkipList {
+    arena: Arena,
+    head: *mut Node,
+    max_height: AtomicUsize,
+    rand_state: AtomicU64,
+}
+
+// next pointers of node are allocated right after it
+// [node,next pointer of level 0,..,next pointer of level height-1]
+#[repr(C)]
+struct Node {
+    key: KeySlice,
+    value: ValueSliceTag,
+    height: usize,
+}
+
+/// iter nodes in internal key order, new nodes inserted after it are visible
+pub struct SkipListIter<'a> {
+    list: &'a SkipList,
+    node: *const Node,
+}
+
+// nodes are only written before they are linked into skiplist
+unsafe impl Send for SkipList {}
+unsafe impl Sync for SkipList {}
+
+impl Node {
+    unsafe fn next(&self, level: usize) -> &AtomicPtr<Node> {
+        debug_assert!(level < self.height);
+        let nexts = (self as *const Node).add(1) as *const AtomicPtr<Node>;
+        &*nexts.add(level)
+    }
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let arena = Arena::new();
+        let head = Self::new_node(&arena, KeySlice::new(&[]), None, MAX_HEIGHT);
+        SkipList {
+            arena,
+            head,
+            max_height: AtomicUsize::new(1),
+            rand_state: AtomicU64::new(0x2545_f491_4f6c_dd1d),
+        }
+    }
+
+    // same internal key can be inserted more than once, newer one is placed before older ones
+    pub fn insert(&self, key: &[u8], seq: SeqNumber, value: Option<&[u8]>) {
+        let value = value.map(|v| ValueSlice::new(self.copy_to_arena(v)));
+        self.insert_value_slice(key, seq, value);
+    }
+
+    pub fn insert_with_expire_time(
+        &self,
+        key: &[u8],
+        seq: SeqNumber,
+        value: &[u8],
+        expire_time: u64,
+    ) {
+        let value = ValueSlice::new(self.copy_to_arena(value)).with_expire_time(expire_time);
+        self.insert_value_slice(key, seq, Some(value));
+    }
+
+    pub fn insert_merge_operand(&self, key: &[u8], seq: SeqNumber, operand: &[u8]) {
+        let value = ValueSlice::new_merge_operand(self.copy_to_arena(operand));
+        self.insert_value_slice(key, seq, Some(value));
+    }
+
+    // value points to arena
+    fn insert_value_slice(&self, key: &[u8], seq: SeqNumber, value: ValueSliceTag) {
+        let key = KeySlice::new_with_seq(self.copy_to_arena(key), seq);
+        let height = self.random_height();
+        let node = Self::new_node(&self.arena, key, value, height);
+        self.max_height.fetch_max(height, Ordering::SeqCst);
+
+        let mut prev = [self.head; MAX_HEIGHT];
+        let mut next = [null_mut(); MAX_HEIGHT];
+        let mut x = self.head;
+        for level in (0..self.max_height.load(Ordering::SeqCst)).rev() {
+            (prev[level], next[level]) = self.find_splice_for_level(&key, x, level);
+            x = prev[level];
+        }
+        // link from bottom, node is visible to readers once it is linked in level 0
+        for level in 0..height {
+            loop {
+                unsafe {
+                    (*node).next(level).store(next[level], Ordering::Relaxed);
+                    let res = (*prev[level]).next(level).compare_exchange(
+                        next[level],
+                        node,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    );
+                    if res.is_ok() {
+                        break;
+                    }
+                }
+                // node is inserted concurrently between prev and next, search again
+                (prev[level], next[level]) = self.find_splice_for_level(&key, prev[level], level);
+            }
+        }
+    }
+
+    // (sequence number,value) of first entry not less than key and sequence number,
+    // None if it has different user key
+    pub fn get(&self, key: &[u8], seq: SeqNumber) -> Option<(SeqNumber, ValueSliceTag)> {
+        let node = self.seek(&KeySlice::new_with_seq(key, seq));
+        if node.is_null() {
+            return None;
+        }
+        unsafe {
+            if (*node).key.data() != key {
+                return None;
+            }
+            Some(((*node).key.seq(), (*node).value))
+        }
+    }
+
+    pub fn iter(&self) -> SkipListIter<'_> {
+        SkipListIter {
+            list: self,
+            node: unsafe { (*self.head).next(0).load(Ordering::Acquire) },
+        }
+    }
+
+    // bytes allocated by arena, include key, value and nodes
+    pub fn memory_usage(&self) -> usize {
+        self.arena.memory_usage()
+    }
+
+    fn new_node(arena: &Arena, key: KeySlice, value: ValueSliceTag, height: usize) -> *mut Node {
+        let size = size_of::<Node>() + height * size_of::<AtomicPtr<Node>>();
+        let node = arena.allocate(size) as *mut Node;
+        unsafe {
+            ptr::write(node, Node { key, value, height });
+            for level in 0..height {
+                let next = (node.add(1) as *mut AtomicPtr<Node>).add(level);
+                ptr::write(next, AtomicPtr::new(null_mut()));
+            }
+        }
+        node
+    }
+
+    fn copy_to_arena(&self, data: &[u8]) -> &[u8] {
+        let ptr = self.arena.allocate(data.len());
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+            std::slice::from_raw_parts(ptr, data.len())
+        }
+    }
+
+    fn random_height(&self) -> usize {
+        // xorshift, races between inserts only make it less random
+        let mut x = self.rand_state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rand_state.store(x, Ordering::Relaxed);
+        let mut height = 1;
+        while height < MAX_HEIGHT && x.is_multiple_of(BRANCHING) {
+            height += 1;
+            x /= BRANCHING;
+        }
+        height
+    }
+
+    // return (last node less than key, first node not less than key) in level, search from start
+    fn find_splice_for_level(
+        &self,
+        key: &KeySlice,
+        start: *mut Node,
+        level: usize,
+    ) -> (*mut Node, *mut Node) {
+        let mut prev = start;
+        loop {
+            let next = unsafe { (*prev).next(level).load(Ordering::Acquire) };
+            if next.is_null() || unsafe { (*next).key >= *key } {
+                return (prev, next);
+            }
+            prev = next;
+        }
+    }
+
+    // first node not less than key, null if not found
+    fn seek(&self, key: &KeySlice) -> *mut Node {
+        let mut x = self.head;
+        let mut next = null_mut();
+        for level in (0..self.max_height.load(Ordering::SeqCst)).rev() {
+            (x, next) = self.find_splice_for_level(key, x, level);
+        }
+        next
+    }
+}
+
+impl<'a> SkipListIter<'a> {
+    // move to first entry not less than key
+    pub fn seek(&mut self, key: &KeySlice) {
+        self.node = self.list.seek(key);
+    }
+
+    pub fn has_next(&self) -> bool {
+        !self.node.is_null()
+    }
+}
+
+impl<'a> Iterator for SkipListIter<'a> {
+    type Item = KVIterItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.node.is_null() {
+            return None;
+        }
+        unsafe {
+            let node = &*self.node;
+            self.node = node.next(0).load(Ordering::Acquire);
+            Some((node.key, node.value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::db::key::{KeySlice, MAX_SEQUENCE};
+
+    use super::SkipList;
+
+    #[test]
+    fn test_skiplist_insert_and_get() {
+        let list = SkipList::new();
+        assert!(list.get(b"a", MAX_SEQUENCE).is_none());
+        list.insert(b"b", 1, Some(b"b1"));
+        list.insert(b"a", 2, Some(b"a2"));
+        list.insert(b"b", 3, None);
+        list.insert(b"c", 4, Some(b"c4"));
+
+        let get = |key: &[u8], seq| {
+            list.get(key, seq)
+                .map(|(_, v)| v.map(|v| unsafe { v.data().to_vec() }))
+        };
+        assert_eq!(get(b"a", MAX_SEQUENCE), Some(Some(b"a2".to_vec())));
+        assert_eq!(get(b"a", 1), None);
+        assert_eq!(get(b"b", MAX_SEQUENCE), Some(None));
+        assert_eq!(get(b"b", 2), Some(Some(b"b1".to_vec())));
+        assert_eq!(list.get(b"b", 2).unwrap().0, 1);
+        assert_eq!(get(b"bb", MAX_SEQUENCE), None);
+
+        // newer insert of same internal key is returned first
+        list.insert(b"c", 4, Some(b"c4new"));
+        assert_eq!(get(b"c", 4), Some(Some(b"c4new".to_vec())));
+
+        let mut iter = list.iter();
+        let res: Vec<String> = iter
+            .by_ref()
+            .map(|(k, _)| format!("{}{}", k, k.seq()))
+            .collect();
+        assert_eq!(res, vec!["a2", "b3", "b1", "c4", "c4"]);
+        assert!(!iter.has_next());
+        iter.seek(&KeySlice::new_with_seq(b"b", 2));
+        let (k, v) = iter.next().unwrap();
+        assert_eq!(format!("{}{}", k, k.seq()), "b1");
+        assert!(v.is_some());
+        assert!(list.memory_usage() > 0);
+    }
+
+    #[test]
+    fn test_skiplist_concurrent_insert() {
+        let list = Arc::new(SkipList::new());
+        let thread_number = 4;
+        let number = 2000u64;
+        let handles: Vec<_> = (0..thread_number)
+            .map(|t| {
+                let list = list.clone();
+                thread::spawn(move || {
+                    for i in 0..number {
+                        let key = format!("{:05}", i * thread_number + t);
+                        list.insert(key.as_bytes(), i, Some(key.as_bytes()));
+                        // read while others are writing
+                        assert!(list.get(key.as_bytes(), MAX_SEQUENCE).is_some());
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let keys: Vec<String> = list.iter().map(|(k, _)| k.to_string()).collect();
+        let expect: Vec<String> = (0..number * thread_number)
+            .map(|i| format!("{:05}", i))
+            .collect();
+        assert_eq!(keys, expect);
+    }
+}
@@ -4,20 +4,43 @@ use log::{debug, info};
 use rmp_serde::{Deserializer, Serializer};
 use serde::Serialize;
 use std::cmp::max;
-use std::fs::File;
-use std::io::{BufWriter, Read, Seek, Write};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-use crate::db::key::Key;
+use crate::db::column_family::{ColumnFamilyId, DEFAULT_COLUMN_FAMILY_ID};
+use crate::db::key::{Key, SeqNumber};
 use crate::db::value::Value;
+use crate::db::write_batch::Operation;
 
 use super::config::Config;
 use super::db_metrics::TimeRecorder;
-use super::key::KEY_SIZE_LIMIT;
-use super::value::VALUE_SIZE_LIMIT;
+use super::error::CorruptionError;
+use super::log_record::{write_record, RecordLen, RecordReader};
 
+// record type after sequence number, record without it is put or delete
+const RECORD_TYPE_PUT_OR_DELETE: u8 = 0;
+const RECORD_TYPE_RANGE_DELETE: u8 = 1;
+const RECORD_TYPE_MERGE: u8 = 2;
+const RECORD_TYPE_PUT_WITH_EXPIRE: u8 = 3;
+// set in record type if record is not in default column family, column family id (u32) is after record type
+const COLUMN_FAMILY_FLAG: u8 = 0x80;
+
+/// memtable log file is {name}_{number}, each memtable writes to its own log
+/// logs with number smaller than memtable log number in version are persisted in sstable
+/// record format: [data len (u32),crc32 of len and data (u32),key and value in msgpack,sequence number (u64)]
+/// record written before sequence number is added has no sequence number, it is read as 0
+/// delete range record saves start key as key and end key as value, with record type after sequence number
+/// merge record saves operand as value, with record type after sequence number
+/// put with expire time record has expire time (u64) after record type
+/// record of other column family than default one has column family id after record type, before data of type
 pub struct MemtableLog {
     buf_writer: BufWriter<File>,
+    home_path: PathBuf,
+    number: u64,
     config: Config,
+    // reused buffer for encoding record
+    record: Vec<u8>,
 }
 
 struct KVEntry {
@@ -27,20 +50,194 @@ struct KVEntry {
 }
 
 impl MemtableLog {
-    pub fn new(file: File, config: Config) -> Self {
+    pub fn create(home_path: &Path, number: u64, config: Config) -> Result<Self> {
+        let path = home_path.join(Self::file_name(&config.memtable_log_file_path, number));
+        let file = File::create(path)?;
         let buffer = BufWriter::new(file);
+        info!("create memtable log {}", number);
 
-        MemtableLog {
+        Ok(MemtableLog {
             buf_writer: buffer,
+            home_path: PathBuf::from(home_path),
+            number,
             config,
+            record: Vec::new(),
+        })
+    }
+
+    // sync current log and switch to log with next number, return new number
+    pub fn roll(&mut self) -> Result<u64> {
+        self.sync_all()?;
+        *self = Self::create(&self.home_path, self.number + 1, self.config.clone())?;
+        Ok(self.number)
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    // log of old version has no number, it is treated as number 0
+    pub fn file_name(name: &str, number: u64) -> String {
+        if number == 0 {
+            return String::from(name);
         }
+        format!("{}_{}", name, number)
     }
-    pub fn add(&mut self, key: &Key, value: Option<&Value>) -> Result<()> {
-        key.serialize(&mut Serializer::new(&mut self.buf_writer))?;
-        value.serialize(&mut Serializer::new(&mut self.buf_writer))?;
+
+    // numbers of all memtable logs in home path, in ascending order
+    pub fn log_numbers(home_path: &Path, name: &str) -> Result<Vec<u64>> {
+        let prefix = format!("{}_", name);
+        let mut res = Vec::new();
+        for entry in fs::read_dir(home_path)? {
+            let file_name = entry?.file_name();
+            let file_name = match file_name.to_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            if file_name == name {
+                res.push(0);
+            } else if let Some(n) = file_name
+                .strip_prefix(&prefix)
+                .and_then(|n| n.parse::<u64>().ok())
+            {
+                res.push(n);
+            }
+        }
+        res.sort_unstable();
+        Ok(res)
+    }
+
+    // delete logs with number smaller than it, data in them is persisted
+    pub fn delete_logs_before(home_path: &Path, name: &str, number: u64) -> Result<()> {
+        for n in Self::log_numbers(home_path, name)? {
+            if n >= number {
+                break;
+            }
+            fs::remove_file(home_path.join(Self::file_name(name, n)))?;
+            info!("delete memtable log {}", n);
+        }
         Ok(())
     }
 
+    pub fn add(&mut self, key: &Key, seq: SeqNumber, value: Option<&Value>) -> Result<()> {
+        let op = match value {
+            Some(value) => Operation::PUT {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            None => Operation::DELETE { key: key.clone() },
+        };
+        self.add_operation(DEFAULT_COLUMN_FAMILY_ID, &op, seq)
+    }
+
+    pub fn add_range_delete(&mut self, start: &Key, end: &Key, seq: SeqNumber) -> Result<()> {
+        let op = Operation::DELETE_RANGE {
+            start: start.clone(),
+            end: end.clone(),
+        };
+        self.add_operation(DEFAULT_COLUMN_FAMILY_ID, &op, seq)
+    }
+
+    pub fn add_merge(&mut self, key: &Key, operand: &Value, seq: SeqNumber) -> Result<()> {
+        let op = Operation::MERGE {
+            key: key.clone(),
+            value: operand.clone(),
+        };
+        self.add_operation(DEFAULT_COLUMN_FAMILY_ID, &op, seq)
+    }
+
+    // expire time is milliseconds since unix epoch
+    pub fn add_with_expire_time(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        expire_time: u64,
+        seq: SeqNumber,
+    ) -> Result<()> {
+        let op = Operation::PUT_WITH_EXPIRE {
+            key: key.clone(),
+            value: value.clone(),
+            expire_time,
+        };
+        self.add_operation(DEFAULT_COLUMN_FAMILY_ID, &op, seq)
+    }
+
+    pub fn add_operation(
+        &mut self,
+        column_family: ColumnFamilyId,
+        op: &Operation,
+        seq: SeqNumber,
+    ) -> Result<()> {
+        match op {
+            Operation::PUT { key, value } => {
+                self.encode_record(
+                    key,
+                    seq,
+                    Some(value),
+                    RECORD_TYPE_PUT_OR_DELETE,
+                    column_family,
+                )?;
+            }
+            Operation::DELETE { key } => {
+                self.encode_record(key, seq, None, RECORD_TYPE_PUT_OR_DELETE, column_family)?;
+            }
+            Operation::DELETE_RANGE { start, end } => {
+                let end = Value::from_u8(end.data());
+                self.encode_record(
+                    start,
+                    seq,
+                    Some(&end),
+                    RECORD_TYPE_RANGE_DELETE,
+                    column_family,
+                )?;
+            }
+            Operation::MERGE { key, value } => {
+                self.encode_record(key, seq, Some(value), RECORD_TYPE_MERGE, column_family)?;
+            }
+            Operation::PUT_WITH_EXPIRE {
+                key,
+                value,
+                expire_time,
+            } => {
+                self.encode_record(
+                    key,
+                    seq,
+                    Some(value),
+                    RECORD_TYPE_PUT_WITH_EXPIRE,
+                    column_family,
+                )?;
+                self.record.write_u64::<LittleEndian>(*expire_time)?;
+            }
+        }
+        self.write_record()
+    }
+
+    // record type is omitted for put or delete in default column family
+    fn encode_record(
+        &mut self,
+        key: &Key,
+        seq: SeqNumber,
+        value: Option<&Value>,
+        record_type: u8,
+        column_family: ColumnFamilyId,
+    ) -> Result<()> {
+        self.record.clear();
+        key.serialize(&mut Serializer::new(&mut self.record))?;
+        value.serialize(&mut Serializer::new(&mut self.record))?;
+        self.record.write_u64::<LittleEndian>(seq)?;
+        if column_family != DEFAULT_COLUMN_FAMILY_ID {
+            self.record.write_u8(record_type | COLUMN_FAMILY_FLAG)?;
+            self.record.write_u32::<LittleEndian>(column_family)?;
+        } else if record_type != RECORD_TYPE_PUT_OR_DELETE {
+            self.record.write_u8(record_type)?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self) -> Result<()> {
+        write_record(RecordLen::U32, &self.record, &mut self.buf_writer)
+    }
+
     pub fn flush_buf(&mut self) -> Result<()> {
         self.buf_writer.flush()?;
         let file = self.buf_writer.get_mut();
@@ -60,42 +257,118 @@ impl MemtableLog {
 }
 
 pub struct MemtableLogReader {
-    file: File,
-    file_size: u64,
+    reader: RecordReader<BufReader<File>>,
+    // log without number is written before records have header
+    has_header: bool,
 }
 
 impl MemtableLogReader {
     pub fn new(file: File) -> Result<Self> {
-        let meta = file.metadata()?;
-        let file_size = meta.len();
+        let file_size = file.metadata()?.len();
+        Ok(MemtableLogReader {
+            reader: RecordReader::new(
+                BufReader::new(file),
+                file_size,
+                RecordLen::U32,
+                "memtable log",
+            ),
+            has_header: true,
+        })
+    }
+
+    pub fn open(home_path: &Path, name: &str, number: u64) -> Result<Self> {
+        let file = File::open(home_path.join(MemtableLog::file_name(name, number)))?;
+        let mut reader = Self::new(file)?;
+        reader.has_header = number != 0;
+        Ok(reader)
+    }
+
+    // return None if reach end of log, record truncated by crash is treated as end of log
+    fn read_record(&mut self) -> Result<Option<(SeqNumber, ColumnFamilyId, Operation)>> {
+        if !self.has_header {
+            return self.reader.read_unframed(|reader| {
+                let key: Key = rmp_serde::decode::from_read(&mut *reader)?;
+                let value: Option<Value> = rmp_serde::decode::from_read(reader)?;
+                Ok((0, DEFAULT_COLUMN_FAMILY_ID, Self::operation(key, value)))
+            });
+        }
+        let record_position = self.reader.position();
+        let data = match self.reader.read_record()? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let mut data = data.as_slice();
+        let key: Key = rmp_serde::decode::from_read(&mut data)?;
+        let value: Option<Value> = rmp_serde::decode::from_read(&mut data)?;
+        let seq = if data.is_empty() {
+            0
+        } else {
+            data.read_u64::<LittleEndian>()?
+        };
+        if data.is_empty() {
+            let op = Self::operation(key, value);
+            return Ok(Some((seq, DEFAULT_COLUMN_FAMILY_ID, op)));
+        }
+        let mut record_type = data.read_u8()?;
+        let mut column_family = DEFAULT_COLUMN_FAMILY_ID;
+        if record_type & COLUMN_FAMILY_FLAG != 0 {
+            record_type &= !COLUMN_FAMILY_FLAG;
+            column_family = data.read_u32::<LittleEndian>()?;
+        }
+        let op = match (record_type, value) {
+            (RECORD_TYPE_PUT_OR_DELETE, value) => Self::operation(key, value),
+            (RECORD_TYPE_RANGE_DELETE, Some(end)) => Operation::DELETE_RANGE {
+                start: key,
+                end: Key::from(end.data()),
+            },
+            (RECORD_TYPE_MERGE, Some(value)) => Operation::MERGE { key, value },
+            (RECORD_TYPE_PUT_WITH_EXPIRE, Some(value)) => Operation::PUT_WITH_EXPIRE {
+                key,
+                value,
+                expire_time: data.read_u64::<LittleEndian>()?,
+            },
+            (t, _) => {
+                return Err(CorruptionError::new(format!(
+                    "memtable log record at {} has unknown type {}",
+                    record_position, t
+                ))
+                .into())
+            }
+        };
+        Ok(Some((seq, column_family, op)))
+    }
 
-        Ok(MemtableLogReader { file, file_size })
+    fn operation(key: Key, value: Option<Value>) -> Operation {
+        match value {
+            Some(value) => Operation::PUT { key, value },
+            None => Operation::DELETE { key },
+        }
     }
 }
 
 impl Iterator for MemtableLogReader {
-    type Item = (Key, Option<Value>);
+    type Item = Result<(SeqNumber, ColumnFamilyId, Operation)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let position = self.file.stream_position().unwrap();
-
-        if position == self.file_size {
-            return None;
+        let res = self.read_record();
+        if res.is_err() {
+            // stop after error
+            self.reader.stop();
         }
-
-        let key: Key = rmp_serde::decode::from_read(&mut self.file).unwrap();
-        let value: Option<Value> = rmp_serde::decode::from_read(&mut self.file).unwrap();
-
-        Some((key, value))
+        res.transpose()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::fs::File;
+    use std::fs::{self, File};
 
+    use rmp_serde::Serializer;
+    use serde::Serialize;
     use tempfile::{tempdir, tempfile};
 
+    use crate::db::log_record::{write_record, RecordLen};
+    use crate::db::write_batch::Operation;
     use crate::db::{config::Config, key::Key, memtable::MemtableIter, value::Value};
 
     use super::{MemtableLog, MemtableLogReader};
@@ -105,24 +378,203 @@ mod test {
         // init_test_log_as_debug();
         // let r = init_metric();
         let dir = tempdir().unwrap();
-        let path = dir.into_path().join("test");
-        let file = File::create(&path).unwrap();
-        let mut log = MemtableLog::new(file, Config::new());
+        let config = Config::new();
+        let mut log = MemtableLog::create(dir.path(), 1, config.clone()).unwrap();
         let key_1 = Key::new("1");
         let value_1 = Value::new("1");
 
         let key_2 = Key::new("2");
         let value_2 = Value::new("2");
 
-        log.add(&key_1, Some(&value_1.clone())).unwrap();
-        log.add(&key_2, Some(&value_2)).unwrap();
+        log.add(&key_1, 1, Some(&value_1.clone())).unwrap();
+        log.add(&key_2, 2, Some(&value_2)).unwrap();
 
         log.sync_all().unwrap();
 
-        let iter = MemtableLogReader::new(File::open(&path).unwrap()).unwrap();
+        let iter = MemtableLogReader::open(dir.path(), &config.memtable_log_file_path, 1).unwrap();
 
-        for (k, v) in iter {
-            assert_eq!(k.data(), v.unwrap().data())
+        for (i, kv) in iter.enumerate() {
+            let (seq, _, op) = kv.unwrap();
+            assert_eq!(seq, i as u64 + 1);
+            match op {
+                Operation::PUT { key, value } => assert_eq!(key.data(), value.data()),
+                _ => panic!("unexpected operation {:?}", op),
+            }
         }
     }
+
+    #[test]
+    fn test_roll_and_delete() {
+        let dir = tempdir().unwrap();
+        let config = Config::new();
+        let name = &config.memtable_log_file_path;
+        // log without number
+        File::create(dir.path().join(name)).unwrap();
+
+        let mut log = MemtableLog::create(dir.path(), 1, config.clone()).unwrap();
+        log.add(&Key::new("1"), 1, Some(&Value::new("1"))).unwrap();
+        assert_eq!(log.roll().unwrap(), 2);
+        log.add(&Key::new("2"), 2, None).unwrap();
+        assert_eq!(log.roll().unwrap(), 3);
+        assert_eq!(log.number(), 3);
+        assert_eq!(
+            MemtableLog::log_numbers(dir.path(), name).unwrap(),
+            vec![0, 1, 2, 3]
+        );
+
+        let data: Vec<(u64, Operation)> = MemtableLogReader::open(dir.path(), name, 2)
+            .unwrap()
+            .map(|kv| kv.map(|(seq, _, op)| (seq, op)).unwrap())
+            .collect();
+        assert_eq!(data, vec![(2, Operation::DELETE { key: Key::new("2") })]);
+
+        MemtableLog::delete_logs_before(dir.path(), name, 2).unwrap();
+        assert_eq!(
+            MemtableLog::log_numbers(dir.path(), name).unwrap(),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn test_read_log_without_checksum() {
+        let dir = tempdir().unwrap();
+        let name = "memtable_log";
+        let mut data = Vec::new();
+        Key::new("1")
+            .serialize(&mut Serializer::new(&mut data))
+            .unwrap();
+        Some(Value::new("1"))
+            .serialize(&mut Serializer::new(&mut data))
+            .unwrap();
+        fs::write(dir.path().join(name), &data).unwrap();
+        let expect = vec![(
+            0,
+            Operation::PUT {
+                key: Key::new("1"),
+                value: Value::new("1"),
+            },
+        )];
+        let res: Vec<(u64, Operation)> = MemtableLogReader::open(dir.path(), name, 0)
+            .unwrap()
+            .map(|kv| kv.map(|(seq, _, op)| (seq, op)).unwrap())
+            .collect();
+        assert_eq!(res, expect);
+
+        // record with checksum but without sequence number
+        let mut record = Vec::new();
+        write_record(RecordLen::U32, &data, &mut record).unwrap();
+        fs::write(dir.path().join(MemtableLog::file_name(name, 1)), &record).unwrap();
+        let res: Vec<(u64, Operation)> = MemtableLogReader::open(dir.path(), name, 1)
+            .unwrap()
+            .map(|kv| kv.map(|(seq, _, op)| (seq, op)).unwrap())
+            .collect();
+        assert_eq!(res, expect);
+    }
+
+    #[test]
+    fn test_range_delete_record() {
+        let dir = tempdir().unwrap();
+        let config = Config::new();
+        let name = &config.memtable_log_file_path;
+        let mut log = MemtableLog::create(dir.path(), 1, config.clone()).unwrap();
+        log.add(&Key::new("a"), 1, Some(&Value::new("a"))).unwrap();
+        log.add_range_delete(&Key::new("a"), &Key::new("c"), 2)
+            .unwrap();
+        log.add(&Key::new("b"), 3, None).unwrap();
+        log.add_merge(&Key::new("b"), &Value::new("1"), 4).unwrap();
+        log.add_with_expire_time(&Key::new("c"), &Value::new("c"), 100, 5)
+            .unwrap();
+        log.sync_all().unwrap();
+
+        let res: Vec<(u64, Operation)> = MemtableLogReader::open(dir.path(), name, 1)
+            .unwrap()
+            .map(|kv| kv.map(|(seq, _, op)| (seq, op)).unwrap())
+            .collect();
+        assert_eq!(
+            res[1],
+            (
+                2,
+                Operation::DELETE_RANGE {
+                    start: Key::new("a"),
+                    end: Key::new("c")
+                }
+            )
+        );
+        assert_eq!(res[2], (3, Operation::DELETE { key: Key::new("b") }));
+        assert_eq!(
+            res[3],
+            (
+                4,
+                Operation::MERGE {
+                    key: Key::new("b"),
+                    value: Value::new("1")
+                }
+            )
+        );
+        assert_eq!(
+            res[4],
+            (
+                5,
+                Operation::PUT_WITH_EXPIRE {
+                    key: Key::new("c"),
+                    value: Value::new("c"),
+                    expire_time: 100
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_column_family_record() {
+        let dir = tempdir().unwrap();
+        let config = Config::new();
+        let name = &config.memtable_log_file_path;
+        let mut log = MemtableLog::create(dir.path(), 1, config.clone()).unwrap();
+        let ops = vec![
+            (
+                0,
+                Operation::PUT {
+                    key: Key::new("a"),
+                    value: Value::new("a"),
+                },
+            ),
+            (
+                2,
+                Operation::PUT {
+                    key: Key::new("a"),
+                    value: Value::new("b"),
+                },
+            ),
+            (2, Operation::DELETE { key: Key::new("b") }),
+            (
+                3,
+                Operation::MERGE {
+                    key: Key::new("c"),
+                    value: Value::new("1"),
+                },
+            ),
+            (
+                3,
+                Operation::PUT_WITH_EXPIRE {
+                    key: Key::new("d"),
+                    value: Value::new("d"),
+                    expire_time: 100,
+                },
+            ),
+        ];
+        for (seq, (column_family, op)) in ops.iter().enumerate() {
+            log.add_operation(*column_family, op, seq as u64 + 1)
+                .unwrap();
+        }
+        log.sync_all().unwrap();
+
+        let res: Vec<(u32, Operation)> = MemtableLogReader::open(dir.path(), name, 1)
+            .unwrap()
+            .map(|kv| {
+                kv.map(|(_, column_family, op)| (column_family, op))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(res, ops);
+    }
 }
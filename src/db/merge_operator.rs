@@ -0,0 +1,85 @@
+use std::fmt::Debug;
+
+use anyhow::{anyhow, Result};
+
+use crate::db::key::Key;
+use crate::db::value::Value;
+
+/// combine merge operands written by merge with value of key, registered in config
+/// operands are read lazily, they are combined when key is read or compacted
+/// eg. counter adds operands to existing value, list appends operands to it
+pub trait MergeOperator: Debug + Send + Sync {
+    // existing is none if key is not found or deleted, operands are ordered from old to new
+    fn full_merge(&self, key: &Key, existing: Option<&Value>, operands: &[Value]) -> Value;
+
+    // combine operands into one operand when existing value is unknown, used by compaction
+    // none if operands can't be combined, they are kept as they are
+    fn partial_merge(&self, key: &Key, operands: &[Value]) -> Option<Value> {
+        None
+    }
+}
+
+// combine operands ordered from new to old with existing value, operator is required if there are operands
+pub fn merge_value(
+    operator: Option<&dyn MergeOperator>,
+    key: &Key,
+    existing: Option<Value>,
+    mut operands: Vec<Value>,
+) -> Result<Option<Value>> {
+    if operands.is_empty() {
+        return Ok(existing);
+    }
+    let operator =
+        operator.ok_or_else(|| anyhow!("key {} has merge operands but no merge operator", key))?;
+    operands.reverse();
+    Ok(Some(operator.full_merge(key, existing.as_ref(), &operands)))
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::db::key::Key;
+    use crate::db::value::Value;
+
+    use super::{merge_value, MergeOperator};
+
+    // add operands to existing value as u64
+    #[derive(Debug)]
+    pub struct AddOperator;
+
+    impl MergeOperator for AddOperator {
+        fn full_merge(&self, key: &Key, existing: Option<&Value>, operands: &[Value]) -> Value {
+            let mut res = existing.map_or(0, Self::to_u64);
+            res += operands.iter().map(Self::to_u64).sum::<u64>();
+            Value::from_u64(res)
+        }
+
+        fn partial_merge(&self, key: &Key, operands: &[Value]) -> Option<Value> {
+            Some(Value::from_u64(operands.iter().map(Self::to_u64).sum()))
+        }
+    }
+
+    impl AddOperator {
+        fn to_u64(v: &Value) -> u64 {
+            std::str::from_utf8(v.data()).unwrap().parse().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_merge_value() {
+        let key = Key::new("a");
+        let operands = vec![Value::from_u64(3), Value::from_u64(2)];
+        let res = merge_value(
+            Some(&AddOperator),
+            &key,
+            Some(Value::from_u64(10)),
+            operands.clone(),
+        );
+        assert_eq!(res.unwrap(), Some(Value::from_u64(15)));
+        let res = merge_value(Some(&AddOperator), &key, None, operands.clone());
+        assert_eq!(res.unwrap(), Some(Value::from_u64(5)));
+        // no operand, existing value is returned as it is
+        let res = merge_value(None, &key, None, vec![]);
+        assert_eq!(res.unwrap(), None);
+        assert!(merge_value(None, &key, None, operands).is_err());
+    }
+}
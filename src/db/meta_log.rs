@@ -1,25 +1,44 @@
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::info;
 
+use super::log_record::{record_size, write_record, RecordLen, RecordReader};
+
+// file contains name of current meta log
+const CURRENT_FILE_NAME: &str = "CURRENT";
+const CURRENT_TMP_FILE_NAME: &str = "CURRENT.tmp";
+
+/// meta log file is {name}_{number}, first record is snapshot of version
+/// meta log is rolled to a new file with larger number when it is too large
+/// record format: [data len (u64),crc32 of len and data (u32),data]
 pub struct MetaLog {
     file: File,
+    home_path: PathBuf,
+    name: String,
+    number: u64,
+    size: u64,
 }
 
 pub struct MetaLogIter {
-    file: File,
-    remain_data_len: usize,
+    reader: RecordReader<File>,
 }
 
 impl MetaLogIter {
     pub fn new(file: File) -> Self {
         let size = file.metadata().unwrap().len();
         MetaLogIter {
-            file,
-            remain_data_len: size as usize,
+            reader: RecordReader::new(file, size, RecordLen::U64, "meta log"),
+        }
+    }
+
+    // meta log without number is written before checksum is added
+    fn without_checksum(file: File) -> Self {
+        let iter = Self::new(file);
+        MetaLogIter {
+            reader: iter.reader.without_checksum(),
         }
     }
 }
@@ -28,69 +47,225 @@ impl Iterator for MetaLogIter {
     type Item = Result<Vec<u8>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.remain_data_len == 0 {
-            return None;
-        }
-        let res = {
-            let len = self
-                .file
-                .read_u64::<LittleEndian>()
-                .expect("should has data");
-            let mut res = vec![0; len as usize];
-            self.file.read(&mut res).expect("should have data");
-            self.remain_data_len -= (len + 8) as usize;
-            Ok(res)
-        };
-        Some(res)
+        self.reader.next()
     }
 }
 
 impl MetaLog {
-    pub fn new(file: File) -> Self {
-        MetaLog { file }
+    // create meta log start with snapshots, switch CURRENT to it and delete old meta logs
+    // each column family has its own snapshot, all of them are written before CURRENT is switched
+    pub fn create(
+        home_path: &Path,
+        name: &str,
+        number: u64,
+        snapshots: &[Vec<u8>],
+    ) -> Result<Self> {
+        let file_name = Self::file_name(name, number);
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(home_path.join(&file_name))?;
+        let mut meta_log = MetaLog {
+            file,
+            home_path: PathBuf::from(home_path),
+            name: String::from(name),
+            number,
+            size: 0,
+        };
+        for snapshot in snapshots {
+            meta_log.add_data(snapshot)?;
+        }
+        Self::set_current(home_path, &file_name)?;
+        Self::delete_old_logs(home_path, name, &file_name)?;
+        info!("create meta log {}", file_name);
+        Ok(meta_log)
+    }
+
+    // replace current log with a new log start with snapshots
+    pub fn roll(&mut self, snapshots: &[Vec<u8>]) -> Result<()> {
+        *self = Self::create(&self.home_path, &self.name, self.number + 1, snapshots)?;
+        Ok(())
     }
 
     pub fn add_data(&mut self, data: &[u8]) -> Result<()> {
-        let len = data.len();
-        self.file.write_u64::<LittleEndian>(len as u64)?;
-        self.file.write(data)?;
+        let size = record_size(RecordLen::U64, data.len());
+        // write record in one call, so it is not split when process is killed
+        let mut record = Vec::with_capacity(size);
+        write_record(RecordLen::U64, data, &mut record)?;
+        self.file.write_all(&record)?;
         self.file.sync_all()?;
+        self.size += size as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // path of current meta log, log without snapshot (no CURRENT file) is named as name
+    pub fn current_path(home_path: &Path, name: &str) -> Result<Option<PathBuf>> {
+        let current_path = home_path.join(CURRENT_FILE_NAME);
+        if current_path.exists() {
+            let file_name = fs::read_to_string(current_path)?;
+            return Ok(Some(home_path.join(file_name.trim())));
+        }
+        let path = home_path.join(name);
+        if path.exists() {
+            return Ok(Some(path));
+        }
+        Ok(None)
+    }
+
+    // iter of current meta log
+    pub fn current_iter(home_path: &Path, name: &str) -> Result<Option<MetaLogIter>> {
+        let has_checksum = home_path.join(CURRENT_FILE_NAME).exists();
+        let path = match Self::current_path(home_path, name)? {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        let file = File::open(path)?;
+        if has_checksum {
+            Ok(Some(MetaLogIter::new(file)))
+        } else {
+            Ok(Some(MetaLogIter::without_checksum(file)))
+        }
+    }
+
+    // number of current meta log, 0 if there is no numbered meta log
+    pub fn current_number(home_path: &Path, name: &str) -> Result<u64> {
+        let current_path = home_path.join(CURRENT_FILE_NAME);
+        if !current_path.exists() {
+            return Ok(0);
+        }
+        let file_name = fs::read_to_string(current_path)?;
+        let number = file_name
+            .trim()
+            .strip_prefix(&format!("{}_", name))
+            .and_then(|n| n.parse::<u64>().ok())
+            .ok_or_else(|| anyhow::anyhow!("invalid current meta log name {}", file_name))?;
+        Ok(number)
+    }
+
+    fn file_name(name: &str, number: u64) -> String {
+        format!("{}_{}", name, number)
+    }
+
+    // write to tmp file then rename, so CURRENT is switched atomically
+    fn set_current(home_path: &Path, file_name: &str) -> Result<()> {
+        let tmp_path = home_path.join(CURRENT_TMP_FILE_NAME);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(file_name.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, home_path.join(CURRENT_FILE_NAME))?;
+        File::open(home_path)?.sync_all()?;
+        Ok(())
+    }
+
+    fn delete_old_logs(home_path: &Path, name: &str, current_file_name: &str) -> Result<()> {
+        let prefix = format!("{}_", name);
+        for entry in fs::read_dir(home_path)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            if file_name == current_file_name {
+                continue;
+            }
+            if file_name == name || file_name.starts_with(&prefix) {
+                fs::remove_file(entry.path())?;
+                info!("delete old meta log {}", file_name);
+            }
+        }
         Ok(())
     }
 
     // for db start
     pub fn to_iter(file: File) -> Result<MetaLogIter> {
-        let len = file.metadata()?.len();
-        Ok(MetaLogIter {
-            file,
-            remain_data_len: len as usize,
-        })
+        Ok(MetaLogIter::new(file))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::env::temp_dir;
+    use std::fs::{self, File};
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use tempfile::tempdir;
 
-    use crate::db::file_storage::FileStorageManager;
     use crate::db::meta_log::MetaLog;
 
     #[test]
     fn test_add_and_check_iter() {
-        let path = temp_dir();
-        let mut file_manager = FileStorageManager::new(&path);
-        let (file, id, _) = file_manager.new_file().unwrap();
-        let mut meta_log = MetaLog::new(file);
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let snapshot: Vec<u8> = vec![0];
+        let mut meta_log =
+            MetaLog::create(path, "meta", 1, std::slice::from_ref(&snapshot)).unwrap();
         let data_a: Vec<u8> = vec![1, 2, 4];
         let data_b: Vec<u8> = vec![2, 5, 2];
         meta_log.add_data(data_a.as_slice()).unwrap();
         meta_log.add_data(data_b.as_slice()).unwrap();
+        assert_eq!(meta_log.size(), 3 * 12 + 7);
 
-        let mut iter =
-            MetaLog::to_iter(FileStorageManager::open_file(&path, &id).unwrap()).unwrap();
+        let current = MetaLog::current_path(path, "meta").unwrap().unwrap();
+        let mut iter = MetaLog::to_iter(File::open(current).unwrap()).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), snapshot);
         let data = iter.next().unwrap().unwrap();
         assert_eq!(data, data_a);
         let data = iter.next().unwrap().unwrap();
         assert_eq!(data, data_b);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_roll() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        assert!(MetaLog::current_path(path, "meta").unwrap().is_none());
+        assert_eq!(MetaLog::current_number(path, "meta").unwrap(), 0);
+
+        // meta log without snapshot
+        fs::write(path.join("meta"), []).unwrap();
+        assert_eq!(
+            MetaLog::current_path(path, "meta").unwrap().unwrap(),
+            path.join("meta")
+        );
+
+        let mut meta_log = MetaLog::create(path, "meta", 1, &[vec![1]]).unwrap();
+        assert!(!path.join("meta").exists());
+        meta_log.add_data(&[2]).unwrap();
+        meta_log.roll(&[vec![3], vec![5]]).unwrap();
+        meta_log.add_data(&[4]).unwrap();
+
+        assert_eq!(MetaLog::current_number(path, "meta").unwrap(), 2);
+        let current = MetaLog::current_path(path, "meta").unwrap().unwrap();
+        assert_eq!(current, path.join("meta_2"));
+        assert!(!path.join("meta_1").exists());
+        let data: Vec<Vec<u8>> = MetaLog::to_iter(File::open(current).unwrap())
+            .unwrap()
+            .map(|d| d.unwrap())
+            .collect();
+        assert_eq!(data, vec![vec![3], vec![5], vec![4]]);
+    }
+
+    #[test]
+    fn test_read_log_without_checksum() {
+        // meta log without CURRENT file has no checksum in record header
+        let dir = tempdir().unwrap();
+        let mut data = Vec::new();
+        for record in [vec![1], vec![2, 3]] {
+            data.write_u64::<LittleEndian>(record.len() as u64).unwrap();
+            data.extend_from_slice(&record);
+        }
+        fs::write(dir.path().join("meta"), &data).unwrap();
+        let res: Vec<Vec<u8>> = MetaLog::current_iter(dir.path(), "meta")
+            .unwrap()
+            .unwrap()
+            .map(|d| d.unwrap())
+            .collect();
+        assert_eq!(res, vec![vec![1], vec![2, 3]]);
     }
 }
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+
+use crate::db::key::{Key, SeqNumber};
+
+/// keys in [start,end) written before seq are deleted, written by delete range
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeTombstone {
+    start: Key,
+    end: Key,
+    seq: SeqNumber,
+}
+
+/// range tombstones of memtable or sstable, ordered by start key
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeTombstones {
+    tombstones: Vec<RangeTombstone>,
+}
+
+impl RangeTombstone {
+    pub fn new(start: Key, end: Key, seq: SeqNumber) -> Self {
+        RangeTombstone { start, end, seq }
+    }
+    pub fn start(&self) -> &Key {
+        &self.start
+    }
+    pub fn end(&self) -> &Key {
+        &self.end
+    }
+    pub fn seq(&self) -> SeqNumber {
+        self.seq
+    }
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.start.data() <= key && key < self.end.data()
+    }
+}
+
+impl RangeTombstones {
+    pub fn new() -> Self {
+        RangeTombstones {
+            tombstones: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, tombstone: RangeTombstone) {
+        let position = self
+            .tombstones
+            .partition_point(|t| t.start <= tombstone.start);
+        self.tombstones.insert(position, tombstone);
+    }
+
+    pub fn extend(&mut self, other: &RangeTombstones) {
+        self.tombstones.extend(other.tombstones.iter().cloned());
+        self.tombstones.sort_by(|a, b| a.start.cmp(&b.start));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tombstones.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tombstones.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RangeTombstone> {
+        self.tombstones.iter()
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&RangeTombstone) -> bool) {
+        self.tombstones.retain(f)
+    }
+
+    // tombstones which contain key, of all sequence numbers
+    pub fn covering<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a RangeTombstone> {
+        let end = self.tombstones.partition_point(|t| t.start.data() <= key);
+        self.tombstones[..end]
+            .iter()
+            .filter(move |t| t.contains(key))
+    }
+
+    // sequence number of newest tombstone which contains key and is not newer than seq
+    pub fn max_covering_seq(&self, key: &[u8], seq: SeqNumber) -> Option<SeqNumber> {
+        self.covering(key)
+            .map(|t| t.seq)
+            .filter(|s| *s <= seq)
+            .max()
+    }
+
+    // true if version of key with entry_seq is deleted for reader with seq
+    pub fn is_deleted(&self, key: &[u8], entry_seq: SeqNumber, seq: SeqNumber) -> bool {
+        self.max_covering_seq(key, seq)
+            .is_some_and(|s| s > entry_seq)
+    }
+
+    // remove and return tombstones which start key is less than key
+    pub fn split_before(&mut self, key: &[u8]) -> RangeTombstones {
+        let position = self.tombstones.partition_point(|t| t.start.data() < key);
+        RangeTombstones {
+            tombstones: self.tombstones.drain(..position).collect(),
+        }
+    }
+
+    // remove and return tombstones which start key is less than key, tombstone which ends after key
+    // is cut at key, its part in [key,end) is kept
+    pub fn split_at(&mut self, key: &[u8]) -> RangeTombstones {
+        let mut before = self.split_before(key);
+        for t in before.tombstones.iter_mut() {
+            if t.end.data() > key {
+                let end = Key::from(key);
+                self.add(RangeTombstone::new(end.clone(), t.end.clone(), t.seq));
+                t.end = end;
+            }
+        }
+        before
+    }
+
+    pub fn first_start(&self) -> Option<&Key> {
+        self.tombstones.first().map(|t| &t.start)
+    }
+
+    pub fn max_end(&self) -> Option<&Key> {
+        self.tombstones.iter().map(|t| &t.end).max()
+    }
+
+    // bytes of keys
+    pub fn size(&self) -> usize {
+        self.tombstones
+            .iter()
+            .map(|t| t.start.len() + t.end.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::key::{Key, MAX_SEQUENCE};
+
+    use super::{RangeTombstone, RangeTombstones};
+
+    fn tombstone(start: &str, end: &str, seq: u64) -> RangeTombstone {
+        RangeTombstone::new(Key::new(start), Key::new(end), seq)
+    }
+
+    #[test]
+    fn test_covering_seq() {
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add(tombstone("d", "f", 5));
+        tombstones.add(tombstone("a", "e", 3));
+        tombstones.add(tombstone("b", "c", 8));
+        assert_eq!(tombstones.first_start(), Some(&Key::new("a")));
+        assert_eq!(tombstones.max_end(), Some(&Key::new("f")));
+
+        assert_eq!(tombstones.max_covering_seq(b"a", MAX_SEQUENCE), Some(3));
+        assert_eq!(tombstones.max_covering_seq(b"b", MAX_SEQUENCE), Some(8));
+        assert_eq!(tombstones.max_covering_seq(b"b", 7), Some(3));
+        assert_eq!(tombstones.max_covering_seq(b"b", 2), None);
+        // end is excluded
+        assert_eq!(tombstones.max_covering_seq(b"c", MAX_SEQUENCE), Some(3));
+        assert_eq!(tombstones.max_covering_seq(b"e", MAX_SEQUENCE), Some(5));
+        assert_eq!(tombstones.max_covering_seq(b"f", MAX_SEQUENCE), None);
+
+        assert!(tombstones.is_deleted(b"d", 4, MAX_SEQUENCE));
+        assert!(!tombstones.is_deleted(b"d", 6, MAX_SEQUENCE));
+        assert!(!tombstones.is_deleted(b"d", 4, 4));
+
+        let before = tombstones.split_before(b"b");
+        assert_eq!(before.len(), 1);
+        assert_eq!(tombstones.first_start(), Some(&Key::new("b")));
+
+        // tombstones across key are cut at key
+        let before = tombstones.split_at(b"e");
+        assert_eq!(
+            before.iter().cloned().collect::<Vec<_>>(),
+            vec![tombstone("b", "c", 8), tombstone("d", "e", 5)]
+        );
+        assert_eq!(
+            tombstones.iter().cloned().collect::<Vec<_>>(),
+            vec![tombstone("e", "f", 5)]
+        );
+    }
+}
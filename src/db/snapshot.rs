@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::db::config::ReadOptions;
+use crate::db::db_iter::DBIter;
+use crate::db::key::{Key, SeqNumber};
+use crate::db::value::Value;
+
+use super::{get_with_sequence, scan_with_sequence, ThreadSafeData};
+
+/// read only view of db at the time it is created, writes after it are invisible
+/// versions it reads are kept by compaction until it is dropped, drop it before db is closed
+pub struct Snapshot {
+    data: ThreadSafeData,
+    seq: SeqNumber,
+    snapshot_list: Arc<SnapshotList>,
+}
+
+/// sequence numbers of live snapshots, compaction keeps versions they read
+pub struct SnapshotList {
+    // writes not greater than it are in memtable and visible to new snapshot
+    last_sequence: AtomicU64,
+    // sequence number -> number of live snapshots with it
+    snapshots: Mutex<BTreeMap<SeqNumber, usize>>,
+}
+
+impl Snapshot {
+    pub(super) fn new(data: ThreadSafeData, snapshot_list: Arc<SnapshotList>) -> Self {
+        let seq = snapshot_list.acquire();
+        Snapshot {
+            data,
+            seq,
+            snapshot_list,
+        }
+    }
+
+    pub fn sequence(&self) -> SeqNumber {
+        self.seq
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<Value>> {
+        self.get_with_options(key, &ReadOptions::new())
+    }
+
+    pub fn get_with_options(&self, key: &Key, options: &ReadOptions) -> Result<Option<Value>> {
+        get_with_sequence(&self.data, key, self.seq, options)
+    }
+
+    pub fn iter(&self) -> Result<DBIter> {
+        scan_with_sequence(
+            &self.data,
+            &Key::new(""),
+            None,
+            self.seq,
+            &ReadOptions::new(),
+        )
+    }
+
+    // iter kvs which key is in [start_key,end_key) in key order
+    pub fn scan(&self, start_key: &Key, end_key: &Key) -> Result<DBIter> {
+        self.scan_with_options(start_key, end_key, &ReadOptions::new())
+    }
+
+    pub fn scan_with_options(
+        &self,
+        start_key: &Key,
+        end_key: &Key,
+        options: &ReadOptions,
+    ) -> Result<DBIter> {
+        scan_with_sequence(&self.data, start_key, Some(end_key), self.seq, options)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.snapshot_list.release(self.seq);
+    }
+}
+
+impl SnapshotList {
+    pub fn new(last_sequence: SeqNumber) -> Self {
+        SnapshotList {
+            last_sequence: AtomicU64::new(last_sequence),
+            snapshots: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn last_sequence(&self) -> SeqNumber {
+        self.last_sequence.load(Ordering::SeqCst)
+    }
+
+    // called by write routine after writes are inserted to memtable
+    pub fn set_last_sequence(&self, seq: SeqNumber) {
+        self.last_sequence.store(seq, Ordering::SeqCst);
+    }
+
+    // sequence numbers of live snapshots in ascending order
+    pub fn sequences(&self) -> Vec<SeqNumber> {
+        self.snapshots.lock().unwrap().keys().copied().collect()
+    }
+
+    // sequence number is read with lock held, so compaction started after it can see the snapshot
+    fn acquire(&self) -> SeqNumber {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let seq = self.last_sequence();
+        *snapshots.entry(seq).or_insert(0) += 1;
+        seq
+    }
+
+    fn release(&self, seq: SeqNumber) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let count = snapshots.get_mut(&seq).expect("snapshot must be acquired");
+        *count -= 1;
+        if *count == 0 {
+            snapshots.remove(&seq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SnapshotList;
+
+    #[test]
+    fn test_snapshot_list() {
+        let list = SnapshotList::new(3);
+        assert_eq!(list.acquire(), 3);
+        assert_eq!(list.acquire(), 3);
+        list.set_last_sequence(7);
+        assert_eq!(list.acquire(), 7);
+        assert_eq!(list.sequences(), vec![3, 7]);
+        list.release(3);
+        assert_eq!(list.sequences(), vec![3, 7]);
+        list.release(3);
+        list.release(7);
+        assert!(list.sequences().is_empty());
+        assert_eq!(list.last_sequence(), 7);
+    }
+}
@@ -1,81 +1,197 @@
+use std::borrow::Borrow;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::SeekFrom::Start;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::sync::Arc;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use log::info;
-use metrics::Gauge;
+use lru::LruCache;
+use metrics::{increment_counter, Gauge};
 use serde::{Deserialize, Serialize};
 
+use crate::db::blob;
+use crate::db::blob::BlobPointer;
 use crate::db::common::{KVIterItem, ValueSliceTag};
-use crate::db::file_storage::FileStorageManager;
-use crate::db::key::{Key, KeySlice, KEY_SIZE_LIMIT};
+use crate::db::config::Config;
+use crate::db::db_metrics::{
+    BLOCK_CACHE_HIT_COUNT, BLOCK_CACHE_MISS_COUNT, BLOOM_FILTER_SKIP_COUNT,
+};
+use crate::db::error::CorruptionError;
+use crate::db::file_storage::{FileId, FileStorageManager};
+use crate::db::key::{Key, KeySlice, SeqNumber, KEY_SIZE_LIMIT};
 use crate::db::level::SStableFileMeta;
+use crate::db::range_tombstone::RangeTombstones;
 use crate::db::sstable::block::{Block, BlockBuilder, BlockIter, BlockMeta, BLOCK_SIZE};
-use crate::db::value::Value;
+use crate::db::sstable::bloom_filter::BloomFilter;
+use crate::db::value::{now_millis, Value, ValueSlice};
 
 use super::common::ValueWithTag;
 use super::db_metrics::TimeRecorder;
 
 mod block;
+mod bloom_filter;
+mod snappy;
 
-const BLOCK_POOL_MEMORY_SIZE: usize = 2 * KEY_SIZE_LIMIT + BLOCK_SIZE;
+// block header, checksum and sequence number of last entry are also read into pool
+const BLOCK_POOL_MEMORY_SIZE: usize = 2 * KEY_SIZE_LIMIT + BLOCK_SIZE + 16;
+// last bytes of sstable which has format version
+const SSTABLE_MAGIC_NUMBER: u64 = 0x6c73_6d2d_7373_7462;
+const SSTABLE_FORMAT_VERSION: u32 = 1;
+
+// key is (file id,block offset)
+pub type BlockCache = LruCache<(FileId, u64), Arc<Block>>;
+// none if block cache is disabled
+pub type ThreadSafeBlockCache = Option<Arc<Mutex<BlockCache>>>;
 
 /// format https://github.com/google/leveldb/blob/main/doc/table_format.md
-/// block 1
+/// block 1 (compressed by config.compression, see BlockBuilder)
 /// block 2
 ///  ...
 /// block n
+/// range tombstones in msgpack (absent if sstable has none, sstable of old version has none)
 /// block meta
+/// bloom filter (empty if bloom_filter_bits_per_key is 0)
+/// footer:
+/// bloom filter size (u64)
 /// block meta number (u64)
 /// block meta offset (u64)
-
+/// format version (u32)
+/// magic number (u64)
+/// sstable of old version has no bloom filter, its footer is only block meta number and block meta offset
 // immutable, own by level
 pub struct SSTable {
     sstable_metas: Arc<SStableBlockMeta>,
     file: RefCell<File>,
+    block_cache: Option<SSTableBlockCache>,
+    // dir of blob files, value in blob file can't be read by get if it is none
+    home_path: Option<PathBuf>,
+    // blob files referenced by sstable, only known when sstable is built by from_iter
+    blob_file_ids: Vec<FileId>,
+    // bytes of records pointed by sstable in each blob file of blob_file_ids
+    blob_live_bytes: Vec<u64>,
+    // earliest expire time of values, 0 if no value expires, only known when sstable is built by from_iter
+    expire_time: u64,
+}
+
+struct SSTableBlockCache {
+    file_id: FileId,
+    cache: Arc<Mutex<BlockCache>>,
+    // put block read from file to cache if true
+    fill_cache: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SStableBlockMeta {
     block_metas: Vec<BlockMeta>,
+    bloom_filter: Option<BloomFilter>,
+    // sstable may have tombstones but no kv
+    range_tombstones: RangeTombstones,
 }
 
-pub struct SStableIter<'a> {
-    block_iter: BlockIter,
-    sstable: &'a SSTable,
+// sstable can be borrowed (&SSTable) or owned (SSTable) by iter
+// kvs deleted by range tombstones of sstable are returned, see range_tombstones
+pub struct SStableIter<S: Borrow<SSTable>> {
+    // none if sstable has no kv
+    block_iter: Option<BlockIter>,
+    sstable: S,
     next_block_number: usize,
+    // iter stops when fail to read block
+    error: Option<anyhow::Error>,
 }
 
-impl<'a> SStableIter<'a> {
-    pub fn new(sstable: &'a SSTable) -> Result<Self> {
-        assert!(sstable.sstable_metas.block_metas.len() > 0);
-        let block = sstable.read_block(0)?;
-        let block_iter = block.into_iter();
+impl<S: Borrow<SSTable>> SStableIter<S> {
+    pub fn new(sstable: S) -> Result<Self> {
+        if sstable.borrow().sstable_metas.block_metas.is_empty() {
+            return Ok(SStableIter {
+                block_iter: None,
+                sstable,
+                next_block_number: 0,
+                error: None,
+            });
+        }
+        let block = sstable.borrow().read_block(0)?;
+        let block_iter = BlockIter::new(block);
         Ok(SStableIter {
-            block_iter,
+            block_iter: Some(block_iter),
             sstable,
             next_block_number: 1,
+            error: None,
+        })
+    }
+
+    // iter start from the first kv which key is greater or equal to key
+    pub fn seek(sstable: S, key: &Key) -> Result<Self> {
+        let block_metas = &sstable.borrow().sstable_metas.block_metas;
+        if block_metas.is_empty() {
+            return Self::new(sstable);
+        }
+        let block_position = block_metas.partition_point(|meta| meta.last_key().lt(key));
+        if block_position == block_metas.len() {
+            // all keys are less than key, return an exhausted iter
+            let block = sstable.borrow().read_block(block_position - 1)?;
+            let mut block_iter = BlockIter::new(block);
+            block_iter.seek(key)?;
+            return Ok(SStableIter {
+                block_iter: Some(block_iter),
+                sstable,
+                next_block_number: block_position,
+                error: None,
+            });
+        }
+        let block = sstable.borrow().read_block(block_position)?;
+        let mut block_iter = BlockIter::new(block);
+        block_iter.seek(key)?;
+        Ok(SStableIter {
+            block_iter: Some(block_iter),
+            sstable,
+            next_block_number: block_position + 1,
+            error: None,
         })
     }
+
+    // tombstones which delete kvs of this and older sstables
+    pub fn range_tombstones(&self) -> &RangeTombstones {
+        self.sstable.borrow().range_tombstones()
+    }
+
+    // iter stops when fail to read block, return the error
+    pub fn finish(self) -> Result<()> {
+        match self.error {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
 }
 
-impl<'a> Iterator for SStableIter<'a> {
+impl<S: Borrow<SSTable>> Iterator for SStableIter<S> {
     type Item = KVIterItem;
     fn next(&mut self) -> Option<Self::Item> {
-        let mut res = self.block_iter.next();
-        if let None = res {
-            if self.next_block_number == self.sstable.sstable_metas.block_metas.len() {
+        let block_iter = self.block_iter.as_mut()?;
+        let mut res = block_iter.next();
+        if res.is_none() {
+            let sstable = self.sstable.borrow();
+            if self.next_block_number == sstable.sstable_metas.block_metas.len() {
                 return None;
             }
-            let block = self.sstable.read_block(self.next_block_number).unwrap();
+            let block = match sstable.read_block(self.next_block_number) {
+                Ok(block) => block,
+                Err(err) => {
+                    self.error = Some(err);
+                    self.block_iter = None;
+                    return None;
+                }
+            };
             self.next_block_number += 1;
-            self.block_iter = block.into_iter();
-            res = self.block_iter.next();
+            let block_iter = self.block_iter.insert(BlockIter::new(block));
+            res = block_iter.next();
             assert!(res.is_some());
         }
         res
@@ -84,33 +200,129 @@ impl<'a> Iterator for SStableIter<'a> {
 
 impl SSTable {
     pub const SSTABLE_SIZE_LIMIT: usize = 1024 * 1024 * 2;
-    pub fn get_meta_from_file(file: &mut File) -> Result<SStableBlockMeta> {
+    // (bloom filter size,block meta number,block meta offset)
+    fn read_footer(file: &mut File) -> Result<(u64, u64, u64)> {
+        let file_size = file.metadata()?.len();
+        if file_size < 16 {
+            return Err(CorruptionError::new(format!(
+                "sstable size {} is too small for footer",
+                file_size
+            ))
+            .into());
+        }
         file.seek(SeekFrom::End(-8))?;
-        let meta_offset = file.read_u64::<LittleEndian>()?;
-        file.seek(SeekFrom::End(-16))?;
+        if file.read_u64::<LittleEndian>()? != SSTABLE_MAGIC_NUMBER {
+            // old version
+            file.seek(SeekFrom::End(-16))?;
+            let meta_number = file.read_u64::<LittleEndian>()?;
+            let meta_offset = file.read_u64::<LittleEndian>()?;
+            return Ok((0, meta_number, meta_offset));
+        }
+        file.seek(SeekFrom::End(-12))?;
+        let version = file.read_u32::<LittleEndian>()?;
+        if version != SSTABLE_FORMAT_VERSION {
+            return Err(anyhow!(
+                "sstable format version {} is not supported, expect {}",
+                version,
+                SSTABLE_FORMAT_VERSION
+            ));
+        }
+        file.seek(SeekFrom::End(-36))?;
+        let bloom_filter_size = file.read_u64::<LittleEndian>()?;
         let meta_number = file.read_u64::<LittleEndian>()?;
+        let meta_offset = file.read_u64::<LittleEndian>()?;
+        Ok((bloom_filter_size, meta_number, meta_offset))
+    }
+    pub fn get_meta_from_file(file: &mut File) -> Result<SStableBlockMeta> {
+        let (bloom_filter_size, meta_number, meta_offset) = Self::read_footer(file)?;
         file.seek(SeekFrom::Start(meta_offset))?;
         let mut metas = Vec::new();
         for _ in 0..meta_number {
             let meta = BlockMeta::read_from_binary(file)?;
             metas.push(meta);
         }
-        Ok(SStableBlockMeta { block_metas: metas })
+        // bloom filter is right after block metas
+        let bloom_filter = if bloom_filter_size > 0 {
+            let mut data = vec![0; bloom_filter_size as usize];
+            file.read_exact(&mut data)?;
+            Some(BloomFilter::from(data))
+        } else {
+            None
+        };
+        // range tombstones are between last block and block metas
+        let blocks_end = metas
+            .last()
+            .map_or(0, |m: &BlockMeta| m.block_offset() + m.size() as u64);
+        let range_tombstones = if meta_offset > blocks_end {
+            file.seek(SeekFrom::Start(blocks_end))?;
+            let mut data = vec![0; (meta_offset - blocks_end) as usize];
+            file.read_exact(&mut data)?;
+            rmp_serde::from_slice(&data)?
+        } else {
+            RangeTombstones::new()
+        };
+        Ok(SStableBlockMeta {
+            block_metas: metas,
+            bloom_filter,
+            range_tombstones,
+        })
     }
     pub fn from_file(mut file: File) -> Result<Self> {
         let sstable_metas = SSTable::get_meta_from_file(&mut file)?;
         Ok(SSTable {
             sstable_metas: Arc::new(sstable_metas),
             file: RefCell::new(file),
+            block_cache: None,
+            home_path: None,
+            blob_file_ids: vec![],
+            blob_live_bytes: vec![],
+            expire_time: 0,
         })
     }
     pub fn from(sstable_metas: Arc<SStableBlockMeta>, file: File) -> Result<Self> {
         Ok(SSTable {
             sstable_metas,
             file: RefCell::new(file),
+            block_cache: None,
+            home_path: None,
+            blob_file_ids: vec![],
+            blob_live_bytes: vec![],
+            expire_time: 0,
+        })
+    }
+    // read block from block cache first, read blob value from file in home_path
+    pub fn from_with_block_cache(
+        sstable_metas: Arc<SStableBlockMeta>,
+        file: File,
+        file_id: FileId,
+        home_path: &Path,
+        block_cache: ThreadSafeBlockCache,
+        fill_cache: bool,
+    ) -> Result<Self> {
+        Ok(SSTable {
+            sstable_metas,
+            file: RefCell::new(file),
+            block_cache: block_cache.map(|cache| SSTableBlockCache {
+                file_id,
+                cache,
+                fill_cache,
+            }),
+            home_path: Some(home_path.to_path_buf()),
+            blob_file_ids: vec![],
+            blob_live_bytes: vec![],
+            expire_time: 0,
         })
     }
 
+    // capacity 0 disables block cache
+    pub fn new_block_cache(capacity: usize) -> ThreadSafeBlockCache {
+        NonZeroUsize::new(capacity).map(|capacity| Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+
+    pub fn file_size(&self) -> Result<u64> {
+        Ok(self.file.borrow().metadata()?.len())
+    }
+
     pub fn block_metadata(&self) -> Arc<SStableBlockMeta> {
         self.sstable_metas.clone()
     }
@@ -121,17 +333,103 @@ impl SSTable {
     pub fn last_key(&self) -> &Key {
         self.sstable_metas.block_metas.last().unwrap().last_key()
     }
-    pub fn get(&self, key: &Key) -> Result<Option<ValueWithTag>> {
-        if self.last_key().lt(key) {
-            return Ok(None);
-        }
-        let block_position = self
+    pub fn range_tombstones(&self) -> &RangeTombstones {
+        &self.sstable_metas.range_tombstones
+    }
+    // newest version of key which sequence number is not greater than seq, merge operands newer than it
+    // are pushed to operands from new to old, None if there are only operands or nothing
+    // expired value is same as deleted
+    pub fn get(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        operands: &mut Vec<Value>,
+    ) -> Result<Option<ValueWithTag>> {
+        let tombstone_seq = self
             .sstable_metas
-            .block_metas
-            .partition_point(|meta| meta.last_key().lt(key));
-        let block = self.read_block(block_position)?;
-        let block_meta = &self.sstable_metas.block_metas[block_position];
-        block.find(key, block_meta.entry_size())
+            .range_tombstones
+            .max_covering_seq(key.data(), seq);
+        let now = now_millis();
+        let mut res = None;
+        self.for_each_version(key, seq, |entry_seq, value| {
+            if tombstone_seq.is_some_and(|t| t > entry_seq) {
+                res = Some(None);
+                return Ok(false);
+            }
+            match value {
+                Some(v) if v.is_merge_operand() => {
+                    operands.push(self.read_value(&v)?);
+                    Ok(true)
+                }
+                Some(v) if !v.is_expired(now) => {
+                    res = Some(Some(self.read_value(&v)?));
+                    Ok(false)
+                }
+                // expired value is same as deleted
+                _ => {
+                    res = Some(None);
+                    Ok(false)
+                }
+            }
+        })?;
+        // versions in older sstables are older than tombstone
+        if res.is_none() && tombstone_seq.is_some() {
+            res = Some(None);
+        }
+        Ok(res)
+    }
+
+    // call f with versions of key from new to old until it returns false, range tombstones are not applied
+    fn for_each_version(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        mut f: impl FnMut(SeqNumber, ValueSliceTag) -> Result<bool>,
+    ) -> Result<()> {
+        let block_metas = &self.sstable_metas.block_metas;
+        if block_metas.is_empty() || self.last_key().lt(key) {
+            return Ok(());
+        }
+        if !self.sstable_metas.may_contain(key) {
+            increment_counter!(BLOOM_FILTER_SKIP_COUNT);
+            return Ok(());
+        }
+        let mut block_position = block_metas.partition_point(|meta| meta.last_key().lt(key));
+        while block_position < block_metas.len() {
+            let block = self.read_block(block_position)?;
+            let block_meta = &block_metas[block_position];
+            if !block.for_each_version(key, seq, block_meta.entry_size(), &mut f)? {
+                break;
+            }
+            // older versions of key may be in next block
+            if !block_meta.last_key().eq(key) {
+                break;
+            }
+            block_position += 1;
+        }
+        Ok(())
+    }
+
+    fn read_value(&self, value: &ValueSlice) -> Result<Value> {
+        match &self.home_path {
+            Some(home_path) => blob::read_value(home_path, value),
+            None if !value.is_blob_pointer() => Ok(Value::from_u8(unsafe { value.data() })),
+            None => Err(anyhow!(
+                "can't read blob value of sstable without home path"
+            )),
+        }
+    }
+
+    pub fn blob_file_ids(&self) -> &[FileId] {
+        &self.blob_file_ids
+    }
+
+    pub fn blob_live_bytes(&self) -> &[u64] {
+        &self.blob_live_bytes
+    }
+
+    pub fn expire_time(&self) -> u64 {
+        self.expire_time
     }
 
     pub fn entry_number(&self) -> usize {
@@ -142,110 +440,210 @@ impl SSTable {
         res
     }
 
-    fn read_block(&self, block_position: usize) -> Result<Block> {
+    fn read_block(&self, block_position: usize) -> Result<Arc<Block>> {
+        let block_cache = match &self.block_cache {
+            None => return Ok(Arc::new(self.read_block_from_file(block_position)?)),
+            Some(c) => c,
+        };
+        let block_meta = &self.sstable_metas.block_metas[block_position];
+        let cache_key = (block_cache.file_id, block_meta.block_offset());
+        if let Some(block) = block_cache.cache.lock().unwrap().get(&cache_key) {
+            increment_counter!(BLOCK_CACHE_HIT_COUNT);
+            return Ok(block.clone());
+        }
+        increment_counter!(BLOCK_CACHE_MISS_COUNT);
+        let block = Arc::new(self.read_block_from_file(block_position)?);
+        if block_cache.fill_cache {
+            block_cache
+                .cache
+                .lock()
+                .unwrap()
+                .put(cache_key, block.clone());
+        }
+        Ok(block)
+    }
+
+    fn read_block_from_file(&self, block_position: usize) -> Result<Block> {
         let block_meta = &self.sstable_metas.block_metas[block_position];
         let mut read_ref = self.file.borrow_mut();
         read_ref.seek(Start(block_meta.block_offset()))?;
         let data_size = block_meta.size();
         assert!(data_size < BLOCK_POOL_MEMORY_SIZE);
         let mut data = [0; BLOCK_POOL_MEMORY_SIZE];
-        read_ref.read_exact(&mut data[..data_size])?;
-        let block = Block::new(data, data_size);
+        if let Err(err) = read_ref.read_exact(&mut data[..data_size]) {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                return Err(CorruptionError::new(format!(
+                    "block at {} is truncated",
+                    block_meta.block_offset()
+                ))
+                .into());
+            }
+            return Err(err.into());
+        }
+        let block = Block::new(data, data_size)?;
         Ok(block)
     }
-    /// build new sstable, may not use out iterator if sstable size reach limit
-    /// use BufWriter if possible
+    /// build new sstable from all kvs of iter
     /// return bool: if is finished
     pub fn from_iter(
         kv_iters: &mut dyn Iterator<Item = KVIterItem>,
         file: File,
+        config: &Config,
     ) -> Result<(Option<SSTable>, bool)> {
-        Self::from_iter_with_file_limit(kv_iters, file, Self::SSTABLE_SIZE_LIMIT)
+        Self::from_iter_with_file_limit(
+            &mut kv_iters.peekable(),
+            &mut RangeTombstones::new(),
+            file,
+            0,
+            config,
+        )
     }
-    pub fn from_iter_with_file_limit(
-        kv_iters: &mut dyn Iterator<Item = KVIterItem>,
+    /// build new sstable, stop when sstable size reach limit, no limit if it is 0
+    /// range tombstones written to sstable are removed from range_tombstones,
+    /// sstable has no kv if iter is empty but there are tombstones
+    /// return bool: true if iter has kvs not written
+    pub fn from_iter_with_file_limit<I: Iterator<Item = KVIterItem>>(
+        kv_iters: &mut Peekable<I>,
+        range_tombstones: &mut RangeTombstones,
         mut file: File,
         limit_file_size: usize,
+        config: &Config,
     ) -> Result<(Option<SSTable>, bool)> {
         let r = TimeRecorder::new("build_sstable_from_iter");
-        let mut block_builder = BlockBuilder::new();
+        let mut block_builder = BlockBuilder::new(config.compression);
         let mut entry_count = 0;
         let mut block_metas = Vec::new();
         let mut last_block_position = 0;
         let mut start_key = None;
-        let mut current_key;
+        let mut key_hashes = Vec::new();
+        let mut blob_files = BTreeMap::new();
+        let mut expire_time = 0;
+        let mut sstable_tombstones = RangeTombstones::new();
         let sstable_writer = &mut file;
-        let iter_has_next;
+        let mut iter_has_next = false;
 
-        let mut next_entry = kv_iters.next();
-        if next_entry.is_none() {
+        if kv_iters.peek().is_none() && range_tombstones.is_empty() {
             return Ok((None, false));
         }
-        loop {
-            match next_entry {
-                Some((key_slice, value)) => {
-                    //     write to block_build
-                    block_builder.append(key_slice, value)?;
-                    entry_count += 1;
-
-                    // save current key, because call next() will make current key invalide
-                    unsafe {
-                        current_key = Some(Key::from(key_slice.data()));
-                    }
-
-                    if start_key == None {
-                        unsafe {
-                            start_key = Some(Key::from(key_slice.data()));
-                        }
-                    }
-
-                    next_entry = kv_iters.next();
-                    //     check block_builder size, if is more than 4k flush it
-                    if block_builder.len() > BLOCK_SIZE || next_entry.is_none() {
-                        assert!(start_key.is_some());
-                        block_metas.push(BlockMeta::new(
-                            start_key.unwrap(),
-                            current_key.unwrap(),
-                            entry_count,
-                            block_builder.len(),
-                            last_block_position,
-                        ));
-                        start_key = None;
-                        last_block_position += block_builder.len() as u64;
-                        block_builder.flush(sstable_writer)?;
-                        entry_count = 0;
-                    }
+        while let Some((key_slice, value)) = kv_iters.next() {
+            //     write to block_build
+            block_builder.append(key_slice, value)?;
+            entry_count += 1;
+            if let Some(v) = value.filter(|v| v.is_blob_pointer()) {
+                let pointer = BlobPointer::decode(unsafe { v.data() })?;
+                *blob_files.entry(pointer.file_id()).or_insert(0) += pointer.record_size();
+            }
+            if let Some(v) = value.filter(|v| v.expire_time() != 0) {
+                if expire_time == 0 || v.expire_time() < expire_time {
+                    expire_time = v.expire_time();
                 }
-                None => {
-                    iter_has_next = false;
-                    break;
+            }
+            unsafe {
+                key_hashes.push(bloom_filter::hash(key_slice.data()));
+            }
+
+            // save current key, because call next() will make current key invalide
+            let current_key = unsafe { Key::from(key_slice.data()) };
+
+            if start_key.is_none() {
+                unsafe {
+                    start_key = Some(Key::from(key_slice.data()));
                 }
             }
-            if limit_file_size > 0 && last_block_position >= limit_file_size as u64 {
+
+            // peeked kv is valid until next() is called
+            let next_entry = kv_iters.peek().copied();
+            //     check block_builder size, if is more than 4k flush it
+            if block_builder.len() > BLOCK_SIZE || next_entry.is_none() {
+                assert!(start_key.is_some());
+                // block size in file is known after compression
+                let block_size = block_builder.flush(sstable_writer)?;
+                block_metas.push(BlockMeta::new(
+                    start_key.unwrap(),
+                    current_key.clone(),
+                    entry_count,
+                    block_size,
+                    last_block_position,
+                ));
+                start_key = None;
+                last_block_position += block_size as u64;
+                entry_count = 0;
+            }
+            // stop after block is flushed, versions of same key are kept in one sstable,
+            // so sstables in level don't overlap
+            let next_key = match &next_entry {
+                Some((k, _)) => unsafe { k.data() },
+                None => break,
+            };
+            if limit_file_size > 0
+                && block_builder.len() == 0
+                && last_block_position >= limit_file_size as u64
+                && !current_key.equal_u8(next_key)
+            {
+                // tombstones are cut at next key, part before it is in this sstable and the rest is in next one,
+                // so end of this sstable may equal to start of next one, the key itself is only in next one
+                sstable_tombstones.extend(&range_tombstones.split_at(next_key));
                 iter_has_next = true;
                 info!("sstable size is {:}, reach file limit", last_block_position);
                 break;
             }
         }
+        if !iter_has_next {
+            sstable_tombstones.extend(range_tombstones);
+            *range_tombstones = RangeTombstones::new();
+        }
 
+        // write range tombstones
+        let mut block_meta_offset = last_block_position;
+        if !sstable_tombstones.is_empty() {
+            let data = rmp_serde::to_vec(&sstable_tombstones)?;
+            sstable_writer.write_all(&data)?;
+            block_meta_offset += data.len() as u64;
+        }
         // write block meta
         for block_meta in &block_metas {
             block_meta.write_to_binary(sstable_writer)?;
         }
+        // write bloom filter
+        let bloom_filter = if config.bloom_filter_bits_per_key > 0 {
+            let filter = BloomFilter::build(&key_hashes, config.bloom_filter_bits_per_key);
+            sstable_writer.write_all(filter.data())?;
+            sstable_writer.write_u64::<LittleEndian>(filter.data().len() as u64)?;
+            Some(filter)
+        } else {
+            sstable_writer.write_u64::<LittleEndian>(0)?;
+            None
+        };
         // write block meta number
         sstable_writer.write_u64::<LittleEndian>(block_metas.len() as u64)?;
-        sstable_writer.write_u64::<LittleEndian>(last_block_position)?;
+        sstable_writer.write_u64::<LittleEndian>(block_meta_offset)?;
+        sstable_writer.write_u32::<LittleEndian>(SSTABLE_FORMAT_VERSION)?;
+        sstable_writer.write_u64::<LittleEndian>(SSTABLE_MAGIC_NUMBER)?;
 
         Ok((
             Some(SSTable {
-                sstable_metas: Arc::new(SStableBlockMeta { block_metas }),
+                sstable_metas: Arc::new(SStableBlockMeta {
+                    block_metas,
+                    bloom_filter,
+                    range_tombstones: sstable_tombstones,
+                }),
                 file: RefCell::new(file),
+                block_cache: None,
+                home_path: None,
+                blob_file_ids: blob_files.keys().copied().collect(),
+                blob_live_bytes: blob_files.values().copied().collect(),
+                expire_time,
             }),
             iter_has_next,
         ))
     }
 
-    pub fn iter(&self) -> Result<SStableIter> {
+    pub fn iter(&self) -> Result<SStableIter<&SSTable>> {
+        SStableIter::new(self)
+    }
+
+    // same as iter, but iter owns the sstable
+    pub fn into_iter(self) -> Result<SStableIter<SSTable>> {
         SStableIter::new(self)
     }
 }
@@ -257,10 +655,9 @@ impl Display for SSTable {
         for i in iter {
             let k: KeySlice = i.0;
             let v: ValueSliceTag = i.1;
-            let v_string = if v.is_none() {
-                String::from("None")
-            } else {
-                format!("{}", v.unwrap())
+            let v_string = match v {
+                Some(v) => format!("{}", v),
+                None => String::from("None"),
             };
             let display = format!("(key: {},value: {})", k, v_string);
             res.push_str(&display);
@@ -270,14 +667,37 @@ impl Display for SSTable {
 }
 
 impl SStableBlockMeta {
+    // key range of sstable covers its range tombstones, end of tombstone is treated as last key
     pub fn last_key(&self) -> Key {
-        let last_meta = self.block_metas.last().expect("wouldn't be empty");
-        last_meta.last_key().clone()
+        let last_meta = self.block_metas.last().map(|m| m.last_key());
+        last_meta
+            .into_iter()
+            .chain(self.range_tombstones.max_end())
+            .max()
+            .expect("wouldn't be empty")
+            .clone()
     }
 
     pub fn first_key(&self) -> Key {
-        let last_meta = self.block_metas.first().expect("wouldn't be empty");
-        last_meta.start_key().clone()
+        let first_meta = self.block_metas.first().map(|m| m.start_key());
+        first_meta
+            .into_iter()
+            .chain(self.range_tombstones.first_start())
+            .min()
+            .expect("wouldn't be empty")
+            .clone()
+    }
+
+    pub fn range_tombstones(&self) -> &RangeTombstones {
+        &self.range_tombstones
+    }
+
+    // false if key is definitely not in sstable
+    pub fn may_contain(&self, key: &Key) -> bool {
+        match &self.bloom_filter {
+            Some(filter) => filter.may_contain(key.data()),
+            None => true,
+        }
     }
 }
 
@@ -285,7 +705,7 @@ impl SStableBlockMeta {
 pub mod test {
     use std::cell::RefCell;
     use std::collections::HashMap;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::{Cursor, Seek, SeekFrom};
     use std::str::from_utf8;
     use std::sync::Arc;
@@ -295,9 +715,11 @@ pub mod test {
     use tempfile::tempdir;
 
     use crate::db::common::{SortedKVIter, ValueWithTag};
+    use crate::db::config::{CompressionType, Config};
     use crate::db::file_storage::FileStorageManager;
-    use crate::db::key::{Key, KeySlice};
-    use crate::db::sstable::SSTable;
+    use crate::db::key::{Key, KeySlice, MAX_SEQUENCE};
+    use crate::db::range_tombstone::{RangeTombstone, RangeTombstones};
+    use crate::db::sstable::{SSTable, SStableIter, SSTABLE_FORMAT_VERSION};
     use crate::db::value::{Value, ValueSlice};
 
     pub fn build_sstable_with_special_value(
@@ -315,7 +737,7 @@ pub mod test {
                 e.1.as_ref().map(|f| ValueSlice::new(f.data())),
             )
         });
-        let (sstable, _) = SSTable::from_iter(&mut it, file).unwrap();
+        let (sstable, _) = SSTable::from_iter(&mut it, file, &Config::new()).unwrap();
         sstable.unwrap()
     }
 
@@ -363,14 +785,14 @@ pub mod test {
             .iter()
             .map(|e| (KeySlice::new(e.0.data()), Some(ValueSlice::new(e.1.data()))));
         let c = tempfile::tempfile().unwrap();
-        let (sstable, _) = SSTable::from_iter(&mut it, c).unwrap();
+        let (sstable, _) = SSTable::from_iter(&mut it, c, &Config::new()).unwrap();
         let sstable = sstable.unwrap();
 
         // check sstable
         for i in 0..number {
             assert_eq!(
                 sstable
-                    .get(&Key::new(&i.to_string()))
+                    .get(&Key::new(&i.to_string()), MAX_SEQUENCE, &mut Vec::new())
                     .unwrap()
                     .unwrap()
                     .unwrap(),
@@ -404,6 +826,32 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn test_stable_iter_seek() {
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        let (file, _, _) = file_manager.new_file().unwrap();
+        // 1000,1002,...,1998, more than one block
+        let sstable = build_sstable(1000, 2000, 2, file);
+
+        let mut iter = SStableIter::seek(&sstable, &Key::new("1501")).unwrap();
+        let (k, _) = iter.next().unwrap();
+        unsafe { assert_eq!(k.data(), "1502".as_bytes()) }
+        assert_eq!(iter.count(), 248);
+
+        let mut iter = SStableIter::seek(&sstable, &Key::new("0")).unwrap();
+        let (k, _) = iter.next().unwrap();
+        unsafe { assert_eq!(k.data(), "1000".as_bytes()) }
+
+        let mut iter = SStableIter::seek(&sstable, &Key::new("2")).unwrap();
+        assert!(iter.next().is_none());
+
+        let mut iter = sstable.into_iter().unwrap();
+        let (k, _) = iter.next().unwrap();
+        unsafe { assert_eq!(k.data(), "1000".as_bytes()) }
+        assert_eq!(iter.count(), 499);
+    }
+
     #[test]
     fn test_build_sstable_on_file() {
         let dir = tempdir().unwrap();
@@ -418,7 +866,8 @@ pub mod test {
 
         let mut sorted_kv_iter = SortedKVIter::new(vec![&mut sstable_1_iter, &mut sstable_2_iter]);
         let sstable_3_file = tempfile::tempfile().unwrap();
-        let (sstable_3, _) = SSTable::from_iter(&mut sorted_kv_iter, sstable_3_file).unwrap();
+        let (sstable_3, _) =
+            SSTable::from_iter(&mut sorted_kv_iter, sstable_3_file, &Config::new()).unwrap();
         let sstable_3 = sstable_3.unwrap();
         let sstable_3_on_file_iter = sstable_3.iter().unwrap();
         for (i, data) in sstable_3_on_file_iter.enumerate() {
@@ -450,7 +899,8 @@ pub mod test {
         let path = dir.into_path();
         let mut file_manager = FileStorageManager::new(path.as_path());
         let (sstable_2_file, id, _) = file_manager.new_file().unwrap();
-        let (sstable_2, _) = SSTable::from_iter(&mut iter_1, sstable_2_file).unwrap();
+        let (sstable_2, _) =
+            SSTable::from_iter(&mut iter_1, sstable_2_file, &Config::new()).unwrap();
         let sstable_2 = sstable_2.unwrap();
         let sstable_2_meta = sstable_2.block_metadata();
 
@@ -459,6 +909,191 @@ pub mod test {
         assert_eq!(format!("{:?}", meta), format!("{:?}", *sstable_2_meta))
     }
 
+    #[test]
+    fn test_sstable_bloom_filter() {
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        let (file, id, _) = file_manager.new_file().unwrap();
+        let sstable = build_sstable(100, 200, 2, file);
+        let meta = sstable.block_metadata();
+        assert!(meta.bloom_filter.is_some());
+        for i in (100..200).step_by(2) {
+            assert!(meta.may_contain(&Key::new(&i.to_string())));
+        }
+        let mut skipped = 0;
+        for i in (101..200).step_by(2) {
+            assert!(sstable
+                .get(&Key::new(&i.to_string()), MAX_SEQUENCE, &mut Vec::new())
+                .unwrap()
+                .is_none());
+            if !meta.may_contain(&Key::new(&i.to_string())) {
+                skipped += 1;
+            }
+        }
+        assert!(skipped > 40);
+
+        // bloom filter is loaded with block meta
+        let mut file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+        let meta_from_file = SSTable::get_meta_from_file(&mut file).unwrap();
+        assert_eq!(meta_from_file.bloom_filter, meta.bloom_filter);
+
+        // no bloom filter
+        let mut config = Config::new();
+        config.bloom_filter_bits_per_key = 0;
+        let data = [(Key::new("a"), Value::new("a"))];
+        let mut it = data
+            .iter()
+            .map(|e| (KeySlice::new(e.0.data()), Some(ValueSlice::new(e.1.data()))));
+        let (file, id, _) = file_manager.new_file().unwrap();
+        let (sstable, _) = SSTable::from_iter(&mut it, file, &config).unwrap();
+        assert!(sstable.unwrap().block_metadata().bloom_filter.is_none());
+        let mut file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+        let meta_from_file = SSTable::get_meta_from_file(&mut file).unwrap();
+        assert!(meta_from_file.bloom_filter.is_none());
+        assert_eq!(meta_from_file.first_key(), Key::new("a"));
+    }
+
+    #[test]
+    fn test_sstable_footer_version() {
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        let mut config = Config::new();
+        config.bloom_filter_bits_per_key = 0;
+        let data: Vec<(Key, Value)> = (0..100)
+            .map(|i| (Key::from_u64(i), Value::from_u64(i)))
+            .collect();
+        let mut it = data
+            .iter()
+            .map(|e| (KeySlice::new(e.0.data()), Some(ValueSlice::new(e.1.data()))));
+        let (file, id, _) = file_manager.new_file().unwrap();
+        SSTable::from_iter(&mut it, file, &config).unwrap();
+        let path = FileStorageManager::file_path(dir.path(), &id);
+        let content = fs::read(&path).unwrap();
+
+        // footer of old version has no bloom filter size, format version and magic number
+        let footer = &content[content.len() - 36..];
+        let mut old = content[..content.len() - 36].to_vec();
+        old.extend_from_slice(&footer[8..24]);
+        fs::write(&path, &old).unwrap();
+        let sstable =
+            SSTable::from_file(FileStorageManager::open_file(dir.path(), &id).unwrap()).unwrap();
+        assert_eq!(sstable.iter().unwrap().count(), 100);
+        assert_eq!(
+            sstable
+                .get(&Key::from_u64(50), MAX_SEQUENCE, &mut Vec::new())
+                .unwrap(),
+            Some(Some(Value::from_u64(50)))
+        );
+
+        // unknown format version is refused
+        let mut newer = content.clone();
+        let position = newer.len() - 12;
+        newer[position] = SSTABLE_FORMAT_VERSION as u8 + 1;
+        fs::write(&path, &newer).unwrap();
+        let mut file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+        let err = SSTable::get_meta_from_file(&mut file).unwrap_err();
+        assert!(err.to_string().contains("format version"));
+    }
+
+    #[test]
+    fn test_sstable_block_cache() {
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        let (file, id, _) = file_manager.new_file().unwrap();
+        let meta = build_sstable(100, 300, 1, file).block_metadata();
+        let cache = SSTable::new_block_cache(10);
+
+        // not fill cache
+        let file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+        let sstable = SSTable::from_with_block_cache(
+            meta.clone(),
+            file,
+            id,
+            dir.path(),
+            cache.clone(),
+            false,
+        )
+        .unwrap();
+        let res = sstable
+            .get(&Key::new("150"), MAX_SEQUENCE, &mut Vec::new())
+            .unwrap();
+        assert_eq!(res, Some(Some(Value::new("150"))));
+        assert_eq!(cache.as_ref().unwrap().lock().unwrap().len(), 0);
+
+        // fill cache, second read of same block hits cache
+        let file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+        let sstable =
+            SSTable::from_with_block_cache(meta.clone(), file, id, dir.path(), cache.clone(), true)
+                .unwrap();
+        sstable
+            .get(&Key::new("150"), MAX_SEQUENCE, &mut Vec::new())
+            .unwrap();
+        assert_eq!(cache.as_ref().unwrap().lock().unwrap().len(), 1);
+        let res = sstable
+            .get(&Key::new("151"), MAX_SEQUENCE, &mut Vec::new())
+            .unwrap();
+        assert_eq!(res, Some(Some(Value::new("151"))));
+        assert_eq!(cache.as_ref().unwrap().lock().unwrap().len(), 1);
+
+        // iter reads all blocks through cache
+        let count = sstable.iter().unwrap().count();
+        assert_eq!(count, 200);
+        assert_eq!(
+            cache.as_ref().unwrap().lock().unwrap().len(),
+            meta.block_metas.len()
+        );
+
+        // capacity 0 disables cache
+        let cache = SSTable::new_block_cache(0);
+        assert!(cache.is_none());
+        let file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+        let sstable =
+            SSTable::from_with_block_cache(meta.clone(), file, id, dir.path(), cache, true)
+                .unwrap();
+        let res = sstable
+            .get(&Key::new("150"), MAX_SEQUENCE, &mut Vec::new())
+            .unwrap();
+        assert_eq!(res, Some(Some(Value::new("150"))));
+        assert_eq!(sstable.iter().unwrap().count(), 200);
+    }
+
+    #[test]
+    fn test_sstable_compression() {
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        let data: Vec<(Key, Value)> = (1000..2000)
+            .map(|i| {
+                let json = format!("{{\"id\":{},\"name\":\"user_{}\",\"active\":true}}", i, i);
+                (Key::new(&i.to_string()), Value::new(&json))
+            })
+            .collect();
+
+        let mut file_size = Vec::new();
+        let mut content = Vec::new();
+        for compression in [CompressionType::None, CompressionType::Snappy] {
+            let mut config = Config::new();
+            config.compression = compression;
+            let mut it = data
+                .iter()
+                .map(|e| (KeySlice::new(e.0.data()), Some(ValueSlice::new(e.1.data()))));
+            let (file, id, _) = file_manager.new_file().unwrap();
+            SSTable::from_iter(&mut it, file, &config).unwrap();
+
+            let file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+            file_size.push(file.metadata().unwrap().len());
+            let sstable = SSTable::from_file(file).unwrap();
+            assert_eq!(
+                sstable
+                    .get(&Key::new("1500"), MAX_SEQUENCE, &mut Vec::new())
+                    .unwrap(),
+                Some(Some(data[500].1.clone()))
+            );
+            content.push(sstable.to_string());
+        }
+        assert_eq!(content[0], content[1]);
+        assert!(file_size[1] < file_size[0] / 2, "{:?}", file_size);
+    }
+
     #[test]
     fn test_stable_meta_last_key() {
         let dir = tempdir().unwrap();
@@ -468,4 +1103,117 @@ pub mod test {
         assert_eq!(sstable_1.sstable_metas.last_key(), Key::new("9"));
         assert_eq!(sstable_1.sstable_metas.first_key(), Key::new("1"));
     }
+
+    #[test]
+    fn test_sstable_range_tombstones() {
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        // sequence number of key i is i
+        let data: Vec<(Key, u64, Value)> = (1000..2000)
+            .map(|i| (Key::from_u64(i), i, Value::from_u64(i)))
+            .collect();
+        let mut tombstones = RangeTombstones::new();
+        let tombstone = |start: u64, end: u64, seq| {
+            RangeTombstone::new(Key::from_u64(start), Key::from_u64(end), seq)
+        };
+        tombstones.add(tombstone(1100, 1300, 1250));
+        tombstones.add(tombstone(1900, 1950, 1000));
+        tombstones.add(tombstone(3000, 4000, 5));
+
+        let mut it = data
+            .iter()
+            .map(|(k, seq, v)| {
+                (
+                    KeySlice::new_with_seq(k.data(), *seq),
+                    Some(ValueSlice::new(v.data())),
+                )
+            })
+            .peekable();
+        let mut ids = Vec::new();
+        loop {
+            let (file, id, _) = file_manager.new_file().unwrap();
+            let (sstable, has_next) = SSTable::from_iter_with_file_limit(
+                &mut it,
+                &mut tombstones,
+                file,
+                1024,
+                &Config::new(),
+            )
+            .unwrap();
+            ids.push(id);
+            if !has_next {
+                break;
+            }
+        }
+        assert!(ids.len() > 2);
+        assert!(tombstones.is_empty());
+
+        let sstables: Vec<SSTable> = ids
+            .iter()
+            .map(|id| {
+                SSTable::from_file(FileStorageManager::open_file(dir.path(), id).unwrap()).unwrap()
+            })
+            .collect();
+        // no kv is lost when sstable is split, sstables don't overlap and cover their tombstones
+        let count: usize = sstables.iter().map(|s| s.iter().unwrap().count()).sum();
+        assert_eq!(count, data.len());
+        let tombstone_count: usize = sstables.iter().map(|s| s.range_tombstones().len()).sum();
+        // tombstone across sstables is cut at their boundary
+        assert!(tombstone_count > 3);
+        for pair in sstables.windows(2) {
+            assert!(pair[0].block_metadata().last_key() <= pair[1].block_metadata().first_key());
+        }
+        assert_eq!(
+            sstables.last().unwrap().block_metadata().last_key(),
+            Key::from_u64(4000)
+        );
+
+        let get = |i: u64, seq| {
+            let key = Key::from_u64(i);
+            // same as level, key equal to end of sstable may be in next one
+            sstables
+                .iter()
+                .filter(|s| s.block_metadata().last_key() >= key)
+                .take_while(|s| s.block_metadata().first_key() <= key)
+                .find_map(|s| s.get(&key, seq, &mut Vec::new()).unwrap())
+        };
+        assert_eq!(get(1150, MAX_SEQUENCE), Some(None));
+        assert_eq!(get(1260, MAX_SEQUENCE), Some(Some(Value::from_u64(1260))));
+        assert_eq!(get(1150, 1200), Some(Some(Value::from_u64(1150))));
+        assert_eq!(get(1920, MAX_SEQUENCE), Some(Some(Value::from_u64(1920))));
+        assert_eq!(get(3500, MAX_SEQUENCE), Some(None));
+        assert_eq!(get(3500, 4), None);
+
+        // sstable which only has tombstones
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add(tombstone(10, 20, 7));
+        let (file, id, _) = file_manager.new_file().unwrap();
+        let mut it = Vec::new().into_iter().peekable();
+        SSTable::from_iter_with_file_limit(&mut it, &mut tombstones, file, 0, &Config::new())
+            .unwrap();
+        let file = FileStorageManager::open_file(dir.path(), &id).unwrap();
+        let sstable = SSTable::from_file(file).unwrap();
+        assert_eq!(sstable.iter().unwrap().count(), 0);
+        assert_eq!(
+            SStableIter::seek(&sstable, &Key::from_u64(15))
+                .unwrap()
+                .count(),
+            0
+        );
+        assert_eq!(
+            sstable
+                .get(&Key::from_u64(15), MAX_SEQUENCE, &mut Vec::new())
+                .unwrap(),
+            Some(None)
+        );
+        assert_eq!(
+            sstable
+                .get(&Key::from_u64(5), MAX_SEQUENCE, &mut Vec::new())
+                .unwrap(),
+            None
+        );
+        let meta = sstable.block_metadata();
+        assert_eq!(meta.first_key(), Key::from_u64(10));
+        assert_eq!(meta.last_key(), Key::from_u64(20));
+    }
 }
@@ -1,33 +1,62 @@
 use std::fmt::{Display, Formatter};
 use std::io::{Read, Write};
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use log::{debug, info, trace};
 use serde::{Deserialize, Serialize};
 
-use crate::db::common::{KVIterItem, ValueSliceTag, ValueWithTag};
-use crate::db::key::{Key, KeySlice};
-use crate::db::sstable::BLOCK_POOL_MEMORY_SIZE;
-use crate::db::value::{Value, ValueSlice};
+use crate::db::common::{KVIterItem, ValueSliceTag};
+use crate::db::config::CompressionType;
+use crate::db::error::CorruptionError;
+use crate::db::key::{Key, KeySlice, SeqNumber};
+use crate::db::sstable::{snappy, BLOCK_POOL_MEMORY_SIZE};
+use crate::db::value::ValueSlice;
 
 pub const BLOCK_SIZE: usize = 4 * 1024;
+// second byte of block header, first entry key size (u16) of block without header is less than KEY_SIZE_LIMIT,
+// so its second byte never equals to it, blocks written before compression are readable
+const BLOCK_HEADER_MARK: u8 = 0xff;
+// block with this mark has checksum at the end, blocks with BLOCK_HEADER_MARK are written before checksum is added
+const BLOCK_HEADER_WITH_CHECKSUM_MARK: u8 = 0xfe;
+// block with this mark has checksum and sequence number in each entry,
+// entries of blocks with other marks have sequence number 0
+const BLOCK_HEADER_WITH_SEQUENCE_MARK: u8 = 0xfd;
+const BLOCK_HEADER_SIZE: usize = 2;
+pub const BLOCK_CHECKSUM_SIZE: usize = 4;
+const SEQUENCE_SIZE: usize = 8;
+// value size is less than VALUE_SIZE_LIMIT, so highest bits are free
+const BLOB_POINTER_FLAG: u16 = 0x8000;
+const MERGE_OPERAND_FLAG: u16 = 0x4000;
+const EXPIRE_TIME_FLAG: u16 = 0x2000;
+const VALUE_FLAGS: u16 = BLOB_POINTER_FLAG | MERGE_OPERAND_FLAG | EXPIRE_TIME_FLAG;
+const EXPIRE_TIME_SIZE: usize = 8;
 
 /// entry format
-/// [key size(u16),key data,value size(u16),value data]
+/// [key size(u16),key data,sequence number(u64),value size(u16),value data]
+/// value size is 0 if value is deleted, BLOB_POINTER_FLAG is set in value size if value data is blob pointer,
+/// MERGE_OPERAND_FLAG is set if value data is merge operand,
+/// EXPIRE_TIME_FLAG is set if value has expire time (u64), it is saved between value size and value data
+/// versions of same key are ordered from new to old
 pub struct Block {
     content: [u8; BLOCK_POOL_MEMORY_SIZE],
     size: usize,
+    // false if block is written before sequence number is added
+    has_sequence: bool,
 }
 
-/// data block,4k default
-/// entry 1
+/// data block,4k default before compression
+/// header [codec (u8),0xfd]
+/// entry 1 (compressed with entry 2..n if codec is not none)
 /// entry 2
 /// ...
 /// entry n
-/// pad
+/// crc32 of header and entries (u32)
 pub struct BlockBuilder {
     content: Vec<u8>,
+    compressed: Vec<u8>,
+    compression: CompressionType,
 }
 
 /// [last_key offset, block_size u16,entry_number u16]
@@ -40,62 +69,160 @@ pub struct BlockMeta {
     entry_number: usize,
 }
 
+// block may be shared with block cache
 pub struct BlockIter {
-    block: Block,
+    block: Arc<Block>,
     next_position: usize,
 }
 
 impl Block {
     const SIZE_LEN: usize = 2;
-    pub fn new(content: [u8; BLOCK_POOL_MEMORY_SIZE], size: usize) -> Self {
-        Block { content, size }
+    // content is data read from file, verify checksum and decompress it according to block header
+    pub fn new(mut content: [u8; BLOCK_POOL_MEMORY_SIZE], mut size: usize) -> Result<Self> {
+        let mark = if size >= BLOCK_HEADER_SIZE {
+            content[1]
+        } else {
+            0
+        };
+        let has_checksum =
+            mark == BLOCK_HEADER_WITH_CHECKSUM_MARK || mark == BLOCK_HEADER_WITH_SEQUENCE_MARK;
+        if has_checksum {
+            if size < BLOCK_HEADER_SIZE + BLOCK_CHECKSUM_SIZE {
+                return Err(
+                    CorruptionError::new(format!("block size {} is too small", size)).into(),
+                );
+            }
+            size -= BLOCK_CHECKSUM_SIZE;
+            let crc = (&content[size..size + BLOCK_CHECKSUM_SIZE]).read_u32::<LittleEndian>()?;
+            if crc32fast::hash(&content[..size]) != crc {
+                return Err(CorruptionError::new(String::from("block checksum mismatch")).into());
+            }
+        }
+        if mark == BLOCK_HEADER_MARK || has_checksum {
+            match CompressionType::from_u8(content[0])? {
+                CompressionType::None => {
+                    content.copy_within(BLOCK_HEADER_SIZE..size, 0);
+                    size -= BLOCK_HEADER_SIZE;
+                }
+                CompressionType::Snappy => {
+                    let mut data = [0; BLOCK_POOL_MEMORY_SIZE];
+                    size = snappy::decompress(&content[BLOCK_HEADER_SIZE..size], &mut data)?;
+                    content = data;
+                }
+            }
+        }
+        Ok(Block {
+            content,
+            size,
+            has_sequence: mark == BLOCK_HEADER_WITH_SEQUENCE_MARK,
+        })
     }
 
-    pub fn find(&self, key: &Key, entry_number: usize) -> Result<Option<ValueWithTag>> {
+    // (sequence number,value) of newest version of key which sequence number is not greater than seq,
+    // value slice points to block content
+    pub fn find(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        entry_number: usize,
+    ) -> Result<Option<(SeqNumber, ValueSliceTag)>> {
+        let mut res = None;
+        self.for_each_version(key, seq, entry_number, |entry_seq, value| {
+            res = Some((entry_seq, value));
+            Ok(false)
+        })?;
+        Ok(res)
+    }
+
+    // call f with versions of key which sequence number is not greater than seq from new to old,
+    // stop if f returns false, return false if it is stopped
+    pub fn for_each_version(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        entry_number: usize,
+        mut f: impl FnMut(SeqNumber, ValueSliceTag) -> Result<bool>,
+    ) -> Result<bool> {
         let mut position = 0;
         let mut count = 0;
         while count < entry_number {
             count += 1;
-            let (key_content, value_content) = self.read_kv_at(&mut position)?;
+            let (key_content, entry_seq, value) = self.read_kv_at(&mut position)?;
 
-            if key.equal_u8(key_content) {
-                if let Some(v) = value_content {
-                    return Ok(Some(Some(Value::from_u8(v))));
-                } else {
-                    return Ok(Some(None));
-                }
+            if key.equal_u8(key_content) && entry_seq <= seq && !f(entry_seq, value)? {
+                return Ok(false);
             }
         }
-        Ok(None)
+        Ok(true)
     }
 
     // value is none if is deleted
-    fn read_kv_at(&self, position: &mut usize) -> Result<(&[u8], Option<&[u8]>)> {
+    fn read_kv_at(&self, position: &mut usize) -> Result<(&[u8], SeqNumber, ValueSliceTag)> {
         let key_size = (&self.content[*position..*position + Self::SIZE_LEN])
             .read_u16::<LittleEndian>()? as usize;
         *position += Self::SIZE_LEN;
 
         let key_content = &self.content[*position..*position + key_size];
         *position += key_size;
-        let value_size = (&self.content[*position..*position + Self::SIZE_LEN])
-            .read_u16::<LittleEndian>()? as usize;
-        if value_size > 0 {
-            *position += Self::SIZE_LEN;
-            let value_content = &self.content[*position..*position + value_size];
-            *position += value_size;
-            Ok((key_content, Some(value_content)))
-        } else {
-            *position += Self::SIZE_LEN;
-            Ok((key_content, None))
+        let mut seq = 0;
+        if self.has_sequence {
+            seq =
+                (&self.content[*position..*position + SEQUENCE_SIZE]).read_u64::<LittleEndian>()?;
+            *position += SEQUENCE_SIZE;
+        }
+        let value_size =
+            (&self.content[*position..*position + Self::SIZE_LEN]).read_u16::<LittleEndian>()?;
+        *position += Self::SIZE_LEN;
+        if value_size == 0 {
+            return Ok((key_content, seq, None));
+        }
+        let is_blob_pointer = value_size & BLOB_POINTER_FLAG != 0;
+        let is_merge_operand = value_size & MERGE_OPERAND_FLAG != 0;
+        let mut expire_time = 0;
+        if value_size & EXPIRE_TIME_FLAG != 0 {
+            expire_time = (&self.content[*position..*position + EXPIRE_TIME_SIZE])
+                .read_u64::<LittleEndian>()?;
+            *position += EXPIRE_TIME_SIZE;
         }
+        let value_size = (value_size & !VALUE_FLAGS) as usize;
+        let value_content = &self.content[*position..*position + value_size];
+        *position += value_size;
+        let value = if is_blob_pointer {
+            ValueSlice::new_blob_pointer(value_content)
+        } else if is_merge_operand {
+            ValueSlice::new_merge_operand(value_content)
+        } else {
+            ValueSlice::new(value_content)
+        };
+        let value = value.with_expire_time(expire_time);
+        Ok((key_content, seq, Some(value)))
     }
 
     pub fn into_iter(self) -> BlockIter {
+        BlockIter::new(Arc::new(self))
+    }
+}
+
+impl BlockIter {
+    pub fn new(block: Arc<Block>) -> Self {
         BlockIter {
-            block: self,
+            block,
             next_position: 0,
         }
     }
+
+    // move to the first entry which key is greater or equal to key
+    pub fn seek(&mut self, key: &Key) -> Result<()> {
+        while self.next_position < self.block.size {
+            let mut position = self.next_position;
+            let (k, _, _) = self.block.read_kv_at(&mut position)?;
+            if k >= key.data() {
+                break;
+            }
+            self.next_position = position;
+        }
+        Ok(())
+    }
 }
 
 impl Iterator for BlockIter {
@@ -105,10 +232,8 @@ impl Iterator for BlockIter {
         if self.next_position == self.block.size {
             return None;
         }
-        let (k, v) = self.block.read_kv_at(&mut self.next_position).unwrap();
-        let next_key_slice = KeySlice::new(k);
-        let next_value_slice = v.map(|data| ValueSlice::new(data));
-        Some((next_key_slice, next_value_slice))
+        let (k, seq, v) = self.block.read_kv_at(&mut self.next_position).unwrap();
+        Some((KeySlice::new_with_seq(k, seq), v))
     }
 }
 
@@ -144,9 +269,9 @@ impl BlockMeta {
     // [key_size,key_content,entry_number]
     pub fn write_to_binary(&self, write: &mut dyn Write) -> Result<()> {
         write.write_u16::<LittleEndian>(self.start_key.len() as u16)?;
-        write.write(self.start_key.data())?;
+        write.write_all(self.start_key.data())?;
         write.write_u16::<LittleEndian>(self.last_key.len() as u16)?;
-        write.write(self.last_key.data())?;
+        write.write_all(self.last_key.data())?;
         write.write_u32::<LittleEndian>(self.block_offset as u32)?;
         write.write_u32::<LittleEndian>(self.size as u32)?;
         write.write_u32::<LittleEndian>(self.entry_number as u32)?;
@@ -156,12 +281,12 @@ impl BlockMeta {
     pub fn read_from_binary(reader: &mut dyn Read) -> Result<BlockMeta> {
         let start_key_len = reader.read_u16::<LittleEndian>()?;
         let mut start_key_data = vec![0; start_key_len as usize];
-        reader.read(&mut start_key_data)?;
+        reader.read_exact(&mut start_key_data)?;
         let start_key = Key::from(&start_key_data);
 
         let end_key_len = reader.read_u16::<LittleEndian>()?;
         let mut end_key_data = vec![0; end_key_len as usize];
-        reader.read(&mut end_key_data)?;
+        reader.read_exact(&mut end_key_data)?;
         let last_key = Key::from(&end_key_data);
 
         let block_offset = reader.read_u32::<LittleEndian>()? as u64;
@@ -194,7 +319,7 @@ impl BlockMeta {
 
             let start_key = Key::from_u8_vec(start_key_data);
             let last_key = Key::from_u8_vec(last_key_data);
-            let block_offset = data.read_u32::<LittleEndian>()? as u32;
+            let block_offset = data.read_u32::<LittleEndian>()?;
             let size = data.read_u32::<LittleEndian>()?;
             let entry_number = data.read_u32::<LittleEndian>()?;
 
@@ -210,10 +335,22 @@ impl BlockMeta {
     }
 }
 
+impl CompressionType {
+    fn from_u8(v: u8) -> Result<Self> {
+        match v {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Snappy),
+            _ => Err(anyhow!("unknown block compression type {}", v)),
+        }
+    }
+}
+
 impl BlockBuilder {
-    pub fn new() -> Self {
+    pub fn new(compression: CompressionType) -> Self {
         BlockBuilder {
             content: Vec::new(),
+            compressed: Vec::new(),
+            compression,
         }
     }
 
@@ -225,14 +362,28 @@ impl BlockBuilder {
         self.content
             .write_u16::<LittleEndian>(key_slice.len() as u16)?;
         unsafe {
-            self.content.write(key_slice.data())?;
+            self.content.write_all(key_slice.data())?;
         }
+        self.content.write_u64::<LittleEndian>(key_slice.seq())?;
 
         if let Some(value_slice) = value_with_tag {
-            self.content
-                .write_u16::<LittleEndian>(value_slice.len() as u16)?;
+            let mut value_size = value_slice.len() as u16;
+            if value_slice.is_blob_pointer() {
+                value_size |= BLOB_POINTER_FLAG;
+            }
+            if value_slice.is_merge_operand() {
+                value_size |= MERGE_OPERAND_FLAG;
+            }
+            if value_slice.expire_time() != 0 {
+                value_size |= EXPIRE_TIME_FLAG;
+            }
+            self.content.write_u16::<LittleEndian>(value_size)?;
+            if value_slice.expire_time() != 0 {
+                self.content
+                    .write_u64::<LittleEndian>(value_slice.expire_time())?;
+            }
             unsafe {
-                self.content.write(value_slice.data())?;
+                self.content.write_all(value_slice.data())?;
             }
         } else {
             self.content.write_u16::<LittleEndian>(0)?;
@@ -240,10 +391,30 @@ impl BlockBuilder {
         Ok(())
     }
 
-    pub fn flush(&mut self, w: &mut dyn Write) -> Result<()> {
-        w.write(self.content.as_slice())?;
+    // return size written to w
+    pub fn flush(&mut self, w: &mut dyn Write) -> Result<usize> {
+        let mut codec = CompressionType::None;
+        if self.compression == CompressionType::Snappy {
+            snappy::compress(&self.content, &mut self.compressed);
+            // store uncompressed if compression saves less than 12.5%, same as leveldb
+            if self.compressed.len() < self.content.len() - self.content.len() / 8 {
+                codec = CompressionType::Snappy;
+            }
+        }
+        let data = match codec {
+            CompressionType::None => &self.content,
+            CompressionType::Snappy => &self.compressed,
+        };
+        let header = [codec as u8, BLOCK_HEADER_WITH_SEQUENCE_MARK];
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        hasher.update(data);
+        w.write_all(&header)?;
+        w.write_all(data)?;
+        w.write_u32::<LittleEndian>(hasher.finalize())?;
+        let size = BLOCK_HEADER_SIZE + data.len() + BLOCK_CHECKSUM_SIZE;
         self.content.clear();
-        Ok(())
+        Ok(size)
     }
 }
 
@@ -251,12 +422,21 @@ impl BlockBuilder {
 pub mod test {
     use std::io::Cursor;
 
-    use crate::db::key::Key;
-    use crate::db::key::KeySlice;
-    use crate::db::sstable::block::{Block, BlockBuilder, BlockMeta};
+    use crate::db::common::ValueWithTag;
+    use crate::db::config::CompressionType;
+    use crate::db::error::CorruptionError;
+    use crate::db::key::{Key, KeySlice, MAX_SEQUENCE};
+    use crate::db::sstable::block::{
+        Block, BlockBuilder, BlockMeta, BLOCK_HEADER_MARK, BLOCK_HEADER_WITH_CHECKSUM_MARK,
+    };
     use crate::db::sstable::BLOCK_POOL_MEMORY_SIZE;
     use crate::db::value::{Value, ValueSlice};
 
+    fn find_value(block: &Block, key: &Key, entry_number: usize) -> Option<ValueWithTag> {
+        let res = block.find(key, MAX_SEQUENCE, entry_number).unwrap();
+        res.map(|(_, v)| v.map(|v| Value::from_u8(unsafe { v.data() })))
+    }
+
     #[test]
     fn test_block_builder_and_read() {
         let data = vec![(1, false), (2, false), (3, true), (6, false), (7, false)];
@@ -264,7 +444,7 @@ pub mod test {
 
         let number = data.len();
         for (key, is_deleted) in data.iter() {
-            let res = block.find(&Key::new(&key.to_string()), number).unwrap();
+            let res = find_value(&block, &Key::new(&key.to_string()), number);
             if *is_deleted {
                 assert!(res.unwrap().is_none());
             } else {
@@ -275,7 +455,14 @@ pub mod test {
 
     // true if is deleted
     pub fn create_block(input: &Vec<(u32, bool)>) -> Block {
-        let mut b_builder = BlockBuilder::new();
+        create_block_with_compression(input, CompressionType::Snappy)
+    }
+
+    fn create_block_with_compression(
+        input: &Vec<(u32, bool)>,
+        compression: CompressionType,
+    ) -> Block {
+        let mut b_builder = BlockBuilder::new(compression);
         let mut content: Vec<u8> = Vec::new();
         for (number, is_deleted) in input {
             let number_string = number.to_string();
@@ -289,13 +476,223 @@ pub mod test {
                 .append(KeySlice::new(number.to_string().as_bytes()), value_slice)
                 .unwrap();
         }
-        b_builder.flush(&mut content).unwrap();
+        let size = b_builder.flush(&mut content).unwrap();
+        assert_eq!(size, content.len());
         assert_eq!(b_builder.len(), 0);
         let mut block_memory: [u8; BLOCK_POOL_MEMORY_SIZE] = [0; BLOCK_POOL_MEMORY_SIZE];
         for (i, data) in content.iter().enumerate() {
             block_memory[i] = *data;
         }
-        Block::new(block_memory, content.len())
+        Block::new(block_memory, content.len()).unwrap()
+    }
+
+    #[test]
+    fn test_block_compression() {
+        let data: Vec<(u32, bool)> = (100..400).map(|i| (i, i % 7 == 0)).collect();
+        let compressed = create_block_with_compression(&data, CompressionType::Snappy);
+        let uncompressed = create_block_with_compression(&data, CompressionType::None);
+        let compressed_kvs: Vec<_> = compressed
+            .into_iter()
+            .map(|(k, v)| unsafe { (k.data().to_vec(), v.map(|v| v.data().to_vec())) })
+            .collect();
+        let uncompressed_kvs: Vec<_> = uncompressed
+            .into_iter()
+            .map(|(k, v)| unsafe { (k.data().to_vec(), v.map(|v| v.data().to_vec())) })
+            .collect();
+        assert_eq!(compressed_kvs.len(), data.len());
+        assert_eq!(compressed_kvs, uncompressed_kvs);
+
+        // repeated data is compressed
+        let mut b_builder = BlockBuilder::new(CompressionType::Snappy);
+        let value = "{\"name\":\"value\",\"list\":[1,2,3]}".repeat(10);
+        for i in 0..10 {
+            b_builder
+                .append(
+                    KeySlice::new(i.to_string().as_bytes()),
+                    Some(ValueSlice::new(value.as_bytes())),
+                )
+                .unwrap();
+        }
+        let len = b_builder.len();
+        let mut content = Vec::new();
+        let size = b_builder.flush(&mut content).unwrap();
+        assert!(size < len / 4, "size {} len {}", size, len);
+    }
+
+    #[test]
+    fn test_read_block_of_old_format() {
+        // entries have no sequence number
+        let data = vec![(1, false), (2, true), (300, false)];
+        let mut content = Vec::new();
+        for (number, is_deleted) in &data {
+            let number_string = number.to_string();
+            content.extend_from_slice(&(number_string.len() as u16).to_le_bytes());
+            content.extend_from_slice(number_string.as_bytes());
+            if *is_deleted {
+                content.extend_from_slice(&0u16.to_le_bytes());
+            } else {
+                content.extend_from_slice(&(number_string.len() as u16).to_le_bytes());
+                content.extend_from_slice(number_string.as_bytes());
+            }
+        }
+        // block written before compression has no header
+        let mut blocks = vec![content.clone()];
+        // block written before checksum is added
+        let mut with_header = vec![CompressionType::None as u8, BLOCK_HEADER_MARK];
+        with_header.extend_from_slice(&content);
+        blocks.push(with_header.clone());
+        // block written before sequence number is added
+        with_header[1] = BLOCK_HEADER_WITH_CHECKSUM_MARK;
+        let crc = crc32fast::hash(&with_header);
+        with_header.extend_from_slice(&crc.to_le_bytes());
+        blocks.push(with_header);
+
+        for block_data in blocks {
+            let mut block_memory = [0; BLOCK_POOL_MEMORY_SIZE];
+            block_memory[..block_data.len()].copy_from_slice(&block_data);
+            let block = Block::new(block_memory, block_data.len()).unwrap();
+            let res = find_value(&block, &Key::new("300"), data.len());
+            assert_eq!(res, Some(Some(Value::new("300"))));
+            let res = find_value(&block, &Key::new("2"), data.len());
+            assert_eq!(res, Some(None));
+            assert!(block.into_iter().all(|(k, _)| k.seq() == 0));
+        }
+    }
+
+    #[test]
+    fn test_block_find_version() {
+        let mut b_builder = BlockBuilder::new(CompressionType::Snappy);
+        let versions = [(9, Some("a9")), (5, None), (3, Some("a3"))];
+        for (seq, v) in versions {
+            b_builder
+                .append(
+                    KeySlice::new_with_seq(b"a", seq),
+                    v.map(|v| ValueSlice::new(v.as_bytes())),
+                )
+                .unwrap();
+        }
+        let mut content = Vec::new();
+        let size = b_builder.flush(&mut content).unwrap();
+        let mut block_memory = [0; BLOCK_POOL_MEMORY_SIZE];
+        block_memory[..size].copy_from_slice(&content);
+        let block = Block::new(block_memory, size).unwrap();
+
+        let find = |seq| {
+            let res = block.find(&Key::new("a"), seq, 3).unwrap();
+            res.map(|(_, v)| v.map(|v| Value::from_u8(unsafe { v.data() })))
+        };
+        assert_eq!(find(MAX_SEQUENCE), Some(Some(Value::new("a9"))));
+        assert_eq!(find(9), Some(Some(Value::new("a9"))));
+        assert_eq!(find(8), Some(None));
+        assert_eq!(find(4), Some(Some(Value::new("a3"))));
+        assert_eq!(find(2), None);
+        assert_eq!(block.find(&Key::new("a"), 4, 3).unwrap().unwrap().0, 3);
+        let seqs: Vec<u64> = block.into_iter().map(|(k, _)| k.seq()).collect();
+        assert_eq!(seqs, vec![9, 5, 3]);
+    }
+
+    #[test]
+    fn test_block_checksum() {
+        let mut b_builder = BlockBuilder::new(CompressionType::Snappy);
+        for i in 100..200 {
+            let s = i.to_string();
+            b_builder
+                .append(
+                    KeySlice::new(s.as_bytes()),
+                    Some(ValueSlice::new(s.as_bytes())),
+                )
+                .unwrap();
+        }
+        let mut content = Vec::new();
+        let size = b_builder.flush(&mut content).unwrap();
+        let read_block = |data: &[u8]| {
+            let mut block_memory = [0; BLOCK_POOL_MEMORY_SIZE];
+            block_memory[..data.len()].copy_from_slice(data);
+            Block::new(block_memory, data.len())
+        };
+        let block = read_block(&content[..size]).unwrap();
+        assert_eq!(
+            find_value(&block, &Key::new("150"), 100),
+            Some(Some(Value::new("150")))
+        );
+
+        for position in [0, 5, size - 1] {
+            let mut damaged = content.clone();
+            damaged[position] ^= 1;
+            let err = read_block(&damaged).err().unwrap();
+            assert!(err.downcast_ref::<CorruptionError>().is_some());
+        }
+    }
+
+    #[test]
+    fn test_blob_pointer_entry() {
+        let mut b_builder = BlockBuilder::new(CompressionType::None);
+        let pointer = [7; 16];
+        b_builder
+            .append(KeySlice::new(b"a"), Some(ValueSlice::new(b"inline")))
+            .unwrap();
+        b_builder
+            .append(
+                KeySlice::new(b"b"),
+                Some(ValueSlice::new_blob_pointer(&pointer)),
+            )
+            .unwrap();
+        let mut content = Vec::new();
+        let size = b_builder.flush(&mut content).unwrap();
+        let mut block_memory = [0; BLOCK_POOL_MEMORY_SIZE];
+        block_memory[..size].copy_from_slice(&content);
+        let block = Block::new(block_memory, size).unwrap();
+
+        let v = block
+            .find(&Key::new("a"), MAX_SEQUENCE, 2)
+            .unwrap()
+            .unwrap()
+            .1
+            .unwrap();
+        assert!(!v.is_blob_pointer());
+        let v = block
+            .find(&Key::new("b"), MAX_SEQUENCE, 2)
+            .unwrap()
+            .unwrap()
+            .1
+            .unwrap();
+        assert!(v.is_blob_pointer());
+        assert_eq!(unsafe { v.data() }, &pointer);
+    }
+
+    #[test]
+    fn test_merge_operand_and_expire_time_entry() {
+        let mut b_builder = BlockBuilder::new(CompressionType::None);
+        let entries = [(3, true), (2, true), (1, false)];
+        for (seq, is_operand) in entries {
+            let value = if is_operand {
+                ValueSlice::new_merge_operand(b"operand")
+            } else {
+                ValueSlice::new(b"value").with_expire_time(100)
+            };
+            b_builder
+                .append(KeySlice::new_with_seq(b"a", seq), Some(value))
+                .unwrap();
+        }
+        let mut content = Vec::new();
+        let size = b_builder.flush(&mut content).unwrap();
+        let mut block_memory = [0; BLOCK_POOL_MEMORY_SIZE];
+        block_memory[..size].copy_from_slice(&content);
+        let block = Block::new(block_memory, size).unwrap();
+
+        let mut versions = Vec::new();
+        let finished = block
+            .for_each_version(&Key::new("a"), 2, 3, |seq, v| {
+                let v = v.unwrap();
+                versions.push((seq, v.expire_time(), unsafe { v.data().to_vec() }));
+                Ok(v.is_merge_operand())
+            })
+            .unwrap();
+        assert!(!finished);
+        assert_eq!(
+            versions,
+            vec![(2, 0, b"operand".to_vec()), (1, 100, b"value".to_vec())]
+        );
     }
 
     #[test]
@@ -335,6 +732,33 @@ pub mod test {
         assert_eq!(block_metas[1].entry_size(), 5);
     }
 
+    #[test]
+    fn test_block_iter_seek() {
+        let data = vec![(1, false), (3, true), (5, false), (7, false)];
+        let block = create_block(&data);
+        let mut block_iter = block.into_iter();
+        block_iter.seek(&Key::new("4")).unwrap();
+        let (k, v) = block_iter.next().unwrap();
+        unsafe {
+            assert_eq!(k.data(), "5".as_bytes());
+            assert_eq!(v.unwrap().data(), "5".as_bytes());
+        }
+
+        let block = create_block(&data);
+        let mut block_iter = block.into_iter();
+        block_iter.seek(&Key::new("3")).unwrap();
+        let (k, v) = block_iter.next().unwrap();
+        unsafe {
+            assert_eq!(k.data(), "3".as_bytes());
+        }
+        assert!(v.is_none());
+
+        let block = create_block(&data);
+        let mut block_iter = block.into_iter();
+        block_iter.seek(&Key::new("8")).unwrap();
+        assert!(block_iter.next().is_none());
+    }
+
     #[test]
     fn test_block_iter() {
         let data = vec![(1, false), (2, false), (3, true), (6, false), (7, false)];
@@ -343,8 +767,8 @@ pub mod test {
         let mut res = Vec::new();
         for (key_slice, value) in block_iter {
             unsafe {
-                if value.is_some() {
-                    assert_eq!(key_slice.data(), value.unwrap().data());
+                if let Some(value) = value {
+                    assert_eq!(key_slice.data(), value.data());
                     res.push((Key::from(key_slice.data()), false));
                 } else {
                     res.push((Key::from(key_slice.data()), true));
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+/// bloom filter of all keys in one sstable, same as leveldb
+/// format: [bit array,k (u8)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+}
+
+const MAX_HASH_FUNCTION_NUMBER: usize = 30;
+const MIN_BITS: usize = 64;
+
+impl BloomFilter {
+    pub fn build(key_hashes: &[u32], bits_per_key: usize) -> Self {
+        // k=ln(2)*bits_per_key is optimal
+        let k = ((bits_per_key as f64 * 0.69) as usize).clamp(1, MAX_HASH_FUNCTION_NUMBER);
+        let bits = (key_hashes.len() * bits_per_key).max(MIN_BITS);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let mut data = vec![0; bytes + 1];
+        for h in key_hashes {
+            // double hashing, see leveldb bloom.cc
+            let mut h = *h;
+            let delta = h.rotate_right(17);
+            for _ in 0..k {
+                let bit_position = h as usize % bits;
+                data[bit_position / 8] |= 1 << (bit_position % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        data[bytes] = k as u8;
+        BloomFilter { data }
+    }
+
+    pub fn from(data: Vec<u8>) -> Self {
+        BloomFilter { data }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // false if key is definitely not in filter
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if self.data.len() < 2 {
+            return true;
+        }
+        let bytes = self.data.len() - 1;
+        let bits = bytes * 8;
+        let k = self.data[bytes] as usize;
+        if k > MAX_HASH_FUNCTION_NUMBER {
+            // reserved for new encoding
+            return true;
+        }
+        let mut h = hash(key);
+        let delta = h.rotate_right(17);
+        for _ in 0..k {
+            let bit_position = h as usize % bits;
+            if self.data[bit_position / 8] & (1 << (bit_position % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+// murmur like hash, see leveldb hash.cc
+pub fn hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f1d34;
+    const M: u32 = 0xc6a4a793;
+    const R: u32 = 24;
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let w = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        h = h.wrapping_add(w);
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, b) in rest.iter().enumerate() {
+            h = h.wrapping_add((*b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> R;
+    }
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, BloomFilter};
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let hashes: Vec<u32> = keys.iter().map(|k| hash(k.as_bytes())).collect();
+        let filter = BloomFilter::build(&hashes, 10);
+        for k in &keys {
+            assert!(filter.may_contain(k.as_bytes()));
+        }
+
+        let mut false_positive = 0;
+        for i in 1000..11000 {
+            if filter.may_contain(i.to_string().as_bytes()) {
+                false_positive += 1;
+            }
+        }
+        // expect about 1% with 10 bits per key
+        assert!(false_positive < 200, "false positive {}", false_positive);
+
+        let filter = BloomFilter::from(filter.data().to_vec());
+        assert!(filter.may_contain("999".as_bytes()));
+    }
+
+    #[test]
+    fn test_empty_bloom_filter() {
+        let filter = BloomFilter::build(&[], 10);
+        assert!(!filter.may_contain("a".as_bytes()));
+        let filter = BloomFilter::from(vec![]);
+        assert!(filter.may_contain("a".as_bytes()));
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{anyhow, Result};
+
+/// snappy raw format, see https://github.com/google/snappy/blob/main/format_description.txt
+/// [uncompressed length (varint),element 1,element 2 ... element n]
+/// element is literal or copy, only 2 bytes offset copy is used when compress
+const HASH_TABLE_BITS: u32 = 12;
+const MIN_MATCH: usize = 4;
+const MAX_COPY_LEN: usize = 64;
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+const TAG_LITERAL: u8 = 0;
+const TAG_COPY_1: u8 = 1;
+const TAG_COPY_2: u8 = 2;
+const TAG_COPY_4: u8 = 3;
+
+pub fn compress(input: &[u8], output: &mut Vec<u8>) {
+    output.clear();
+    write_varint(output, input.len() as u32);
+
+    // position + 1 of last 4 bytes with same hash, 0 means empty
+    let mut table = vec![0usize; 1 << HASH_TABLE_BITS];
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i + MIN_MATCH <= input.len() {
+        let h = hash(read_u32(input, i));
+        let candidate = table[h];
+        table[h] = i + 1;
+        if candidate == 0
+            || i + 1 - candidate > MAX_OFFSET
+            || read_u32(input, candidate - 1) != read_u32(input, i)
+        {
+            i += 1;
+            continue;
+        }
+        let candidate = candidate - 1;
+        let mut len = MIN_MATCH;
+        while i + len < input.len() && input[candidate + len] == input[i + len] {
+            len += 1;
+        }
+        emit_literal(output, &input[literal_start..i]);
+        emit_copy(output, i - candidate, len);
+        i += len;
+        literal_start = i;
+    }
+    emit_literal(output, &input[literal_start..]);
+}
+
+// return uncompressed length
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize> {
+    let (len, mut position) = read_varint(input)?;
+    let len = len as usize;
+    if len > output.len() {
+        return Err(anyhow!(
+            "uncompressed length {} is larger than buffer {}",
+            len,
+            output.len()
+        ));
+    }
+    let mut out_position = 0;
+    while position < input.len() {
+        let tag = input[position];
+        position += 1;
+        match tag & 3 {
+            TAG_LITERAL => {
+                let mut literal_len = (tag >> 2) as usize;
+                if literal_len >= 60 {
+                    let bytes = literal_len - 59;
+                    let data = slice(input, position, bytes)?;
+                    literal_len = data.iter().rev().fold(0, |acc, b| (acc << 8) | *b as usize);
+                    position += bytes;
+                }
+                literal_len += 1;
+                let data = slice(input, position, literal_len)?;
+                if out_position + literal_len > len {
+                    return Err(anyhow!("literal is out of uncompressed length"));
+                }
+                output[out_position..out_position + literal_len].copy_from_slice(data);
+                out_position += literal_len;
+                position += literal_len;
+            }
+            copy_tag => {
+                let (copy_len, offset) = match copy_tag {
+                    TAG_COPY_1 => {
+                        let data = slice(input, position, 1)?;
+                        position += 1;
+                        (
+                            4 + ((tag >> 2) & 7) as usize,
+                            ((tag as usize >> 5) << 8) | data[0] as usize,
+                        )
+                    }
+                    TAG_COPY_2 => {
+                        let data = slice(input, position, 2)?;
+                        position += 2;
+                        (
+                            (tag >> 2) as usize + 1,
+                            u16::from_le_bytes([data[0], data[1]]) as usize,
+                        )
+                    }
+                    TAG_COPY_4 => {
+                        let data = slice(input, position, 4)?;
+                        position += 4;
+                        (
+                            (tag >> 2) as usize + 1,
+                            u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize,
+                        )
+                    }
+                    _ => unreachable!(),
+                };
+                if offset == 0 || offset > out_position || out_position + copy_len > len {
+                    return Err(anyhow!("invalid copy, offset {} len {}", offset, copy_len));
+                }
+                // copy may overlap with itself, so copy byte by byte
+                for _ in 0..copy_len {
+                    output[out_position] = output[out_position - offset];
+                    out_position += 1;
+                }
+            }
+        }
+    }
+    if out_position != len {
+        return Err(anyhow!(
+            "uncompressed length is {}, expect {}",
+            out_position,
+            len
+        ));
+    }
+    Ok(len)
+}
+
+fn emit_literal(output: &mut Vec<u8>, literal: &[u8]) {
+    if literal.is_empty() {
+        return;
+    }
+    let n = literal.len() - 1;
+    if n < 60 {
+        output.push((n as u8) << 2 | TAG_LITERAL);
+    } else {
+        let bytes = (usize::BITS - n.leading_zeros()).div_ceil(8) as usize;
+        output.push(((59 + bytes) as u8) << 2 | TAG_LITERAL);
+        output.extend_from_slice(&n.to_le_bytes()[..bytes]);
+    }
+    output.extend_from_slice(literal);
+}
+
+fn emit_copy(output: &mut Vec<u8>, offset: usize, mut len: usize) {
+    while len > 0 {
+        let n = len.min(MAX_COPY_LEN);
+        output.push(((n - 1) as u8) << 2 | TAG_COPY_2);
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        len -= n;
+    }
+}
+
+fn hash(v: u32) -> usize {
+    (v.wrapping_mul(0x1e35a7bd) >> (32 - HASH_TABLE_BITS)) as usize
+}
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes([
+        data[position],
+        data[position + 1],
+        data[position + 2],
+        data[position + 3],
+    ])
+}
+
+fn slice(data: &[u8], position: usize, len: usize) -> Result<&[u8]> {
+    data.get(position..position + len)
+        .ok_or_else(|| anyhow!("compressed data is truncated"))
+}
+
+fn write_varint(output: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        output.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    output.push(v as u8);
+}
+
+// return (value,bytes read)
+fn read_varint(data: &[u8]) -> Result<(u32, usize)> {
+    let mut res = 0u32;
+    for (i, b) in data.iter().enumerate().take(5) {
+        res |= ((b & 0x7f) as u32) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok((res, i + 1));
+        }
+    }
+    Err(anyhow!("invalid varint"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, decompress};
+
+    fn check_round_trip(input: &[u8]) -> usize {
+        let mut compressed = Vec::new();
+        compress(input, &mut compressed);
+        let mut output = vec![0; input.len()];
+        let len = decompress(&compressed, &mut output).unwrap();
+        assert_eq!(len, input.len());
+        assert_eq!(output, input);
+        compressed.len()
+    }
+
+    #[test]
+    fn test_compress_and_decompress() {
+        check_round_trip(&[]);
+        check_round_trip("a".as_bytes());
+        check_round_trip("abcabcabcabcabcabcabc".as_bytes());
+
+        let mut json = String::new();
+        for i in 0..100 {
+            json.push_str(&format!(
+                "{{\"id\":{},\"name\":\"user_{}\",\"active\":true}}",
+                i, i
+            ));
+        }
+        let size = check_round_trip(json.as_bytes());
+        assert!(size < json.len() / 2, "compressed size {}", size);
+
+        // long literal and long match
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        check_round_trip(&data);
+        check_round_trip(&vec![1; 5000]);
+    }
+
+    #[test]
+    fn test_decompress_invalid_data() {
+        let mut compressed = Vec::new();
+        compress("abcabcabcabcabcabcabc".as_bytes(), &mut compressed);
+        let mut output = vec![0; 100];
+        // truncated
+        assert!(decompress(&compressed[..compressed.len() - 1], &mut output).is_err());
+        // buffer too small
+        assert!(decompress(&compressed, &mut output[..3]).is_err());
+        // copy before start of output
+        assert!(decompress(&[4, 0b0000_1110, 10, 0], &mut output).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::{bounded, Sender};
+
+use crate::db::config::{ReadOptions, TransactionMode};
+use crate::db::key::{Key, SeqNumber};
+use crate::db::lock_manager::{LockManager, LockMode, TransactionId};
+use crate::db::snapshot::{Snapshot, SnapshotList};
+use crate::db::value::Value;
+use crate::db::write_batch::{Operation, WriteBatch};
+
+use super::{check_write_batch, get_with_sequence, ThreadSafeData, WriteRequest};
+
+/// transaction created by DBClient::begin_transaction, writes are buffered until commit
+/// get reads db when transaction begins and writes of transaction
+/// optimistic: commit fails with ConflictError if a key it read from db is written by others after it begins,
+/// or with TransactionTooOldError if flushed memtables with writes after it begins are dropped from memory,
+/// nothing is written then and transaction can be retried
+/// pessimistic: put, delete and get_for_update lock key until transaction is dropped,
+/// so keys it read for update can't be changed by other transactions
+pub struct Transaction {
+    id: TransactionId,
+    mode: TransactionMode,
+    // versions read by transaction are kept until it is finished
+    snapshot: Snapshot,
+    data: ThreadSafeData,
+    snapshot_list: Arc<SnapshotList>,
+    write_request_sender: Sender<WriteRequest>,
+    lock_manager: Arc<LockManager>,
+    lock_timeout: Duration,
+    write_batch: WriteBatch,
+    // key read from db -> sequence number it is read at, checked by write routine on commit
+    read_keys: HashMap<Key, SeqNumber>,
+    // released when transaction is dropped
+    locked_keys: HashSet<Key>,
+}
+
+/// keys read by transaction, batch is not written if any of them has a version newer than it is read
+pub struct ConflictCheck {
+    pub read_keys: Vec<(Key, SeqNumber)>,
+}
+
+impl Transaction {
+    pub(super) fn new(
+        mode: TransactionMode,
+        data: ThreadSafeData,
+        snapshot_list: Arc<SnapshotList>,
+        write_request_sender: Sender<WriteRequest>,
+        lock_manager: Arc<LockManager>,
+        lock_timeout: Duration,
+    ) -> Self {
+        Transaction {
+            id: lock_manager.new_transaction_id(),
+            mode,
+            snapshot: Snapshot::new(data.clone(), snapshot_list.clone()),
+            data,
+            snapshot_list,
+            write_request_sender,
+            lock_manager,
+            lock_timeout,
+            write_batch: WriteBatch::new(),
+            read_keys: HashMap::new(),
+            locked_keys: HashSet::new(),
+        }
+    }
+
+    // sequence number of db it reads
+    pub fn start_sequence(&self) -> SeqNumber {
+        self.snapshot.sequence()
+    }
+
+    // key is not locked, it is checked on commit only in optimistic mode
+    pub fn get(&mut self, key: &Key) -> Result<Option<Value>> {
+        if let Some(value) = self.buffered_value(key) {
+            return Ok(value);
+        }
+        if self.mode == TransactionMode::Optimistic {
+            self.read_keys
+                .entry(key.clone())
+                .or_insert(self.snapshot.sequence());
+        }
+        self.snapshot.get(key)
+    }
+
+    // same as get in optimistic mode
+    // pessimistic mode locks key and reads its newest value, exclusive lock blocks other readers for update
+    pub fn get_for_update(&mut self, key: &Key, exclusive: bool) -> Result<Option<Value>> {
+        if self.mode == TransactionMode::Optimistic {
+            return self.get(key);
+        }
+        let mode = if exclusive {
+            LockMode::Exclusive
+        } else {
+            LockMode::Shared
+        };
+        self.lock(key, mode)?;
+        if let Some(value) = self.buffered_value(key) {
+            return Ok(value);
+        }
+        // writes without transaction don't lock key, they are still checked on commit
+        let seq = self.snapshot_list.last_sequence();
+        self.read_keys.entry(key.clone()).or_insert(seq);
+        get_with_sequence(&self.data, key, seq, &ReadOptions::new())
+    }
+
+    pub fn put(&mut self, key: &Key, value: Value) -> Result<()> {
+        self.lock_for_write(key)?;
+        self.write_batch.put(key.clone(), value);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &Key) -> Result<()> {
+        self.lock_for_write(key)?;
+        self.write_batch.delete(key.clone());
+        Ok(())
+    }
+
+    // write buffered writes atomically, drop transaction to roll back
+    pub fn commit(mut self) -> Result<()> {
+        if self.write_batch.is_empty() {
+            return Ok(());
+        }
+        check_write_batch(&self.write_batch)?;
+        let (sender, receiver) = bounded(1);
+        let write_batch = mem::take(&mut self.write_batch);
+        let mut request = WriteRequest::new(sender, write_batch);
+        request.conflict_check = Some(ConflictCheck {
+            read_keys: mem::take(&mut self.read_keys).into_iter().collect(),
+        });
+        self.write_request_sender
+            .send(request)
+            .map_err(|_| anyhow!("db is closed"))?;
+        // locks are released after batch is written
+        receiver.recv()?
+    }
+
+    fn lock_for_write(&mut self, key: &Key) -> Result<()> {
+        if self.mode == TransactionMode::Pessimistic {
+            self.lock(key, LockMode::Exclusive)?;
+        }
+        Ok(())
+    }
+
+    fn lock(&mut self, key: &Key, mode: LockMode) -> Result<()> {
+        self.lock_manager
+            .lock(self.id, key, mode, self.lock_timeout)?;
+        self.locked_keys.insert(key.clone());
+        Ok(())
+    }
+
+    // newest value of key written by transaction, none if it is not written
+    fn buffered_value(&self, key: &Key) -> Option<Option<Value>> {
+        self.write_batch
+            .to_opertions()
+            .iter()
+            .rev()
+            .find_map(|op| match op {
+                Operation::PUT { key: k, value } if k == key => Some(Some(value.clone())),
+                Operation::DELETE { key: k } if k == key => Some(None),
+                _ => None,
+            })
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.locked_keys.is_empty() {
+            self.lock_manager.unlock(self.id, self.locked_keys.drain());
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use core::slice;
 use std::fmt::{Display, Formatter};
 use std::slice::from_raw_parts;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 
@@ -13,6 +14,12 @@ pub struct Value {
 pub struct ValueSlice {
     ptr: *const u8,
     size: usize,
+    // data is pointer to value in blob file
+    blob_pointer: bool,
+    // data is operand written by merge
+    merge_operand: bool,
+    // milliseconds since unix epoch after which value reads as absent, 0 if it never expires
+    expire_time: u64,
 }
 
 impl ValueSlice {
@@ -20,11 +27,48 @@ impl ValueSlice {
         ValueSlice {
             ptr: v.as_ptr(),
             size: v.len(),
+            blob_pointer: false,
+            merge_operand: false,
+            expire_time: 0,
         }
     }
+    pub fn new_blob_pointer(v: &[u8]) -> Self {
+        ValueSlice {
+            blob_pointer: true,
+            ..Self::new(v)
+        }
+    }
+    pub fn new_merge_operand(v: &[u8]) -> Self {
+        ValueSlice {
+            merge_operand: true,
+            ..Self::new(v)
+        }
+    }
+    pub fn is_blob_pointer(&self) -> bool {
+        self.blob_pointer
+    }
+    pub fn is_merge_operand(&self) -> bool {
+        self.merge_operand
+    }
+    pub fn with_expire_time(mut self, expire_time: u64) -> Self {
+        self.expire_time = expire_time;
+        self
+    }
+    pub fn expire_time(&self) -> u64 {
+        self.expire_time
+    }
+    // now is milliseconds since unix epoch
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire_time != 0 && self.expire_time <= now
+    }
     pub fn len(&self) -> usize {
         self.size
     }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+    /// # Safety
+    /// data the slice points to must be alive and unchanged
     pub unsafe fn data(&self) -> &[u8] {
         slice::from_raw_parts(self.ptr, self.size)
     }
@@ -40,8 +84,16 @@ impl Display for ValueSlice {
     }
 }
 
+// limit of value saved in sstable block, larger value is saved in blob file
 pub const VALUE_SIZE_LIMIT: usize = 1024;
 
+// milliseconds since unix epoch, compared with expire time of value
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
 impl Value {
     pub fn from_u8(s: &[u8]) -> Self {
         Value { v: Vec::from(s) }
@@ -50,7 +102,6 @@ impl Value {
         Self::new(&s.to_string())
     }
     pub fn new(s: &str) -> Self {
-        assert!(s.len() < VALUE_SIZE_LIMIT);
         Value {
             v: Vec::from(s.as_bytes()),
         }
@@ -62,6 +113,10 @@ impl Value {
     pub fn len(&self) -> usize {
         self.data().len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.v.is_empty()
+    }
 }
 
 #[cfg(test)]
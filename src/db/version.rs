@@ -2,25 +2,31 @@ use crossbeam::channel::Sender;
 use metrics::histogram;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use log::{error, info};
 
+use crate::db::blob::{BlobSeparateIter, BlobWriter};
+use crate::db::common::{CompactKVIter, IterError};
 use crate::db::config;
-use crate::db::config::Config;
+use crate::db::config::{CompactionStyle, Config, ReadOptions};
 use crate::db::db_metrics::READ_HIT_SSTABLE_LEVEL;
 use crate::db::file_storage::{FileId, FileStorageManager, ThreadSafeFileManager};
-use crate::db::key::Key;
+use crate::db::key::{Key, SeqNumber, MAX_SEQUENCE};
 use crate::db::level::{
-    CompactSStableResult, Level, LevelChange, SStableFileMeta, ThreadSafeSSTableMetaCache,
+    CompactSStableResult, Level, LevelChange, LevelIter, SStableFileMeta,
+    ThreadSafeSSTableMetaCache,
 };
 use crate::db::memtable::Memtable;
+use crate::db::merge_operator;
 use crate::db::meta_log::{MetaLog, MetaLogIter};
-use crate::db::sstable::SSTable;
-use crate::db::value::Value;
+use crate::db::range_tombstone::RangeTombstones;
+use crate::db::sstable::{SSTable, ThreadSafeBlockCache};
+use crate::db::value::{now_millis, Value};
 
 use super::common::ValueWithTag;
 use super::db_metrics::{DBMetric, TimeRecorder, SSTABLE_COMPACT_TIME};
@@ -32,10 +38,38 @@ pub struct Version {
     // all level info,order by level number,vec[0]->level 0
     levels: HashMap<usize, Level>,
     sstable_cache: ThreadSafeSSTableMetaCache,
+    block_cache: ThreadSafeBlockCache,
     file_manager: ThreadSafeFileManager,
     home_path: PathBuf,
     config: Config,
     file_id_sender: Sender<HashSet<FileId>>,
+    // memtable logs with number smaller than it are persisted in sstable
+    memtable_log_number: u64,
+    // largest sequence number of writes persisted in sstable
+    last_sequence: SeqNumber,
+}
+
+// one sstable of level and sstables of next level overlap with it, they are merged to next level
+// or sorted runs of level 0 are merged together in universal compaction
+#[derive(Clone)]
+pub struct CompactionTask {
+    style: CompactionStyle,
+    level: usize,
+    // one sstable of level, or sorted runs from new to old in universal compaction
+    sstables: Vec<SStableFileMeta>,
+    input_file_ids: Vec<FileId>,
+    // key range of sstables written to next level, none in universal compaction
+    output_range: Option<(Key, Key)>,
+    // picked by compact range or for expired kvs, sstable is rewritten when it is compacted to bottom level
+    manual: bool,
+    // sstable is rewritten in its own level instead of merged to next level, for sstable in bottom level
+    rewrite: bool,
+}
+
+// compactions picked but not installed, shared by compaction threads
+pub struct CompactingFiles {
+    tasks: HashMap<u64, CompactionTask>,
+    next_task_id: u64,
 }
 
 impl Version {
@@ -43,15 +77,20 @@ impl Version {
         home_path: &Path,
         file_manager: ThreadSafeFileManager,
         sstable_cache: ThreadSafeSSTableMetaCache,
+        block_cache: ThreadSafeBlockCache,
         file_id_sender: Sender<HashSet<FileId>>,
+        config: Config,
     ) -> Self {
         Version {
             levels: HashMap::new(),
             sstable_cache,
+            block_cache,
             file_manager,
             home_path: PathBuf::from(home_path),
-            config: Config::new(),
+            config,
             file_id_sender,
+            memtable_log_number: 0,
+            last_sequence: 0,
         }
     }
 
@@ -63,127 +102,620 @@ impl Version {
     ) -> Result<Self> {
         let (s, r) = crossbeam::channel::unbounded();
         dump_recv(r);
-        Self::from(level_change_iter, home_path, file_manager, sstable_cache, s)
+        Self::from(
+            level_change_iter,
+            home_path,
+            file_manager,
+            sstable_cache,
+            SSTable::new_block_cache(10),
+            s,
+            Config::new(),
+        )
     }
     pub fn from(
         level_change_iter: &mut dyn Iterator<Item = LevelChange>,
         home_path: PathBuf,
         file_manager: ThreadSafeFileManager,
         sstable_cache: ThreadSafeSSTableMetaCache,
+        block_cache: ThreadSafeBlockCache,
         file_id_sender: Sender<HashSet<FileId>>,
+        config: Config,
     ) -> Result<Self> {
         // iter meta log,get level change
         let mut level_sstable_file_metas: HashMap<usize, Vec<SStableFileMeta>> = HashMap::new();
+        let mut memtable_log_number = 0;
+        let mut last_sequence = 0;
         for level_change in level_change_iter {
             // let level_change: LevelChange = serde_json::from_slice(data?.as_slice())?;
-            Version::apply_level_change(&mut level_sstable_file_metas, level_change)
+            Version::apply_level_change(
+                &mut level_sstable_file_metas,
+                &mut memtable_log_number,
+                &mut last_sequence,
+                level_change,
+            )
+        }
+        // sstable size and create time are not saved in meta log of old version
+        for meta in level_sstable_file_metas.values_mut().flatten() {
+            if meta.file_size() == 0 || meta.create_time() == 0 {
+                let path = FileStorageManager::file_path(&home_path, &meta.file_id());
+                let file_metadata = std::fs::metadata(path)?;
+                meta.set_file_size(file_metadata.len());
+                let modified = file_metadata.modified()?.duration_since(UNIX_EPOCH)?;
+                meta.set_create_time(modified.as_secs());
+            }
         }
         let mut levels = HashMap::new();
         Version::build_level(
             &home_path,
             &file_manager,
             &sstable_cache,
+            &block_cache,
             &mut level_sstable_file_metas,
             &mut levels,
         );
         Ok(Version {
             levels,
             sstable_cache,
+            block_cache,
             file_manager,
             home_path,
-            config: Config::new(),
+            config,
             file_id_sender,
+            memtable_log_number,
+            last_sequence,
         })
     }
 
-    // pick and find one level to compact
-    pub fn compact_one_level(&self) -> Result<Option<LevelChange>> {
-        // pick one level from 0 to n
-        let depth = self.depth();
-        for level_number in 0..depth {
-            let level_option = self.levels.get(&level_number);
-            if level_option.is_none() {
-                continue;
+    // pick and find one level to compact, versions read by snapshots are kept
+    pub fn compact_one_level(&self, snapshots: &[SeqNumber]) -> Result<Option<LevelChange>> {
+        match self.pick_compaction(&CompactingFiles::new()) {
+            Some(task) => Ok(Some(self.compact(&task, snapshots)?)),
+            None => Ok(None),
+        }
+    }
+
+    // skip files and key ranges used by running compactions
+    pub fn pick_compaction(&self, compacting: &CompactingFiles) -> Option<CompactionTask> {
+        let task = match self.config.compaction_style {
+            CompactionStyle::Level => self.pick_level_compaction(compacting),
+            CompactionStyle::Universal => self.pick_universal_compaction(compacting),
+            CompactionStyle::Fifo => return self.pick_fifo_compaction(compacting),
+        };
+        task.or_else(|| self.pick_expired_compaction(compacting))
+            .or_else(|| self.pick_blob_gc_compaction(compacting))
+    }
+
+    // find one sstable to compact from level with highest score
+    fn pick_level_compaction(&self, compacting: &CompactingFiles) -> Option<CompactionTask> {
+        let mut level_scores: Vec<(usize, f64)> = (0..self.depth())
+            .filter_map(|l| {
+                self.levels
+                    .get(&l)
+                    .map(|level| (l, self.level_score(l, level)))
+            })
+            .filter(|(_, score)| *score > 1.0)
+            .collect();
+        level_scores.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        for (level_number, score) in level_scores {
+            let level = self.levels.get(&level_number).unwrap();
+            let next_level = self.levels.get(&(level_number + 1));
+            let new_task = |meta: &SStableFileMeta| {
+                let task = CompactionTask::new(level_number, meta, next_level);
+                if compacting.conflict(&task) {
+                    None
+                } else {
+                    Some(task)
+                }
+            };
+            let task = if level_number == 0 {
+                // sstables in level 0 may overlap, newer one can't go to level 1 before older one
+                level
+                    .pick_file_to_compact(|meta| !compacting.contains(meta))
+                    .and_then(new_task)
+            } else {
+                level
+                    .pick_file_with_min_overlap(next_level, |meta| new_task(meta).is_some())
+                    .and_then(new_task)
+            };
+            if task.is_some() {
+                info!(
+                    "pick level {} with score {} to compact",
+                    level_number, score
+                );
+                return task;
             }
-            let level = level_option.unwrap();
-            let len = level.len();
-            if len > Self::level_file_number_limit(level_number, &self.config) {
-                info!("start compact level {}", level_number);
-                let res = self.do_compact(level_number, level);
-                info!("{} compact finished", level_number);
-                return res;
+        }
+        None
+    }
+
+    // compact sstable with expired values when no level needs compaction, so they don't stay forever
+    // level 0 sstables must go down from the oldest one, sstable in bottom level is rewritten in place
+    // universal style rewrites the sorted run in level 0
+    fn pick_expired_compaction(&self, compacting: &CompactingFiles) -> Option<CompactionTask> {
+        let now = now_millis();
+        if self.config.compaction_style == CompactionStyle::Universal {
+            let metas = self.levels.get(&0)?.copy_sstable_meta();
+            let meta = metas
+                .iter()
+                .find(|meta| meta.has_expired_value(now) && !compacting.contains(meta))?;
+            info!("pick sorted run {} to drop expired kvs", meta.file_id());
+            let mut task =
+                CompactionTask::new_level_0(CompactionStyle::Universal, std::slice::from_ref(meta));
+            task.manual = true;
+            return Some(task);
+        }
+        let bottom = self.depth().saturating_sub(1).max(1);
+        for level_number in 0..bottom {
+            let level = match self.levels.get(&level_number) {
+                Some(level) => level,
+                None => continue,
+            };
+            let metas = level.copy_sstable_meta();
+            let meta = match metas.iter().find(|meta| meta.has_expired_value(now)) {
+                // level 0 files overlap, compact the oldest one first to keep newer kvs above
+                Some(_) if level_number == 0 => match level.pick_file_to_compact(|_| true) {
+                    Some(oldest) => oldest,
+                    None => continue,
+                },
+                Some(meta) => meta,
+                None => continue,
+            };
+            let mut task =
+                CompactionTask::new(level_number, meta, self.levels.get(&(level_number + 1)));
+            if compacting.conflict(&task) {
+                continue;
             }
+            info!(
+                "pick sstable {} in level {} to drop expired kvs",
+                meta.file_id(),
+                level_number
+            );
+            task.manual = true;
+            return Some(task);
         }
-        return Ok(None);
+        let metas = self.levels.get(&bottom)?.copy_sstable_meta();
+        metas
+            .iter()
+            .filter(|meta| meta.has_expired_value(now))
+            .map(|meta| CompactionTask::new_rewrite(bottom, meta))
+            .find(|task| !compacting.conflict(task))
+            .inspect(|task| {
+                info!(
+                    "rewrite sstable in bottom level {} to drop expired kvs",
+                    bottom
+                )
+            })
     }
 
-    fn do_compact(&self, level_number: usize, level: &Level) -> Result<Option<LevelChange>> {
-        let recorder = TimeRecorder::new(SSTABLE_COMPACT_TIME);
+    // rewrite sstable pointing to blob file with much garbage when no level needs compaction,
+    // so its live values are moved and the file is deleted after all sstables pointing to it are rewritten
+    // level 0 sstables of level style are left to level compaction
+    fn pick_blob_gc_compaction(&self, compacting: &CompactingFiles) -> Option<CompactionTask> {
+        let garbage_blob_files = self.garbage_blob_files();
+        if garbage_blob_files.is_empty() {
+            return None;
+        }
+        let has_garbage = |meta: &SStableFileMeta| {
+            meta.blob_file_ids()
+                .iter()
+                .any(|id| garbage_blob_files.contains(id))
+        };
+        for (level_number, level) in &self.levels {
+            for meta in level.copy_sstable_meta().iter().filter(|m| has_garbage(m)) {
+                let task = match (level_number, self.config.compaction_style) {
+                    (0, CompactionStyle::Level) => break,
+                    (0, _) => {
+                        let mut task = CompactionTask::new_level_0(
+                            CompactionStyle::Universal,
+                            std::slice::from_ref(meta),
+                        );
+                        task.manual = true;
+                        task
+                    }
+                    _ => CompactionTask::new_rewrite(*level_number, meta),
+                };
+                if !compacting.conflict(&task) {
+                    info!(
+                        "rewrite sstable {} in level {} to collect blob files",
+                        meta.file_id(),
+                        level_number
+                    );
+                    return Some(task);
+                }
+            }
+        }
+        None
+    }
 
-        let sstable_for_compact = level.pick_file_to_compact();
-        let next_level_number = level_number + 1;
-        let next_level_option = self.levels.get(&(&next_level_number));
-        // next level is empty just remove sstable from current level and put them to next level
-        if next_level_option.is_none() {
-            let level_change = LevelChange::LevelCompact {
-                compact_from_level: level_number,
-                compact_sstable: sstable_for_compact.clone(),
-                compact_result: CompactSStableResult {
-                    remove_sstables: vec![],
-                    add_sstables: vec![sstable_for_compact.clone()],
-                    position: 0,
-                },
+    // blob files which ratio of bytes not pointed by any sstable is not less than blob_garbage_ratio
+    // blob file pointed by sstable of old meta log is never collected as its live bytes are unknown
+    pub fn garbage_blob_files(&self) -> HashSet<FileId> {
+        let mut res = HashSet::new();
+        if self.config.blob_garbage_ratio >= 1.0 {
+            return res;
+        }
+        let mut live_bytes: HashMap<FileId, Option<u64>> = HashMap::new();
+        for level in self.levels.values() {
+            for meta in level.copy_sstable_meta() {
+                match meta.blob_live_bytes() {
+                    Some(iter) => iter.for_each(|(id, bytes)| {
+                        if let Some(live) = live_bytes.entry(id).or_insert(Some(0)) {
+                            *live += bytes;
+                        }
+                    }),
+                    None => meta.blob_file_ids().iter().for_each(|id| {
+                        live_bytes.insert(*id, None);
+                    }),
+                }
+            }
+        }
+        for (file_id, live) in live_bytes {
+            let live = match live {
+                Some(live) => live,
+                None => continue,
             };
-            return Ok(Some(level_change));
+            let path = FileStorageManager::file_path(&self.home_path, &file_id);
+            let size = match fs::metadata(path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            if size > 0 && 1.0 - live as f64 / size as f64 >= self.config.blob_garbage_ratio {
+                res.insert(file_id);
+            }
+        }
+        res
+    }
+
+    // merge consecutive sorted runs (level 0 sstables) not in compaction, from new to old
+    // start from newest runs, older run is added if its size is not much larger than runs added
+    // if no runs have similar size, newest runs are merged to reduce number of runs
+    fn pick_universal_compaction(&self, compacting: &CompactingFiles) -> Option<CompactionTask> {
+        let sorted_runs = self.levels.get(&0)?.copy_sstable_meta();
+        let max_sorted_runs = self.config.universal_max_sorted_runs;
+        if sorted_runs.len() <= max_sorted_runs {
+            return None;
+        }
+        let size_ratio = 100 + self.config.universal_size_ratio as u64;
+        for start in 0..sorted_runs.len() {
+            if compacting.contains(&sorted_runs[start]) {
+                continue;
+            }
+            let mut size = sorted_runs[start].file_size();
+            let mut end = start + 1;
+            while end < sorted_runs.len()
+                && !compacting.contains(&sorted_runs[end])
+                && sorted_runs[end].file_size() * 100 <= size * size_ratio
+            {
+                size += sorted_runs[end].file_size();
+                end += 1;
+            }
+            if end - start >= 2 {
+                info!(
+                    "pick {} sorted runs with similar size to compact",
+                    end - start
+                );
+                return Some(CompactionTask::new_level_0(
+                    CompactionStyle::Universal,
+                    &sorted_runs[start..end],
+                ));
+            }
+        }
+        let start = sorted_runs
+            .iter()
+            .position(|meta| !compacting.contains(meta))?;
+        let number = (sorted_runs.len() - max_sorted_runs + 1).max(2);
+        let runs: Vec<SStableFileMeta> = sorted_runs[start..]
+            .iter()
+            .take_while(|meta| !compacting.contains(meta))
+            .take(number)
+            .cloned()
+            .collect();
+        if runs.len() < 2 {
+            return None;
+        }
+        info!(
+            "too many sorted runs, pick {} newest to compact",
+            runs.len()
+        );
+        Some(CompactionTask::new_level_0(
+            CompactionStyle::Universal,
+            &runs,
+        ))
+    }
+
+    // drop oldest sstables of level 0 until total size and their age are in limit
+    fn pick_fifo_compaction(&self, compacting: &CompactingFiles) -> Option<CompactionTask> {
+        let metas = self.levels.get(&0)?.copy_sstable_meta();
+        let size_limit = self.config.fifo_max_table_files_size as u64;
+        let ttl = self.config.fifo_ttl.as_secs();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let mut total_size: u64 = metas.iter().map(|meta| meta.file_size()).sum();
+        let mut sstables = Vec::new();
+        // from old to new
+        for meta in metas.iter().rev() {
+            let too_large = size_limit > 0 && total_size > size_limit;
+            let expired = ttl > 0 && meta.create_time() + ttl < now;
+            if !(too_large || expired) || compacting.contains(meta) {
+                break;
+            }
+            total_size -= meta.file_size();
+            sstables.push(meta.clone());
+        }
+        if sstables.is_empty() {
+            return None;
         }
+        info!("pick {} oldest sstables to drop", sstables.len());
+        Some(CompactionTask::new_level_0(
+            CompactionStyle::Fifo,
+            &sstables,
+        ))
+    }
+
+    // next compaction of sstables overlapping [start_key,end_key] in compact range, none if it is finished
+    // level style moves them level by level to bottom level and rewrites bottom level, universal style merges
+    // sorted runs overlapping range
+    // only sstables in file_ids (files when compact range starts) are waited in level 0 and rewritten in bottom
+    // level, so sstables flushed or written by compact range don't make it endless
+    pub fn pick_range_compaction(
+        &self,
+        start_key: &Key,
+        end_key: &Key,
+        file_ids: &HashSet<FileId>,
+    ) -> Option<CompactionTask> {
+        let overlap = |meta: &SStableFileMeta| {
+            file_ids.contains(&meta.file_id())
+                && meta.start_key().le(end_key)
+                && meta.last_key().ge(start_key)
+        };
+        let mut task = match self.config.compaction_style {
+            CompactionStyle::Level => self.pick_level_range_compaction(start_key, end_key, overlap),
+            CompactionStyle::Universal => {
+                let metas = self.levels.get(&0)?.copy_sstable_meta();
+                let first = metas.iter().position(overlap)?;
+                let last = metas.iter().rposition(overlap)?;
+                Some(CompactionTask::new_level_0(
+                    CompactionStyle::Universal,
+                    &metas[first..last + 1],
+                ))
+            }
+            // sstables are never merged
+            CompactionStyle::Fifo => None,
+        }?;
+        task.manual = true;
+        Some(task)
+    }
 
-        let next_level = next_level_option.unwrap();
-        // pick files  do compact
+    fn pick_level_range_compaction(
+        &self,
+        start_key: &Key,
+        end_key: &Key,
+        overlap: impl Fn(&SStableFileMeta) -> bool,
+    ) -> Option<CompactionTask> {
+        let bottom = self.depth().saturating_sub(1).max(1);
+        if let Some(level_0) = self.levels.get(&0) {
+            // older sstables in level 0 must be compacted first
+            if level_0.copy_sstable_meta().iter().any(&overlap) {
+                let oldest = level_0.pick_file_to_compact(|_| true).unwrap();
+                return Some(CompactionTask::new(0, oldest, self.levels.get(&1)));
+            }
+        }
+        for level_number in 1..bottom {
+            let level = self.levels.get(&level_number).unwrap();
+            if let Some((metas, _)) = level.key_overlap(start_key, end_key) {
+                return Some(CompactionTask::new(
+                    level_number,
+                    &metas[0],
+                    self.levels.get(&(level_number + 1)),
+                ));
+            }
+        }
+        // sstables moved to bottom level without merging may still have deleted kvs
+        let metas = self.levels.get(&bottom)?.copy_sstable_meta();
+        metas
+            .iter()
+            .find(|meta| overlap(meta))
+            .map(|meta| CompactionTask::new_rewrite(bottom, meta))
+    }
 
-        let next_level_is_depthest = next_level_number == self.depth() - 1;
-        let compact_res = next_level
-            .compact_sstable(vec![sstable_for_compact.clone()], next_level_is_depthest)?;
+    // compact sstables of task, version must be the one task is picked from
+    pub fn compact(&self, task: &CompactionTask, snapshots: &[SeqNumber]) -> Result<LevelChange> {
+        let recorder = TimeRecorder::new(SSTABLE_COMPACT_TIME);
+        match task.style {
+            CompactionStyle::Level => {}
+            CompactionStyle::Universal => return self.compact_sorted_runs(task, snapshots),
+            CompactionStyle::Fifo => {
+                return Ok(LevelChange::DropSStables {
+                    level: task.level,
+                    sstables: task.sstables.clone(),
+                })
+            }
+        }
+
+        if task.rewrite {
+            return self.rewrite_sstable(task, snapshots);
+        }
+        let level_number = task.level;
+        let sstable_for_compact = &task.sstables[0];
+        let next_level_number = level_number + 1;
+        let empty_level;
+        let next_level = match self.levels.get(&next_level_number) {
+            Some(level) => level,
+            // sstable of manual compaction is rewritten to drop deleted kvs
+            None if task.manual => {
+                empty_level = Level::new(
+                    vec![],
+                    self.home_path.clone(),
+                    self.sstable_cache.clone(),
+                    self.block_cache.clone(),
+                    self.file_manager.clone(),
+                );
+                &empty_level
+            }
+            // next level is empty just remove sstable from current level and put them to next level
+            None => {
+                let level_change = LevelChange::LevelCompact {
+                    compact_from_level: level_number,
+                    compact_sstable: sstable_for_compact.clone(),
+                    compact_result: CompactSStableResult {
+                        remove_sstables: vec![],
+                        add_sstables: vec![sstable_for_compact.clone()],
+                        position: 0,
+                    },
+                };
+                return Ok(level_change);
+            }
+        };
+
+        let next_level_is_depthest = next_level_number + 1 >= self.depth();
+        let compact_res = next_level.compact_sstable(
+            vec![sstable_for_compact.clone()],
+            next_level_is_depthest,
+            task.manual && next_level_is_depthest,
+            snapshots,
+            &self.garbage_blob_files(),
+            &self.config,
+        )?;
+        info!("{} compact finished", level_number);
         let level_change = LevelChange::LevelCompact {
             compact_from_level: level_number,
             compact_sstable: sstable_for_compact.clone(),
             compact_result: compact_res,
         };
-        return Ok(Some(level_change));
+        Ok(level_change)
+    }
+
+    fn rewrite_sstable(
+        &self,
+        task: &CompactionTask,
+        snapshots: &[SeqNumber],
+    ) -> Result<LevelChange> {
+        let level = self.levels.get(&task.level).unwrap();
+        let add_sstables = level.rewrite_sstables(
+            task.sstables.clone(),
+            task.level + 1 >= self.depth(),
+            snapshots,
+            &self.garbage_blob_files(),
+            &self.config,
+        )?;
+        info!("sstable in level {} rewrite finished", task.level);
+        Ok(LevelChange::LevelRewrite {
+            level: task.level,
+            remove_sstables: task.sstables.clone(),
+            add_sstables,
+        })
+    }
+
+    fn compact_sorted_runs(
+        &self,
+        task: &CompactionTask,
+        snapshots: &[SeqNumber],
+    ) -> Result<LevelChange> {
+        let level_0 = self.levels.get(&0).unwrap();
+        // deleted kvs are dropped if no older data is left
+        let oldest = level_0.copy_sstable_meta().last().unwrap().file_id();
+        let discard_deleted_kv =
+            self.depth() == 1 && task.sstables.last().unwrap().file_id() == oldest;
+        let add_sstable = level_0.merge_sorted_runs(
+            task.sstables.clone(),
+            discard_deleted_kv,
+            snapshots,
+            &self.garbage_blob_files(),
+            &self.config,
+        )?;
+        info!("{} sorted runs compact finished", task.sstables.len());
+        Ok(LevelChange::SortedRunCompact {
+            remove_sstables: task.sstables.clone(),
+            add_sstables: add_sstable.into_iter().collect(),
+        })
     }
 
     pub fn get_str(&self, key: &str) -> Result<Option<Value>> {
         self.get(&Key::new(key))
     }
     pub fn get(&self, key: &Key) -> Result<Option<Value>> {
+        self.get_with_options(key, MAX_SEQUENCE, &ReadOptions::new())
+    }
+    // newest version of key which sequence number is not greater than seq
+    pub fn get_with_options(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        options: &ReadOptions,
+    ) -> Result<Option<Value>> {
+        self.get_with_operands(key, seq, Vec::new(), options)
+    }
+    // operands are merge operands of key found in memtables from new to old,
+    // they are combined with value found in levels lazily
+    pub fn get_with_operands(
+        &self,
+        key: &Key,
+        seq: SeqNumber,
+        mut operands: Vec<Value>,
+        options: &ReadOptions,
+    ) -> Result<Option<Value>> {
         // call get key from level 0 to level n
-        if self.depth() == 0 {
-            return Ok(None);
-        }
-        let level_0 = self.levels.get(&0).unwrap();
-        let res = level_0.get_in_level_0(key)?;
-        if let Some(taged_value) = res {
-            histogram!(READ_HIT_SSTABLE_LEVEL, 0.0);
-            if let Some(v) = &taged_value {
-                return Ok(Some(v.clone()));
+        for l in 0..self.depth() {
+            let level = self.levels.get(&l).unwrap();
+            let res = if l == 0 {
+                level.get_in_level_0(key, seq, &mut operands, options)?
             } else {
-                return Ok(None);
+                level.get(key, seq, &mut operands, options)?
+            };
+            if let Some(taged_value) = res {
+                histogram!(READ_HIT_SSTABLE_LEVEL, l as f64);
+                return self.merge(key, taged_value, operands);
             }
         }
-
-        for l in 1..self.depth() {
+        self.merge(key, None, operands)
+    }
+    // combine merge operands ordered from new to old with existing value by merge operator of config
+    pub fn merge(
+        &self,
+        key: &Key,
+        existing: Option<Value>,
+        operands: Vec<Value>,
+    ) -> Result<Option<Value>> {
+        merge_operator::merge_value(
+            self.config.merge_operator.as_deref(),
+            key,
+            existing,
+            operands,
+        )
+    }
+    // iters of all levels for key in [start_key,end_key), order by priority: level 0 sstables from new to old, then level 1 to n
+    // iter which fails to read sstable stops and sets error
+    pub fn range_iters(
+        &self,
+        start_key: &Key,
+        end_key: Option<&Key>,
+        options: &ReadOptions,
+        error: &IterError,
+    ) -> Vec<LevelIter> {
+        let mut res = Vec::new();
+        for l in 0..self.depth() {
             let level = self.levels.get(&l).unwrap();
-            let res = level.get(key)?;
-            if let Some(taged_value) = res {
-                histogram!(READ_HIT_SSTABLE_LEVEL, l as f64);
-                if let Some(v) = &taged_value {
-                    return Ok(Some(v.clone()));
-                } else {
-                    return Ok(None);
-                }
+            if l == 0 {
+                res.append(&mut level.range_iters_in_level_0(start_key, end_key, options, error));
+            } else {
+                res.push(level.range_iter(start_key, end_key, options, error));
             }
         }
-        Ok(None)
+        res
     }
+
+    // range tombstones of all levels which may delete key in [start_key,end_key)
+    pub fn range_tombstones(
+        &self,
+        start_key: &Key,
+        end_key: Option<&Key>,
+    ) -> Result<RangeTombstones> {
+        let mut res = RangeTombstones::new();
+        for level in self.levels.values() {
+            res.extend(&level.range_tombstones(start_key, end_key)?);
+        }
+        Ok(res)
+    }
+
     // for test
     pub fn get_level_for_test(&self, level: usize) -> &Level {
         self.levels.get(&level).unwrap()
@@ -193,41 +725,102 @@ impl Version {
         self.config = config
     }
 
-    pub fn add_memtable_to_level_0(&self, memtable: &Memtable) -> Result<LevelChange> {
-        // build sstable from memtable (sstable::build)
-        let mut iter = memtable.iter();
+    // memtable_log_number is number of log after memtable's logs
+    // versions of memtable read by snapshots are kept
+    pub fn add_memtable_to_level_0(
+        &self,
+        memtable: &Memtable,
+        memtable_log_number: u64,
+        snapshots: &[SeqNumber],
+    ) -> Result<LevelChange> {
+        // build sstable from memtable (sstable::build), large values are written to blob file
+        let mut blob_writer = BlobWriter::new(self.file_manager.clone());
+        let mut range_tombstones = memtable.range_tombstones();
+        let mut iter = BlobSeparateIter::new(
+            CompactKVIter::new(memtable.iter(), snapshots.to_vec(), false)
+                .with_range_tombstones(range_tombstones.clone())
+                .with_merge_operator(self.config.merge_operator.clone()),
+            &mut blob_writer,
+            self.config.blob_value_threshold,
+        );
         let (file, file_id, _) = self.file_manager.lock().unwrap().new_file()?;
-        let (sstable_opt, has_next) = SSTable::from_iter_with_file_limit(&mut iter, file, 0)?;
-        let sstable = sstable_opt.unwrap();
-        let sstable_meta = SStableFileMeta::from(&sstable, file_id);
+        // no file limit, all kvs are written
+        let (sstable_opt, has_next) = SSTable::from_iter_with_file_limit(
+            &mut iter.by_ref().peekable(),
+            &mut range_tombstones,
+            file,
+            0,
+            &self.config,
+        )?;
         assert!(iter.next().is_none());
+        iter.finish()?;
+        blob_writer.finish()?;
+        let sstable = sstable_opt.unwrap();
+        let sstable_meta = SStableFileMeta::from(&sstable, file_id)?;
         let level_change = LevelChange::MemtableCompact {
             sstable_file_metas: sstable_meta,
+            memtable_log_number,
+            last_sequence: memtable.last_sequence(),
         };
         Ok(level_change)
     }
 
+    // level change which rebuild this version from empty
+    pub fn snapshot(&self) -> LevelChange {
+        let levels = (0..self.levels.len())
+            .map(|l| self.levels.get(&l).unwrap().copy_sstable_meta())
+            .collect();
+        LevelChange::Snapshot {
+            levels,
+            memtable_log_number: self.memtable_log_number,
+            last_sequence: self.last_sequence,
+        }
+    }
+
+    pub fn memtable_log_number(&self) -> u64 {
+        self.memtable_log_number
+    }
+
+    pub fn last_sequence(&self) -> SeqNumber {
+        self.last_sequence
+    }
+
+    pub fn home_path(&self) -> &Path {
+        &self.home_path
+    }
+
     pub fn apply_change(&self, level_change: LevelChange) -> Self {
         let mut map = HashMap::new();
         for (l, level) in &self.levels {
             map.insert(*l, level.copy_sstable_meta());
         }
-        Self::apply_level_change(&mut map, level_change);
+        let mut memtable_log_number = self.memtable_log_number;
+        let mut last_sequence = self.last_sequence;
+        Self::apply_level_change(
+            &mut map,
+            &mut memtable_log_number,
+            &mut last_sequence,
+            level_change,
+        );
         let mut levels = HashMap::new();
         Version::build_level(
             &self.home_path,
             &self.file_manager,
             &self.sstable_cache,
+            &self.block_cache,
             &mut map,
             &mut levels,
         );
         Version {
             levels,
             sstable_cache: self.sstable_cache.clone(),
+            block_cache: self.block_cache.clone(),
             file_manager: self.file_manager.clone(),
             home_path: self.home_path.clone(),
             config: self.config.clone(),
             file_id_sender: self.file_id_sender.clone(),
+            memtable_log_number,
+            last_sequence,
         }
     }
 
@@ -236,7 +829,7 @@ impl Version {
         if self.levels.is_empty() {
             return 0;
         }
-        let max_level = self.levels.keys().max().unwrap_or_else(|| &0);
+        let max_level = self.levels.keys().max().unwrap_or(&0);
         for l in (0..*max_level + 1).rev() {
             let level = self.levels.get(&l).unwrap();
             if level.len() != 0 {
@@ -256,9 +849,10 @@ impl Version {
     }
 
     fn build_level(
-        home_path: &PathBuf,
+        home_path: &Path,
         file_manager: &ThreadSafeFileManager,
         sstable_cache: &ThreadSafeSSTableMetaCache,
+        block_cache: &ThreadSafeBlockCache,
         level_sstable_file_metas: &mut HashMap<usize, Vec<SStableFileMeta>>,
         levels: &mut HashMap<usize, Level>,
     ) {
@@ -267,8 +861,9 @@ impl Version {
             let metas = level_sstable_file_metas.remove(&i).unwrap();
             let level = Level::new(
                 metas,
-                home_path.clone(),
+                home_path.to_path_buf(),
                 sstable_cache.clone(),
+                block_cache.clone(),
                 file_manager.clone(),
             );
             levels.insert(i, level);
@@ -276,7 +871,9 @@ impl Version {
     }
 
     fn apply_level_change(
-        mut level_sstable_file_metas: &mut HashMap<usize, Vec<SStableFileMeta>>,
+        level_sstable_file_metas: &mut HashMap<usize, Vec<SStableFileMeta>>,
+        memtable_log_number: &mut u64,
+        last_sequence: &mut SeqNumber,
         level_change: LevelChange,
     ) {
         match level_change {
@@ -287,29 +884,95 @@ impl Version {
             } => {
                 // remove sstable from level
                 let compact_level_metas: &mut Vec<SStableFileMeta> =
-                    Self::get_or_default(&mut level_sstable_file_metas, compact_from_level);
+                    Self::get_or_default(level_sstable_file_metas, compact_from_level);
                 compact_level_metas.retain(|meta| meta.file_id().ne(&compact_sstable.file_id()));
 
                 // remove and add sstable in next level
+                // other compactions may be applied after position is computed, so find sstables by id and key
                 let next_level_metas: &mut Vec<SStableFileMeta> =
-                    Self::get_or_default(&mut level_sstable_file_metas, compact_from_level + 1);
+                    Self::get_or_default(level_sstable_file_metas, compact_from_level + 1);
+                let remove_ids: HashSet<FileId> = compact_result
+                    .remove_sstables
+                    .iter()
+                    .map(|meta| meta.file_id())
+                    .collect();
+                next_level_metas.retain(|meta| !remove_ids.contains(&meta.file_id()));
 
-                let remove_length = compact_result.remove_sstables.len();
-
-                for _ in 0..remove_length {
-                    next_level_metas.remove(compact_result.position);
-                }
                 let mut add_sstables = compact_result.add_sstables;
-                while !add_sstables.is_empty() {
-                    next_level_metas.insert(compact_result.position, add_sstables.pop().unwrap())
+                if let Some(first) = add_sstables.first() {
+                    // end of sstable equals to start of next one if range tombstone is cut between them
+                    let position = next_level_metas
+                        .partition_point(|meta| meta.last_key().le(&first.start_key()));
+                    while !add_sstables.is_empty() {
+                        next_level_metas.insert(position, add_sstables.pop().unwrap())
+                    }
                 }
             }
             LevelChange::MemtableCompact {
                 sstable_file_metas: sstable_file_meta,
+                memtable_log_number: log_number,
+                last_sequence: sequence,
             } => {
                 let metas: &mut Vec<SStableFileMeta> =
-                    Self::get_or_default(&mut level_sstable_file_metas, 0);
-                metas.insert(0, sstable_file_meta)
+                    Self::get_or_default(level_sstable_file_metas, 0);
+                metas.insert(0, sstable_file_meta);
+                *memtable_log_number = (*memtable_log_number).max(log_number);
+                *last_sequence = (*last_sequence).max(sequence);
+            }
+            LevelChange::LevelRewrite {
+                level,
+                remove_sstables,
+                add_sstables,
+            } => {
+                let metas: &mut Vec<SStableFileMeta> =
+                    Self::get_or_default(level_sstable_file_metas, level);
+                let remove_ids: HashSet<FileId> =
+                    remove_sstables.iter().map(|meta| meta.file_id()).collect();
+                metas.retain(|meta| !remove_ids.contains(&meta.file_id()));
+                if let Some(first) = add_sstables.first() {
+                    let position =
+                        metas.partition_point(|meta| meta.last_key().le(&first.start_key()));
+                    for meta in add_sstables.into_iter().rev() {
+                        metas.insert(position, meta);
+                    }
+                }
+            }
+            LevelChange::DropSStables { level, sstables } => {
+                let metas: &mut Vec<SStableFileMeta> =
+                    Self::get_or_default(level_sstable_file_metas, level);
+                let remove_ids: HashSet<FileId> =
+                    sstables.iter().map(|meta| meta.file_id()).collect();
+                metas.retain(|meta| !remove_ids.contains(&meta.file_id()));
+            }
+            LevelChange::SortedRunCompact {
+                remove_sstables,
+                add_sstables,
+            } => {
+                // newer sorted runs may be flushed after compaction starts, find position by id
+                let metas: &mut Vec<SStableFileMeta> =
+                    Self::get_or_default(level_sstable_file_metas, 0);
+                let remove_ids: HashSet<FileId> =
+                    remove_sstables.iter().map(|meta| meta.file_id()).collect();
+                let position = metas
+                    .iter()
+                    .position(|meta| remove_ids.contains(&meta.file_id()))
+                    .unwrap_or(metas.len());
+                metas.retain(|meta| !remove_ids.contains(&meta.file_id()));
+                for meta in add_sstables.into_iter().rev() {
+                    metas.insert(position, meta);
+                }
+            }
+            LevelChange::Snapshot {
+                levels,
+                memtable_log_number: log_number,
+                last_sequence: sequence,
+            } => {
+                *memtable_log_number = log_number;
+                *last_sequence = sequence;
+                level_sstable_file_metas.clear();
+                for (l, metas) in levels.into_iter().enumerate() {
+                    level_sstable_file_metas.insert(l, metas);
+                }
             }
         }
     }
@@ -322,26 +985,145 @@ impl Version {
             return map.get_mut(&key).unwrap();
         }
         map.insert(key, Vec::new());
-        return map.get_mut(&key).unwrap();
+        map.get_mut(&key).unwrap()
     }
 
-    fn level_file_number_limit(level: usize, config: &Config) -> usize {
-        if level == 0 {
-            return config.level_0_file_limit;
+    // level need compaction if score is greater than 1
+    // level 0 is scored by file number, since each file is read by get and files may overlap
+    fn level_score(&self, level_number: usize, level: &Level) -> f64 {
+        if level_number == 0 {
+            return level.len() as f64 / self.config.level_0_file_limit as f64;
         }
-        ((config.level_size_expand_factor as u32).pow(level as u32) as usize) * 1024 * 1024
-            / config.sstable_file_limit
+        level.size() as f64 / Self::level_size_limit(level_number, &self.config) as f64
+    }
+
+    fn level_size_limit(level: usize, config: &Config) -> u64 {
+        // deep level or large factor saturates instead of overflow
+        (config.level_size_expand_factor as u64)
+            .checked_pow(level as u32)
+            .unwrap_or(u64::MAX)
+            .saturating_mul(config.level_size_base as u64)
     }
 
     pub fn get_all_file_ids(&self) -> HashSet<FileId> {
         let mut res = HashSet::new();
-        for (_, level) in &self.levels {
+        for level in self.levels.values() {
             res.extend(level.get_all_file_id());
         }
         res
     }
 }
 
+impl CompactionTask {
+    fn new(level: usize, sstable: &SStableFileMeta, next_level: Option<&Level>) -> Self {
+        let mut start_key = sstable.start_key();
+        let mut last_key = sstable.last_key();
+        let mut input_file_ids = vec![sstable.file_id()];
+        // output covers overlapped sstables of next level too
+        if let Some((overlap, _)) = next_level.and_then(|l| l.key_overlap(&start_key, &last_key)) {
+            start_key = start_key.min(overlap.first().unwrap().start_key());
+            last_key = last_key.max(overlap.last().unwrap().last_key());
+            input_file_ids.extend(overlap.iter().map(|meta| meta.file_id()));
+        }
+        CompactionTask {
+            style: CompactionStyle::Level,
+            level,
+            sstables: vec![sstable.clone()],
+            input_file_ids,
+            output_range: Some((start_key, last_key)),
+            manual: false,
+            rewrite: false,
+        }
+    }
+
+    // rewrite sstable of bottom level to drop deleted kvs
+    fn new_rewrite(level: usize, sstable: &SStableFileMeta) -> Self {
+        CompactionTask {
+            style: CompactionStyle::Level,
+            level,
+            sstables: vec![sstable.clone()],
+            input_file_ids: vec![sstable.file_id()],
+            output_range: Some((sstable.start_key(), sstable.last_key())),
+            manual: true,
+            rewrite: true,
+        }
+    }
+
+    // sstables of level 0 are merged or dropped
+    fn new_level_0(style: CompactionStyle, sstables: &[SStableFileMeta]) -> Self {
+        CompactionTask {
+            style,
+            level: 0,
+            sstables: sstables.to_vec(),
+            input_file_ids: sstables.iter().map(|meta| meta.file_id()).collect(),
+            output_range: None,
+            manual: false,
+            rewrite: false,
+        }
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    // level sstables of task are written to
+    fn output_level(&self) -> usize {
+        if self.rewrite {
+            self.level
+        } else {
+            self.level + 1
+        }
+    }
+}
+
+impl CompactingFiles {
+    pub fn new() -> Self {
+        CompactingFiles {
+            tasks: HashMap::new(),
+            next_task_id: 0,
+        }
+    }
+
+    // return id to remove task when it is finished
+    pub fn add(&mut self, task: CompactionTask) -> u64 {
+        let id = self.next_task_id;
+        self.next_task_id += 1;
+        self.tasks.insert(id, task);
+        id
+    }
+
+    pub fn remove(&mut self, task_id: u64) {
+        self.tasks.remove(&task_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn contains(&self, meta: &SStableFileMeta) -> bool {
+        self.tasks
+            .values()
+            .any(|t| t.input_file_ids.contains(&meta.file_id()))
+    }
+
+    // true if task reads file of running compaction or writes to key range of it in same level
+    pub fn conflict(&self, task: &CompactionTask) -> bool {
+        self.tasks.values().any(|t| {
+            let range_overlap = match (&t.output_range, &task.output_range) {
+                (Some((t_start, t_last)), Some((start, last))) => {
+                    t.output_level() == task.output_level() && t_start.le(last) && start.le(t_last)
+                }
+                _ => false,
+            };
+            range_overlap
+                || task
+                    .input_file_ids
+                    .iter()
+                    .any(|id| t.input_file_ids.contains(id))
+        })
+    }
+}
+
 impl Drop for Version {
     fn drop(&mut self) {
         let file_ids = self.get_all_file_ids();
@@ -378,20 +1160,21 @@ mod test {
     use std::collections::HashMap;
     use std::num::NonZeroUsize;
     use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     use anyhow::Result;
     use crossbeam::channel::{bounded, unbounded};
     use lru::LruCache;
     use tempfile::tempdir;
 
-    use crate::db::config::Config;
+    use crate::db::config::{CompactionStyle, Config};
     use crate::db::file_storage::FileStorageManager;
     use crate::db::key::Key;
     use crate::db::level::{CompactSStableResult, LevelChange, SStableFileMeta};
     use crate::db::memtable::Memtable;
     use crate::db::sstable::test::{build_sstable, build_sstable_with_special_value};
     use crate::db::value::Value;
-    use crate::db::version::Version;
+    use crate::db::version::{CompactingFiles, Version};
 
     fn build_level() -> Result<Version> {
         // level 0: sstable_a[12,18),sstable_b[15,20)
@@ -403,31 +1186,39 @@ mod test {
         let (file_b, file_b_id, _) = file_manager.new_file().unwrap();
         let (file_a, file_a_id, _) = file_manager.new_file().unwrap();
         let sstable_c = build_sstable(11, 15, 1, file_c);
-        let c_meta = SStableFileMeta::from(&sstable_c, file_c_id);
+        let c_meta = SStableFileMeta::from(&sstable_c, file_c_id).unwrap();
         let sstable_d = build_sstable(17, 21, 1, file_d);
-        let d_meta = SStableFileMeta::from(&sstable_d, file_d_id);
+        let d_meta = SStableFileMeta::from(&sstable_d, file_d_id).unwrap();
 
         let mut map = HashMap::new();
         map.insert(16, Some(Value::new("a")));
         let sstable_a = build_sstable_with_special_value(12, 18, 1, map, file_a);
-        let a_meta = SStableFileMeta::from(&sstable_a, file_a_id);
+        let a_meta = SStableFileMeta::from(&sstable_a, file_a_id).unwrap();
         let mut map = HashMap::new();
         map.insert(16, Some(Value::new("b")));
         map.insert(18, Some(Value::new("b")));
         let sstable_b = build_sstable_with_special_value(15, 20, 1, map, file_b);
-        let b_meta = SStableFileMeta::from(&sstable_b, file_b_id);
+        let b_meta = SStableFileMeta::from(&sstable_b, file_b_id).unwrap();
 
         let level_0_level_change_b = LevelChange::MemtableCompact {
             sstable_file_metas: b_meta,
+            memtable_log_number: 0,
+            last_sequence: 0,
         };
         let level_0_level_change_a = LevelChange::MemtableCompact {
             sstable_file_metas: a_meta,
+            memtable_log_number: 0,
+            last_sequence: 0,
         };
         let level_0_level_change_c = LevelChange::MemtableCompact {
             sstable_file_metas: c_meta.clone(),
+            memtable_log_number: 0,
+            last_sequence: 0,
         };
         let level_0_level_change_d = LevelChange::MemtableCompact {
             sstable_file_metas: d_meta.clone(),
+            memtable_log_number: 0,
+            last_sequence: 0,
         };
         let level_1_level_change_c = LevelChange::LevelCompact {
             compact_from_level: 0,
@@ -474,6 +1265,39 @@ mod test {
         assert_eq!(format!("{:?}", version), s);
     }
 
+    #[test]
+    pub fn test_snapshot() {
+        let version = build_level().unwrap();
+        let dir = tempdir().unwrap();
+        let file_manager = FileStorageManager::new(dir.path());
+        // snapshot replaces levels built by changes before it
+        let meta_log = vec![
+            LevelChange::MemtableCompact {
+                sstable_file_metas: version.get_level_for_test(1).copy_sstable_meta()[0].clone(),
+                memtable_log_number: 3,
+                last_sequence: 10,
+            },
+            version.snapshot(),
+        ];
+        let mut iter = meta_log.into_iter();
+        let version_from_snapshot = Version::from_for_test(
+            &mut iter,
+            dir.into_path(),
+            Arc::new(Mutex::new(file_manager)),
+            Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()))),
+        )
+        .unwrap();
+        assert_eq!(
+            format!("{:?}", version_from_snapshot),
+            format!("{:?}", version)
+        );
+        assert_eq!(
+            version_from_snapshot.memtable_log_number(),
+            version.memtable_log_number()
+        );
+        assert_eq!(version_from_snapshot.last_sequence(), 0);
+    }
+
     #[test]
     pub fn test_depth() {
         let version = build_level().unwrap();
@@ -500,14 +1324,15 @@ mod test {
         use crate::db::sstable::{SSTable, SStableBlockMeta, SStableIter};
         let version = build_level().unwrap();
         let memtable = Memtable::new();
-        memtable.insert(&Key::new("12"), &Value::new("mem"));
-        memtable.insert(&Key::new("7"), &Value::new("mem"));
+        memtable.insert(&Key::new("12"), 1, &Value::new("mem"));
+        memtable.insert(&Key::new("7"), 2, &Value::new("mem"));
 
-        let level_change = version.add_memtable_to_level_0(&memtable).unwrap();
+        let level_change = version.add_memtable_to_level_0(&memtable, 5, &[]).unwrap();
 
         println!("level change {:?}", level_change);
         let new_version = version.apply_change(level_change);
         println!("version {:?}", new_version);
+        assert_eq!(new_version.memtable_log_number(), 5);
         assert_eq!(
             version.get(&Key::new("17")).unwrap(),
             Some(Value::new("17"))
@@ -534,25 +1359,172 @@ mod test {
         let mut config = Config::new();
         config.level_0_file_limit = 1;
         config.level_size_expand_factor = 1;
+        // score of level 1 is 1.5, less than score 2 of level 0
+        config.level_size_base = (version_0.get_level_for_test(1).size() * 2 / 3) as usize;
         version_0.set_config(config);
-        let level_change = version_0.compact_one_level().unwrap().unwrap();
+        let level_change = version_0.compact_one_level(&[]).unwrap().unwrap();
         let version_1 = version_0.apply_change(level_change);
         // println!("{:?}", version_1);
         assert_eq!(format!("{:?}", version_1),"level: 0,data file_id:3,file_start_key:Key { k: \"12\" },file_end_key:Key { k: \"17\" }\n\nlevel: 1,data file_id:0,file_start_key:Key { k: \"11\" },file_end_key:Key { k: \"14\" }\nfile_id:4,file_start_key:Key { k: \"15\" },file_end_key:Key { k: \"20\" }\n\n");
         let res = version_1.get(&Key::new("16")).unwrap().unwrap();
         assert_eq!(res, Value::new("a"));
 
-        let level_change = version_1.compact_one_level().unwrap().unwrap();
-        let version_2 = version_1.apply_change(level_change);
+        let level_change = version_1.compact_one_level(&[]).unwrap().unwrap();
+        let mut version_2 = version_1.apply_change(level_change);
         // println!("{:?}", version_2);
         assert_eq!(format!("{:?}", version_2),"level: 0,data file_id:3,file_start_key:Key { k: \"12\" },file_end_key:Key { k: \"17\" }\n\nlevel: 1,data file_id:4,file_start_key:Key { k: \"15\" },file_end_key:Key { k: \"20\" }\n\nlevel: 2,data file_id:0,file_start_key:Key { k: \"11\" },file_end_key:Key { k: \"14\" }\n\n");
 
-        let level_change = version_2.compact_one_level().unwrap().unwrap();
+        // only level 1 is larger than target size
+        let mut config = version_2.config.clone();
+        config.level_size_base = version_2.get_level_for_test(1).size() as usize - 1;
+        version_2.set_config(config);
+        let level_change = version_2.compact_one_level(&[]).unwrap().unwrap();
         let version_3 = version_2.apply_change(level_change);
         // println!("{:?}", version_3);
         assert_eq!(format!("{:?}", version_3),"level: 0,data file_id:3,file_start_key:Key { k: \"12\" },file_end_key:Key { k: \"17\" }\n\nlevel: 1,data \nlevel: 2,data file_id:0,file_start_key:Key { k: \"11\" },file_end_key:Key { k: \"14\" }\nfile_id:4,file_start_key:Key { k: \"15\" },file_end_key:Key { k: \"20\" }\n\n");
     }
 
+    #[test]
+    pub fn test_parallel_compaction() {
+        let mut version = build_level().unwrap();
+        let mut config = Config::new();
+        config.level_0_file_limit = 1;
+        config.level_size_expand_factor = 1;
+        config.level_size_base = (version.get_level_for_test(1).size() * 2 / 3) as usize;
+        version.set_config(config);
+        let mut compacting = CompactingFiles::new();
+
+        // level 0 [15,19] with level 1 [17,20]
+        let task_0 = version.pick_compaction(&compacting).unwrap();
+        assert_eq!(task_0.level(), 0);
+        let task_0_id = compacting.add(task_0.clone());
+        // level 0 [12,17] overlaps level 1 [17,20] which is being compacted, pick level 1 [11,14]
+        let task_1 = version.pick_compaction(&compacting).unwrap();
+        assert_eq!(task_1.level(), 1);
+        compacting.add(task_1.clone());
+        assert!(version.pick_compaction(&compacting).is_none());
+        compacting.remove(task_0_id);
+        assert_eq!(compacting.len(), 1);
+
+        // compactions are applied in the order they finish
+        let change_0 = version.compact(&task_0, &[]).unwrap();
+        let change_1 = version.compact(&task_1, &[]).unwrap();
+        let new_version = version.apply_change(change_1).apply_change(change_0);
+        assert_eq!(format!("{:?}", new_version),"level: 0,data file_id:3,file_start_key:Key { k: \"12\" },file_end_key:Key { k: \"17\" }\n\nlevel: 1,data file_id:4,file_start_key:Key { k: \"15\" },file_end_key:Key { k: \"20\" }\n\nlevel: 2,data file_id:0,file_start_key:Key { k: \"11\" },file_end_key:Key { k: \"14\" }\n\n");
+        for (k, v) in [("11", "11"), ("16", "a"), ("18", "b"), ("20", "20")] {
+            assert_eq!(new_version.get_str(k).unwrap(), Some(Value::new(v)));
+        }
+    }
+
+    #[test]
+    pub fn test_universal_compaction() {
+        // level 0: a [10,13) set 11 to a, b [10,13) set 11 to b, c [10,90)
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        let mut meta_log = vec![];
+        let runs: [(usize, Option<&str>); 3] = [(90, None), (13, Some("b")), (13, Some("a"))];
+        for (end, value) in runs {
+            let (file, file_id, _) = file_manager.new_file().unwrap();
+            let mut map = HashMap::new();
+            if let Some(v) = value {
+                map.insert(11, Some(Value::new(v)));
+            }
+            let sstable = build_sstable_with_special_value(10, end, 1, map, file);
+            meta_log.push(LevelChange::MemtableCompact {
+                sstable_file_metas: SStableFileMeta::from(&sstable, file_id).unwrap(),
+                memtable_log_number: 0,
+                last_sequence: 0,
+            });
+        }
+        let mut version = Version::from_for_test(
+            &mut meta_log.into_iter(),
+            dir.into_path(),
+            Arc::new(Mutex::new(file_manager)),
+            Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()))),
+        )
+        .unwrap();
+        let mut config = Config::new();
+        config.compaction_style = CompactionStyle::Universal;
+        config.universal_max_sorted_runs = 3;
+        version.set_config(config.clone());
+        assert!(version.compact_one_level(&[]).unwrap().is_none());
+
+        // a and b have same size
+        config.universal_max_sorted_runs = 2;
+        version.set_config(config.clone());
+        let level_change = version.compact_one_level(&[]).unwrap().unwrap();
+        let mut version = version.apply_change(level_change);
+        assert_eq!(version.depth(), 1);
+        let metas = version.get_level_for_test(0).copy_sstable_meta();
+        assert_eq!(metas.len(), 2);
+        assert_eq!(metas[0].file_id(), 3);
+        assert_eq!(metas[1].file_id(), 0);
+        assert_eq!(version.get_str("11").unwrap(), Some(Value::new("a")));
+
+        // runs have different size, merge newest ones to reduce number of runs
+        config.universal_max_sorted_runs = 1;
+        version.set_config(config);
+        let level_change = version.compact_one_level(&[]).unwrap().unwrap();
+        let version = version.apply_change(level_change);
+        assert_eq!(version.get_level_for_test(0).len(), 1);
+        assert_eq!(version.get_str("11").unwrap(), Some(Value::new("a")));
+        assert_eq!(version.get_str("12").unwrap(), Some(Value::new("12")));
+        assert_eq!(version.get_str("89").unwrap(), Some(Value::new("89")));
+    }
+
+    #[test]
+    pub fn test_fifo_compaction() {
+        // level 0: c [30,40), b [20,30), a [10,20) which is created long ago
+        let dir = tempdir().unwrap();
+        let mut file_manager = FileStorageManager::new(dir.path());
+        let mut meta_log = vec![];
+        for start in [10, 20, 30] {
+            let (file, file_id, _) = file_manager.new_file().unwrap();
+            let sstable = build_sstable(start, start + 10, 1, file);
+            let mut meta = SStableFileMeta::from(&sstable, file_id).unwrap();
+            if start == 10 {
+                meta.set_create_time(1000);
+            }
+            meta_log.push(LevelChange::MemtableCompact {
+                sstable_file_metas: meta,
+                memtable_log_number: 0,
+                last_sequence: 0,
+            });
+        }
+        let mut version = Version::from_for_test(
+            &mut meta_log.into_iter(),
+            dir.into_path(),
+            Arc::new(Mutex::new(file_manager)),
+            Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()))),
+        )
+        .unwrap();
+        let mut config = Config::new();
+        config.compaction_style = CompactionStyle::Fifo;
+        config.fifo_max_table_files_size = 0;
+        config.fifo_ttl = Duration::from_secs(24 * 3600);
+        version.set_config(config.clone());
+
+        // a is expired
+        let level_change = version.compact_one_level(&[]).unwrap().unwrap();
+        let mut version = version.apply_change(level_change);
+        assert_eq!(version.get_level_for_test(0).len(), 2);
+        assert!(version.get_str("15").unwrap().is_none());
+        assert_eq!(version.get_str("25").unwrap(), Some(Value::new("25")));
+        assert!(version.compact_one_level(&[]).unwrap().is_none());
+
+        // b and c are larger than limit
+        let metas = version.get_level_for_test(0).copy_sstable_meta();
+        config.fifo_max_table_files_size = metas[0].file_size() as usize;
+        config.fifo_ttl = Duration::ZERO;
+        version.set_config(config);
+        let level_change = version.compact_one_level(&[]).unwrap().unwrap();
+        let version = version.apply_change(level_change);
+        let metas = version.get_level_for_test(0).copy_sstable_meta();
+        assert_eq!(metas.len(), 1);
+        assert_eq!(metas[0].file_id(), 2);
+        assert!(version.compact_one_level(&[]).unwrap().is_none());
+    }
+
     #[test]
     pub fn test_get() {
         let version = build_level().unwrap();
@@ -576,9 +1548,9 @@ mod test {
     #[test]
     pub fn test_level_size_limit() {
         let config = Config::new();
-        assert_eq!(Version::level_file_number_limit(0, &config), 4);
-        assert_eq!(Version::level_file_number_limit(1, &config), 5);
-        assert_eq!(Version::level_file_number_limit(2, &config), 50);
+        assert_eq!(Version::level_size_limit(1, &config), 10 * 1024 * 1024);
+        assert_eq!(Version::level_size_limit(2, &config), 100 * 1024 * 1024);
+        assert_eq!(Version::level_size_limit(100, &config), u64::MAX);
     }
     #[test]
     pub fn test_get_file_ids() {
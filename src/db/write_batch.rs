@@ -1,40 +1,149 @@
-use super::{key::Key, value::Value};
+use super::{
+    column_family::{ColumnFamilyId, DEFAULT_COLUMN_FAMILY_ID},
+    key::Key,
+    value::{now_millis, Value},
+};
 use anyhow::Result;
+use std::time::Duration;
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
 pub enum Operation {
-    PUT { key: Key, value: Value },
-    DELETE { key: Key },
+    PUT {
+        key: Key,
+        value: Value,
+    },
+    DELETE {
+        key: Key,
+    },
+    // delete all keys in [start,end)
+    DELETE_RANGE {
+        start: Key,
+        end: Key,
+    },
+    // operand combined with value of key by merge operator
+    MERGE {
+        key: Key,
+        value: Value,
+    },
+    // value is read as deleted after expire time, milliseconds since unix epoch
+    PUT_WITH_EXPIRE {
+        key: Key,
+        value: Value,
+        expire_time: u64,
+    },
 }
+// operations of all column families in batch are written atomically
 pub struct WriteBatch {
     ops: Vec<Operation>,
+    // column family of each operation
+    column_families: Vec<ColumnFamilyId>,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WriteBatch {
     pub fn new() -> Self {
-        WriteBatch { ops: Vec::new() }
+        WriteBatch {
+            ops: Vec::new(),
+            column_families: Vec::new(),
+        }
     }
     pub fn put(&mut self, key: Key, value: Value) {
-        self.ops.push(Operation::PUT {
-            key: key,
-            value: value,
-        });
+        self.put_cf(DEFAULT_COLUMN_FAMILY_ID, key, value);
+    }
+    pub fn put_cf(&mut self, column_family: ColumnFamilyId, key: Key, value: Value) {
+        self.push(column_family, Operation::PUT { key, value });
     }
     pub fn delete(&mut self, key: Key) {
-        self.ops.push(Operation::DELETE { key: key });
+        self.delete_cf(DEFAULT_COLUMN_FAMILY_ID, key);
+    }
+    pub fn delete_cf(&mut self, column_family: ColumnFamilyId, key: Key) {
+        self.push(column_family, Operation::DELETE { key });
+    }
+    // delete keys in [start,end), nothing is deleted if start is not less than end
+    pub fn delete_range(&mut self, start: Key, end: Key) {
+        self.delete_range_cf(DEFAULT_COLUMN_FAMILY_ID, start, end);
+    }
+    pub fn delete_range_cf(&mut self, column_family: ColumnFamilyId, start: Key, end: Key) {
+        self.push(column_family, Operation::DELETE_RANGE { start, end });
+    }
+    // value expires after ttl from now
+    pub fn put_with_ttl(&mut self, key: Key, value: Value, ttl: Duration) {
+        self.put_with_ttl_cf(DEFAULT_COLUMN_FAMILY_ID, key, value, ttl);
+    }
+    pub fn put_with_ttl_cf(
+        &mut self,
+        column_family: ColumnFamilyId,
+        key: Key,
+        value: Value,
+        ttl: Duration,
+    ) {
+        let expire_time = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.push(
+            column_family,
+            Operation::PUT_WITH_EXPIRE {
+                key,
+                value,
+                expire_time,
+            },
+        );
+    }
+    pub fn merge(&mut self, key: Key, value: Value) {
+        self.merge_cf(DEFAULT_COLUMN_FAMILY_ID, key, value);
+    }
+    // operand is combined by merge operator of column family
+    pub fn merge_cf(&mut self, column_family: ColumnFamilyId, key: Key, value: Value) {
+        self.push(column_family, Operation::MERGE { key, value });
+    }
+    fn push(&mut self, column_family: ColumnFamilyId, op: Operation) {
+        self.ops.push(op);
+        self.column_families.push(column_family);
+    }
+    // true if key of default column family is written or deleted by batch
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.iter().any(|(column_family, op)| {
+            column_family == DEFAULT_COLUMN_FAMILY_ID && Self::has_key(op, key)
+        })
+    }
+    fn has_key(op: &Operation, key: &Key) -> bool {
+        match op {
+            Operation::PUT { key: k, .. }
+            | Operation::DELETE { key: k }
+            | Operation::MERGE { key: k, .. }
+            | Operation::PUT_WITH_EXPIRE { key: k, .. } => k == key,
+            Operation::DELETE_RANGE { start, end } => start <= key && key < end,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
     }
     pub fn to_opertions(&self) -> &Vec<Operation> {
         &self.ops
     }
+    // operations with their column families in order
+    pub fn iter(&self) -> impl Iterator<Item = (ColumnFamilyId, &Operation)> {
+        self.column_families.iter().copied().zip(self.ops.iter())
+    }
     pub fn size(&self) -> usize {
         let mut res = 0;
         for entry in &self.ops {
             match entry {
-                Operation::PUT { key, value } => {
+                Operation::PUT { key, value }
+                | Operation::MERGE { key, value }
+                | Operation::PUT_WITH_EXPIRE { key, value, .. } => {
                     res += key.len() + value.len();
                 }
                 Operation::DELETE { key } => {
                     res += key.len();
                 }
+                Operation::DELETE_RANGE { start, end } => {
+                    res += start.len() + end.len();
+                }
             }
         }
         res
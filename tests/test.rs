@@ -4,13 +4,19 @@ use lsm_db::{
 };
 use std::{
     collections::HashSet,
+    env,
     fs::{create_dir, remove_dir_all},
+    io::{BufRead, BufReader, Write},
     path::PathBuf,
+    process::{Command, Stdio},
     sync::{atomic::AtomicU64, Arc, Mutex},
     thread::spawn,
 };
 use tempfile::tempdir;
 
+// sampled (key_id, value_id) pairs shared by write and check routine, value_id is None if deleted
+type CheckMap = Vec<Arc<Mutex<Option<(u64, Option<u64>)>>>>;
+
 fn random_number(num: u64) -> u64 {
     (num * 1103515245 + 12345) % (2 << 31)
 }
@@ -65,19 +71,96 @@ fn full_test() {
     let db_client = db.new_client().unwrap();
     check_routine(round, 10, db_client, lock_map);
 }
+
+// set in writer process of kill_and_reopen_test
+const KILL_TEST_DIR: &str = "LSM_DB_KILL_TEST_DIR";
+const KILL_TEST_START: &str = "LSM_DB_KILL_TEST_START";
+
+fn kill_test_config() -> Config {
+    let mut c = Config::new();
+    // flush memtable, compact and roll meta log frequently
+    c.memtable_size_limit = 10 * 1024;
+    c.meta_log_size_limit = 4 * 1024;
+    c
+}
+
+// writer process is killed in each generation, all finished writes are kept after reopen
+#[test]
+fn kill_and_reopen_test() {
+    if let Ok(dir) = env::var(KILL_TEST_DIR) {
+        let start = env::var(KILL_TEST_START).unwrap().parse().unwrap();
+        kill_test_writer(PathBuf::from(dir), start);
+        return;
+    }
+    let generation = 5;
+    let write_per_generation = 3000;
+    let dir = tempdir().unwrap();
+    let config = kill_test_config();
+    DBServer::new_with_confing(PathBuf::from(dir.path()), config.clone())
+        .unwrap()
+        .close()
+        .unwrap();
+
+    // keys in [0,finished) are written
+    let mut finished = 0;
+    for g in 1..=generation {
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args(["kill_and_reopen_test", "--exact", "--nocapture"])
+            .env(KILL_TEST_DIR, dir.path())
+            .env(KILL_TEST_START, finished.to_string())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let reader = BufReader::new(child.stdout.take().unwrap());
+        for line in reader.lines() {
+            if let Some(n) = line.unwrap().strip_prefix("finished ") {
+                finished = n.parse().unwrap();
+            }
+            if finished >= g * write_per_generation {
+                break;
+            }
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(finished >= g * write_per_generation, "writer exits early");
+
+        let db = DBServer::open_db(PathBuf::from(dir.path()), config.clone()).unwrap();
+        let db_client = db.new_client().unwrap();
+        for i in 0..finished {
+            let res = db_client.get(&Key::from_u64(i)).unwrap();
+            assert_eq!(res, Some(Value::from_u64(i)), "generation {} key {}", g, i);
+        }
+        drop(db_client);
+        db.close().unwrap();
+    }
+}
+
+// write keys from start until killed, print number of finished keys
+fn kill_test_writer(path: PathBuf, start: u64) {
+    let db = DBServer::open_db(path, kill_test_config()).unwrap();
+    let mut db_client = db.new_client().unwrap();
+    let mut stdout = std::io::stdout();
+    for i in start.. {
+        db_client
+            .put(&Key::from_u64(i), Value::from_u64(i))
+            .unwrap();
+        writeln!(stdout, "finished {}", i + 1).unwrap();
+    }
+}
+
 fn write_routine(
     round: u64,
     mut rand: u64,
     global_id: Arc<AtomicU64>,
     mut db_client: DBClient,
     check_set: HashSet<u64>,
-    lock_map: Vec<Arc<Mutex<Option<(u64, Option<u64>)>>>>,
+    lock_map: CheckMap,
 ) {
     for _ in 0..round {
         let mut lock_option = None;
         rand = random_number(rand);
         let mut is_checked_id = false;
-        let id = if rand % 50 == 0 {
+        let id = if rand.is_multiple_of(50) {
             let id = rand % 100;
             let lock = &lock_map[id as usize];
             lock_option = Some(lock.lock().unwrap());
@@ -146,12 +229,7 @@ fn write_routine(
         }
     }
 }
-fn check_routine(
-    round: u64,
-    mut rand: u64,
-    db_client: DBClient,
-    lock_map: Vec<Arc<Mutex<Option<(u64, Option<u64>)>>>>,
-) {
+fn check_routine(round: u64, mut rand: u64, db_client: DBClient, lock_map: CheckMap) {
     for _ in 0..round {
         rand = random_number(rand);
         let kv = lock_map
@@ -162,7 +240,7 @@ fn check_routine(
 
         if let Some((key_id, value_id)) = kv.as_ref() {
             let res = db_client.get(&Key::from_u64(*key_id)).unwrap();
-            let expect = value_id.map(|n| Value::from_u64(n));
+            let expect = value_id.map(Value::from_u64);
             assert_eq!(res, expect, "key not match {}", key_id);
         }
     }